@@ -0,0 +1,110 @@
+use std::{
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use anyhow::{anyhow, Context, Result};
+use flate2::{write::GzEncoder, Compression};
+
+use crate::{config, file_ops};
+
+/// Writes the dir, and everything in it, to a gzipped tar file at
+/// `archive_path`. The entries are named after the dir, e.g. 'dir/ta/ta.cer'.
+/// The archive is written to a temporary file first, which is renamed when
+/// complete, so a file at `archive_path` is always a complete archive.
+/// Returns the size of the archive.
+pub fn tar_gz_dir(dir: &Path, archive_path: &Path) -> Result<u64> {
+    let name = dir
+        .file_name()
+        .ok_or_else(|| anyhow!("Cannot archive dir without a name: {}", dir.display()))?
+        .to_string_lossy()
+        .to_string();
+    let tmp_path = file_ops::path_with_extension(archive_path, config::TMP_FILE_EXT);
+
+    let file = file_ops::create_file(&tmp_path)?;
+    let mut tar = tar::Builder::new(GzEncoder::new(BufWriter::new(file), Compression::default()));
+    write_tar(&mut tar, dir, &name)
+        .and_then(|()| {
+            let mut out = tar.into_inner()?.finish()?;
+            out.flush()?;
+            out.get_ref().sync_all()?;
+            Ok(())
+        })
+        .with_context(|| format!("Could not write archive: {}", tmp_path.display()))?;
+
+    std::fs::rename(&tmp_path, archive_path).with_context(|| {
+        format!("Could not rename {} to {}", tmp_path.display(), archive_path.display())
+    })?;
+
+    Ok(std::fs::metadata(archive_path)
+        .with_context(|| format!("Cannot read archive: {}", archive_path.display()))?
+        .len())
+}
+
+/// Adds the dirs and then the files under `dir` to the archive, in order of
+/// path. The content of each file is streamed from disk.
+fn write_tar<W: Write>(tar: &mut tar::Builder<W>, dir: &Path, name: &str) -> Result<()> {
+    let mut dirs = file_ops::dirs_depth_first(dir)?;
+    dirs.sort();
+    let mut files = file_ops::files_recursive(dir)?;
+    files.sort();
+
+    let entry_name = |path: &Path| -> Result<_> {
+        Ok(Path::new(name).join(path.strip_prefix(dir)?))
+    };
+
+    for path in &dirs {
+        tar.append_dir(entry_name(path)?, path)
+            .with_context(|| format!("Cannot archive dir: {}", path.display()))?;
+    }
+
+    for path in &files {
+        tar.append_path_with_name(path, entry_name(path)?)
+            .with_context(|| format!("Cannot archive file: {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::util::test_with_dir;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn archive_dir_readable_by_tar() {
+        test_with_dir("archive_archive_dir_readable_by_tar", |dir| {
+            let revision = dir.join("serial_1");
+            let long_name = format!("repo/{}.roa", "a".repeat(120));
+            file_ops::write_buf(&revision.join("ta/ta.cer"), b"cer").unwrap();
+            file_ops::write_buf(&revision.join(&long_name), &[7; 1000]).unwrap();
+            std::fs::create_dir_all(revision.join("empty")).unwrap();
+
+            let archive_path = dir.join("archive/serial_1.tar.gz");
+            let size = tar_gz_dir(&revision, &archive_path).unwrap();
+            assert_eq!(size, std::fs::metadata(&archive_path).unwrap().len());
+            assert!(!dir.join("archive/serial_1.tar.gz.tmp").exists());
+
+            let extracted = dir.join("extracted");
+            std::fs::create_dir_all(&extracted).unwrap();
+            let status = std::process::Command::new("tar")
+                .arg("-xzf")
+                .arg(&archive_path)
+                .arg("-C")
+                .arg(&extracted)
+                .status()
+                .unwrap();
+            assert!(status.success());
+
+            assert_eq!(std::fs::read(extracted.join("serial_1/ta/ta.cer")).unwrap(), b"cer");
+            assert_eq!(
+                std::fs::read(extracted.join("serial_1").join(&long_name)).unwrap(),
+                vec![7; 1000]
+            );
+            assert!(extracted.join("serial_1/empty").is_dir());
+        })
+    }
+}
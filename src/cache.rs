@@ -0,0 +1,152 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use filetime::{set_file_mtime, FileTime};
+use rpki::{rrdp::Hash, uri::Https};
+use tracing::{debug, info, warn};
+
+use crate::file_ops;
+
+//------------ RrdpCache -----------------------------------------------------
+
+/// An on-disk cache for RRDP snapshot and delta files, which is kept across
+/// runs. Published files never change, so an entry is identified by the URI
+/// and hash of the file. The hash is checked again when an entry is used.
+///
+/// The total size of the entries is kept under `max_bytes` by evicting the
+/// least recently used entries. The mtime of an entry is updated whenever it
+/// is used, so that it reflects the last use.
+#[derive(Clone, Debug)]
+pub struct RrdpCache {
+    dir: PathBuf,
+    max_bytes: u64,
+}
+
+impl RrdpCache {
+    pub fn new(dir: PathBuf, max_bytes: u64) -> Self {
+        RrdpCache { dir, max_bytes }
+    }
+
+    fn path(&self, uri: &Https, hash: Hash) -> PathBuf {
+        let key = Hash::from_data(format!("{}\n{}", uri, hash).as_bytes());
+        self.dir.join(key.to_string())
+    }
+
+    /// Copies the cached file for the URI and hash to the target. Returns
+    /// false if there is no usable entry, in which case the file should be
+    /// downloaded. An entry which does not match the hash is removed.
+    pub fn get(&self, uri: &Https, hash: Hash, target: &Path) -> Result<bool> {
+        let path = self.path(uri, hash);
+        if !path.is_file() {
+            return Ok(false);
+        }
+
+        let bytes = file_ops::read_file(&path)?;
+        if !hash.matches(bytes.as_ref()) {
+            warn!(
+                "Cached file for {} does not match hash {}, will download it again",
+                uri, hash
+            );
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Cannot remove cached file: {}", path.display()))?;
+            return Ok(false);
+        }
+
+        file_ops::write_buf(target, bytes.as_ref())?;
+        set_file_mtime(&path, FileTime::now())
+            .with_context(|| format!("Cannot update mtime of cached file: {}", path.display()))?;
+
+        debug!("Using cached file for {}", uri);
+        Ok(true)
+    }
+
+    /// Adds the downloaded file at `source` to the cache, and then evicts the
+    /// least recently used entries if the cache is over its size limit.
+    pub fn put(&self, uri: &Https, hash: Hash, source: &Path) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)
+            .with_context(|| format!("Cannot create cache dir: {}", self.dir.display()))?;
+
+        let path = self.path(uri, hash);
+        std::fs::copy(source, &path).with_context(|| {
+            format!("Cannot copy {} to cache at {}", source.display(), path.display())
+        })?;
+
+        self.evict()
+    }
+
+    fn evict(&self) -> Result<()> {
+        let mut entries = vec![];
+        let mut total = 0;
+
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Cannot read cache dir: {}", self.dir.display()))?
+        {
+            let entry = entry?;
+            let meta = entry.metadata()?;
+            if meta.is_file() {
+                total += meta.len();
+                entries.push((FileTime::from_last_modification_time(&meta), meta.len(), entry.path()));
+            }
+        }
+
+        // Oldest first
+        entries.sort();
+
+        let mut evicted = 0;
+        for (_, size, path) in entries {
+            if total <= self.max_bytes {
+                break;
+            }
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Cannot remove cached file: {}", path.display()))?;
+            total -= size;
+            evicted += 1;
+        }
+
+        if evicted > 0 {
+            info!("Evicted {} files from the RRDP cache", evicted);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::util::{https, test_with_dir};
+
+    use super::*;
+
+    #[test]
+    fn evict_least_recently_used() {
+        test_with_dir("cache_evict_least_recently_used", |dir| {
+            let cache = RrdpCache::new(dir.join("cache"), 25);
+            let source = dir.join("source.xml");
+            let target = dir.join("target.xml");
+
+            let mut entries = vec![];
+            for i in 0..3 {
+                let data = format!("delta number {}", i);
+                std::fs::write(&source, &data).unwrap();
+                let uri = https(&format!("https://example.com/rrdp/{}/delta.xml", i));
+                let hash = Hash::from_data(data.as_bytes());
+                cache.put(&uri, hash, &source).unwrap();
+                entries.push((uri, hash));
+
+                // Make the order of use unambiguous
+                let time = FileTime::from_unix_time(1_600_000_000 + i, 0);
+                set_file_mtime(cache.path(&entries[i as usize].0, hash), time).unwrap();
+            }
+
+            // Each entry is 14 bytes, so only the most recently used one fits.
+            assert!(!cache.get(&entries[1].0, entries[1].1, &target).unwrap());
+            assert!(!cache.get(&entries[0].0, entries[0].1, &target).unwrap());
+            assert!(cache.get(&entries[2].0, entries[2].1, &target).unwrap());
+            assert_eq!(std::fs::read_to_string(&target).unwrap(), "delta number 2");
+
+            // A wrong hash is not found
+            assert!(!cache.get(&entries[2].0, entries[0].1, &target).unwrap());
+        })
+    }
+}
@@ -0,0 +1,195 @@
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+
+use anyhow::{anyhow, Context, Result};
+use rpki::rrdp::Hash;
+use tracing::{debug, info};
+
+use crate::{
+    config,
+    file_ops::{self, Fsync},
+};
+
+//------------ ObjectStore ---------------------------------------------------
+
+/// A content-addressed store for object data, which is kept across runs.
+///
+/// Every distinct object is stored once, in a file named after the hex
+/// encoded SHA-256 hash of its content. The files in rsync revision dirs
+/// are hard links to these files. So, objects which are unchanged between
+/// revisions or sessions, or which were merely moved to another URI, share
+/// their data on disk.
+///
+/// With `shard_chars` set, entries are kept in subdirectories named after
+/// that many leading hex characters of their hash, e.g. `ab/abcd..`, so that
+/// no single directory gets hundreds of thousands of entries. This is only
+/// the layout of the store, the rsync paths of the objects do not change.
+///
+/// An entry which is no longer linked from any revision dir has a link
+/// count of one, and is removed by `gc`. This relies on the unix link count,
+/// so the store is only supported on unix.
+#[derive(Debug)]
+pub struct ObjectStore {
+    dir: PathBuf,
+    shard_chars: usize,
+    tmp_counter: AtomicUsize,
+
+    /// The dirs that entries were added to, see `sync_added_dirs`.
+    added_dirs: Mutex<BTreeSet<PathBuf>>,
+}
+
+impl ObjectStore {
+    pub fn new(dir: PathBuf, shard_chars: usize) -> Self {
+        ObjectStore {
+            dir,
+            shard_chars,
+            tmp_counter: AtomicUsize::new(0),
+            added_dirs: Mutex::new(BTreeSet::new()),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, hash: Hash) -> PathBuf {
+        entry_path(&self.dir, hash, self.shard_chars)
+    }
+
+    /// Returns the path of the entry for the given content, storing it first
+    /// if it is not yet present. The content is written to a temporary file
+    /// which is renamed when complete, so that an entry is never partial,
+    /// not even if another thread stores the same content concurrently.
+    ///
+    /// The `prepare` function is called on the temporary file before it is
+    /// renamed, e.g. to set the mtime or flush it to disk. Returns the path
+    /// and whether the entry was added.
+    pub fn store<F>(&self, hash: Hash, data: &[u8], prepare: F) -> Result<(PathBuf, bool)>
+    where
+        F: FnOnce(&Path) -> Result<()>,
+    {
+        let path = self.path(hash);
+        if path.is_file() {
+            return Ok((path, false));
+        }
+
+        // In the same dir as the entry, so that it can be renamed.
+        let tmp_path = path.with_file_name(format!(
+            "{}.{}.{}",
+            hash,
+            self.tmp_counter.fetch_add(1, Ordering::Relaxed),
+            config::TMP_FILE_EXT
+        ));
+
+        file_ops::write_buf(&tmp_path, data)
+            .with_context(|| format!("Could not write object store entry: {}", tmp_path.display()))?;
+        prepare(&tmp_path)?;
+        std::fs::rename(&tmp_path, &path).with_context(|| {
+            format!("Could not rename {} to {}", tmp_path.display(), path.display())
+        })?;
+        if let Some(parent) = path.parent() {
+            self.added_dirs.lock().unwrap().insert(parent.to_path_buf());
+        }
+
+        Ok((path, true))
+    }
+
+    /// Flushes the dirs that entries were added to, i.e. the shard dirs if
+    /// any, and then the store dir itself, which may have new shard dirs. So
+    /// links to the new entries do not outlive the entries after a crash.
+    pub fn sync_added_dirs(&self, fsync: &dyn Fsync) -> Result<()> {
+        for dir in self.added_dirs.lock().unwrap().iter().filter(|dir| *dir != &self.dir) {
+            fsync.sync_dir(dir)?;
+        }
+        fsync.sync_dir(&self.dir)
+    }
+
+    /// Removes all entries which are no longer linked from any revision dir,
+    /// as well as temporary files left behind by interrupted runs. Returns
+    /// the number of removed entries.
+    #[cfg(unix)]
+    pub fn gc(&self) -> Result<usize> {
+        if !self.dir.is_dir() {
+            return Ok(0);
+        }
+
+        // Entries may be in shard dirs, or directly in the store dir, also
+        // when the number of shard chars was changed since they were stored.
+        let mut removed = Self::gc_dir(&self.dir)?;
+        let entries = std::fs::read_dir(&self.dir)
+            .with_context(|| format!("Cannot read object store dir: {}", self.dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                let path = entry.path();
+                removed += Self::gc_dir(&path)?;
+                if std::fs::read_dir(&path)?.next().is_none() {
+                    std::fs::remove_dir(&path).with_context(|| {
+                        format!("Cannot remove object store dir: {}", path.display())
+                    })?;
+                }
+            }
+        }
+
+        if removed > 0 {
+            info!("Removed {} unused object store entries", removed);
+        }
+
+        Ok(removed)
+    }
+
+    #[cfg(unix)]
+    fn gc_dir(dir: &Path) -> Result<usize> {
+        use std::os::unix::fs::MetadataExt;
+
+        let mut removed = 0;
+
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Cannot read object store dir: {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let meta = entry.metadata()?;
+
+            let is_tmp = path.extension().map(|ext| ext == config::TMP_FILE_EXT) == Some(true);
+            if meta.is_file() && (is_tmp || meta.nlink() <= 1) {
+                debug!("Removing unused object store entry: {}", path.display());
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Cannot remove object store entry: {}", path.display()))?;
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    #[cfg(not(unix))]
+    pub fn gc(&self) -> Result<usize> {
+        Err(anyhow!("The object store is only supported on unix"))
+    }
+
+    /// Checks that the object store can be used on this platform.
+    pub fn check_supported() -> Result<()> {
+        if cfg!(unix) {
+            Ok(())
+        } else {
+            Err(anyhow!("The object store is only supported on unix"))
+        }
+    }
+}
+
+/// Returns the path of the entry for the hash in a dir with the layout of an
+/// object store, see `ObjectStore`.
+pub fn entry_path(dir: &Path, hash: Hash, shard_chars: usize) -> PathBuf {
+    let name = hash.to_string();
+    match shard_chars {
+        0 => dir.join(name),
+        _ => dir.join(&name[..shard_chars]).join(name),
+    }
+}
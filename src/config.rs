@@ -1,14 +1,35 @@
-use std::path::{Path, PathBuf};
-use std::usize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::{anyhow, Result};
-use log::LevelFilter;
+use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
 use structopt::clap::{crate_name, crate_version};
 use structopt::StructOpt;
+use tracing::{info, warn};
+use tracing_subscriber::{
+    filter::{LevelFilter, Targets},
+    fmt::{format::Writer, time::FormatTime},
+    prelude::*,
+};
 
 use rpki::uri::Https;
+use uuid::Uuid;
 
-use crate::fetch::{FetchMap, FetchMode, FetchSource, Fetcher};
+use crate::{
+    cache::RrdpCache,
+    cas::ObjectStore,
+    fetch::{FetchMap, FetchMode, FetchSource, Fetcher, HttpHeader, HttpSettings, IpVersion},
+    file_ops::{self, FilePermissions, FsRetry},
+    progress::{Progress, ProgressReporter},
+    target::{LocalTarget, SftpTarget, WriteTarget},
+    util,
+};
 
 pub const DELTA_FNAME: &str = "delta.xml";
 pub const NOTIFICATION_FNAME: &str = "notification.xml";
@@ -18,12 +39,31 @@ pub const REPORT_MIN: usize = 200;
 pub const SNAPSHOT_FNAME: &str = "snapshot.xml";
 pub const TMP_FILE_EXT: &str = "tmp";
 pub const OLD_FILE_EXT: &str = "old";
+pub const RSYNC_MANIFEST_FNAME: &str = ".krill-sync-manifest.json";
+pub const RSYNC_INDEX_FNAME: &str = ".krill-sync-index.xml";
+pub const EXPORT_CHANGES_FNAME: &str = ".krill-sync-changes.json";
+const MAX_CAS_SHARD_CHARS: usize = 4;
 pub const USER_AGENT: &str = concat!(crate_name!(), "/", crate_version!());
 
 /// The default number of seconds after we have published a snapshot or delta
 /// that it becomes, if unreferenced, eligible for cleanup.
 pub const DEFAULT_CLEANUP_SECONDS: &str = "3600"; // 60 minutes
 
+/// The default maximum number of seconds since the last successful
+/// synchronization that is considered healthy.
+pub const DEFAULT_MAX_STALENESS_SECONDS: &str = "600";
+
+/// The default timeouts for fetching RRDP files, in seconds.
+pub const DEFAULT_CONNECT_TIMEOUT_SECONDS: &str = "30";
+pub const DEFAULT_READ_TIMEOUT_SECONDS: &str = "120";
+pub const DEFAULT_TOTAL_FETCH_TIMEOUT_SECONDS: &str = "300";
+
+/// The default number of seconds between synchronization runs in daemon mode.
+pub const DEFAULT_INTERVAL_SECONDS: &str = "60";
+
+/// The default number of seconds between progress events, see --progress.
+pub const DEFAULT_PROGRESS_INTERVAL_SECONDS: &str = "5";
+
 /// The default location to write our process ID to so that on invocation we can
 /// check if we are already running. Cleared on boot according to the Linux FHS.
 /// See: https://www.pathname.com/fhs/pub/fhs-2.3.html#VARRUNRUNTIMEVARIABLEDATA
@@ -39,6 +79,9 @@ pub const DEFAULT_RRDP_NOTIFY_DELAY_SECONDS: &str = "0";
 /// The default location in which to store Rsync repository files.
 pub const DEFAULT_RSYNC_DIR: &str = concat!("/var/lib/", crate_name!(), "/rsync");
 
+/// The default template for the names of rsync revision directories.
+pub const DEFAULT_RSYNC_DIR_NAME_TEMPLATE: &str = "session_{session}_serial_{serial}";
+
 /// The default location in which to store our state.
 /// See: https://www.pathname.com/fhs/pub/fhs-2.3.html#VARLIBVARIABLESTATEINFORMATION
 pub const DEFAULT_STATE_DIR: &str = concat!("/var/lib/", crate_name!());
@@ -51,7 +94,7 @@ impl Replace for PathBuf {
     fn replace(&self, from_str: &str, to: &Path) -> PathBuf {
         let to_str = format!("{}", to.display());
         let self_str = format!("{}", self.display());
-        PathBuf::from(self_str.replace(&from_str, &to_str))
+        PathBuf::from(self_str.replace(from_str, &to_str))
     }
 }
 
@@ -75,6 +118,23 @@ pub struct Config {
     #[structopt(short = "q", long = "quiet", conflicts_with = "verbose")]
     pub quiet: bool,
 
+    /// The format for log output: 'text', or 'json' with the fields of each phase
+    #[structopt(long = "log-format", value_name = "format", default_value = "text")]
+    pub log_format: LogFormat,
+
+    /// Machine-readable output on stdout: log as with '--log-format json', write the report of
+    /// each run as with '--report-path -', unless another path is given, and report a failure
+    /// as a JSON object
+    #[structopt(long = "json")]
+    pub json: bool,
+
+    /// Read additional options from this file: one option per line as on the command line,
+    /// e.g. '--cleanup-after 600'. Can be repeated, options in a later file override those in
+    /// earlier ones, and options on the command line override all files. In daemon mode the
+    /// files are read again on SIGHUP
+    #[structopt(long = "config", value_name = "path", parse(from_os_str))]
+    pub config_files: Vec<PathBuf>,
+
     /// The directory to write state to
     #[structopt(long = "state-dir", value_name = "dir", short = "s", parse(from_os_str), default_value = DEFAULT_STATE_DIR)]
     pub state_dir: PathBuf,
@@ -91,12 +151,67 @@ pub struct Config {
     #[structopt(long = "rrdp-max-deltas", value_name = "number")]
     pub rrdp_max_deltas: Option<usize>,
 
+    /// Download the snapshot instead, if the new deltas together have more than this many bytes
+    #[structopt(long = "max-delta-total-bytes", value_name = "bytes")]
+    pub max_delta_total_bytes: Option<u64>,
+
+    /// Build the new snapshot by applying the new deltas to the current one, rather than
+    /// downloading it. This only works if the publisher writes snapshots exactly like
+    /// krill-sync does, e.g. Krill, otherwise the snapshot is downloaded after all
+    #[structopt(long = "rrdp-apply-deltas")]
+    pub rrdp_apply_deltas: bool,
+
+    /// Accept an RRDP notification file with a lower serial than the one last processed for the
+    /// same session, e.g. after the upstream was restored from a backup
+    #[structopt(long = "allow-serial-rollback")]
+    pub allow_serial_rollback: bool,
+
     /// The directory to write Rsync files to
     #[structopt(long = "rsync-dir", value_name = "dir", parse(from_os_str), default_value = DEFAULT_RSYNC_DIR)]
     pub rsync_dir: PathBuf,
 
-    /// Force using directory moves rather than symlinks on unix systems. Added for unit testing this
-    /// code path, not for giving this bad idea to users! So skip it for structopt.
+    /// Do not create the rsync dir and its parents if they do not exist, but fail instead
+    #[structopt(long = "no-create-rsync-dir")]
+    pub no_create_rsync_dir: bool,
+
+    /// Name template for rsync revision dirs, supports {session}, {serial} and {timestamp}
+    #[structopt(long = "rsync-dir-name-template", value_name = "template", default_value = DEFAULT_RSYNC_DIR_NAME_TEMPLATE)]
+    pub rsync_dir_name_template: String,
+
+    /// A template file to copy to the root of each new rsync revision dir, under the same file
+    /// name. Supports {session}, {serial} and {timestamp} in its content
+    #[structopt(long = "rsync-module-readme", value_name = "file", parse(from_os_str))]
+    pub rsync_module_readme: Option<PathBuf>,
+
+    /// A dir, relative to the root of each rsync revision, which is created even if no object is
+    /// written into it, e.g. for an empty publication point (can be repeated)
+    #[structopt(long = "preserve-empty-dir", value_name = "path", parse(from_os_str))]
+    pub preserve_empty_dirs: Vec<PathBuf>,
+
+    /// How to make a new rsync revision current: 'symlink', 'rename', or 'auto' to check
+    /// whether the rsync dir supports replacing symlinks and use them if so
+    #[structopt(long = "rsync-swap", value_name = "mode", default_value = "symlink")]
+    pub rsync_swap: RsyncSwap,
+
+    /// Point the 'current' symlink at the absolute path of the revision dir, rather than at its
+    /// name relative to the rsync dir
+    #[structopt(long = "symlink-absolute")]
+    pub symlink_absolute: bool,
+
+    /// Also publish each rsync revision to a remote dir, using the sftp client in batch mode:
+    /// sftp://[user@]host[:port]/path. The remote dir gets its own 'current' symlink
+    #[structopt(long = "rsync-target-url", value_name = "url")]
+    pub rsync_target_url: Option<SftpTarget>,
+
+    /// Write the rsync content directly into the rsync dir, rather than into revision dirs with a
+    /// 'current' symlink. A new revision is written next to it and swapped in atomically where
+    /// possible, so no old revisions are kept
+    #[structopt(long = "rsync-flat-layout")]
+    pub rsync_flat_layout: bool,
+
+    /// Force using directory moves rather than symlinks. Added for unit testing this code path,
+    /// not for giving this bad idea to users! So skip it for structopt. Note that on Windows we
+    /// fall back to directory moves automatically if symlinks cannot be used.
     #[structopt(skip)]
     pub rsync_dir_force_moves: bool,
 
@@ -108,68 +223,1217 @@ pub struct Config {
     #[structopt(long = "rsync-include-host")]
     pub rsync_include_host: bool,
 
-    /// Remove unreferenced files and directories older than X seconds
-    #[structopt(long = "cleanup-after", value_name = "seconds", default_value = DEFAULT_CLEANUP_SECONDS)]
+    /// Include the module, but not the host: <rsync_dir>/current/<module>/.. Do not also end
+    /// the rsync dir (or the path of the rsync daemon module) in the module name
+    #[structopt(long = "rsync-preserve-module")]
+    pub rsync_preserve_module: bool,
+
+    /// Number of threads used to write rsync files (defaults to the number of CPUs)
+    #[structopt(long = "rsync-write-threads", value_name = "number")]
+    pub rsync_write_threads: Option<usize>,
+
+    /// Write each object as it is read from the snapshot, rather than reading all objects into
+    /// memory first. This reads the snapshot twice, and writes with a single thread
+    #[structopt(long = "rsync-stream-objects")]
+    pub rsync_stream_objects: bool,
+
+    /// Set the mtime of all written rsync files and dirs to this unix timestamp, rather than to
+    /// the time derived from each object, so that the tree is reproducible
+    #[structopt(long = "normalize-mtime", value_name = "timestamp")]
+    pub normalize_mtime: Option<i64>,
+
+    /// Set the mtime of all written rsync files and dirs to the Last-Modified time of the RRDP
+    /// notification file, or the mtime of a local source file. Without one, --normalize-mtime
+    /// is used if set, or else the time derived from each object
+    #[structopt(long = "rsync-mtime-from-rrdp")]
+    pub rsync_mtime_from_rrdp: bool,
+
+    /// On a failure to write an rsync file: 'abort', or 'collect' to write all other files first
+    /// and report all failures together. The new revision is never made current after a failure
+    #[structopt(long = "write-error-mode", value_name = "mode", default_value = "abort")]
+    pub write_error_mode: WriteErrorMode,
+
+    /// Check that each object is a well-formed RPKI object of the type of its file extension,
+    /// before writing a new rsync revision. This is a structural check only: it does not check
+    /// the signatures of signed objects, or validate certificate paths
+    #[structopt(long = "validate-objects")]
+    pub validate_objects: bool,
+
+    /// What to do with malformed objects found by --validate-objects: 'abort' to refuse the new
+    /// revision, 'skip' to leave them out, or 'keep' to only warn about them
+    #[structopt(long = "invalid-objects", value_name = "action", default_value = "abort")]
+    pub invalid_objects: InvalidObjectAction,
+
+    /// What to do if objects with different content map to the same rsync file, e.g. objects of
+    /// different modules without --rsync-preserve-module: 'abort' to refuse the new revision, or
+    /// 'warn' to only write the object with the lowest URI
+    #[structopt(long = "on-path-collision", value_name = "action", default_value = "abort")]
+    pub on_path_collision: PathCollisionAction,
+
+    /// The maximum size of a single object in a snapshot or delta, to guard against a broken or
+    /// malicious RRDP source
+    #[structopt(long = "max-object-bytes", value_name = "bytes", default_value = "67108864")]
+    pub max_object_bytes: u64,
+
+    /// What to do with objects larger than --max-object-bytes: 'abort' to refuse the new
+    /// revision, or 'skip' to leave them out
+    #[structopt(long = "oversized-objects", value_name = "action", default_value = "abort")]
+    pub oversized_objects: OversizedObjectAction,
+
+    /// Do not fsync written rsync files and directories before making them current
+    #[structopt(long = "rsync-no-fsync")]
+    pub rsync_no_fsync: bool,
+
+    /// Hard-link unchanged objects from the previous rsync revision, rather than writing them again
+    #[structopt(long = "rsync-use-hardlinks")]
+    pub rsync_dir_use_hardlinks: bool,
+
+    /// Write a manifest with the URI, path, size and hash of all objects to each new rsync
+    /// revision dir, which 'verify' then also checks
+    #[structopt(long = "rsync-write-manifest")]
+    pub rsync_write_manifest: bool,
+
+    /// Write an index with the session, serial, and the URI, path and hash of each object to
+    /// .krill-sync-index.xml in the root of each rsync revision, structured like an RRDP snapshot
+    #[structopt(long = "write-rsync-index")]
+    pub write_rsync_index: bool,
+
+    /// Store object data once in this content-addressed dir, and hard-link the rsync files to it.
+    /// Must be on the same file system as the rsync dir. Only supported on unix.
+    #[structopt(long = "rsync-cas-dir", value_name = "dir", parse(from_os_str))]
+    pub rsync_cas_dir: Option<PathBuf>,
+
+    /// Keep the entries of the --rsync-cas-dir in subdirectories named after this many leading
+    /// hex characters of their hash, for very large repositories. Also used for --rsync-base-dir
+    #[structopt(long = "rsync-cas-shard-chars", value_name = "number", default_value = "0")]
+    pub rsync_cas_shard_chars: usize,
+
+    /// A read-only dir with objects named after the hex encoded SHA-256 hash of their content,
+    /// e.g. a copy of an --rsync-cas-dir. Objects found there are hard-linked, rather than
+    /// written, if the dir is on the same file system as the rsync dir
+    #[structopt(long = "rsync-base-dir", value_name = "dir", parse(from_os_str))]
+    pub rsync_base_dir: Option<PathBuf>,
+
+    /// Symlink objects from the --rsync-base-dir instead, also across file systems. An rsync
+    /// server sends these as symlinks, so this only works for clients using --copy-links
+    #[structopt(long = "rsync-base-symlinks")]
+    pub rsync_base_symlinks: bool,
+
+    /// Do not check that objects linked from the --rsync-base-dir match their hash
+    #[structopt(long = "rsync-base-no-verify-hashes")]
+    pub rsync_base_no_verify_hashes: bool,
+
+    /// The mode for written rsync files in octal, e.g. 644 (defaults to the umask)
+    #[structopt(long = "file-mode", value_name = "mode", parse(try_from_str = file_ops::parse_mode))]
+    pub file_mode: Option<u32>,
+
+    /// The mode for created rsync directories in octal, e.g. 755 (defaults to the umask)
+    #[structopt(long = "dir-mode", value_name = "mode", parse(try_from_str = file_ops::parse_mode))]
+    pub dir_mode: Option<u32>,
+
+    /// The user (name or ID) to own written rsync files and directories. Requires privileges.
+    #[structopt(long = "file-owner", value_name = "user")]
+    pub file_owner: Option<String>,
+
+    /// The group (name or ID) to own written rsync files and directories
+    #[structopt(long = "file-group", value_name = "group")]
+    pub file_group: Option<String>,
+
+    // Resolved from the options above in post_configure.
+    #[structopt(skip)]
+    pub file_permissions: FilePermissions,
+
+    /// Always keep at least this many of the most recent old rsync revisions, regardless of age
+    #[structopt(long = "rsync-keep-min-revisions", value_name = "number", default_value = "0")]
+    pub rsync_keep_min_revisions: usize,
+
+    /// Remove rsync revision directories which are not tracked in the state, rather than only
+    /// warning about them
+    #[structopt(long = "rsync-remove-orphans")]
+    pub rsync_remove_orphans: bool,
+
+    /// Write new rsync revisions to this directory first, e.g. on fast local storage, rather
+    /// than next to them in the rsync dir. If it is on another file system, then each complete
+    /// revision is copied to the rsync dir before it is renamed into place
+    #[structopt(long = "tmp-dir", value_name = "dir", parse(from_os_str))]
+    pub tmp_dir: Option<PathBuf>,
+
+    /// Archive old rsync revisions as <archive-dir>/<dir name>.tar.gz before removing them
+    #[structopt(long = "archive-old-revisions")]
+    pub archive_old_revisions: bool,
+
+    /// The directory for archives of old rsync revisions, see --archive-old-revisions
+    #[structopt(long = "archive-dir", value_name = "dir", parse(from_os_str))]
+    pub archive_dir: Option<PathBuf>,
+
+    /// Do not write rsync files for objects with a URI matching this glob pattern, e.g.
+    /// 'rsync://rpki.example.net/repo/test-ca/*' (can be repeated)
+    #[structopt(long = "rsync-exclude", value_name = "pattern")]
+    pub rsync_exclude: Vec<Pattern>,
+
+    /// Only write rsync files for objects with a URI starting with this prefix, e.g.
+    /// 'rsync://rpki.example.net/repo/tenant-ca/' (can be repeated)
+    #[structopt(long = "rsync-include-prefix", value_name = "uri")]
+    pub rsync_include_prefixes: Vec<String>,
+
+    /// Rewrite object URIs starting with a prefix in revision manifests and reports, as:
+    /// <from-prefix>=<to-prefix> (can be repeated, the first match applies). The paths of the
+    /// rsync files do not change
+    #[structopt(long = "rsync-uri-rewrite", value_name = "from=to")]
+    pub rsync_uri_rewrites: Vec<UriRewrite>,
+
+    /// Refuse to make a new rsync revision current if it has more than this percentage fewer
+    /// objects than the current revision
+    #[structopt(long = "max-object-shrink-pct", value_name = "percentage", default_value = "20")]
+    pub max_object_shrink_pct: u8,
+
+    /// Make a new rsync revision current even if it exceeds --max-object-shrink-pct
+    #[structopt(long = "accept-shrink")]
+    pub accept_shrink: bool,
+
+    /// Make a new rsync revision current even if it has no objects at all
+    #[structopt(long = "allow-empty")]
+    pub allow_empty: bool,
+
+    /// Bytes that must remain free on the rsync dir file system after writing a new revision
+    #[structopt(long = "min-free-bytes", value_name = "bytes", default_value = "0")]
+    pub min_free_bytes: u64,
+
+    /// Hold an advisory lock (flock) on this file while writing a new rsync revision and
+    /// making it current, so that cooperating tools can wait for a consistent rsync dir
+    #[structopt(long = "rsync-lock-path", value_name = "file", parse(from_os_str))]
+    pub rsync_lock_path: Option<PathBuf>,
+
+    /// Wait for another krill-sync process using the same state dir to finish, rather than
+    /// exiting with an error
+    #[structopt(long = "wait")]
+    pub wait: bool,
+
+    /// Print the resolved configuration, with secrets redacted, and exit
+    #[structopt(long = "print-config")]
+    pub print_config: bool,
+
+    /// Report the planned rsync changes without writing rsync files or state. The RRDP files
+    /// are still downloaded, because they are needed to determine the changes, but to a
+    /// temporary dir in --tmp-dir, or the system temp dir, which is removed afterwards
+    #[structopt(long = "dry-run")]
+    pub dry_run: bool,
+
+    /// Manual override: write a new rsync revision for this serial and make it current, even
+    /// if there is no change. It must be the serial of the local RRDP snapshot
+    #[structopt(long = "force-serial", value_name = "serial")]
+    pub force_serial: Option<u64>,
+
+    /// Recovery: write a new rsync revision for the local RRDP snapshot from scratch and make it
+    /// current, even if there is no change. No objects are linked from the previous revision, the
+    /// base dir or the object store
+    #[structopt(long = "rebuild")]
+    pub force_full_write: bool,
+
+    /// The session of the revision for --force-serial, defaults to the session of the local
+    /// RRDP snapshot
+    #[structopt(long = "force-session", value_name = "uuid")]
+    pub force_session: Option<Uuid>,
+
+    /// Only check that the RRDP source is well-formed and consistent, without writing any
+    /// RRDP or rsync files or state
+    #[structopt(long = "validate-only")]
+    pub validate_only: bool,
+
+    /// Remove unreferenced files and directories older than this, in seconds or with a unit,
+    /// e.g. '30m', '12h' or '2d'
+    #[structopt(
+        long = "cleanup-after",
+        value_name = "duration",
+        default_value = DEFAULT_CLEANUP_SECONDS,
+        parse(try_from_str = util::parse_duration_seconds)
+    )]
     pub cleanup_after: i64,
 
+    /// Remove old rsync revisions which are due for cleanup before writing a new revision,
+    /// rather than after, to free space on a nearly full disk
+    #[structopt(long = "clean-before-write")]
+    pub clean_before_write: bool,
+
+    /// Do not remove any old rsync revisions in this run, e.g. to keep them for a rollback
+    #[structopt(long = "no-clean")]
+    pub no_clean: bool,
+
+    /// The maximum time since the last successful synchronization for 'krill-sync health', in
+    /// seconds or with a unit, e.g. '30m'
+    #[structopt(
+        long = "max-staleness",
+        value_name = "duration",
+        default_value = DEFAULT_MAX_STALENESS_SECONDS,
+        parse(try_from_str = util::parse_duration_seconds)
+    )]
+    pub max_staleness_seconds: i64,
+
+    /// Keep running, and synchronize every --interval-seconds, rather than synchronizing once
+    #[structopt(long = "daemon")]
+    pub daemon: bool,
+
+    /// Seconds between the start of synchronization runs in daemon mode
+    #[structopt(long = "interval-seconds", value_name = "seconds", default_value = DEFAULT_INTERVAL_SECONDS)]
+    pub interval_seconds: u64,
+
+    /// Write the progress of each synchronization run to stderr as JSON lines, with the phase
+    /// and the number of objects and bytes written so far
+    #[structopt(long = "progress")]
+    pub progress: bool,
+
+    /// The time between progress events, in seconds or with a unit, e.g. '1m'
+    #[structopt(
+        long = "progress-interval",
+        value_name = "duration",
+        default_value = DEFAULT_PROGRESS_INTERVAL_SECONDS,
+        parse(try_from_str = util::parse_duration_seconds)
+    )]
+    pub progress_interval_seconds: i64,
+
+    // The progress of the current run, see --progress.
+    #[structopt(skip)]
+    pub run_progress: Arc<Progress>,
+
+    /// A shell command to run before a new rsync revision is made current, which can veto it by
+    /// exiting non-zero. It gets KRILL_SYNC_REVISION_DIR, KRILL_SYNC_SESSION and KRILL_SYNC_SERIAL
+    /// in its environment. A vetoed revision is kept as staged
+    #[structopt(long = "pre-publish-command", value_name = "command")]
+    pub pre_publish_command: Option<String>,
+
+    /// A shell command to run after a successful synchronization, if anything was published. It
+    /// gets KRILL_SYNC_SESSION, KRILL_SYNC_SERIAL and KRILL_SYNC_CHANGED in its environment
+    #[structopt(long = "post-sync-command", value_name = "command")]
+    pub post_sync_command: Option<String>,
+
+    /// Also run the post sync command if nothing was published
+    #[structopt(long = "post-sync-on-no-change")]
+    pub post_sync_on_no_change: bool,
+
+    /// Write Prometheus metrics for the last successful run to this file
+    #[structopt(long = "metrics-path", value_name = "file", parse(from_os_str))]
+    pub metrics_path: Option<PathBuf>,
+
+    /// Write a JSON report of each run to this file, or to stdout if '-'
+    #[structopt(long = "report-path", value_name = "file", parse(from_os_str))]
+    pub report_path: Option<PathBuf>,
+
+    /// Append the URI of each object written for a new rsync revision to this file, regardless
+    /// of the log level
+    #[structopt(long = "log-objects", value_name = "file", parse(from_os_str))]
+    pub object_log_path: Option<PathBuf>,
+
+    /// Also write the size and hash of each object to the --log-objects file
+    #[structopt(long = "log-object-hashes")]
+    pub log_object_hashes: bool,
+
+    /// Number of times to retry a failed RRDP download, if the error is transient
+    #[structopt(long = "fetch-retries", value_name = "number", default_value = "3")]
+    pub fetch_retries: u32,
+
+    /// Milliseconds to wait before the first retry, doubled for each next retry
+    #[structopt(long = "fetch-retry-base-ms", value_name = "ms", default_value = "500")]
+    pub fetch_retry_base_ms: u64,
+
+    /// Number of times to retry renaming or removing an rsync dir, if the error is transient,
+    /// e.g. ESTALE or EBUSY on a network file system
+    #[structopt(long = "fs-retries", value_name = "number", default_value = "3")]
+    pub fs_retries: u32,
+
+    /// Milliseconds to wait before the first file system retry, doubled for each next retry
+    #[structopt(long = "fs-retry-delay-ms", value_name = "ms", default_value = "100")]
+    pub fs_retry_delay_ms: u64,
+
+    /// Give up connecting to the RRDP server after this time, in seconds or with a unit, 0 means
+    /// no timeout
+    #[structopt(
+        long = "connect-timeout",
+        value_name = "duration",
+        default_value = DEFAULT_CONNECT_TIMEOUT_SECONDS,
+        parse(try_from_str = util::parse_duration_seconds)
+    )]
+    pub connect_timeout: i64,
+
+    /// Give up on an RRDP download if no data is received for longer than this, in seconds or
+    /// with a unit, 0 means no timeout. A slow download which keeps making progress is only
+    /// limited by --total-fetch-timeout
+    #[structopt(
+        long = "read-timeout",
+        value_name = "duration",
+        default_value = DEFAULT_READ_TIMEOUT_SECONDS,
+        parse(try_from_str = util::parse_duration_seconds)
+    )]
+    pub read_timeout: i64,
+
+    /// Abort the run if fetching the notification file, snapshot and deltas, including retries,
+    /// takes longer than this, in seconds or with a unit, 0 means no timeout
+    #[structopt(
+        long = "total-fetch-timeout",
+        value_name = "duration",
+        default_value = DEFAULT_TOTAL_FETCH_TIMEOUT_SECONDS,
+        parse(try_from_str = util::parse_duration_seconds)
+    )]
+    pub total_fetch_timeout: i64,
+
+    /// Number of RRDP delta files to download at the same time
+    #[structopt(long = "delta-fetch-concurrency", value_name = "number", default_value = "4")]
+    pub delta_fetch_concurrency: usize,
+
+    /// The User-Agent for RRDP HTTP requests [default: krill-sync/<version>]
+    #[structopt(long = "user-agent", value_name = "agent")]
+    pub user_agent: Option<String>,
+
+    /// An extra header for all RRDP HTTP requests, as 'name: value' (can be repeated)
+    #[structopt(long = "http-header", value_name = "header")]
+    pub http_headers: Vec<HttpHeader>,
+
+    /// A PEM client certificate to authenticate RRDP HTTP requests with
+    #[structopt(long = "client-cert", value_name = "file", parse(from_os_str))]
+    pub client_cert: Option<PathBuf>,
+
+    /// The PEM (PKCS#8) private key for the client certificate
+    #[structopt(long = "client-key", value_name = "file", parse(from_os_str))]
+    pub client_key: Option<PathBuf>,
+
+    /// A PEM CA certificate to trust for RRDP HTTP requests, e.g. for a private CA
+    #[structopt(long = "ca-cert", value_name = "file", parse(from_os_str))]
+    pub ca_cert: Option<PathBuf>,
+
+    // The HTTP settings resolved from the options above, see post_configure.
+    #[structopt(skip)]
+    pub http: HttpSettings,
+
+    /// Limit the download rate of RRDP files, for all concurrent downloads together. 0 means
+    /// unlimited
+    #[structopt(long = "max-download-bytes-per-sec", value_name = "bytes", default_value = "0")]
+    pub max_download_bytes_per_sec: u64,
+
+    /// Keep downloaded RRDP snapshot and delta files in this dir, to reuse them in later runs
+    #[structopt(long = "rrdp-cache-dir", value_name = "dir", parse(from_os_str))]
+    pub rrdp_cache_dir: Option<PathBuf>,
+
+    /// The maximum total size of the RRDP cache, least recently used files are evicted first
+    #[structopt(long = "rrdp-cache-max-bytes", value_name = "bytes", default_value = "536870912")]
+    pub rrdp_cache_max_bytes: u64,
+
+    /// The address family for RRDP HTTP connections: 'auto', 'v4' or 'v6'
+    #[structopt(long = "ip-version", value_name = "version", default_value = "auto")]
+    pub ip_version: IpVersion,
+
     /// Whether or not localhost connections and self-signed certificates are allowed
     #[structopt(long = "insecure")]
     pub insecure: bool,
 
-    /// The public RRDP notification URI
-    pub notification_uri: Https,
+    /// The public RRDP notification URI (required unless a subcommand is used)
+    pub notification_uri: Option<Https>,
 
-    /// Slash terminated base uri for the notify file source
+    /// Slash terminated base uri for the notify file source, or a local dir (or 'file://' URI)
+    /// to read the RRDP files from disk without any network access
     #[structopt(long = "source_uri_base", value_name = "uri")]
     pub source_uri_base: Option<FetchSource>,
 
     #[structopt(skip)]
     pub fetch_map: Option<FetchMap>,
+
+    /// Another RRDP notification URI, or local file, to fetch from if fetching from the
+    /// notification URI or the source_uri_base fails with a transient error. Tried in order (can
+    /// be repeated)
+    #[structopt(long = "rrdp-notify-uri", value_name = "uri")]
+    pub rrdp_notify_uris: Vec<FetchSource>,
+
+    /// An additional RRDP source to write to its own rsync module directory,
+    /// as: <module>=<notification-uri>[,<source_uri_base>] (can be repeated)
+    #[structopt(long = "rsync-module", value_name = "module=uri")]
+    pub rsync_modules: Vec<ModuleSource>,
+
+    // The name of the module if this config was derived for one of the
+    // additional sources, see Config::for_module.
+    #[structopt(skip)]
+    pub module: Option<String>,
+
+    // The command line arguments, so that the config can be reloaded.
+    #[structopt(skip)]
+    pub args: Vec<OsString>,
+
+    // Where each option which was not left at its default was set: the path
+    // of a config file, or 'command line', by field name.
+    #[structopt(skip)]
+    pub provenance: BTreeMap<String, String>,
+
+    /// Run a maintenance command, or synchronize in two phases, rather than synchronizing
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+// Maintenance commands which work on the existing local state only, and do
+// not fetch anything from the RRDP source, except for 'stage' which does the
+// first half of synchronizing. Note: this is not a doc comment, because
+// structopt would use it as the about text for krill-sync itself.
+#[derive(Clone, Debug, Eq, PartialEq, StructOpt)]
+pub enum Command {
+    /// Remove old rsync revisions which are eligible for cleanup
+    Clean {
+        /// Remove all old rsync revisions immediately, ignoring --cleanup-after
+        #[structopt(long = "force")]
+        force: bool,
+    },
+
+    /// Verify that the current rsync dir contains exactly the objects of the last RRDP snapshot
+    Verify,
+
+    /// Synchronize, but do not make the new rsync revision current or update the notification file
+    Stage,
+
+    /// Make the last staged rsync revision current and update the notification file
+    Publish,
+
+    /// Check that the last successful synchronization is at most --max-staleness ago, e.g.
+    /// for a liveness probe. This only reads the state, and does not wait for the lock
+    Health,
+
+    /// Print the objects which were added, removed and changed between the rsync revisions for
+    /// two serials
+    Diff {
+        /// The serial of the older revision
+        #[structopt(long = "from", value_name = "serial")]
+        from: u64,
+
+        /// The serial of the newer revision
+        #[structopt(long = "to", value_name = "serial")]
+        to: u64,
+
+        /// The output format: 'text' or 'json'
+        #[structopt(long = "format", value_name = "format", default_value = "text")]
+        format: LogFormat,
+    },
+
+    /// Copy the objects which were added or changed since the rsync revision for a serial to a
+    /// separate dir, with a list of all changes including removed objects
+    Export {
+        /// The serial of the older revision
+        #[structopt(long = "since", value_name = "serial")]
+        since: u64,
+
+        /// The dir to copy to, which must not exist yet or be empty
+        #[structopt(long = "export-dir", value_name = "dir", parse(from_os_str))]
+        export_dir: PathBuf,
+    },
+
+    /// Make an old rsync revision current again, if its directory was not cleaned up yet
+    Rollback {
+        /// The serial of the revision
+        #[structopt(long = "to", value_name = "serial")]
+        to: u64,
+
+        /// Roll back to a revision of another RRDP session than the current revision
+        #[structopt(long = "force")]
+        force: bool,
+    },
+}
+
+impl Command {
+    /// Returns whether this command fetches from the RRDP source.
+    pub fn synchronizes(&self) -> bool {
+        matches!(self, Command::Stage)
+    }
+}
+
+//------------ RsyncSwap -----------------------------------------------------
+
+/// How a new rsync revision is made current.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RsyncSwap {
+    /// Replace the 'current' symlink with one to the new revision dir.
+    Symlink,
+
+    /// Rename the new revision dir to 'current', after renaming the previous.
+    Rename,
+
+    /// Use symlinks if a test shows that they can be replaced in the rsync
+    /// dir, rename otherwise. The result is remembered in the rsync state.
+    Auto,
+}
+
+impl FromStr for RsyncSwap {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "symlink" => Ok(RsyncSwap::Symlink),
+            "rename" => Ok(RsyncSwap::Rename),
+            "auto" => Ok(RsyncSwap::Auto),
+            _ => Err(anyhow!("Expected 'symlink', 'rename' or 'auto', got: {}", s)),
+        }
+    }
+}
+
+//------------ RsyncPathPrefix -----------------------------------------------
+
+/// Which parts of an rsync URI before the path are kept as directories
+/// in an rsync revision.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum RsyncPathPrefix {
+    /// Only the path, the rsync dir is for a single module.
+    None,
+
+    /// The module, but not the host.
+    Module,
+
+    /// The host and the module.
+    HostAndModule,
+}
+
+//------------ LogFormat -----------------------------------------------------
+
+/// The format of log output, or of the output of a subcommand.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+
+    /// One JSON object per line, with the fields of the event and its spans.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow!("Expected 'text' or 'json', got: {}", s)),
+        }
+    }
+}
+
+//------------ WriteErrorMode ------------------------------------------------
+
+/// What to do when writing an object for a new rsync revision fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum WriteErrorMode {
+    /// Stop writing at the first failure.
+    Abort,
+
+    /// Write all other objects, and then report all failures together. The
+    /// new revision is not made current either way.
+    Collect,
+}
+
+impl FromStr for WriteErrorMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(WriteErrorMode::Abort),
+            "collect" => Ok(WriteErrorMode::Collect),
+            _ => Err(anyhow!("Expected 'abort' or 'collect', got: {}", s)),
+        }
+    }
+}
+
+//------------ InvalidObjectAction -------------------------------------------
+
+/// What to do with malformed objects, see `inspect::inspect_object`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InvalidObjectAction {
+    /// Do not write a new revision.
+    Abort,
+
+    /// Write the revision without them.
+    Skip,
+
+    /// Write them anyway, after warning about them.
+    Keep,
+}
+
+impl FromStr for InvalidObjectAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(InvalidObjectAction::Abort),
+            "skip" => Ok(InvalidObjectAction::Skip),
+            "keep" => Ok(InvalidObjectAction::Keep),
+            _ => Err(anyhow!("Expected 'abort', 'skip' or 'keep', got: {}", s)),
+        }
+    }
+}
+
+//------------ PathCollisionAction -------------------------------------------
+
+/// What to do with objects which map to the same rsync file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PathCollisionAction {
+    /// Do not write a new revision.
+    Abort,
+
+    /// Write only the first object by URI, after warning about the others.
+    Warn,
+}
+
+impl FromStr for PathCollisionAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(PathCollisionAction::Abort),
+            "warn" => Ok(PathCollisionAction::Warn),
+            _ => Err(anyhow!("Expected 'abort' or 'warn', got: {}", s)),
+        }
+    }
+}
+
+//------------ OversizedObjectAction -----------------------------------------
+
+/// What to do with objects larger than `Config::max_object_bytes`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OversizedObjectAction {
+    /// Do not write a new revision.
+    Abort,
+
+    /// Write the revision without them.
+    Skip,
+}
+
+impl FromStr for OversizedObjectAction {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "abort" => Ok(OversizedObjectAction::Abort),
+            "skip" => Ok(OversizedObjectAction::Skip),
+            _ => Err(anyhow!("Expected 'abort' or 'skip', got: {}", s)),
+        }
+    }
+}
+
+//------------ UriRewrite ----------------------------------------------------
+
+/// Replaces a prefix of the URIs of objects where they are recorded, see
+/// `Config::rewrite_rsync_uri`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct UriRewrite {
+    from: String,
+    to: String,
+}
+
+impl UriRewrite {
+    fn apply(&self, uri: &str) -> Option<String> {
+        uri.strip_prefix(self.from.as_str())
+            .map(|rest| format!("{}{}", self.to, rest))
+    }
+}
+
+impl FromStr for UriRewrite {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once('=') {
+            Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok(UriRewrite {
+                from: from.to_string(),
+                to: to.to_string(),
+            }),
+            _ => Err(anyhow!("Expected '<from-prefix>=<to-prefix>', got: {}", s)),
+        }
+    }
+}
+
+//------------ ModuleSource --------------------------------------------------
+
+/// An additional RRDP source, which is synchronized to its own module
+/// directory under the rsync dir.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ModuleSource {
+    name: String,
+    notification_uri: Https,
+    source_uri_base: Option<FetchSource>,
+}
+
+impl ModuleSource {
+    pub fn new(name: String, notification_uri: Https, source_uri_base: Option<FetchSource>) -> Self {
+        ModuleSource {
+            name,
+            notification_uri,
+            source_uri_base,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn notification_uri(&self) -> &Https {
+        &self.notification_uri
+    }
+}
+
+impl FromStr for ModuleSource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, source) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected <module>=<notification-uri>, got: {}", s))?;
+
+        // The module name is used as a directory name in the rsync and rrdp
+        // dirs, and as part of the state file names.
+        if name.is_empty()
+            || name == "."
+            || name == ".."
+            || name == "current"
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            return Err(anyhow!("Invalid rsync module name: '{}'", name));
+        }
+
+        let (uri, source_uri_base) = match source.split_once(',') {
+            Some((uri, base)) => (uri, Some(FetchSource::from_str(base)?)),
+            None => (source, None),
+        };
+
+        let notification_uri = Https::from_str(uri)
+            .map_err(|e| anyhow!("Invalid notification URI '{}' for module {}: {}", uri, name, e))?;
+
+        Ok(ModuleSource::new(name.to_string(), notification_uri, source_uri_base))
+    }
 }
 
 impl Config {
+    /// Returns the notification URI, or an error if it was not specified.
+    pub fn notification_uri(&self) -> Result<&Https> {
+        self.notification_uri
+            .as_ref()
+            .ok_or_else(|| anyhow!("The notification URI is required for synchronizing"))
+    }
+
     pub fn rsync_enabled(&self) -> bool {
         !self.rsync_disable
     }
 
+    /// Returns whether symlinks may be used for the current rsync dir. In
+    /// 'auto' mode this still depends on whether the rsync dir supports them.
     pub fn rsync_dir_use_symlinks(&self) -> bool {
-        if cfg!(unix) {
-            !self.rsync_dir_force_moves
+        if cfg!(any(unix, windows)) {
+            !self.rsync_dir_force_moves && self.rsync_swap != RsyncSwap::Rename
         } else {
             false
         }
     }
 
+    pub fn verify_base_hashes(&self) -> bool {
+        !self.rsync_base_no_verify_hashes
+    }
+
+    pub fn fsync(&self) -> bool {
+        !self.rsync_no_fsync
+    }
+
+    pub fn create_rsync_dir(&self) -> bool {
+        !self.no_create_rsync_dir
+    }
+
+    pub fn rsync_write_threads(&self) -> usize {
+        self.rsync_write_threads
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism()
+                    .map(|n| n.get())
+                    .unwrap_or(1)
+            })
+            .max(1)
+    }
+
+    /// Returns whether no rsync file should be written for the object with
+    /// this URI, because it matches `rsync_exclude`, or because it does not
+    /// start with any of the `rsync_include_prefixes`, if there are any.
+    pub fn rsync_excluded(&self, uri: &rpki::uri::Rsync) -> bool {
+        let uri = uri.as_str();
+        let included = self.rsync_include_prefixes.is_empty()
+            || self
+                .rsync_include_prefixes
+                .iter()
+                .any(|prefix| uri.starts_with(prefix.as_str()));
+
+        !included || self.rsync_exclude.iter().any(|pattern| pattern.matches(uri))
+    }
+
+    /// Returns the URI of an object as it is recorded in revision manifests
+    /// and reports, i.e. after the first matching `rsync_uri_rewrites`.
+    pub fn rewrite_rsync_uri(&self, uri: &rpki::uri::Rsync) -> String {
+        self.rsync_uri_rewrites
+            .iter()
+            .find_map(|rewrite| rewrite.apply(uri.as_str()))
+            .unwrap_or_else(|| uri.to_string())
+    }
+
+    /// Starts writing the progress of the current run to stderr, if
+    /// `progress` is set.
+    pub fn progress_reporter(&self) -> Option<ProgressReporter> {
+        self.progress.then(|| {
+            ProgressReporter::start(
+                self.run_progress.clone(),
+                self.module.clone(),
+                Duration::from_secs(self.progress_interval_seconds as u64),
+            )
+        })
+    }
+
+    /// Returns which parts of the rsync URIs are kept before their path.
+    /// Including the host implies including the module.
+    pub fn rsync_path_prefix(&self) -> RsyncPathPrefix {
+        if self.rsync_include_host {
+            RsyncPathPrefix::HostAndModule
+        } else if self.rsync_preserve_module {
+            RsyncPathPrefix::Module
+        } else {
+            RsyncPathPrefix::None
+        }
+    }
+
+    /// Returns the dir with the content of the current rsync revision. This is
+    /// the rsync dir itself with `rsync_flat_layout`.
     pub fn rsync_dir_current(&self) -> PathBuf {
-        self.rsync_dir.join("current")
+        if self.rsync_flat_layout {
+            self.rsync_dir.clone()
+        } else {
+            self.rsync_dir.join("current")
+        }
+    }
+
+    /// Returns where the rsync revisions are published, in addition to the
+    /// local rsync dir.
+    pub fn write_target(&self) -> Box<dyn WriteTarget> {
+        match &self.rsync_target_url {
+            Some(target) => Box::new(target.clone()),
+            None => Box::new(LocalTarget),
+        }
+    }
+
+    pub fn fs_retry(&self) -> FsRetry {
+        FsRetry::new(self.fs_retries, Duration::from_millis(self.fs_retry_delay_ms))
     }
 
-    pub fn fetcher(&self) -> Fetcher {
+    pub fn fetcher(&self) -> Result<Fetcher> {
         let mode = if self.insecure {
             FetchMode::Insecure
         } else {
             FetchMode::Strict
         };
 
-        Fetcher::new(self.notification_uri.clone(), self.fetch_map.clone(), mode)
+        Ok(Fetcher::new(self.notification_uri()?.clone(), self.fetch_map.clone(), mode)
+            .with_retries(
+                self.fetch_retries,
+                Duration::from_millis(self.fetch_retry_base_ms),
+            )
+            .with_http(self.http.clone())
+            .with_total_timeout(timeout(self.total_fetch_timeout))
+            .with_failover(self.rrdp_notify_uris.clone())
+            .with_concurrency(self.delta_fetch_concurrency)
+            .with_cache(
+                self.rrdp_cache_dir
+                    .clone()
+                    .map(|dir| RrdpCache::new(dir, self.rrdp_cache_max_bytes)),
+            ))
     }
 
     pub fn rrdp_state_path(&self) -> PathBuf {
-        self.state_dir.join("rrdp-state.json")
+        self.state_path("rrdp-state")
     }
 
     pub fn rsync_state_path(&self) -> PathBuf {
-        self.state_dir.join("rsync-state.json")
+        self.state_path("rsync-state")
+    }
+
+    /// The objects of the current and pending rsync revision, which are kept
+    /// out of the rsync state to keep that small, see `rsync::ObjectChanges`.
+    pub fn rsync_objects_path(&self) -> PathBuf {
+        self.state_path("rsync-objects")
+    }
+
+    /// The time of the last successful synchronization, see `crate::health`.
+    pub fn last_success_path(&self) -> PathBuf {
+        self.state_path("last-success")
+    }
+
+    /// The snapshot of a partially applied sequence of RRDP deltas, to resume
+    /// from in the next run, see `RrdpState::update`.
+    pub fn rrdp_checkpoint_path(&self) -> PathBuf {
+        self.state_file("rrdp-checkpoint", "xml")
+    }
+
+    fn state_path(&self, name: &str) -> PathBuf {
+        self.state_file(name, "json")
+    }
+
+    fn state_file(&self, name: &str, ext: &str) -> PathBuf {
+        match &self.module {
+            None => self.state_dir.join(format!("{}.{}", name, ext)),
+            Some(module) => self.state_dir.join(format!("{}-{}.{}", name, module, ext)),
+        }
+    }
+
+    /// Derives the config for synchronizing an additional RRDP source to its
+    /// own module. The RRDP and rsync content go to a sub-directory named
+    /// after the module, and the state is kept separately, so that cleanup
+    /// and the 'current' symlink are handled independently for each module.
+    pub fn for_module(&self, module: &ModuleSource) -> Result<Config> {
+        let mut config = self.clone();
+
+        config.module = Some(module.name.clone());
+        config.rsync_modules = vec![];
+        config.rrdp_dir = self.rrdp_dir.join(&module.name);
+        config.rsync_dir = self.rsync_dir.join(&module.name);
+        config.tmp_dir = self.tmp_dir.as_ref().map(|dir| dir.join(&module.name));
+        config.archive_dir = self.archive_dir.as_ref().map(|dir| dir.join(&module.name));
+        config.rsync_target_url =
+            self.rsync_target_url.as_ref().map(|target| target.join(&module.name));
+
+        // Metrics and reports are written to separate files per module, but
+        // a report to stdout stays on stdout.
+        let module_file = |path: &PathBuf| {
+            if path == Path::new("-") {
+                path.clone()
+            } else {
+                let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+                path.with_file_name(format!("{}-{}", module.name, file_name))
+            }
+        };
+        config.metrics_path = self.metrics_path.as_ref().map(module_file);
+        config.report_path = self.report_path.as_ref().map(module_file);
+        config.object_log_path = self.object_log_path.as_ref().map(module_file);
+
+        config.notification_uri = Some(module.notification_uri.clone());
+        config.source_uri_base = module.source_uri_base.clone();
+        if let Some(FetchSource::File(path)) = config.source_uri_base.as_mut() {
+            *path = util::expand_env_path(path)?;
+        }
+        config.fetch_map = make_fetch_map(
+            &module.notification_uri,
+            &mut config.source_uri_base,
+            config.insecure,
+        )?;
+        // The failover endpoints are for the main source only
+        config.rrdp_notify_uris = vec![];
+
+        Ok(config)
     }
 
     pub fn lock_file(&self) -> PathBuf {
         self.state_dir.join("krill-sync.lock")
     }
+
+    /// Reads the config again from the same command line arguments and the
+    /// current content of the config file, e.g. on SIGHUP in daemon mode.
+    /// The changes are logged. Options which cannot be changed while running
+    /// are kept, with a warning.
+    pub fn reload(&self) -> Result<Config> {
+        let config = Config::from_iter_safe(&self.args)?;
+        let mut config = post_configure(with_config_files(config, self.args.clone())?)?;
+        self.keep_fixed(&mut config);
+
+        let changes = changed_fields(self, &config);
+        if changes.is_empty() {
+            info!("Reloaded config, nothing changed");
+        } else {
+            info!("Reloaded config, changed: {}", changes.join(", "));
+        }
+        Ok(config)
+    }
+
+    /// Restores the options in the reloaded config which cannot be changed
+    /// while running: the dirs and files with the state and content which are
+    /// locked and recovered at startup, or which earlier runs wrote to, the
+    /// options which decide the paths and modules in the rsync dir, which
+    /// cleaning and linking from a previous revision rely on, and the options
+    /// for logging, which cannot be initialized twice.
+    fn keep_fixed(&self, reloaded: &mut Config) {
+        macro_rules! keep {
+            ($($field:ident),*) => {
+                $(
+                    if reloaded.$field != self.$field {
+                        warn!(
+                            "Option {} cannot be changed while running, ignoring the new value",
+                            stringify!($field)
+                        );
+                        reloaded.$field = self.$field.clone();
+                    }
+                )*
+            };
+        }
+        keep!(
+            config_files,
+            state_dir,
+            rrdp_dir,
+            rsync_dir,
+            rsync_flat_layout,
+            rsync_include_host,
+            rsync_preserve_module,
+            rsync_dir_name_template,
+            rsync_modules,
+            rsync_cas_dir,
+            rsync_cas_shard_chars,
+            rsync_base_dir,
+            rsync_lock_path,
+            tmp_dir,
+            archive_dir,
+            rrdp_cache_dir,
+            verbose,
+            quiet,
+            log_format,
+            json,
+            daemon,
+            command
+        );
+    }
 }
 
 pub fn configure() -> Result<Config> {
-    let config = Config::from_args();
-    post_configure(config)
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let config = match Config::from_iter_safe(&args) {
+        Ok(config) => config,
+        // The help and the version are not errors
+        Err(e) if !e.use_stderr() => e.exit(),
+        Err(e) => return Err(e.into()),
+    };
+    post_configure(with_config_files(config, args)?)
+}
+
+/// Parses the arguments again, with the options from the config files, if
+/// any, before the command line arguments. The files are layered: an option
+/// in a later file replaces all occurrences of that option in earlier files,
+/// and an option on the command line replaces it in all files. So, for an
+/// option which can be repeated, the values are taken from one place only.
+fn with_config_files(config: Config, args: Vec<OsString>) -> Result<Config> {
+    let field_names: Vec<String> = debug_fields(&config)
+        .iter()
+        .filter_map(|field| field.split_once(':').map(|(name, _)| name.to_string()))
+        .collect();
+    // The arguments are named after the fields, in kebab case.
+    let fields_of = |option_args: &[OsString]| -> Result<Vec<String>> {
+        let matches = Config::clap().get_matches_from_safe(args[..1].iter().chain(option_args))?;
+        Ok(field_names
+            .iter()
+            .filter(|name| matches.occurrences_of(name.replace('_', "-")) > 0)
+            .cloned()
+            .collect())
+    };
+
+    let mut provenance = BTreeMap::new();
+    let mut overridden = HashSet::new();
+    for field in fields_of(&args[1..])? {
+        provenance.insert(field.clone(), "command line".to_string());
+        overridden.insert(field);
+    }
+
+    // Go through the files from the last to the first, so that we know
+    // which options are overridden.
+    let mut layers = vec![];
+    for path in config.config_files.iter().rev() {
+        let source = path.display().to_string();
+        let mut kept = vec![];
+        let mut fields = HashSet::new();
+        for option_args in read_config_file(path)? {
+            let option_fields = fields_of(&option_args).with_context(|| {
+                format!("Invalid options in config file: {}", path.display())
+            })?;
+            if option_fields.iter().any(|field| field == "config_files") {
+                return Err(anyhow!("--config cannot be used in config file: {}", path.display()));
+            }
+            if option_fields.iter().any(|field| overridden.contains(field)) {
+                continue;
+            }
+            for field in option_fields {
+                provenance.entry(field.clone()).or_insert_with(|| source.clone());
+                fields.insert(field);
+            }
+            kept.push(option_args);
+        }
+        overridden.extend(fields);
+        layers.push(kept);
+    }
+
+    let mut all_args = args[..1].to_vec();
+    all_args.extend(layers.into_iter().rev().flatten().flatten());
+    all_args.extend_from_slice(&args[1..]);
+    let mut config = Config::from_iter_safe(all_args)
+        .with_context(|| "Invalid combination of options in config files")?;
+    config.args = args;
+    config.provenance = provenance;
+    Ok(config)
+}
+
+/// Reads the options from a config file. Each line has an option, and its
+/// value if any, separated by whitespace. Empty lines and lines starting
+/// with '#' are skipped. Returns the arguments for each option.
+fn read_config_file(path: &Path) -> Result<Vec<Vec<OsString>>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("Cannot read config file: {}", path.display()))?;
+
+    let mut options = vec![];
+    for line in content.lines().map(str::trim) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(char::is_whitespace) {
+            Some((option, value)) => options.push(vec![option.into(), value.trim_start().into()]),
+            None => options.push(vec![line.into()]),
+        }
+    }
+    Ok(options)
+}
+
+impl Config {
+    /// Returns each field with its value, as in the debug output, and where
+    /// it was set, for --print-config.
+    pub fn describe(&self) -> String {
+        let mut res = String::new();
+        for field in debug_fields(self) {
+            let name = field.split_once(':').map(|(name, _)| name).unwrap_or_default();
+            if name == "args" || name == "provenance" {
+                continue;
+            }
+            let source = self.provenance.get(name).map(String::as_str).unwrap_or("default");
+            res.push_str(&format!("{} # {}\n", field.trim_end_matches(','), source));
+        }
+        res
+    }
+}
+
+/// Returns the top-level fields of the pretty debug output, each with its
+/// value on a single line.
+fn debug_fields(config: &Config) -> Vec<String> {
+    // In the pretty debug output each field starts on a line which is
+    // indented once, continued by lines which are indented more, and by
+    // the closing bracket of its value.
+    let mut fields: Vec<String> = vec![];
+    for line in format!("{:#?}", config).lines() {
+        let closing = line.trim_start().starts_with([']', '}', ')']);
+        if line.starts_with("    ") && !line.starts_with("     ") && !closing {
+            fields.push(line.trim().to_string());
+        } else if let (true, Some(field)) = (line.starts_with(' '), fields.last_mut()) {
+            field.push(' ');
+            field.push_str(line.trim());
+        }
+    }
+    fields
+}
+
+/// Returns the top-level fields which differ between the configs, with their
+/// new value. This compares the debug output, so that all fields are covered.
+/// The settings derived in post_configure are left out.
+fn changed_fields(old: &Config, new: &Config) -> Vec<String> {
+    debug_fields(old)
+        .into_iter()
+        .zip(debug_fields(new))
+        .filter(|(old, new)| old != new)
+        .map(|(_, new)| new.trim_end_matches(',').to_string())
+        .filter(|new| {
+            let derived = ["http:", "fetch_map:", "file_permissions:", "provenance:"];
+            !derived.iter().any(|f| new.starts_with(f))
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -188,57 +1452,223 @@ pub fn create_test_config(
     let config = Config {
         verbose: 0,
         quiet: false,
+        log_format: LogFormat::Text,
+        json: false,
+        config_files: vec![],
         state_dir,
         rrdp_dir,
         rrdp_notify_delay: 0,
         rrdp_max_deltas: Some(3),
+        max_delta_total_bytes: None,
+        rrdp_apply_deltas: false,
+        allow_serial_rollback: false,
         rsync_dir,
+        rsync_dir_name_template: DEFAULT_RSYNC_DIR_NAME_TEMPLATE.to_string(),
+        rsync_module_readme: None,
+        preserve_empty_dirs: vec![],
+        rsync_swap: RsyncSwap::Symlink,
+        symlink_absolute: false,
+        rsync_target_url: None,
+        rsync_flat_layout: false,
         rsync_dir_force_moves,
         rsync_disable: false,
         rsync_include_host: false,
+        rsync_preserve_module: false,
+        rsync_write_threads: Some(2),
+        rsync_stream_objects: false,
+        normalize_mtime: None,
+        rsync_mtime_from_rrdp: false,
+        write_error_mode: WriteErrorMode::Abort,
+        validate_objects: false,
+        invalid_objects: InvalidObjectAction::Abort,
+        on_path_collision: PathCollisionAction::Abort,
+        max_object_bytes: 67108864,
+        oversized_objects: OversizedObjectAction::Abort,
+        rsync_no_fsync: false,
+        no_create_rsync_dir: false,
+        rsync_dir_use_hardlinks: false,
+        rsync_write_manifest: false,
+        write_rsync_index: false,
+        rsync_cas_dir: None,
+        rsync_cas_shard_chars: 0,
+        rsync_base_dir: None,
+        rsync_base_symlinks: false,
+        rsync_base_no_verify_hashes: false,
+        file_mode: None,
+        dir_mode: None,
+        file_owner: None,
+        file_group: None,
+        file_permissions: FilePermissions::default(),
+        rsync_keep_min_revisions: 0,
+        rsync_remove_orphans: false,
+        archive_old_revisions: false,
+        tmp_dir: None,
+        archive_dir: None,
+        rsync_exclude: vec![],
+        rsync_include_prefixes: vec![],
+        rsync_uri_rewrites: vec![],
+        max_object_shrink_pct: 20,
+        accept_shrink: false,
+        allow_empty: false,
+        min_free_bytes: 0,
+        rsync_lock_path: None,
+        wait: false,
+        print_config: false,
+        dry_run: false,
+        force_serial: None,
+        force_full_write: false,
+        force_session: None,
+        validate_only: false,
         cleanup_after: 2,
+        clean_before_write: false,
+        no_clean: false,
+        max_staleness_seconds: 600,
+        daemon: false,
+        interval_seconds: 60,
+        progress: false,
+        progress_interval_seconds: 5,
+        run_progress: Arc::default(),
+        pre_publish_command: None,
+        post_sync_command: None,
+        post_sync_on_no_change: false,
+        metrics_path: None,
+        report_path: None,
+        object_log_path: None,
+        log_object_hashes: false,
+        fetch_retries: 3,
+        fetch_retry_base_ms: 1,
+        fs_retries: 3,
+        fs_retry_delay_ms: 1,
+        connect_timeout: 30,
+        read_timeout: 120,
+        total_fetch_timeout: 300,
+        delta_fetch_concurrency: 4,
+        user_agent: None,
+        http_headers: vec![],
+        client_cert: None,
+        client_key: None,
+        ca_cert: None,
+        http: HttpSettings::default(),
+        max_download_bytes_per_sec: 0,
+        rrdp_cache_dir: None,
+        rrdp_cache_max_bytes: 536870912,
+        ip_version: IpVersion::Auto,
         insecure: false,
-        notification_uri,
+        notification_uri: Some(notification_uri),
         source_uri_base: Some(source_uri_base),
         fetch_map: None, // will be set in post_configure
+        rrdp_notify_uris: vec![],
+        rsync_modules: vec![],
+        module: None,
+        args: vec![],
+        provenance: BTreeMap::new(),
+        command: None,
     };
     post_configure(config).unwrap()
 }
 
 pub fn post_configure(mut config: Config) -> Result<Config> {
+    if config.json {
+        config.log_format = LogFormat::Json;
+        if config.report_path.is_none() {
+            config.report_path = Some(PathBuf::from("-"));
+        }
+        if let Some(Command::Diff { format, .. }) = config.command.as_mut() {
+            *format = LogFormat::Json;
+        }
+    }
     initialize_logging(&config);
+    expand_env_paths(&mut config)?;
 
-    let base_uri = config
-        .notification_uri
-        .parent()
-        .ok_or_else(|| anyhow!("Notification URI should contain a path to a file"))?;
-
-    // If a source_uri_base was specified together with --insecure,
-    // then we will need update the default 'strict' config. This is
-    // needed because the source map uses FromStr and is only aware
-    // of the URI / disk path.
-    if config.insecure {
-        let source_uri_base_opt = config.source_uri_base.take();
-
-        if let Some(source_uri_base) = source_uri_base_opt {
-            let source_uri_base = match source_uri_base {
-                FetchSource::File(file) => FetchSource::File(file),
-                FetchSource::Uri(uri, _) => FetchSource::Uri(uri, FetchMode::Insecure),
-            };
-
-            config.source_uri_base.replace(source_uri_base);
-        }
+    let mut module_names: Vec<&str> = config.rsync_modules.iter().map(|m| m.name()).collect();
+    module_names.sort_unstable();
+    if module_names.windows(2).any(|pair| pair[0] == pair[1]) {
+        return Err(anyhow!("rsync module names must be unique"));
     }
 
-    if let Some(base_fetch) = config.source_uri_base.as_ref() {
-        if !base_fetch.is_dir() {
+    // Module dirs live under the rsync dir, which is replaced as a whole
+    // with the flat layout.
+    if config.rsync_flat_layout && !config.rsync_modules.is_empty() {
+        return Err(anyhow!("--rsync-flat-layout cannot be combined with --rsync-module"));
+    }
+    if config.force_session.is_some() && config.force_serial.is_none() {
+        return Err(anyhow!("--force-session requires --force-serial"));
+    }
+    // A forced revision is a one-off, for a single source.
+    if config.force_serial.is_some() && (config.daemon || !config.rsync_modules.is_empty()) {
+        return Err(anyhow!("--force-serial cannot be combined with --daemon or --rsync-module"));
+    }
+    // These must stay inside the revision dir.
+    for dir in &config.preserve_empty_dirs {
+        if dir.as_os_str().is_empty()
+            || !dir.components().all(|c| matches!(c, std::path::Component::Normal(_)))
+        {
             return Err(anyhow!(
-                "source_uri_dir is not a readable dir or base path ending in a slash"
+                "--preserve-empty-dir must be a relative path without '..': {}",
+                dir.display()
             ));
-        } else {
-            config.fetch_map = Some(FetchMap::new(base_uri, base_fetch.clone()))
         }
     }
+    if config.force_full_write && config.daemon {
+        return Err(anyhow!("--rebuild cannot be combined with --daemon"));
+    }
+    if config.archive_old_revisions && config.archive_dir.is_none() {
+        return Err(anyhow!("--archive-old-revisions requires --archive-dir"));
+    }
+    if config.clean_before_write && config.no_clean {
+        return Err(anyhow!("--clean-before-write cannot be combined with --no-clean"));
+    }
+    if config.log_object_hashes && config.object_log_path.is_none() {
+        return Err(anyhow!("--log-object-hashes requires --log-objects"));
+    }
+    if config.progress_interval_seconds == 0 {
+        return Err(anyhow!("--progress-interval must be at least 1 second"));
+    }
+    if config.rsync_flat_layout && config.rsync_target_url.is_some() {
+        return Err(anyhow!("--rsync-flat-layout cannot be combined with --rsync-target-url"));
+    }
+
+    // Maintenance commands do not need a notification URI, in which case
+    // there is no source to configure.
+    let synchronizes = config.command.as_ref().map(Command::synchronizes).unwrap_or(true);
+    if !synchronizes && config.notification_uri.is_none() {
+        return Ok(config);
+    }
+
+    if let Some(notification_uri) = config.notification_uri.clone() {
+        config.fetch_map =
+            make_fetch_map(&notification_uri, &mut config.source_uri_base, config.insecure)?;
+    } else if config.rsync_modules.is_empty() {
+        return Err(anyhow!("The notification URI is required for synchronizing"));
+    }
+
+    if config.rsync_cas_dir.is_some() {
+        ObjectStore::check_supported()?;
+    }
+    if config.rsync_base_symlinks && config.rsync_base_dir.is_none() {
+        return Err(anyhow!("--rsync-base-symlinks requires --rsync-base-dir"));
+    }
+    if config.rsync_base_no_verify_hashes && config.rsync_base_dir.is_none() {
+        return Err(anyhow!("--rsync-base-no-verify-hashes requires --rsync-base-dir"));
+    }
+    if config.rsync_cas_shard_chars > MAX_CAS_SHARD_CHARS {
+        return Err(anyhow!("--rsync-cas-shard-chars must be at most {}", MAX_CAS_SHARD_CHARS));
+    }
+
+    config.file_permissions = resolve_file_permissions(&config)?;
+    config.http = resolve_http_settings(&config)?;
+
+    // The revision directories live next to 'current' in the rsync dir, and
+    // every new revision needs to get a new directory.
+    let template = &config.rsync_dir_name_template;
+    if template.contains('/') || template.contains('\\') {
+        return Err(anyhow!("rsync dir name template must not contain path separators"));
+    }
+    if !template.contains("{serial}") && !template.contains("{timestamp}") {
+        return Err(anyhow!(
+            "rsync dir name template must include {{serial}} or {{timestamp}}"
+        ));
+    }
 
     // If --state-dir was changed from the default, ensure that --rrdp-dir and
     // --rsync-dir follow the change if their defaults were not overridden. This
@@ -263,56 +1693,334 @@ pub fn post_configure(mut config: Config) -> Result<Config> {
     Ok(config)
 }
 
+/// Expands environment variable references in all path options, see
+/// `util::expand_vars`. Hook commands are not expanded here, because they
+/// are run by a shell, which may also use the variables that we set.
+fn expand_env_paths(config: &mut Config) -> Result<()> {
+    for path in [&mut config.state_dir, &mut config.rrdp_dir, &mut config.rsync_dir] {
+        *path = util::expand_env_path(path)?;
+    }
+
+    for path in vec![
+        &mut config.rsync_module_readme,
+        &mut config.rsync_cas_dir,
+        &mut config.rsync_base_dir,
+        &mut config.tmp_dir,
+        &mut config.archive_dir,
+        &mut config.rsync_lock_path,
+        &mut config.metrics_path,
+        &mut config.report_path,
+        &mut config.object_log_path,
+        &mut config.client_cert,
+        &mut config.client_key,
+        &mut config.ca_cert,
+        &mut config.rrdp_cache_dir,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        *path = util::expand_env_path(path)?;
+    }
+
+    if let Some(FetchSource::File(path)) = config.source_uri_base.as_mut() {
+        *path = util::expand_env_path(path)?;
+    }
+    for source in &mut config.rrdp_notify_uris {
+        if let FetchSource::File(path) = source {
+            *path = util::expand_env_path(path)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(unix)]
+fn resolve_file_permissions(config: &Config) -> Result<FilePermissions> {
+    Ok(FilePermissions {
+        file_mode: config.file_mode,
+        dir_mode: config.dir_mode,
+        uid: config.file_owner.as_deref().map(file_ops::resolve_uid).transpose()?,
+        gid: config.file_group.as_deref().map(file_ops::resolve_gid).transpose()?,
+    })
+}
+
+#[cfg(not(unix))]
+fn resolve_file_permissions(config: &Config) -> Result<FilePermissions> {
+    if config.file_mode.is_some()
+        || config.dir_mode.is_some()
+        || config.file_owner.is_some()
+        || config.file_group.is_some()
+    {
+        Err(anyhow!("File modes and ownership are only supported on unix"))
+    } else {
+        Ok(FilePermissions::default())
+    }
+}
+
+/// Loads the TLS material now, so that we fail before fetching anything if
+/// it cannot be used.
+fn resolve_http_settings(config: &Config) -> Result<HttpSettings> {
+    let user_agent = config.user_agent.as_deref().unwrap_or(USER_AGENT);
+    let mut http = HttpSettings::new(user_agent.to_string(), &config.http_headers)
+        .with_rate_limit(config.max_download_bytes_per_sec)
+        .with_ip_version(config.ip_version)
+        .with_timeouts(timeout(config.connect_timeout), timeout(config.read_timeout));
+
+    match (&config.client_cert, &config.client_key) {
+        (Some(cert), Some(key)) => http = http.with_client_cert(cert, key)?,
+        (None, None) => {}
+        _ => return Err(anyhow!("--client-cert and --client-key must be used together")),
+    }
+
+    if let Some(ca_cert) = &config.ca_cert {
+        http = http.with_ca_cert(ca_cert)?;
+    }
+
+    Ok(http)
+}
+
+/// Converts a timeout in seconds, where 0 means no timeout.
+fn timeout(seconds: i64) -> Option<Duration> {
+    if seconds > 0 {
+        Some(Duration::from_secs(seconds as u64))
+    } else {
+        None
+    }
+}
+
+/// Maps the source_uri_base, if any, to the base of the notification URI.
+fn make_fetch_map(
+    notification_uri: &Https,
+    source_uri_base: &mut Option<FetchSource>,
+    insecure: bool,
+) -> Result<Option<FetchMap>> {
+    let base_uri = notification_uri
+        .parent()
+        .ok_or_else(|| anyhow!("Notification URI should contain a path to a file"))?;
+
+    // If a source_uri_base was specified together with --insecure,
+    // then we will need update the default 'strict' config. This is
+    // needed because the source map uses FromStr and is only aware
+    // of the URI / disk path.
+    if insecure {
+        if let Some(FetchSource::Uri(_, mode)) = source_uri_base.as_mut() {
+            *mode = FetchMode::Insecure;
+        }
+    }
+
+    match source_uri_base.as_ref() {
+        None => Ok(None),
+        Some(base_fetch) => {
+            if !base_fetch.is_dir() {
+                Err(anyhow!(
+                    "source_uri_dir is not a readable dir or base path ending in a slash"
+                ))
+            } else {
+                Ok(Some(FetchMap::new(base_uri, base_fetch.clone())))
+            }
+        }
+    }
+}
+
 fn initialize_logging(config: &Config) {
     let (ks_log_level, other_log_level) = if config.quiet {
-        (LevelFilter::Error, LevelFilter::Error)
+        (LevelFilter::ERROR, LevelFilter::ERROR)
     } else {
         match config.verbose {
-            0 => (LevelFilter::Warn, LevelFilter::Warn),
-            1 => (LevelFilter::Info, LevelFilter::Warn),
-            2 => (LevelFilter::Debug, LevelFilter::Warn),
-            3 => (LevelFilter::Trace, LevelFilter::Warn),
-            4 => (LevelFilter::Trace, LevelFilter::Info),
-            5 => (LevelFilter::Trace, LevelFilter::Debug),
-            _ => (LevelFilter::Trace, LevelFilter::Trace),
+            0 => (LevelFilter::WARN, LevelFilter::WARN),
+            1 => (LevelFilter::INFO, LevelFilter::WARN),
+            2 => (LevelFilter::DEBUG, LevelFilter::WARN),
+            3 => (LevelFilter::TRACE, LevelFilter::WARN),
+            4 => (LevelFilter::TRACE, LevelFilter::INFO),
+            5 => (LevelFilter::TRACE, LevelFilter::DEBUG),
+            _ => (LevelFilter::TRACE, LevelFilter::TRACE),
         }
     };
 
+    let filter = Targets::new()
+        .with_default(other_log_level)
+        .with_target("krill_sync", ks_log_level);
+
+    // Events are logged with the fields of the spans they are in, e.g. the
+    // phase, session and serial. Log records from dependencies which use
+    // the log crate are included as events.
+    let fmt = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stdout)
+        .with_timer(LocalTime)
+        .with_target(ks_log_level > LevelFilter::DEBUG);
+
     // ignore the result - this will only fail if logging was already initialized,
     // and that may happen when running tests in parallel.
-    let _ = fern::Dispatch::new()
-        .format(move |out, message, record| {
-            if ks_log_level <= LevelFilter::Debug {
-                log_without_target(out, message, record)
-            } else {
-                log_with_target(out, message, record)
-            }
+    let _ = match config.log_format {
+        LogFormat::Text => tracing_subscriber::registry()
+            .with(fmt.with_filter(filter))
+            .try_init(),
+        LogFormat::Json => tracing_subscriber::registry()
+            .with(fmt.json().with_span_list(true).with_filter(filter))
+            .try_init(),
+    };
+}
+
+/// Formats the time of log lines like krill-sync always did.
+struct LocalTime;
+
+impl FormatTime for LocalTime {
+    fn format_time(&self, w: &mut Writer<'_>) -> std::fmt::Result {
+        write!(w, "{}", chrono::Local::now().format("%Y/%m/%d %H:%M:%S"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::util::test_with_dir;
+
+    use super::*;
+
+    #[test]
+    fn reload_config_file() {
+        test_with_dir("config_reload_config_file", |dir| {
+            let config_path = dir.join("krill-sync.conf");
+            let write_config = |cleanup_after: u64, rsync_dir: &str| {
+                let mut content = format!(
+                    "# Options for testing\n\n--cleanup-after {}\n--rsync-dir {}\n--rsync-exclude *.crl\n",
+                    cleanup_after,
+                    dir.join(rsync_dir).display()
+                );
+                // Options for the layout of the rsync dir, only for the later file
+                if rsync_dir != "rsync" {
+                    content.push_str("--rsync-include-host\n--rsync-cas-shard-chars 2\n");
+                    content.push_str("--rsync-dir-name-template rev-{serial}\n");
+                    content.push_str("--rsync-module other=https://rrdp.example.net/notification.xml\n");
+                }
+                for option in ["rsync-base-dir", "tmp-dir", "archive-dir", "rrdp-cache-dir"] {
+                    let path = dir.join(format!("{}-{}", rsync_dir, option));
+                    content.push_str(&format!("--{} {}\n", option, path.display()));
+                }
+                let lock_path = dir.join(format!("{}.lock", rsync_dir));
+                content.push_str(&format!("--rsync-lock-path {}\n", lock_path.display()));
+                std::fs::write(&config_path, content).unwrap();
+            };
+            write_config(600, "rsync");
+
+            let args: Vec<OsString> = vec![
+                "krill-sync".into(),
+                "--config".into(),
+                config_path.clone().into(),
+                "--state-dir".into(),
+                dir.join("state").into(),
+                "--rrdp-dir".into(),
+                dir.join("rrdp").into(),
+                "--source_uri_base".into(),
+                "./test-resources/rrdp-rev2656/".into(),
+                "https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml".into(),
+            ];
+            let config = Config::from_iter_safe(&args).unwrap();
+            let config = post_configure(with_config_files(config, args).unwrap()).unwrap();
+            assert_eq!(config.cleanup_after, 600);
+            assert_eq!(config.rsync_dir, dir.join("rsync"));
+            assert_eq!(config.rsync_exclude, vec![Pattern::new("*.crl").unwrap()]);
+
+            // The rsync dir and the other dirs and files used by earlier runs
+            // are in use, so they are kept
+            write_config(900, "other");
+            let reloaded = config.reload().unwrap();
+            assert_eq!(reloaded.cleanup_after, 900);
+            assert_eq!(reloaded.rsync_dir, dir.join("rsync"));
+            assert_eq!(reloaded.rsync_base_dir, Some(dir.join("rsync-rsync-base-dir")));
+            assert_eq!(reloaded.tmp_dir, Some(dir.join("rsync-tmp-dir")));
+            assert_eq!(reloaded.archive_dir, Some(dir.join("rsync-archive-dir")));
+            assert_eq!(reloaded.rrdp_cache_dir, Some(dir.join("rsync-rrdp-cache-dir")));
+            assert_eq!(reloaded.rsync_lock_path, Some(dir.join("rsync.lock")));
+            assert!(!reloaded.rsync_include_host);
+            assert_eq!(reloaded.rsync_cas_shard_chars, 0);
+            assert_eq!(reloaded.rsync_dir_name_template, config.rsync_dir_name_template);
+            assert!(reloaded.rsync_modules.is_empty());
+            assert_eq!(changed_fields(&config, &reloaded), vec!["cleanup_after: 900"]);
+
+            std::fs::write(&config_path, "--no-such-option\n").unwrap();
+            assert!(config.reload().is_err());
         })
-        .level(other_log_level)
-        .level_for("krill_sync", ks_log_level)
-        .chain(std::io::stdout())
-        .apply();
-}
-
-fn log_without_target(
-    out: fern::FormatCallback,
-    message: &std::fmt::Arguments,
-    record: &log::Record,
-) {
-    out.finish(format_args!(
-        "{} {}: {}",
-        chrono::Local::now().format("%Y/%m/%d %H:%M:%S"),
-        record.level(),
-        message,
-    ))
-}
-
-fn log_with_target(out: fern::FormatCallback, message: &std::fmt::Arguments, record: &log::Record) {
-    out.finish(format_args!(
-        "{} {} [{}] {}",
-        chrono::Local::now().format("%Y/%m/%d %H:%M:%S"),
-        record.level(),
-        record.target(),
-        message,
-    ))
+    }
+
+    #[test]
+    fn layered_config_files() {
+        test_with_dir("config_layered_config_files", |dir| {
+            let base_path = dir.join("base.conf");
+            let host_path = dir.join("host.conf");
+            std::fs::write(
+                &base_path,
+                "--cleanup-after 600\n--rsync-exclude *.crl\n--rsync-exclude *.gbr\n--insecure\n",
+            )
+            .unwrap();
+            std::fs::write(&host_path, "--cleanup-after 900\n--rsync-exclude *.mft\n").unwrap();
+
+            let args: Vec<OsString> = vec![
+                "krill-sync".into(),
+                "--config".into(),
+                base_path.clone().into(),
+                "--config".into(),
+                host_path.clone().into(),
+                "--state-dir".into(),
+                dir.join("state").into(),
+                "--cleanup-after".into(),
+                "1200".into(),
+                "https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml".into(),
+            ];
+            let config = Config::from_iter_safe(&args).unwrap();
+            let config = with_config_files(config, args).unwrap();
+
+            // Repeated options are replaced as a whole
+            assert_eq!(config.cleanup_after, 1200);
+            assert_eq!(config.rsync_exclude, vec![Pattern::new("*.mft").unwrap()]);
+            assert!(config.insecure);
+
+            let host = host_path.display().to_string();
+            let base = base_path.display().to_string();
+            assert_eq!(config.provenance["cleanup_after"], "command line");
+            assert_eq!(config.provenance["rsync_exclude"], host);
+            assert_eq!(config.provenance["insecure"], base);
+            assert!(!config.provenance.contains_key("rsync_dir"));
+
+            let description = config.describe();
+            assert!(description.contains("cleanup_after: 1200 # command line\n"));
+            assert!(description.contains(&format!("insecure: true # {}\n", base)));
+            assert!(description.contains("rsync_disable: false # default\n"));
+            assert!(!description.contains("provenance"));
+
+            // Config files cannot be nested
+            std::fs::write(&host_path, format!("--config {}\n", base)).unwrap();
+            let args: Vec<OsString> =
+                vec!["krill-sync".into(), "--config".into(), host_path.into()];
+            let config = Config::from_iter_safe(&args).unwrap();
+            assert!(with_config_files(config, args).is_err());
+        })
+    }
+
+    #[test]
+    fn json_output() {
+        test_with_dir("config_json_output", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                util::https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.json = true;
+            config.command = Some(Command::Diff {
+                from: 2656,
+                to: 2657,
+                format: LogFormat::Text,
+            });
+            let mut config = post_configure(config).unwrap();
+            assert_eq!(config.log_format, LogFormat::Json);
+            assert_eq!(config.report_path, Some(PathBuf::from("-")));
+            assert!(matches!(config.command, Some(Command::Diff { format: LogFormat::Json, .. })));
+
+            // An explicit report path is kept
+            config.report_path = Some(dir.join("report.json"));
+            let config = post_configure(config).unwrap();
+            assert_eq!(config.report_path, Some(dir.join("report.json")));
+        })
+    }
 }
@@ -0,0 +1,189 @@
+//! The exit codes of the krill-sync binary, so that scripts can tell classes
+//! of failures apart. Errors are classified by the types in their chain,
+//! using the marker types below for failures which are not otherwise typed.
+
+use std::{fmt, io};
+
+use rpki::{rrdp::ProcessError, xml::decode};
+use serde::Serialize;
+
+use crate::fetch::{FetchTimeout, UnexpectedStatus};
+
+//------------ ExitCode ------------------------------------------------------
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExitCode {
+    /// Any other failure, e.g. a vetoing hook or an unhealthy health check.
+    Other = 1,
+
+    /// Invalid options or config file.
+    Config = 2,
+
+    /// Fetching from the RRDP server failed, e.g. a connection error, an
+    /// HTTP error status or a timeout.
+    Fetch = 3,
+
+    /// The RRDP or RPKI data is invalid, e.g. a hash mismatch or malformed
+    /// XML, or verification found problems.
+    InvalidData = 4,
+
+    /// Reading or writing local files failed.
+    Io = 5,
+
+    /// Another krill-sync process holds the lock.
+    Locked = 6,
+}
+
+impl ExitCode {
+    /// Classifies an error by the most specific cause in its chain. A local
+    /// IO error is only reported if nothing else explains the failure,
+    /// because it may well be the cause of a fetch error.
+    pub fn for_error(err: &anyhow::Error) -> Self {
+        let any = |f: fn(&(dyn std::error::Error + 'static)) -> bool| err.chain().any(f);
+
+        if any(|cause| cause.is::<LockHeld>()) {
+            ExitCode::Locked
+        } else if any(is_invalid_data) {
+            ExitCode::InvalidData
+        } else if any(is_fetch_error) {
+            ExitCode::Fetch
+        } else if any(|cause| cause.is::<io::Error>() || cause.is::<ProcessError>()) {
+            ExitCode::Io
+        } else {
+            ExitCode::Other
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        self as i32
+    }
+}
+
+fn is_fetch_error(cause: &(dyn std::error::Error + 'static)) -> bool {
+    cause.is::<reqwest::Error>() || cause.is::<UnexpectedStatus>() || cause.is::<FetchTimeout>()
+}
+
+fn is_invalid_data(cause: &(dyn std::error::Error + 'static)) -> bool {
+    if let Some(err) = cause.downcast_ref::<ProcessError>() {
+        matches!(err, ProcessError::Xml(_))
+    } else {
+        cause.is::<InvalidData>() || cause.is::<decode::Error>()
+    }
+}
+
+//------------ ErrorReport ---------------------------------------------------
+
+/// A failure as reported with `--json`, before exiting with its code.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ErrorReport {
+    /// The outermost message of the error.
+    pub error: String,
+
+    /// The messages of the causes of the error, outermost first.
+    pub causes: Vec<String>,
+    pub exit_code: i32,
+}
+
+impl ErrorReport {
+    pub fn new(err: &anyhow::Error, code: ExitCode) -> Self {
+        ErrorReport {
+            error: err.to_string(),
+            causes: err.chain().skip(1).map(ToString::to_string).collect(),
+            exit_code: code.code(),
+        }
+    }
+}
+
+//------------ InvalidData ---------------------------------------------------
+
+/// The RRDP or RPKI data is invalid. Use `invalid_data` to make one.
+#[derive(Debug)]
+pub struct InvalidData(String);
+
+impl fmt::Display for InvalidData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for InvalidData {}
+
+/// Returns an error with this message, which is classified as invalid data.
+pub fn invalid_data(msg: impl fmt::Display) -> anyhow::Error {
+    anyhow::Error::new(InvalidData(msg.to_string()))
+}
+
+//------------ LockHeld ------------------------------------------------------
+
+/// Another krill-sync process holds the lock.
+#[derive(Debug)]
+pub struct LockHeld(pub String);
+
+impl fmt::Display for LockHeld {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for LockHeld {}
+
+#[cfg(test)]
+mod tests {
+
+    use anyhow::anyhow;
+
+    use crate::{
+        fetch::{FetchMode, Fetcher},
+        util::https,
+    };
+
+    use super::*;
+
+    #[test]
+    fn classify_error_chains() {
+        let io_err = || io::Error::new(io::ErrorKind::PermissionDenied, "denied");
+
+        assert_eq!(ExitCode::for_error(&anyhow!("hook vetoed")), ExitCode::Other);
+        assert_eq!(
+            ExitCode::for_error(&anyhow::Error::new(io_err()).context("Cannot write file")),
+            ExitCode::Io
+        );
+        assert_eq!(
+            ExitCode::for_error(&invalid_data("Hash mismatch").context("Cannot apply delta")),
+            ExitCode::InvalidData
+        );
+        assert_eq!(
+            ExitCode::for_error(&anyhow::Error::new(ProcessError::Xml(decode::Error::Malformed))),
+            ExitCode::InvalidData
+        );
+        assert_eq!(
+            ExitCode::for_error(&anyhow::Error::new(ProcessError::Io(io_err()))),
+            ExitCode::Io
+        );
+        assert_eq!(
+            ExitCode::for_error(&anyhow::Error::new(LockHeld("held".to_string()))),
+            ExitCode::Locked
+        );
+
+        // The connection error also has an IO error in its chain
+        let fetcher = Fetcher::new(
+            https("https://localhost:1/rrdp/notification.xml"),
+            None,
+            FetchMode::Strict,
+        );
+        let err = fetcher.read_notification_file(None).err().unwrap();
+        assert!(err.chain().any(|cause| cause.is::<io::Error>()));
+        assert_eq!(ExitCode::for_error(&err), ExitCode::Fetch);
+        assert_eq!(ExitCode::Fetch.code(), 3);
+    }
+
+    #[test]
+    fn report_error_as_json() {
+        let err = invalid_data("Hash mismatch").context("Cannot apply delta");
+        let report = ErrorReport::new(&err, ExitCode::for_error(&err));
+        assert_eq!(
+            serde_json::to_string(&report).unwrap(),
+            r#"{"error":"Cannot apply delta","causes":["Hash mismatch"],"exit_code":4}"#
+        );
+    }
+}
@@ -1,27 +1,491 @@
 use std::{
     fmt::{self, Debug},
+    io::{self, Read},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::{Path, PathBuf},
     str::FromStr,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
 use reqwest::{
-    blocking::Client,
-    header::{ETAG, IF_NONE_MATCH, USER_AGENT},
-    StatusCode,
+    blocking::{Client, RequestBuilder, Response},
+    Certificate,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH,
+        LAST_MODIFIED,
+    },
+    Identity, StatusCode,
 };
+use tracing::{info, warn};
 
 use rpki::{
-    rrdp::{Hash, NotificationFile},
+    rrdp::{DeltaInfo, Hash, NotificationFile, SnapshotInfo},
     uri::{self, Https},
 };
 
-use crate::{config, file_ops};
+use crate::{cache::RrdpCache, config, exit, file_ops};
+
+//------------ CacheValidators -----------------------------------------------
+
+/// The ETag and Last-Modified values from a previous response, used to
+/// make a conditional request for the next fetch.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct CacheValidators {
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+impl CacheValidators {
+    fn from_headers(headers: &HeaderMap) -> Result<Self> {
+        let header = |name: HeaderName| -> Result<Option<String>> {
+            match headers.get(&name) {
+                None => Ok(None),
+                Some(header_value) => Ok(Some(
+                    header_value
+                        .to_str()
+                        .with_context(|| format!("invalid {} in response header", name))?
+                        .to_owned(),
+                )),
+            }
+        };
+
+        Ok(CacheValidators {
+            etag: header(ETAG)?,
+            last_modified: header(LAST_MODIFIED)?,
+        })
+    }
+}
+
+/// Formats the time like an HTTP Last-Modified header.
+fn http_date(time: std::time::SystemTime) -> String {
+    chrono::DateTime::<chrono::Utc>::from(time).format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+//------------ HttpHeader ----------------------------------------------------
+
+/// An extra header for all RRDP HTTP requests, parsed from 'name: value'.
+///
+/// The value may hold a secret, e.g. an authorization token, so it is not
+/// included in the Debug output.
+#[derive(Clone, Eq, PartialEq)]
+pub struct HttpHeader {
+    name: HeaderName,
+    value: HeaderValue,
+}
+
+impl Debug for HttpHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: <redacted>", self.name)
+    }
+}
+
+impl FromStr for HttpHeader {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected 'name: value' for HTTP header, got: {}", s))?;
+
+        let name = HeaderName::from_str(name.trim())
+            .with_context(|| format!("Invalid HTTP header name in: {}", s))?;
+        let value = HeaderValue::from_str(value.trim())
+            .with_context(|| format!("Invalid HTTP header value in: {}", s))?;
+
+        Ok(HttpHeader { name, value })
+    }
+}
+
+//------------ IpVersion -----------------------------------------------------
+
+/// The address family used to connect to RRDP servers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IpVersion {
+    /// Use whatever the resolver and the connector prefer.
+    Auto,
+
+    /// Only connect to IPv4 addresses.
+    V4,
+
+    /// Only connect to IPv6 addresses.
+    V6,
+}
+
+impl IpVersion {
+    /// The unspecified local address to bind to, which makes the connector
+    /// skip resolved addresses of the other family.
+    fn local_address(self) -> Option<IpAddr> {
+        match self {
+            IpVersion::Auto => None,
+            IpVersion::V4 => Some(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+            IpVersion::V6 => Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+        }
+    }
+}
+
+impl FromStr for IpVersion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(IpVersion::Auto),
+            "v4" => Ok(IpVersion::V4),
+            "v6" => Ok(IpVersion::V6),
+            _ => Err(anyhow!("Expected 'auto', 'v4' or 'v6', got: {}", s)),
+        }
+    }
+}
+
+//------------ HttpSettings --------------------------------------------------
+
+/// The User-Agent, extra headers and TLS material used for all RRDP HTTP
+/// requests. The Debug output leaves out header values and the client key.
+///
+/// The HTTP clients are built when first needed and then shared by all
+/// requests, also from other threads, so that connections are reused.
+#[derive(Clone)]
+pub struct HttpSettings {
+    user_agent: String,
+    headers: HeaderMap,
+    identity: Option<Identity>,
+    ca_cert: Option<Certificate>,
+    max_bytes_per_sec: u64,
+    rate_limit: Option<Arc<Mutex<TokenBucket>>>,
+    ip_version: IpVersion,
+    connect_timeout: Option<Duration>,
+    read_timeout: Option<Duration>,
+    clients: Arc<Mutex<Vec<(FetchMode, Client)>>>,
+}
+
+impl HttpSettings {
+    pub fn new(user_agent: String, headers: &[HttpHeader]) -> Self {
+        let mut map = HeaderMap::new();
+        for header in headers {
+            map.append(header.name.clone(), header.value.clone());
+        }
+
+        HttpSettings {
+            user_agent,
+            headers: map,
+            identity: None,
+            ca_cert: None,
+            max_bytes_per_sec: 0,
+            rate_limit: None,
+            ip_version: IpVersion::Auto,
+            connect_timeout: None,
+            read_timeout: None,
+            clients: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    /// Only connect to RRDP servers over this address family.
+    pub fn with_ip_version(mut self, ip_version: IpVersion) -> Self {
+        self.ip_version = ip_version;
+        self
+    }
+
+    /// Limit the download rate of response bodies, 0 means unlimited. The
+    /// limit is for all requests together, also when they are concurrent.
+    pub fn with_rate_limit(mut self, max_bytes_per_sec: u64) -> Self {
+        self.max_bytes_per_sec = max_bytes_per_sec;
+        self.rate_limit = (max_bytes_per_sec > 0)
+            .then(|| Arc::new(Mutex::new(TokenBucket::new(max_bytes_per_sec))));
+        self
+    }
+
+    /// Give up connecting after `connect`, and give up on a request if no
+    /// data is received for longer than `read`, while waiting for the
+    /// response or reading its body. A slow download which keeps making
+    /// progress is not given up on. None means no timeout.
+    pub fn with_timeouts(mut self, connect: Option<Duration>, read: Option<Duration>) -> Self {
+        self.connect_timeout = connect;
+        self.read_timeout = read;
+        self
+    }
+
+    /// Authenticate with a client certificate, loaded from PEM files. The
+    /// key must be in PKCS#8 format.
+    pub fn with_client_cert(mut self, cert_path: &Path, key_path: &Path) -> Result<Self> {
+        let cert = file_ops::read_file(cert_path)
+            .with_context(|| format!("Cannot read client certificate: {}", cert_path.display()))?;
+        let key = file_ops::read_file(key_path)
+            .with_context(|| format!("Cannot read client key: {}", key_path.display()))?;
+
+        let identity = Identity::from_pkcs8_pem(&cert, &key).with_context(|| {
+            format!(
+                "Cannot load client certificate {} with key {}",
+                cert_path.display(),
+                key_path.display()
+            )
+        })?;
+
+        self.identity = Some(identity);
+        Ok(self)
+    }
+
+    /// Trust the CA certificate in the given PEM file, in addition to the
+    /// system's trusted CAs.
+    pub fn with_ca_cert(mut self, path: &Path) -> Result<Self> {
+        let pem = file_ops::read_file(path)
+            .with_context(|| format!("Cannot read CA certificate: {}", path.display()))?;
+        let ca_cert = Certificate::from_pem(&pem)
+            .with_context(|| format!("Cannot load CA certificate: {}", path.display()))?;
+
+        self.ca_cert = Some(ca_cert);
+        Ok(self)
+    }
+
+    fn client(&self, mode: FetchMode) -> Result<Client> {
+        let mut clients = self.clients.lock().unwrap();
+        if let Some((_, client)) = clients.iter().find(|(m, _)| *m == mode) {
+            // Clones share the connection pool
+            return Ok(client.clone());
+        }
+
+        let client = self.build_client(mode)?;
+        clients.push((mode, client.clone()));
+        Ok(client)
+    }
+
+    fn build_client(&self, mode: FetchMode) -> Result<Client> {
+        // Ask for gzip compressed responses, which are decompressed
+        // transparently before we verify the hash. Responses from
+        // servers which ignore the Accept-Encoding are used as is.
+        let mut builder = Client::builder()
+            .gzip(true)
+            .user_agent(self.user_agent.as_str())
+            .default_headers(self.headers.clone())
+            .danger_accept_invalid_certs(mode.accept_insecure())
+            .danger_accept_invalid_hostnames(mode.accept_insecure())
+            .local_address(self.ip_version.local_address())
+            .connect_timeout(self.connect_timeout)
+            .timeout(None::<Duration>);
+
+        if let Some(identity) = &self.identity {
+            builder = builder.identity(identity.clone());
+        }
+        if let Some(ca_cert) = &self.ca_cert {
+            builder = builder.add_root_certificate(ca_cert.clone());
+        }
+
+        Ok(builder.build()?)
+    }
+}
+
+impl Debug for HttpSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HttpSettings")
+            .field("user_agent", &self.user_agent)
+            .field("headers", &self.headers.keys().collect::<Vec<_>>())
+            .field("client_cert", &self.identity.as_ref().map(|_| "<redacted>"))
+            .field("ca_cert", &self.ca_cert.is_some())
+            .field("max_bytes_per_sec", &self.max_bytes_per_sec)
+            .field("ip_version", &self.ip_version)
+            .field("connect_timeout", &self.connect_timeout)
+            .field("read_timeout", &self.read_timeout)
+            .finish()
+    }
+}
+
+impl Default for HttpSettings {
+    fn default() -> Self {
+        HttpSettings::new(config::USER_AGENT.to_string(), &[])
+    }
+}
+
+//------------ StallTimeoutReader --------------------------------------------
+
+/// The size of the chunks read ahead by a `StallTimeoutReader`.
+const STALL_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Gives up when no data arrives from the inner reader for longer than the
+/// timeout, rather than when reading all of it takes too long. The inner
+/// reader is read on another thread, which is left behind if it stalls, until
+/// its own read fails, e.g. at the timeout of the request.
+struct StallTimeoutReader {
+    chunks: mpsc::Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+    timeout: Duration,
+    done: bool,
+}
+
+impl StallTimeoutReader {
+    fn new<R: Read + Send + 'static>(mut inner: R, timeout: Duration) -> Self {
+        // Only one chunk is read ahead, so that a rate limit on reading from
+        // this reader also limits reading from the inner reader.
+        let (sender, chunks) = mpsc::sync_channel(1);
+        std::thread::spawn(move || {
+            let mut buf = vec![0; STALL_CHUNK_SIZE];
+            loop {
+                let chunk = match inner.read(&mut buf) {
+                    Ok(len) => Ok(buf[..len].to_vec()),
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let last = !matches!(&chunk, Ok(data) if !data.is_empty());
+                if sender.send(chunk).is_err() || last {
+                    return;
+                }
+            }
+        });
+
+        StallTimeoutReader {
+            chunks,
+            chunk: vec![],
+            pos: 0,
+            timeout,
+            done: false,
+        }
+    }
+}
+
+impl Read for StallTimeoutReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            if self.done || buf.is_empty() {
+                return Ok(0);
+            }
+            match self.chunks.recv_timeout(self.timeout) {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                    self.done = self.chunk.is_empty();
+                }
+                Err(RecvTimeoutError::Timeout) => return Err(stalled(self.timeout)),
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reading was aborted"))
+                }
+            }
+        }
+
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+/// Sends the request on another thread, and gives up if there is no response
+/// within the timeout, like `StallTimeoutReader`.
+fn send_within(request: RequestBuilder, timeout: Option<Duration>) -> Result<Response> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return Ok(request.send()?),
+    };
+
+    let (sender, response) = mpsc::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(request.send());
+    });
+    match response.recv_timeout(timeout) {
+        Ok(response) => Ok(response?),
+        Err(_) => Err(anyhow::Error::new(stalled(timeout))),
+    }
+}
+
+fn stalled(timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("No data received for {:?}, giving up", timeout),
+    )
+}
+
+//------------ RateLimitedReader ---------------------------------------------
+
+/// A token bucket which holds at most one second worth of data. It is shared
+/// by all readers for the same settings, so that concurrent downloads stay
+/// within the rate together.
+#[derive(Debug)]
+struct TokenBucket {
+    bytes_per_sec: u64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: u64) -> Self {
+        TokenBucket {
+            bytes_per_sec,
+            tokens: bytes_per_sec as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let earned = now.duration_since(self.last_refill).as_secs_f64() * self.bytes_per_sec as f64;
+        self.tokens = (self.tokens + earned).min(self.bytes_per_sec as f64);
+        self.last_refill = now;
+    }
+
+    /// Waits until there is at least one token, and then takes up to `max`.
+    /// The lock is not held while waiting. Returns the number taken.
+    fn take(bucket: &Mutex<TokenBucket>, max: usize) -> usize {
+        loop {
+            let wait = {
+                let mut bucket = bucket.lock().unwrap();
+                bucket.refill();
+                if bucket.tokens >= 1.0 {
+                    let taken = (bucket.tokens as usize).min(max);
+                    bucket.tokens -= taken as f64;
+                    return taken;
+                }
+                (1.0 - bucket.tokens) / bucket.bytes_per_sec as f64
+            };
+            std::thread::sleep(Duration::from_secs_f64(wait));
+        }
+    }
+
+    /// Returns tokens which were taken, but not used.
+    fn put_back(bucket: &Mutex<TokenBucket>, unused: usize) {
+        let mut bucket = bucket.lock().unwrap();
+        bucket.tokens = (bucket.tokens + unused as f64).min(bucket.bytes_per_sec as f64);
+    }
+}
+
+/// Limits the rate at which data is read from the inner reader, using a
+/// shared token bucket.
+struct RateLimitedReader<R> {
+    inner: R,
+    bucket: Arc<Mutex<TokenBucket>>,
+}
+
+impl<R: Read> RateLimitedReader<R> {
+    fn new(inner: R, bucket: Arc<Mutex<TokenBucket>>) -> Self {
+        RateLimitedReader { inner, bucket }
+    }
+}
+
+impl<R: Read> Read for RateLimitedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let allowed = TokenBucket::take(&self.bucket, buf.len());
+        let read = self.inner.read(&mut buf[..allowed]);
+        let used = *read.as_ref().unwrap_or(&0);
+        if used < allowed {
+            TokenBucket::put_back(&self.bucket, allowed - used);
+        }
+        read
+    }
+}
 
 //------------ FetchResponse -------------------------------------------------
 pub enum FetchResponse {
-    Data { bytes: Bytes, etag: Option<String> },
+    Data {
+        bytes: Bytes,
+        validators: CacheValidators,
+    },
     Saved,
     UnModified,
 }
@@ -30,15 +494,18 @@ pub enum FetchResponse {
 pub enum NotificationFileResponse {
     Data {
         notification: NotificationFile,
-        etag: Option<String>,
+        validators: CacheValidators,
     },
     Unmodified,
 }
 
 impl NotificationFileResponse {
-    pub fn content(self) -> Result<(NotificationFile, Option<String>)> {
+    pub fn content(self) -> Result<(NotificationFile, CacheValidators)> {
         match self {
-            NotificationFileResponse::Data { notification, etag } => Ok((notification, etag)),
+            NotificationFileResponse::Data {
+                notification,
+                validators,
+            } => Ok((notification, validators)),
             NotificationFileResponse::Unmodified => {
                 Err(anyhow!("Cannot get content from unmodified response"))
             }
@@ -76,14 +543,19 @@ impl FetchSource {
 impl FetchSource {
     /// Gets the data from the fetch source.
     /// - checks the hash if it is provided
-    /// - uses the etag to avoid expensive http fetching if provided
+    /// - uses the etag and/or last modified time of a previous response, if
+    ///   provided, to avoid expensive http fetching
     /// - if the target_file is provided then the data will be copied there
     ///   rather than be returned.
+    /// - if a timeout is provided then the request is given up on after that
+    ///   time, if the HttpSettings do not give up earlier.
     pub fn fetch(
         &self,
+        http: &HttpSettings,
         hash: Option<Hash>,
-        etag: Option<&String>,
+        validators: Option<&CacheValidators>,
         target_file: Option<&Path>,
+        timeout: Option<Duration>,
     ) -> Result<FetchResponse> {
         // Fetch the data into memory, even if we mean to write it to disk.
         // We could modify this to save straight to disk instead, but then
@@ -94,49 +566,59 @@ impl FetchSource {
         // in keeping them temporarily in memory.
         let fetch_response = match self {
             FetchSource::Uri(uri, mode) => {
-                let client = Client::builder()
-                    .danger_accept_invalid_certs(mode.accept_insecure())
-                    .danger_accept_invalid_hostnames(mode.accept_insecure())
-                    .build()?;
+                let client = http.client(*mode)?;
 
                 let mut request_builder = client.get(uri.as_str());
-                request_builder = request_builder.header(USER_AGENT, config::USER_AGENT);
 
-                if let Some(etag) = etag {
-                    request_builder = request_builder.header(IF_NONE_MATCH, etag);
+                if let Some(timeout) = timeout {
+                    request_builder = request_builder.timeout(timeout);
+                }
+
+                if let Some(validators) = validators {
+                    if let Some(etag) = &validators.etag {
+                        request_builder = request_builder.header(IF_NONE_MATCH, etag);
+                    }
+                    if let Some(last_modified) = &validators.last_modified {
+                        request_builder = request_builder.header(IF_MODIFIED_SINCE, last_modified);
+                    }
                 }
 
-                let response = request_builder
-                    .send()
+                let response = send_within(request_builder, http.read_timeout)
                     .with_context(|| format!("Could not GET: {}", uri))?;
 
                 match response.status() {
                     StatusCode::OK => {
-                        let etag = match response.headers().get(ETAG) {
-                            None => None,
-                            Some(header_value) => Some(
-                                header_value
-                                    .to_str()
-                                    .with_context(|| "invalid ETag in response header")?
-                                    .to_owned(),
-                            ),
+                        let validators = CacheValidators::from_headers(response.headers())?;
+
+                        let mut reader: Box<dyn Read> = match http.read_timeout {
+                            Some(read_timeout) => {
+                                Box::new(StallTimeoutReader::new(response, read_timeout))
+                            }
+                            None => Box::new(response),
                         };
+                        if let Some(bucket) = &http.rate_limit {
+                            reader = Box::new(RateLimitedReader::new(reader, bucket.clone()));
+                        }
 
-                        let bytes = response.bytes().with_context(|| {
-                            format!(
-                                "Got no response from '{}' even though the status was OK",
-                                uri
-                            )
-                        })?;
+                        let mut buf = vec![];
+                        let bytes = reader
+                            .read_to_end(&mut buf)
+                            .map(|_| Bytes::from(buf))
+                            .map_err(anyhow::Error::new)
+                            .with_context(|| {
+                                format!(
+                                    "Got no response from '{}' even though the status was OK",
+                                    uri
+                                )
+                            })?;
 
-                        Ok(FetchResponse::Data { bytes, etag })
+                        Ok(FetchResponse::Data { bytes, validators })
                     }
                     StatusCode::NOT_MODIFIED => Ok(FetchResponse::UnModified),
-                    _ => Err(anyhow!(
-                        "Got unexpected HTTP response to GET for {}: {}",
-                        uri,
-                        response.status()
-                    )),
+                    status => Err(anyhow::Error::new(UnexpectedStatus {
+                        uri: uri.clone(),
+                        status,
+                    })),
                 }
             }
             FetchSource::File(path) => {
@@ -146,7 +628,16 @@ impl FetchSource {
                         path.to_string_lossy()
                     )
                 })?;
-                Ok(FetchResponse::Data { bytes, etag: None })
+                // Files are always read again, but the mtime is kept like
+                // the Last-Modified header, see `Config::rsync_mtime_from_rrdp`.
+                let last_modified = std::fs::metadata(path).and_then(|meta| meta.modified()).ok();
+                Ok(FetchResponse::Data {
+                    bytes,
+                    validators: CacheValidators {
+                        etag: None,
+                        last_modified: last_modified.map(http_date),
+                    },
+                })
             }
         }?;
 
@@ -154,11 +645,10 @@ impl FetchSource {
         if let Some(hash) = hash {
             if let FetchResponse::Data { bytes, .. } = &fetch_response {
                 if !hash.matches(bytes.as_ref()) {
-                    return Err(anyhow!(
+                    return Err(exit::invalid_data(format!(
                         "Data at source: {} does not match hash '{}'",
-                        self,
-                        hash
-                    ));
+                        self, hash
+                    )));
                 }
             }
         }
@@ -187,6 +677,14 @@ impl FetchSource {
         }
     }
 
+    /// Returns the directory of this source, ending in a slash for a URI.
+    pub fn parent(&self) -> Option<FetchSource> {
+        match self {
+            FetchSource::File(path) => path.parent().map(|dir| FetchSource::File(dir.into())),
+            FetchSource::Uri(uri, mode) => uri.parent().map(|uri| FetchSource::Uri(uri, *mode)),
+        }
+    }
+
     pub fn is_dir(&self) -> bool {
         match self {
             FetchSource::File(path) => path.is_dir(),
@@ -212,15 +710,92 @@ impl fmt::Display for FetchSource {
 impl FromStr for FetchSource {
     type Err = std::convert::Infallible;
 
+    /// Parses an HTTPS URI, or a local path which may also be given as a
+    /// 'file://' URI, e.g. for an RRDP export copied to an air-gapped host.
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         if let Ok(uri) = uri::Https::from_str(s) {
             Ok(FetchSource::Uri(uri, FetchMode::Strict))
+        } else if let Some(path) = s.strip_prefix("file://") {
+            let path = path.strip_prefix("localhost").unwrap_or(path);
+            Ok(FetchSource::File(PathBuf::from(path)))
         } else {
             Ok(FetchSource::File(PathBuf::from(s)))
         }
     }
 }
 
+//------------ UnexpectedStatus ----------------------------------------------
+
+/// An unexpected HTTP response status. Kept as a type, rather than just a
+/// message, so that we can tell whether a retry is worthwhile.
+#[derive(Debug)]
+pub struct UnexpectedStatus {
+    uri: uri::Https,
+    status: StatusCode,
+}
+
+impl fmt::Display for UnexpectedStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Got unexpected HTTP response to GET for {}: {}",
+            self.uri, self.status
+        )
+    }
+}
+
+impl std::error::Error for UnexpectedStatus {}
+
+//------------ FetchTimeout --------------------------------------------------
+
+/// Fetching took longer than the total fetch timeout. This is not transient
+/// within a run, a next run can try again.
+#[derive(Debug)]
+pub struct FetchTimeout {
+    source: String,
+    timeout: Duration,
+}
+
+impl fmt::Display for FetchTimeout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Gave up fetching {}, the total fetch timeout of {}s was exceeded",
+            self.source,
+            self.timeout.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for FetchTimeout {}
+
+/// Returns whether a fetch error is transient, i.e. caused by a timeout,
+/// connection problem or server error (5xx). Other errors, such as a 404 or
+/// a hash mismatch, will not go away by trying again.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    let is_transient_reqwest =
+        |err: &reqwest::Error| err.is_timeout() || err.is_connect() || err.is_request() || err.is_body();
+
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            is_transient_reqwest(err)
+        } else if let Some(err) = cause.downcast_ref::<io::Error>() {
+            // Errors reading a response body are wrapped, and a stalled
+            // download times out, see `StallTimeoutReader`.
+            err.kind() == io::ErrorKind::TimedOut
+                || err
+                    .get_ref()
+                    .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+                    .map(is_transient_reqwest)
+                    .unwrap_or(false)
+        } else if let Some(err) = cause.downcast_ref::<UnexpectedStatus>() {
+            err.status.is_server_error()
+        } else {
+            false
+        }
+    })
+}
+
 //------------ FetchMap ------------------------------------------------------
 
 #[derive(Clone, Debug)]
@@ -259,6 +834,17 @@ pub struct Fetcher {
     notification_uri: uri::Https,
     fetch_map: Option<FetchMap>,
     mode: FetchMode,
+    http: HttpSettings,
+    retries: u32,
+    retry_base: Duration,
+    cache: Option<RrdpCache>,
+    concurrency: usize,
+    deadline: Option<(Instant, Duration)>,
+    failover: Vec<FetchSource>,
+
+    /// The mapping for the failover endpoint which served the notification
+    /// file, if any, which is then used for all other files.
+    endpoint: Mutex<Option<FetchMap>>,
 }
 
 impl Fetcher {
@@ -267,25 +853,121 @@ impl Fetcher {
             notification_uri,
             fetch_map,
             mode,
+            http: HttpSettings::default(),
+            retries: 0,
+            retry_base: Duration::ZERO,
+            cache: None,
+            concurrency: 1,
+            deadline: None,
+            failover: vec![],
+            endpoint: Mutex::new(None),
         }
     }
 
+    /// Retry failed fetches with transient errors up to `retries` times. The
+    /// first retry is done after `retry_base`, and the wait is doubled for
+    /// each next retry.
+    pub fn with_retries(mut self, retries: u32, retry_base: Duration) -> Self {
+        self.retries = retries;
+        self.retry_base = retry_base;
+        self
+    }
+
+    /// Use this User-Agent and these extra headers for HTTP requests.
+    pub fn with_http(mut self, http: HttpSettings) -> Self {
+        self.http = http;
+        self
+    }
+
+    /// Keep downloaded snapshot and delta files in this cache, and use them
+    /// from there in later runs.
+    pub fn with_cache(mut self, cache: Option<RrdpCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Retrieve up to this many files at the same time in `retrieve_files`.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Give up on all fetching by this fetcher, i.e. of the notification
+    /// file and the snapshot or deltas, after this time from now. None
+    /// means no timeout.
+    pub fn with_total_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.deadline = timeout.map(|timeout| (Instant::now() + timeout, timeout));
+        self
+    }
+
+    /// Fetch the notification file from these sources, in order, if fetching
+    /// it from the notification URI, or its source, fails with a transient
+    /// error. The fetch mode of this fetcher is used for URIs.
+    pub fn with_failover(mut self, failover: Vec<FetchSource>) -> Self {
+        self.failover = failover
+            .into_iter()
+            .map(|source| match source {
+                FetchSource::Uri(uri, _) => FetchSource::Uri(uri, self.mode),
+                source => source,
+            })
+            .collect();
+        self
+    }
+
     pub fn notification_uri(&self) -> &uri::Https {
         &self.notification_uri
     }
 
     pub fn read_notification_file(
         &self,
-        etag: Option<&String>,
+        validators: Option<&CacheValidators>,
     ) -> Result<NotificationFileResponse> {
-        let snapshot_source = self.resolve_source(&self.notification_uri)?;
-        let resp = match snapshot_source.fetch(None, etag, None)? {
-            FetchResponse::Data { bytes, etag } => {
-                let notification = NotificationFile::parse(bytes.as_ref())
+        *self.endpoint.lock().unwrap() = None;
+        let source = self.resolve_source(&self.notification_uri)?;
+        let mut res = self.fetch_with_retries(&source, None, validators, None);
+        let mut served_by = None;
+
+        for endpoint in &self.failover {
+            match &res {
+                Err(e) if is_transient(e) && e.downcast_ref::<FetchTimeout>().is_none() => {
+                    warn!(
+                        "Could not fetch notification file from {}, failing over to {}. Error: {:?}",
+                        served_by.as_ref().unwrap_or(&source),
+                        endpoint,
+                        e
+                    );
+                }
+                _ => break,
+            }
+            res = self.fetch_with_retries(endpoint, None, validators, None);
+            served_by = Some(endpoint.clone());
+        }
+
+        let resp = match res? {
+            FetchResponse::Data { bytes, validators } => {
+                let mut notification = NotificationFile::parse(bytes.as_ref())
                     .with_context(|| "Failed to parse notification file")?;
-                NotificationFileResponse::Data { notification, etag }
+                info!(
+                    "Fetched notification file for session {} serial {} from {}",
+                    notification.session_id(),
+                    notification.serial(),
+                    served_by.as_ref().unwrap_or(&source)
+                );
+                if let Some(endpoint) = &served_by {
+                    notification = self.use_endpoint(endpoint, notification)?;
+                }
+                NotificationFileResponse::Data {
+                    notification,
+                    validators,
+                }
+            }
+            FetchResponse::UnModified => {
+                info!(
+                    "Notification file at {} was not modified",
+                    served_by.as_ref().unwrap_or(&source)
+                );
+                NotificationFileResponse::Unmodified
             }
-            FetchResponse::UnModified => NotificationFileResponse::Unmodified,
             FetchResponse::Saved => {
                 unreachable!("For the notification file we get data instead of saving")
             }
@@ -294,16 +976,207 @@ impl Fetcher {
         Ok(resp)
     }
 
+    /// Fetches all other files from the failover endpoint which served the
+    /// notification file. Its snapshot and delta URIs may be under either
+    /// the base of the endpoint or the base of the notification URI. They
+    /// are rewritten to the latter, so that the local RRDP files do not
+    /// depend on the endpoint.
+    fn use_endpoint(
+        &self,
+        endpoint: &FetchSource,
+        notification: NotificationFile,
+    ) -> Result<NotificationFile> {
+        let public_base = self
+            .notification_uri
+            .parent()
+            .ok_or_else(|| anyhow!("Notification URI has no parent: {}", self.notification_uri))?;
+        let endpoint_base = endpoint
+            .parent()
+            .ok_or_else(|| anyhow!("Failover source has no parent: {}", endpoint))?;
+
+        let rewrite = |uri: &uri::Https| -> Result<uri::Https> {
+            if uri.as_str().starts_with(public_base.as_str()) {
+                return Ok(uri.clone());
+            }
+            let rel = match &endpoint_base {
+                FetchSource::Uri(base, _) => uri.as_str().strip_prefix(base.as_str()),
+                FetchSource::File(_) => None,
+            };
+            match rel {
+                Some(rel) => Ok(public_base.join(rel.as_bytes())?),
+                None => Err(exit::invalid_data(format!(
+                    "Notification file at {} refers to {}, which is not under {} or {}",
+                    endpoint, uri, endpoint_base, public_base
+                ))),
+            }
+        };
+
+        let snapshot = notification.snapshot();
+        let snapshot = SnapshotInfo::new(rewrite(snapshot.uri())?, snapshot.hash());
+        let deltas = notification
+            .deltas()
+            .iter()
+            .map(|delta| Ok(DeltaInfo::new(delta.serial(), rewrite(delta.uri())?, delta.hash())))
+            .collect::<Result<Vec<_>>>()?;
+
+        *self.endpoint.lock().unwrap() = Some(FetchMap::new(public_base, endpoint_base));
+
+        Ok(NotificationFile::new(
+            notification.session_id(),
+            notification.serial(),
+            snapshot,
+            deltas,
+        ))
+    }
+
     pub fn retrieve_file(&self, uri: &Https, hash: Hash, target: &Path) -> Result<()> {
+        if let Some(cache) = &self.cache {
+            match cache.get(uri, hash, target) {
+                Ok(true) => return Ok(()),
+                Ok(false) => {}
+                Err(e) => warn!("Could not use cached file for {}: {:?}", uri, e),
+            }
+        }
+
         let source = self.resolve_source(uri)?;
-        source
-            .fetch(Some(hash), None, Some(target))
-            .map_err(|e| anyhow!("Could not read snapshot: {}", e))?;
+        self.fetch_with_retries(&source, Some(hash), None, Some(target))
+            .with_context(|| format!("Could not read snapshot: {}", uri))?;
+
+        if let Some(cache) = &self.cache {
+            if let Err(e) = cache.put(uri, hash, target) {
+                warn!("Could not add {} to the cache: {:?}", uri, e);
+            }
+        }
 
         Ok(())
     }
 
+    /// Retrieves all files, each with its URI, hash and target, using up to
+    /// `concurrency` threads. Each thread keeps at most one file in memory.
+    /// Stops at, and returns, the first error after any retries.
+    ///
+    /// Returns Ok(false) if the retrieved files together have more than
+    /// `max_total_bytes`. No more files are retrieved then, but files which
+    /// were retrieved already are left in place.
+    pub fn retrieve_files(
+        &self,
+        files: &[(&Https, Hash, PathBuf)],
+        max_total_bytes: Option<u64>,
+    ) -> Result<bool> {
+        let threads = self.concurrency.min(files.len()).max(1);
+        let next = AtomicUsize::new(0);
+        let total_bytes = AtomicU64::new(0);
+        let error: Mutex<Option<anyhow::Error>> = Mutex::new(None);
+        let exceeded = |total: u64| max_total_bytes.is_some_and(|max| total > max);
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    let total = total_bytes.load(Ordering::Relaxed);
+                    if error.lock().unwrap().is_some() || exceeded(total) {
+                        break;
+                    }
+
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let (uri, hash, target) = match files.get(idx) {
+                        Some(file) => file,
+                        None => break,
+                    };
+
+                    if let Err(e) = self.retrieve_file(uri, *hash, target) {
+                        error.lock().unwrap().get_or_insert(e);
+                        break;
+                    }
+                    if max_total_bytes.is_some() {
+                        let size = target.metadata().map(|meta| meta.len()).unwrap_or(0);
+                        total_bytes.fetch_add(size, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+
+        match error.into_inner().unwrap() {
+            Some(e) => Err(e),
+            None => Ok(!exceeded(total_bytes.into_inner())),
+        }
+    }
+
+    /// Fetches the file at the URI into memory, checking its hash.
+    pub fn fetch_file(&self, uri: &Https, hash: Hash) -> Result<Bytes> {
+        let source = self.resolve_source(uri)?;
+        match self.fetch_with_retries(&source, Some(hash), None, None)? {
+            FetchResponse::Data { bytes, .. } => Ok(bytes),
+            _ => Err(anyhow!("Got no data for {}", uri)),
+        }
+    }
+
+    fn fetch_with_retries(
+        &self,
+        source: &FetchSource,
+        hash: Option<Hash>,
+        validators: Option<&CacheValidators>,
+        target_file: Option<&Path>,
+    ) -> Result<FetchResponse> {
+        let mut attempt = 0;
+        loop {
+            let remaining = self.remaining(source)?;
+            let e = match source.fetch(&self.http, hash, validators, target_file, remaining) {
+                Err(e) => e,
+                res => return res,
+            };
+
+            // A request which was cut short, or a retry which would only
+            // start after the deadline, is reported as a total timeout.
+            let left = self.remaining(source).unwrap_or(Some(Duration::ZERO));
+            if left == Some(Duration::ZERO) {
+                return Err(e.context(self.timeout_error(source)));
+            }
+
+            if attempt >= self.retries || !is_transient(&e) {
+                return Err(e);
+            }
+
+            let delay = self.retry_base.saturating_mul(2u32.saturating_pow(attempt));
+            if left.is_some_and(|left| delay >= left) {
+                return Err(e.context(self.timeout_error(source)));
+            }
+
+            attempt += 1;
+            warn!(
+                "Fetching {} failed, retry {} of {} in {}ms. Error: {:?}",
+                source,
+                attempt,
+                self.retries,
+                delay.as_millis(),
+                e
+            );
+            std::thread::sleep(delay);
+        }
+    }
+
+    /// Returns the time left before the total timeout, if any, or an error
+    /// if there is no time left.
+    fn remaining(&self, source: &FetchSource) -> Result<Option<Duration>> {
+        match self.deadline {
+            None => Ok(None),
+            Some((deadline, _)) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => Ok(Some(remaining)),
+                _ => Err(anyhow::Error::new(self.timeout_error(source))),
+            },
+        }
+    }
+
+    fn timeout_error(&self, source: &FetchSource) -> FetchTimeout {
+        FetchTimeout {
+            source: source.to_string(),
+            timeout: self.deadline.map(|(_, timeout)| timeout).unwrap_or_default(),
+        }
+    }
+
     pub fn resolve_source(&self, uri: &uri::Https) -> Result<FetchSource> {
+        if let Some(endpoint) = self.endpoint.lock().unwrap().as_ref() {
+            return endpoint.source(uri);
+        }
         match &self.fetch_map {
             None => Ok(FetchSource::Uri(uri.clone(), self.mode)),
             Some(map) => map.source(uri),
@@ -314,7 +1187,7 @@ impl Fetcher {
 #[cfg(test)]
 mod tests {
 
-    use crate::util::https;
+    use crate::util::{https, test_with_dir};
 
     use super::*;
 
@@ -336,6 +1209,14 @@ mod tests {
             notification_uri,
             fetch_map,
             mode: FetchMode::Strict,
+            http: HttpSettings::default(),
+            retries: 0,
+            retry_base: Duration::ZERO,
+            cache: None,
+            concurrency: 1,
+            deadline: None,
+            failover: vec![],
+            endpoint: Mutex::new(None),
         };
 
         let file_source = fetcher
@@ -351,6 +1232,26 @@ mod tests {
             .is_err());
     }
 
+    #[test]
+    fn parse_fetch_source() {
+        assert_eq!(
+            FetchSource::from_str("https://hidden.server/rrdp/").unwrap(),
+            FetchSource::strict_uri(https("https://hidden.server/rrdp/"))
+        );
+        assert_eq!(
+            FetchSource::from_str("file:///media/usb/rrdp/").unwrap(),
+            FetchSource::File(PathBuf::from("/media/usb/rrdp/"))
+        );
+        assert_eq!(
+            FetchSource::from_str("file://localhost/media/usb/rrdp/").unwrap(),
+            FetchSource::File(PathBuf::from("/media/usb/rrdp/"))
+        );
+        assert_eq!(
+            FetchSource::from_str("./media/usb/rrdp/").unwrap(),
+            FetchSource::File(PathBuf::from("./media/usb/rrdp/"))
+        );
+    }
+
     #[test]
     fn resolve_fetch_to_uri() {
         let base_uri = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/");
@@ -367,6 +1268,14 @@ mod tests {
             notification_uri,
             fetch_map,
             mode: FetchMode::Strict,
+            http: HttpSettings::default(),
+            retries: 0,
+            retry_base: Duration::ZERO,
+            cache: None,
+            concurrency: 1,
+            deadline: None,
+            failover: vec![],
+            endpoint: Mutex::new(None),
         };
 
         let file_source = fetcher
@@ -382,4 +1291,375 @@ mod tests {
             .resolve_source(&https("https://other.host/rrdp/foo.txt"))
             .is_err());
     }
+
+    #[test]
+    fn retrieve_files_concurrently() {
+        test_with_dir("fetch_retrieve_files_concurrently", |dir| {
+            let base_uri = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/");
+            let notification_uri = base_uri.join(b"notification.xml").unwrap();
+            let fetch_map = FetchMap::new(
+                base_uri.clone(),
+                FetchSource::File(PathBuf::from("test-resources/rrdp-rev2658/")),
+            );
+            let fetcher = Fetcher::new(notification_uri, Some(fetch_map), FetchMode::Strict)
+                .with_concurrency(2);
+
+            let session = "e9be21e7-c537-4564-b742-64700978c6b4";
+            let mut uris = vec![];
+            let mut hashes = vec![];
+            for serial in 2653..=2656 {
+                let rel = format!("{}/{}/delta.xml", session, serial);
+                let data = std::fs::read(format!("test-resources/rrdp-rev2658/{}", rel)).unwrap();
+                uris.push(base_uri.join(rel.as_bytes()).unwrap());
+                hashes.push(Hash::from_data(&data));
+            }
+
+            let files: Vec<_> = uris
+                .iter()
+                .zip(hashes.iter())
+                .enumerate()
+                .map(|(i, (uri, hash))| (uri, *hash, dir.join(format!("delta-{}.xml", i))))
+                .collect();
+            assert!(fetcher.retrieve_files(&files, None).unwrap());
+            for (_, _, target) in &files {
+                assert!(target.is_file());
+            }
+
+            // Files are retrieved until they have more bytes than the maximum
+            let total: u64 =
+                files.iter().map(|(_, _, target)| target.metadata().unwrap().len()).sum();
+            assert!(fetcher.retrieve_files(&files, Some(total)).unwrap());
+            assert!(!fetcher.retrieve_files(&files, Some(total - 1)).unwrap());
+
+            // A hash mismatch in any of the files is an error
+            let mut files = files;
+            files[2].1 = hashes[0];
+            assert!(fetcher.retrieve_files(&files, None).is_err());
+        })
+    }
+
+    #[test]
+    fn fail_over_to_next_notification_source() {
+        let base_uri = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/");
+        let notification_uri = base_uri.join(b"notification.xml").unwrap();
+        let failover = vec![FetchSource::File(PathBuf::from(
+            "test-resources/rrdp-rev2656/notification.xml",
+        ))];
+
+        // The connection is refused, which is transient
+        let unreachable = FetchMap::new(
+            base_uri.clone(),
+            FetchSource::strict_uri(https("https://localhost:1/rrdp/")),
+        );
+        let fetcher = Fetcher::new(notification_uri.clone(), Some(unreachable), FetchMode::Strict)
+            .with_failover(failover.clone());
+        let (notification, _) = fetcher.read_notification_file(None).unwrap().content().unwrap();
+        assert_eq!(notification.serial(), 2656);
+
+        // The snapshot comes from the same source
+        let snapshot = notification.snapshot();
+        assert_eq!(
+            fetcher.resolve_source(snapshot.uri()).unwrap(),
+            FetchSource::File(PathBuf::from(
+                "test-resources/rrdp-rev2656/e9be21e7-c537-4564-b742-64700978c6b4/2656/snapshot.xml"
+            ))
+        );
+        fetcher.fetch_file(snapshot.uri(), snapshot.hash()).unwrap();
+
+        // A missing file is not transient
+        let missing =
+            FetchMap::new(base_uri, FetchSource::File(PathBuf::from("test-resources/none/")));
+        let fetcher = Fetcher::new(notification_uri, Some(missing), FetchMode::Strict)
+            .with_failover(failover);
+        assert!(fetcher.read_notification_file(None).is_err());
+    }
+
+    #[test]
+    fn rewrite_uris_of_failover_endpoint() {
+        let notification_uri = https("https://rrdp.example.net/rrdp/notification.xml");
+        let endpoint =
+            FetchSource::strict_uri(https("https://rrdp2.example.net/rrdp/notification.xml"));
+        let fetcher = Fetcher::new(notification_uri, None, FetchMode::Strict);
+
+        let hash = Hash::from_data(b"snapshot");
+        let notification = |snapshot: &str, delta: &str| {
+            NotificationFile::new(
+                uuid::Uuid::nil(),
+                2,
+                SnapshotInfo::new(https(snapshot), hash),
+                vec![DeltaInfo::new(2, https(delta), hash)],
+            )
+        };
+
+        let rewritten = fetcher
+            .use_endpoint(
+                &endpoint,
+                notification(
+                    "https://rrdp2.example.net/rrdp/session/2/snapshot.xml",
+                    "https://rrdp.example.net/rrdp/session/2/delta.xml",
+                ),
+            )
+            .unwrap();
+        let snapshot_uri = rewritten.snapshot().uri();
+        assert_eq!(snapshot_uri, &https("https://rrdp.example.net/rrdp/session/2/snapshot.xml"));
+        assert_eq!(
+            rewritten.deltas()[0].uri(),
+            &https("https://rrdp.example.net/rrdp/session/2/delta.xml")
+        );
+        assert_eq!(
+            fetcher.resolve_source(snapshot_uri).unwrap(),
+            FetchSource::strict_uri(https("https://rrdp2.example.net/rrdp/session/2/snapshot.xml"))
+        );
+
+        assert!(fetcher
+            .use_endpoint(
+                &endpoint,
+                notification(
+                    "https://other.example.net/rrdp/session/2/snapshot.xml",
+                    "https://rrdp.example.net/rrdp/session/2/delta.xml",
+                ),
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn abort_after_total_fetch_timeout() {
+        // A server which accepts connections, but never responds
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let notification_uri = https(&format!("https://127.0.0.1:{}/rrdp/notification.xml", port));
+
+        let start = Instant::now();
+        let fetcher = Fetcher::new(notification_uri.clone(), None, FetchMode::Strict)
+            .with_retries(3, Duration::from_millis(1))
+            .with_total_timeout(Some(Duration::from_millis(300)));
+        let err = fetcher.read_notification_file(None).err().unwrap();
+        assert!(err.downcast_ref::<FetchTimeout>().is_some(), "{:?}", err);
+        assert!(start.elapsed() < Duration::from_secs(10));
+
+        // No time left at all
+        let fetcher = Fetcher::new(notification_uri, None, FetchMode::Strict)
+            .with_total_timeout(Some(Duration::ZERO));
+        let err = fetcher.read_notification_file(None).err().unwrap();
+        assert!(err.downcast_ref::<FetchTimeout>().is_some(), "{:?}", err);
+
+        drop(listener);
+    }
+
+    #[test]
+    fn retry_transient_errors_only() {
+        let status = |status| {
+            anyhow::Error::new(UnexpectedStatus {
+                uri: https("https://localhost/rrdp/notification.xml"),
+                status,
+            })
+        };
+        assert!(is_transient(&status(StatusCode::SERVICE_UNAVAILABLE)));
+        assert!(is_transient(
+            &status(StatusCode::BAD_GATEWAY).context("Could not read snapshot")
+        ));
+        assert!(!is_transient(&status(StatusCode::NOT_FOUND)));
+        assert!(!is_transient(&anyhow!("Data at source does not match hash")));
+
+        // Nothing listens on port 1, so the connection is refused
+        let fetcher = Fetcher::new(
+            https("https://localhost:1/rrdp/notification.xml"),
+            None,
+            FetchMode::Strict,
+        )
+        .with_retries(2, Duration::from_millis(1));
+        let err = match fetcher.read_notification_file(None) {
+            Err(err) => err,
+            Ok(_) => panic!("Expected a connection error"),
+        };
+        assert!(is_transient(&err));
+    }
+
+    #[test]
+    fn cache_validators_from_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(
+            CacheValidators::from_headers(&headers).unwrap(),
+            CacheValidators::default()
+        );
+
+        headers.insert(ETAG, "\"5f3e\"".parse().unwrap());
+        headers.insert(LAST_MODIFIED, "Wed, 21 Oct 2015 07:28:00 GMT".parse().unwrap());
+        assert_eq!(
+            CacheValidators::from_headers(&headers).unwrap(),
+            CacheValidators {
+                etag: Some("\"5f3e\"".to_string()),
+                last_modified: Some("Wed, 21 Oct 2015 07:28:00 GMT".to_string()),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_http_header() {
+        let header = HttpHeader::from_str("X-Mirror:  krill-sync ").unwrap();
+        assert_eq!(header.name, HeaderName::from_static("x-mirror"));
+        assert_eq!(header.value, HeaderValue::from_static("krill-sync"));
+
+        assert!(HttpHeader::from_str("X-Mirror").is_err());
+        assert!(HttpHeader::from_str("X Mirror: krill-sync").is_err());
+        assert!(HttpHeader::from_str("X-Mirror: krill\nsync").is_err());
+
+        let http = HttpSettings::new("mirror/1.0".to_string(), &[header.clone(), header]);
+        assert_eq!(http.user_agent, "mirror/1.0");
+        assert_eq!(http.headers.get_all("x-mirror").iter().count(), 2);
+
+        // Header values may be secret
+        let secret = HttpHeader::from_str("Authorization: Bearer secret").unwrap();
+        assert_eq!(format!("{:?}", secret), "authorization: <redacted>");
+        let http = HttpSettings::new("mirror/1.0".to_string(), &[secret]);
+        assert!(!format!("{:?}", http).contains("secret"));
+    }
+
+    #[test]
+    fn parse_ip_version() {
+        assert_eq!(IpVersion::from_str("auto").unwrap(), IpVersion::Auto);
+        assert_eq!(IpVersion::from_str("v4").unwrap(), IpVersion::V4);
+        assert_eq!(IpVersion::from_str("v6").unwrap(), IpVersion::V6);
+        assert!(IpVersion::from_str("ipv6").is_err());
+
+        assert_eq!(IpVersion::Auto.local_address(), None);
+        assert_eq!(IpVersion::V6.local_address(), Some(IpAddr::V6(Ipv6Addr::UNSPECIFIED)));
+    }
+
+    #[test]
+    fn reject_unusable_tls_files() {
+        crate::util::test_with_dir("fetch_reject_unusable_tls_files", |dir| {
+            let not_pem = dir.join("not.pem");
+            std::fs::write(&not_pem, "not a certificate").unwrap();
+            let missing = dir.join("missing.pem");
+
+            let err = HttpSettings::default().with_ca_cert(&missing).unwrap_err();
+            assert!(err.to_string().contains("Cannot read CA certificate"));
+
+            let err = HttpSettings::default().with_ca_cert(&not_pem).unwrap_err();
+            assert!(err.to_string().contains("Cannot load CA certificate"));
+
+            let err = HttpSettings::default()
+                .with_client_cert(&not_pem, &not_pem)
+                .unwrap_err();
+            assert!(err.to_string().contains("Cannot load client certificate"));
+        })
+    }
+
+    #[test]
+    fn time_out_stalled_reads_only() {
+        /// Returns the chunks after a delay each, and then stalls, if asked.
+        struct SlowReader {
+            chunks: Vec<Vec<u8>>,
+            delay: Duration,
+            stall: bool,
+        }
+
+        impl Read for SlowReader {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                std::thread::sleep(self.delay);
+                match self.chunks.pop() {
+                    Some(chunk) => {
+                        buf[..chunk.len()].copy_from_slice(&chunk);
+                        Ok(chunk.len())
+                    }
+                    None if self.stall => {
+                        std::thread::sleep(Duration::from_secs(5));
+                        Ok(0)
+                    }
+                    None => Ok(0),
+                }
+            }
+        }
+
+        let slow_reader = |stall| SlowReader {
+            chunks: (0..6u8).map(|i| vec![i; 100]).collect(),
+            delay: Duration::from_millis(100),
+            stall,
+        };
+
+        // Reading all takes longer than the timeout, but data keeps coming
+        let started = Instant::now();
+        let mut read = vec![];
+        StallTimeoutReader::new(slow_reader(false), Duration::from_millis(400))
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(started.elapsed() > Duration::from_millis(600));
+        assert_eq!(read.len(), 600);
+        assert_eq!(read[..100], [5; 100]);
+
+        // No more data after the last chunk
+        let started = Instant::now();
+        let mut read = vec![];
+        let err = StallTimeoutReader::new(slow_reader(true), Duration::from_millis(400))
+            .read_to_end(&mut read)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert!(started.elapsed() < Duration::from_secs(2));
+        assert!(is_transient(&anyhow::Error::new(err)));
+    }
+
+    #[test]
+    fn rate_limit_reads() {
+        let data: Vec<u8> = (0..15_000u32).map(|i| i as u8).collect();
+
+        let bucket = || Arc::new(Mutex::new(TokenBucket::new(10_000)));
+
+        let started = Instant::now();
+        let mut read = vec![];
+        RateLimitedReader::new(io::Cursor::new(&data), bucket())
+            .read_to_end(&mut read)
+            .unwrap();
+
+        // The first 10,000 bytes are in the bucket, the rest takes 0.5s.
+        assert_eq!(read, data);
+        assert!(started.elapsed() >= Duration::from_millis(450));
+
+        // Concurrent readers share the rate, so reading the same data twice
+        // takes 2s rather than 0.5s.
+        let shared = bucket();
+        let started = Instant::now();
+        std::thread::scope(|scope| {
+            for _ in 0..2 {
+                scope.spawn(|| {
+                    let mut read = vec![];
+                    RateLimitedReader::new(io::Cursor::new(&data), shared.clone())
+                        .read_to_end(&mut read)
+                        .unwrap();
+                    assert_eq!(read, data);
+                });
+            }
+        });
+        assert!(started.elapsed() >= Duration::from_millis(1950));
+    }
+
+    #[test]
+    fn retrieve_file_from_cache() {
+        test_with_dir("fetch_retrieve_file_from_cache", |dir| {
+            let source_dir = dir.join("source");
+            std::fs::create_dir_all(&source_dir).unwrap();
+            let data = b"<delta/>";
+            std::fs::write(source_dir.join("delta.xml"), data).unwrap();
+
+            let base_uri = https("https://example.com/rrdp/");
+            let fetcher = Fetcher::new(
+                base_uri.join(b"notification.xml").unwrap(),
+                Some(FetchMap::new(base_uri.clone(), FetchSource::File(source_dir.clone()))),
+                FetchMode::Strict,
+            )
+            .with_cache(Some(RrdpCache::new(dir.join("cache"), 1024)));
+
+            let uri = base_uri.join(b"delta.xml").unwrap();
+            let hash = Hash::from_data(data);
+            let target = dir.join("target.xml");
+
+            fetcher.retrieve_file(&uri, hash, &target).unwrap();
+
+            // The source is gone, so this can only succeed from the cache
+            std::fs::remove_dir_all(&source_dir).unwrap();
+            std::fs::remove_file(&target).unwrap();
+            fetcher.retrieve_file(&uri, hash, &target).unwrap();
+            assert_eq!(std::fs::read(&target).unwrap(), data);
+        })
+    }
 }
@@ -1,12 +1,13 @@
 use std::{
     fs::File,
-    io::prelude::*, // for File::write_all()
+    io::{self, prelude::*}, // for File::write_all()
     path::{Path, PathBuf},
+    time::Duration,
 };
 
 use anyhow::{anyhow, Context, Result};
 use bytes::Bytes;
-use log::trace;
+use tracing::{trace, warn};
 
 pub fn write_buf(file_path: &Path, buf: &[u8]) -> Result<()> {
     create_file(file_path)?
@@ -16,16 +17,90 @@ pub fn write_buf(file_path: &Path, buf: &[u8]) -> Result<()> {
     Ok(())
 }
 
+/// Flushes written files and directories to disk. This is a trait so that
+/// the order of sync operations can be verified in tests.
+pub trait Fsync: Sync {
+    /// Flushes the content and metadata of the file at the given path.
+    fn sync_file(&self, path: &Path) -> Result<()>;
+
+    /// Flushes the entries of the directory at the given path.
+    fn sync_dir(&self, path: &Path) -> Result<()>;
+}
+
+/// Fsync implementation which uses the actual file system.
+pub struct DiskFsync;
+
+impl Fsync for DiskFsync {
+    fn sync_file(&self, path: &Path) -> Result<()> {
+        File::open(path)
+            .and_then(|file| file.sync_all())
+            .with_context(|| format!("Cannot fsync file {}", path.display()))
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, path: &Path) -> Result<()> {
+        File::open(path)
+            .and_then(|dir| dir.sync_all())
+            .with_context(|| format!("Cannot fsync dir {}", path.display()))
+    }
+
+    // Directories cannot be opened as files on other platforms. Their
+    // entries are flushed together with the files.
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _path: &Path) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Returns all directories under (and including) the given path, ordered
+/// so that sub-directories come before their parents.
+pub fn dirs_depth_first(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = vec![];
+    let mut todo = vec![path.to_path_buf()];
+
+    while let Some(dir) = todo.pop() {
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Cannot read dir {}", dir.display()))? {
+            let entry = entry.with_context(|| format!("Cannot read entry in dir {}", dir.display()))?;
+            if entry.file_type()?.is_dir() {
+                todo.push(entry.path());
+            }
+        }
+        dirs.push(dir);
+    }
+
+    dirs.sort_by_key(|dir| std::cmp::Reverse(dir.components().count()));
+    Ok(dirs)
+}
+
+/// Returns all files under the given directory, recursively.
+pub fn files_recursive(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for dir in dirs_depth_first(path)? {
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Cannot read dir {}", dir.display()))? {
+            let entry = entry.with_context(|| format!("Cannot read entry in dir {}", dir.display()))?;
+            if !entry.file_type()?.is_dir() {
+                files.push(entry.path());
+            }
+        }
+    }
+    Ok(files)
+}
+
 /// Create an empty file for a path
 pub fn create_file(file_path: &Path) -> Result<std::fs::File> {
+    create_parent_dir(file_path)?;
+
+    std::fs::File::create(file_path).with_context(|| format!("Cannot create file {}", file_path.display()))
+}
+
+/// Create the parent directory for a path, if it does not exist yet
+pub fn create_parent_dir(file_path: &Path) -> Result<()> {
     let dir = file_path
         .parent()
         .ok_or_else(|| anyhow!("Error determining parent of {}", file_path.display()))?;
 
-    std::fs::create_dir_all(&dir)
-        .with_context(|| format!("Cannot create dir {} for file {}", dir.to_string_lossy(), file_path.display()))?;
-
-    std::fs::File::create(file_path).with_context(|| format!("Cannot create file {}", file_path.display()))
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Cannot create dir {} for file {}", dir.to_string_lossy(), file_path.display()))
 }
 
 pub fn remove_file_and_empty_parent_dirs(path: &Path) -> Result<()> {
@@ -45,6 +120,427 @@ pub fn remove_file_and_empty_parent_dirs(path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Creates a symlink at `link` which points to the directory `target`.
+#[cfg(unix)]
+pub fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(target, link)
+        .with_context(|| format!("Cannot create symlink {} -> {}", link.display(), target.display()))
+}
+
+/// Creates a symlink at `link` which points to the directory `target`.
+///
+/// Note that this typically requires either administrator privileges or
+/// developer mode to be enabled on Windows.
+#[cfg(windows)]
+pub fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    std::os::windows::fs::symlink_dir(target, link)
+        .with_context(|| format!("Cannot create symlink {} -> {}", link.display(), target.display()))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn create_symlink(target: &Path, link: &Path) -> Result<()> {
+    Err(anyhow!(
+        "Cannot create symlink {} -> {}, symlinks are not supported on this platform",
+        link.display(),
+        target.display()
+    ))
+}
+
+/// Creates a symlink at `link` which points to the file `target`.
+#[cfg(unix)]
+pub fn create_file_symlink(target: &Path, link: &Path) -> Result<()> {
+    create_symlink(target, link)
+}
+
+/// Creates a symlink at `link` which points to the file `target`. See
+/// `create_symlink` for the privileges this needs on Windows.
+#[cfg(windows)]
+pub fn create_file_symlink(target: &Path, link: &Path) -> Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+        .with_context(|| format!("Cannot create symlink {} -> {}", link.display(), target.display()))
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn create_file_symlink(target: &Path, link: &Path) -> Result<()> {
+    create_symlink(target, link)
+}
+
+/// Removes a symlink created by `create_symlink`, but not the directory it
+/// points to. Directory symlinks on Windows have to be removed as directories.
+pub fn remove_symlink(link: &Path) -> Result<()> {
+    if cfg!(windows) {
+        std::fs::remove_dir(link)
+    } else {
+        std::fs::remove_file(link)
+    }
+    .with_context(|| format!("Cannot remove symlink {}", link.display()))
+}
+
+/// Checks whether symlinks can be created in the given directory, and whether
+/// an existing symlink can be replaced by renaming another one over it. Some
+/// filesystems, e.g. certain network mounts, support neither.
+pub fn symlink_replace_supported(dir: &Path) -> bool {
+    let link = dir.join(".symlink-probe.tmp");
+    let other = dir.join(".symlink-probe-other.tmp");
+
+    for path in [&link, &other] {
+        if path.symlink_metadata().is_ok() {
+            let _ = remove_symlink(path);
+        }
+    }
+
+    let supported = create_symlink(Path::new("."), &link).is_ok()
+        && create_symlink(Path::new("."), &other).is_ok()
+        && std::fs::rename(&other, &link).is_ok()
+        && std::fs::read_link(&link).is_ok();
+
+    for path in [&link, &other] {
+        if path.symlink_metadata().is_ok() {
+            let _ = remove_symlink(path);
+        }
+    }
+
+    supported
+}
+
+/// Atomically exchanges the two paths, which must both exist, so that there
+/// is no moment at which either of them is missing. Fails with an error of
+/// kind `Unsupported` where this cannot be done: other than on Linux, and on
+/// file systems which do not support it.
+#[cfg(target_os = "linux")]
+pub fn exchange_paths(a: &Path, b: &Path) -> io::Result<()> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let c_path = |path: &Path| {
+        CString::new(path.as_os_str().as_bytes())
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    };
+    let (a, b) = (c_path(a)?, c_path(b)?);
+
+    // Safe because both paths are valid C strings. The syscall is used
+    // directly, as older C libraries have no wrapper for it.
+    let res = unsafe {
+        libc::syscall(
+            libc::SYS_renameat2,
+            libc::AT_FDCWD,
+            a.as_ptr(),
+            libc::AT_FDCWD,
+            b.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+    if res == 0 {
+        return Ok(());
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EINVAL) | Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+            Err(io::Error::new(io::ErrorKind::Unsupported, err))
+        }
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn exchange_paths(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Exchanging paths is not supported on this platform",
+    ))
+}
+
+/// The permissions and ownership to apply to written rsync files and dirs.
+/// Anything which is not set is left to the defaults of the process, i.e.
+/// its umask and user.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct FilePermissions {
+    pub file_mode: Option<u32>,
+    pub dir_mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+}
+
+impl FilePermissions {
+    pub fn is_default(&self) -> bool {
+        self == &FilePermissions::default()
+    }
+
+    pub fn apply_to_file(&self, path: &Path) -> Result<()> {
+        self.apply(path, self.file_mode)
+    }
+
+    pub fn apply_to_dir(&self, path: &Path) -> Result<()> {
+        self.apply(path, self.dir_mode)
+    }
+
+    /// Sets the mode, if any, and changes the ownership, if any. Failing to
+    /// change the ownership is only logged, because that requires privileges
+    /// that we may not have.
+    #[cfg(unix)]
+    fn apply(&self, path: &Path, mode: Option<u32>) -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        if let Some(mode) = mode {
+            std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).with_context(
+                || format!("Cannot set mode {:o} on {}", mode, path.display()),
+            )?;
+        }
+
+        if self.uid.is_some() || self.gid.is_some() {
+            if let Err(e) = std::os::unix::fs::chown(path, self.uid, self.gid) {
+                warn!("Cannot change ownership of {}: {}", path.display(), e);
+            }
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(unix))]
+    fn apply(&self, _path: &Path, _mode: Option<u32>) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Parses a file mode in octal notation, e.g. "644" or "0644".
+pub fn parse_mode(s: &str) -> Result<u32> {
+    let mode =
+        u32::from_str_radix(s, 8).with_context(|| format!("Invalid octal file mode: {}", s))?;
+    if mode > 0o7777 {
+        Err(anyhow!("Invalid octal file mode: {}", s))
+    } else {
+        Ok(mode)
+    }
+}
+
+/// Resolves a user name, or numeric user ID, to a user ID.
+#[cfg(unix)]
+pub fn resolve_uid(user: &str) -> Result<u32> {
+    if let Ok(uid) = user.parse() {
+        return Ok(uid);
+    }
+
+    let name = std::ffi::CString::new(user)?;
+    // Safe because we only read from the returned struct, and only before
+    // the next call to getpwnam. Note that this is called while configuring,
+    // when there are no other threads yet.
+    let passwd = unsafe { libc::getpwnam(name.as_ptr()) };
+    if passwd.is_null() {
+        Err(anyhow!("Unknown user: {}", user))
+    } else {
+        Ok(unsafe { (*passwd).pw_uid })
+    }
+}
+
+/// Resolves a group name, or numeric group ID, to a group ID.
+#[cfg(unix)]
+pub fn resolve_gid(group: &str) -> Result<u32> {
+    if let Ok(gid) = group.parse() {
+        return Ok(gid);
+    }
+
+    let name = std::ffi::CString::new(group)?;
+    // Safe for the same reasons as getpwnam in resolve_uid.
+    let group_entry = unsafe { libc::getgrnam(name.as_ptr()) };
+    if group_entry.is_null() {
+        Err(anyhow!("Unknown group: {}", group))
+    } else {
+        Ok(unsafe { (*group_entry).gr_gid })
+    }
+}
+
+/// Returns the number of bytes available to unprivileged users on the file
+/// system of the given path, or of its nearest existing ancestor if it does
+/// not exist yet.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> Result<Option<u64>> {
+    use std::os::unix::ffi::OsStrExt;
+
+    let existing = path
+        .ancestors()
+        .find(|ancestor| ancestor.exists())
+        .unwrap_or_else(|| Path::new("."));
+    let name = std::ffi::CString::new(existing.as_os_str().as_bytes())?;
+
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // Safe because the name is a valid C string, and stat is only read
+    // after the call succeeded.
+    if unsafe { libc::statvfs(name.as_ptr(), &mut stat) } != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("Cannot get free space for {}", existing.display()));
+    }
+
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+/// The free space is not checked on other platforms.
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> Result<Option<u64>> {
+    Ok(None)
+}
+
+/// Returns whether both paths, or their nearest existing ancestors, are on
+/// the same file system. Returns None if this cannot be determined.
+#[cfg(unix)]
+pub fn same_file_system(a: &Path, b: &Path) -> Option<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let device = |path: &Path| {
+        let existing = path.ancestors().find(|ancestor| ancestor.exists())?;
+        existing.metadata().ok().map(|meta| meta.dev())
+    };
+    Some(device(a)? == device(b)?)
+}
+
+#[cfg(not(unix))]
+pub fn same_file_system(_a: &Path, _b: &Path) -> Option<bool> {
+    None
+}
+
+/// Copies the dir with everything in it to `to`, which must not exist yet.
+/// Files keep their permissions and mtime, and symlinks are copied as
+/// symlinks. If `fsync` is given, then all files and dirs are flushed.
+pub fn copy_dir_all(from: &Path, to: &Path, fsync: Option<&dyn Fsync>) -> Result<()> {
+    let mut dirs = dirs_depth_first(from)?;
+    dirs.reverse();
+    for dir in &dirs {
+        let target = to.join(dir.strip_prefix(from)?);
+        std::fs::create_dir_all(&target)
+            .with_context(|| format!("Cannot create dir {}", target.display()))?;
+    }
+
+    for file in files_recursive(from)? {
+        let target = to.join(file.strip_prefix(from)?);
+        let meta = file
+            .symlink_metadata()
+            .with_context(|| format!("Cannot read metadata of {}", file.display()))?;
+        if meta.file_type().is_symlink() {
+            let link_target = std::fs::read_link(&file)
+                .with_context(|| format!("Cannot read symlink {}", file.display()))?;
+            create_file_symlink(&link_target, &target)?;
+            continue;
+        }
+
+        std::fs::copy(&file, &target).with_context(|| {
+            format!("Cannot copy {} to {}", file.display(), target.display())
+        })?;
+        filetime::set_file_mtime(&target, filetime::FileTime::from_last_modification_time(&meta))
+            .with_context(|| format!("Cannot modify mtime for {}", target.display()))?;
+        if let Some(fsync) = fsync {
+            fsync.sync_file(&target)?;
+        }
+    }
+
+    for dir in dirs.iter().rev() {
+        let target = to.join(dir.strip_prefix(from)?);
+        let meta = dir
+            .metadata()
+            .with_context(|| format!("Cannot read metadata of {}", dir.display()))?;
+        std::fs::set_permissions(&target, meta.permissions())
+            .with_context(|| format!("Cannot set permissions for {}", target.display()))?;
+        filetime::set_file_mtime(&target, filetime::FileTime::from_last_modification_time(&meta))
+            .with_context(|| format!("Cannot modify mtime for {}", target.display()))?;
+        if let Some(fsync) = fsync {
+            fsync.sync_dir(&target)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// An exclusive advisory lock on a file, see `lock_exclusive`. The lock is
+/// released when this is dropped.
+pub struct FileLock {
+    #[cfg(unix)]
+    _file: File,
+
+    #[cfg(not(unix))]
+    _lock: fslock::LockFile,
+}
+
+/// Takes an exclusive advisory lock on the file at the given path, creating
+/// the file if needed, and blocks until the lock can be taken.
+///
+/// On unix this uses flock(2), so the lock belongs to the open file, and it
+/// conflicts with locks taken through other opens of the same file, also in
+/// the same process. Other platforms use the locking from fslock, i.e.
+/// LockFileEx on Windows.
+pub fn lock_exclusive(path: &Path) -> Result<FileLock> {
+    take_lock(path, true)?.ok_or_else(|| anyhow!("Cannot lock file {}", path.display()))
+}
+
+/// Takes an exclusive advisory lock like `lock_exclusive`, but returns None
+/// rather than blocking if another holds the lock.
+pub fn try_lock_exclusive(path: &Path) -> Result<Option<FileLock>> {
+    take_lock(path, false)
+}
+
+#[cfg(unix)]
+fn take_lock(path: &Path, block: bool) -> Result<Option<FileLock>> {
+    use std::os::unix::io::AsRawFd;
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("Cannot open lock file {}", path.display()))?;
+
+    let operation = if block { libc::LOCK_EX } else { libc::LOCK_EX | libc::LOCK_NB };
+    loop {
+        // Safe because the file descriptor is open for the lifetime of `file`.
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(Some(FileLock { _file: file }));
+        }
+
+        let err = std::io::Error::last_os_error();
+        match err.kind() {
+            std::io::ErrorKind::Interrupted => {}
+            std::io::ErrorKind::WouldBlock => return Ok(None),
+            _ => return Err(err).with_context(|| format!("Cannot lock file {}", path.display())),
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn take_lock(path: &Path, block: bool) -> Result<Option<FileLock>> {
+    let mut lock = fslock::LockFile::open(path)
+        .with_context(|| format!("Cannot open lock file {}", path.display()))?;
+    if block {
+        lock.lock()
+            .with_context(|| format!("Cannot lock file {}", path.display()))?;
+    } else if !lock
+        .try_lock()
+        .with_context(|| format!("Cannot lock file {}", path.display()))?
+    {
+        return Ok(None);
+    }
+    Ok(Some(FileLock { _lock: lock }))
+}
+
+impl FileLock {
+    /// Replaces the content of the locked file with the ID of this process,
+    /// so that it can be reported while we hold the lock, see `lock_holder`.
+    /// This does nothing on other platforms than unix.
+    pub fn write_pid(&self) -> Result<()> {
+        #[cfg(unix)]
+        {
+            let mut file = &self._file;
+            file.set_len(0)?;
+            file.write_all(std::process::id().to_string().as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the process ID written to a lock file by `FileLock::write_pid`,
+/// if any. This may be stale if the lock is not held.
+pub fn lock_holder(path: &Path) -> Option<u32> {
+    std::fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
 pub fn read_file(file_path: &Path) -> Result<Bytes> {
     trace!("Loading file {}", file_path.display());
     let mut f = File::open(file_path)?;
@@ -58,3 +554,182 @@ pub fn path_with_extension(path: &Path, ext: &str) -> PathBuf {
     res.set_extension(ext);
     res
 }
+
+/// Retries file system operations which fail with a transient error, see
+/// `is_transient_fs_error`, up to `retries` times. The delay before the
+/// first retry is doubled for each next retry. Other errors are returned
+/// immediately.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FsRetry {
+    retries: u32,
+    delay: Duration,
+}
+
+impl FsRetry {
+    pub fn new(retries: u32, delay: Duration) -> Self {
+        FsRetry { retries, delay }
+    }
+
+    pub fn rename(&self, from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
+        let (from, to) = (from.as_ref(), to.as_ref());
+        self.run("rename", from, || std::fs::rename(from, to))
+    }
+
+    pub fn exchange(&self, a: impl AsRef<Path>, b: impl AsRef<Path>) -> io::Result<()> {
+        let (a, b) = (a.as_ref(), b.as_ref());
+        self.run("exchange", a, || exchange_paths(a, b))
+    }
+
+    pub fn remove_dir_all(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        self.run("remove", path, || std::fs::remove_dir_all(path))
+    }
+
+    fn run<T>(
+        &self,
+        what: &str,
+        path: &Path,
+        mut op: impl FnMut() -> io::Result<T>,
+    ) -> io::Result<T> {
+        let mut delay = self.delay;
+        let mut retry = 0;
+        loop {
+            match op() {
+                Err(e) if retry < self.retries && is_transient_fs_error(&e) => {
+                    retry += 1;
+                    warn!(
+                        "Could not {} {}, retry {} of {} in {}ms: {}",
+                        what,
+                        path.display(),
+                        retry,
+                        self.retries,
+                        delay.as_millis(),
+                        e
+                    );
+                    std::thread::sleep(delay);
+                    delay = delay.saturating_mul(2);
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Returns whether the error may well go away if the operation is tried
+/// again, e.g. on a busy NFS mount.
+#[cfg(unix)]
+pub fn is_transient_fs_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(code) => [libc::ESTALE, libc::EBUSY, libc::EAGAIN, libc::EINTR].contains(&code),
+        None => err.kind() == io::ErrorKind::Interrupted,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn is_transient_fs_error(err: &io::Error) -> bool {
+    matches!(err.kind(), io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn retry_transient_fs_errors() {
+        let retry = FsRetry::new(2, Duration::from_millis(1));
+        let path = Path::new("dir");
+        let fail = |errors: &mut Vec<io::ErrorKind>| match errors.pop() {
+            Some(kind) => Err(io::Error::from(kind)),
+            None => Ok(()),
+        };
+        let interrupted = io::ErrorKind::Interrupted;
+
+        let mut errors = vec![interrupted; 2];
+        assert!(retry.run("test", path, || fail(&mut errors)).is_ok());
+
+        let mut errors = vec![interrupted; 3];
+        assert!(retry.run("test", path, || fail(&mut errors)).is_err());
+        assert!(errors.is_empty());
+
+        let mut errors = vec![interrupted, io::ErrorKind::NotFound];
+        let err = retry.run("test", path, || fail(&mut errors)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(errors.len(), 1);
+
+        #[cfg(unix)]
+        {
+            assert!(is_transient_fs_error(&io::Error::from_raw_os_error(libc::ESTALE)));
+            assert!(is_transient_fs_error(&io::Error::from_raw_os_error(libc::EBUSY)));
+            assert!(!is_transient_fs_error(&io::Error::from_raw_os_error(libc::EACCES)));
+        }
+    }
+
+    #[test]
+    fn parse_octal_modes() {
+        assert_eq!(parse_mode("644").unwrap(), 0o644);
+        assert_eq!(parse_mode("0755").unwrap(), 0o755);
+        assert!(parse_mode("999").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn try_lock_held_file() {
+        crate::util::test_with_dir("file_ops_try_lock_held_file", |dir| {
+            let path = dir.join("test.lock");
+
+            let lock = try_lock_exclusive(&path).unwrap().unwrap();
+            lock.write_pid().unwrap();
+            assert!(try_lock_exclusive(&path).unwrap().is_none());
+            if cfg!(unix) {
+                assert_eq!(lock_holder(&path), Some(std::process::id()));
+            }
+
+            drop(lock);
+            assert!(try_lock_exclusive(&path).unwrap().is_some());
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn copy_dir_with_files_and_symlinks() {
+        crate::util::test_with_dir("file_ops_copy_dir_with_files_and_symlinks", |dir| {
+            let from = dir.join("from");
+            write_buf(&from.join("ta/ta.cer"), b"cer").unwrap();
+            std::fs::create_dir_all(from.join("empty")).unwrap();
+            create_file_symlink(Path::new("/base/0123"), &from.join("ta/ta.crl")).unwrap();
+            let mtime = filetime::FileTime::from_unix_time(1_600_000_000, 0);
+            filetime::set_file_mtime(from.join("ta/ta.cer"), mtime).unwrap();
+            filetime::set_file_mtime(from.join("ta"), mtime).unwrap();
+
+            let to = dir.join("to");
+            copy_dir_all(&from, &to, None).unwrap();
+
+            assert_eq!(std::fs::read(to.join("ta/ta.cer")).unwrap(), b"cer");
+            assert!(to.join("empty").is_dir());
+            assert_eq!(std::fs::read_link(to.join("ta/ta.crl")).unwrap(), Path::new("/base/0123"));
+            for path in ["ta/ta.cer", "ta"] {
+                let meta = to.join(path).metadata().unwrap();
+                assert_eq!(filetime::FileTime::from_last_modification_time(&meta), mtime);
+            }
+        })
+    }
+
+    #[test]
+    fn exchange_dirs() {
+        crate::util::test_with_dir("file_ops_exchange_dirs", |dir| {
+            let (a, b) = (dir.join("a"), dir.join("b"));
+            write_buf(&a.join("file"), b"a").unwrap();
+            write_buf(&b.join("file"), b"b").unwrap();
+
+            match exchange_paths(&a, &b) {
+                Ok(()) => {
+                    assert_eq!(std::fs::read(a.join("file")).unwrap(), b"b");
+                    assert_eq!(std::fs::read(b.join("file")).unwrap(), b"a");
+                }
+                Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+            }
+            assert!(exchange_paths(&a, &dir.join("missing")).is_err());
+        })
+    }
+}
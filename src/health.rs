@@ -0,0 +1,91 @@
+use std::path::Path;
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{config, file_ops, util::Time};
+
+/// The time of the last successful synchronization of a source, persisted
+/// in the state dir so that the health can be checked by another process,
+/// without fetching anything.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct LastSuccess {
+    time: Time,
+}
+
+impl LastSuccess {
+    pub fn now() -> Self {
+        LastSuccess { time: Time::now() }
+    }
+
+    pub fn time(&self) -> Time {
+        self.time
+    }
+
+    /// Reads the last success from disk, if there was one yet.
+    pub fn read(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let json = std::fs::read(path)
+            .with_context(|| format!("Cannot read last success from: {}", path.display()))?;
+        serde_json::from_slice(&json)
+            .map(Some)
+            .with_context(|| format!("Cannot parse last success from: {}", path.display()))
+    }
+
+    /// Writes the last success to disk. Will first write to a temporary file
+    /// and then rename it, so that the health check never sees a partial
+    /// file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let tmp_path = file_ops::path_with_extension(path, config::TMP_FILE_EXT);
+        let json = serde_json::to_string(self)?;
+        file_ops::write_buf(&tmp_path, json.as_bytes())
+            .with_context(|| format!("Could not write last success to: {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Could not rename {} to {}", tmp_path.display(), path.display()))
+    }
+}
+
+/// Returns an error if the last success recorded at `path` is missing, or
+/// more than `max_staleness` seconds before `now`. Otherwise returns the
+/// number of seconds since the last success.
+pub fn check_staleness(path: &Path, max_staleness: i64, now: Time) -> Result<i64> {
+    let last_success = LastSuccess::read(path)?
+        .ok_or_else(|| anyhow!("There was no successful synchronization yet"))?;
+
+    let age = now.timestamp() - last_success.time().timestamp();
+    if age > max_staleness {
+        Err(anyhow!(
+            "The last successful synchronization was {} seconds ago, at {}, which exceeds --max-staleness {}",
+            age,
+            last_success.time(),
+            max_staleness
+        ))
+    } else {
+        Ok(age)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::util::test_with_dir;
+
+    use super::*;
+
+    #[test]
+    fn check_last_success_staleness() {
+        test_with_dir("health_check_last_success_staleness", |dir| {
+            let path = dir.join("last-success.json");
+            assert!(check_staleness(&path, 600, Time::now()).is_err());
+
+            LastSuccess::now().write(&path).unwrap();
+            let last_success = LastSuccess::read(&path).unwrap().unwrap();
+
+            let later = |seconds: i64| Time::from_timestamp(last_success.time().timestamp() + seconds);
+            assert_eq!(check_staleness(&path, 600, later(600)).unwrap(), 600);
+            assert!(check_staleness(&path, 600, later(601)).is_err());
+        })
+    }
+}
@@ -0,0 +1,56 @@
+use std::process::Command;
+
+use anyhow::{anyhow, Context, Result};
+use tracing::info;
+
+/// Runs an operator supplied hook command using the shell, with the given
+/// extra environment variables. Returns an error if the command could not
+/// be started, or if it did not exit successfully.
+pub fn run(command: &str, env: &[(&str, String)]) -> Result<()> {
+    info!("Running hook command: {}", command);
+
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    shell.arg(command);
+    for (name, value) in env {
+        shell.env(name, value);
+    }
+
+    let status = shell
+        .status()
+        .with_context(|| format!("Could not run hook command: {}", command))?;
+
+    if status.success() {
+        Ok(())
+    } else {
+        Err(anyhow!("Hook command '{}' failed with {}", command, status))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+
+    use crate::util::test_with_dir;
+
+    use super::*;
+
+    #[test]
+    fn run_hook_with_env() {
+        test_with_dir("hook_run_hook_with_env", |dir| {
+            let out = dir.join("out.txt");
+            let command = format!("echo \"$KRILL_SYNC_SERIAL\" > {}", out.display());
+
+            run(&command, &[("KRILL_SYNC_SERIAL", "2658".to_string())]).unwrap();
+            assert_eq!(std::fs::read_to_string(&out).unwrap(), "2658\n");
+
+            assert!(run("exit 3", &[]).is_err());
+        })
+    }
+}
@@ -0,0 +1,222 @@
+use std::{fmt, panic::AssertUnwindSafe};
+
+use anyhow::{anyhow, Result};
+use rpki::repository::{aspa::Aspa, sigobj::SignedObject, Cert, Crl, Manifest, Roa};
+use tracing::{info, warn};
+
+use crate::{
+    config::{Config, InvalidObjectAction},
+    exit,
+    rsync::{CurrentObject, ObjectType},
+};
+
+/// Decodes the object as the RPKI object type of its URI, and checks what
+/// can be checked without its issuer: the formal requirements for a CA or
+/// router certificate, or for the EE certificate of a signed object, and the
+/// signature of a self-signed certificate. For a signed object this is a
+/// structural decode only: neither its CMS signature nor its message digest
+/// is checked, as that needs the issuer. This is not validation of the
+/// certificate path, so a well-formed object may still be invalid for
+/// relying parties.
+///
+/// Returns false if the object has an unknown type, and was not checked.
+pub fn inspect_object(object: &CurrentObject) -> Result<bool> {
+    // The decoder panics on some malformed input, rather than returning an
+    // error. The panic is still reported by the panic hook.
+    std::panic::catch_unwind(AssertUnwindSafe(|| inspect_decoded(object)))
+        .unwrap_or_else(|_| Err(anyhow!("Decoder panicked on malformed object")))
+}
+
+fn inspect_decoded(object: &CurrentObject) -> Result<bool> {
+    let data = object.data().clone();
+    let strict = false;
+
+    match object.object_type() {
+        ObjectType::Cer => inspect_cert(&Cert::decode(data).map_err(err)?)?,
+        ObjectType::Crl => {
+            Crl::decode(data).map_err(err)?;
+        }
+        ObjectType::Mft => {
+            Manifest::decode(data, strict).map_err(err)?.cert().inspect_ee(strict).map_err(err)?
+        }
+        ObjectType::Roa => {
+            Roa::decode(data, strict).map_err(err)?.cert().inspect_ee(strict).map_err(err)?
+        }
+        ObjectType::Asa => {
+            Aspa::decode(data, strict).map_err(err)?.cert().inspect_ee(strict).map_err(err)?
+        }
+        // RPKI signed checklists are not published at the URI in their EE
+        // certificate, if they are published at all.
+        ObjectType::Sig => SignedObject::decode(data, strict)
+            .map_err(err)?
+            .cert()
+            .inspect_detached_ee(strict)
+            .map_err(err)?,
+        ObjectType::Gbr | ObjectType::Tak => SignedObject::decode(data, strict)
+            .map_err(err)?
+            .cert()
+            .inspect_ee(strict)
+            .map_err(err)?,
+        ObjectType::Other => return Ok(false),
+    }
+
+    Ok(true)
+}
+
+fn inspect_cert(cert: &Cert) -> Result<()> {
+    let strict = false;
+    if !cert.is_ca() {
+        cert.inspect_router(strict).map_err(err)
+    } else if cert.is_self_signed() {
+        cert.inspect_ta(strict).map_err(err)?;
+        cert.verify_signature(cert, strict).map_err(err)
+    } else {
+        cert.inspect_ca(strict).map_err(err)
+    }
+}
+
+fn err(e: impl fmt::Display) -> anyhow::Error {
+    anyhow!("{}", e)
+}
+
+/// Inspects the objects for a new rsync revision, see `inspect_object`, and
+/// decides what to do with malformed objects according to
+/// `config.invalid_objects`.
+pub struct ObjectInspector<'a> {
+    config: &'a Config,
+    checked: usize,
+    unknown: usize,
+    invalid: Vec<String>,
+}
+
+impl<'a> ObjectInspector<'a> {
+    pub fn new(config: &'a Config) -> Self {
+        ObjectInspector {
+            config,
+            checked: 0,
+            unknown: 0,
+            invalid: vec![],
+        }
+    }
+
+    /// Inspects the object, and returns whether it should be written.
+    /// Objects which are excluded from rsync are not inspected.
+    pub fn check(&mut self, object: &CurrentObject) -> bool {
+        if self.config.rsync_excluded(object.uri()) {
+            return true;
+        }
+
+        match inspect_object(object) {
+            Ok(true) => self.checked += 1,
+            Ok(false) => self.unknown += 1,
+            Err(e) => {
+                warn!("Malformed RPKI object {}: {:#}", object.uri(), e);
+                self.invalid.push(object.uri().to_string());
+                return self.config.invalid_objects != InvalidObjectAction::Skip;
+            }
+        }
+        true
+    }
+
+    /// Logs the counts, and returns the URIs of the malformed objects.
+    /// Returns an error if there are any and `config.invalid_objects` is
+    /// 'abort'.
+    pub fn finish(self) -> Result<Vec<String>> {
+        info!(
+            "Inspected {} objects, {} were malformed, {} have an unknown type and were not inspected",
+            self.checked + self.invalid.len(),
+            self.invalid.len(),
+            self.unknown
+        );
+
+        if self.config.invalid_objects == InvalidObjectAction::Abort && !self.invalid.is_empty() {
+            Err(exit::invalid_data(format!(
+                "Found {} malformed RPKI objects, e.g. {}, use --invalid-objects skip or keep to write a revision anyway",
+                self.invalid.len(),
+                self.invalid[0]
+            )))
+        } else {
+            Ok(self.invalid)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{path::Path, str::FromStr};
+
+    use bytes::Bytes;
+
+    use crate::{
+        config::create_test_config,
+        rsync::read_snapshot_objects,
+        util::{https, test_with_dir},
+    };
+
+    use super::*;
+
+    #[test]
+    fn inspect_objects_from_snapshot() {
+        test_with_dir("inspect_objects_from_snapshot", |dir| {
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let snapshot_path = Path::new(
+                "./test-resources/rrdp-rev2656/e9be21e7-c537-4564-b742-64700978c6b4/2656/snapshot.xml",
+            );
+            let objects = read_snapshot_objects(snapshot_path, &config).unwrap();
+            for object in &objects {
+                assert!(inspect_object(object).unwrap(), "{}", object.uri());
+            }
+
+            let roa = objects.iter().find(|o| o.object_type() == ObjectType::Roa).unwrap();
+            let mut data = roa.data().to_vec();
+            data.truncate(data.len() / 2);
+            let truncated = CurrentObject::new(roa.uri().clone(), Bytes::from(data));
+            assert!(inspect_object(&truncated).is_err());
+
+            // A certificate is not a ROA
+            let cer = objects.iter().find(|o| o.object_type() == ObjectType::Cer).unwrap();
+            let misnamed = CurrentObject::new(roa.uri().clone(), cer.data().clone());
+            assert!(inspect_object(&misnamed).is_err());
+
+            let uri = rpki::uri::Rsync::from_str("rsync://host/repo/readme.txt").unwrap();
+            let unknown = CurrentObject::new(uri, Bytes::from_static(b"text"));
+            assert!(!inspect_object(&unknown).unwrap());
+        })
+    }
+
+    #[test]
+    fn invalid_object_actions() {
+        test_with_dir("inspect_invalid_object_actions", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let uri = rpki::uri::Rsync::from_str("rsync://host/repo/ca/0/AS65000.roa").unwrap();
+            let malformed = CurrentObject::new(uri, Bytes::from_static(b"not a roa"));
+
+            for (action, written) in [
+                (InvalidObjectAction::Abort, true),
+                (InvalidObjectAction::Skip, false),
+                (InvalidObjectAction::Keep, true),
+            ] {
+                config.invalid_objects = action;
+                let mut inspector = ObjectInspector::new(&config);
+                assert_eq!(inspector.check(&malformed), written);
+                let res = inspector.finish();
+                if action == InvalidObjectAction::Abort {
+                    assert!(res.is_err());
+                } else {
+                    assert_eq!(res.unwrap(), vec!["rsync://host/repo/ca/0/AS65000.roa"]);
+                }
+            }
+        })
+    }
+}
@@ -1,15 +1,39 @@
+//! Synchronizes an RRDP and/or rsync server with a remote RRDP publication
+//! point. Use `run` to embed a single krill-sync run elsewhere, the
+//! `process` module has the functions for the krill-sync subcommands.
+
 extern crate anyhow;
 extern crate bytes;
 extern crate chrono;
-extern crate log;
 extern crate reqwest;
 extern crate rpki;
 extern crate serde;
 
+pub mod archive;
+pub mod cache;
+pub mod cas;
 pub mod config;
+pub mod exit;
 pub mod fetch;
 pub mod file_ops;
+pub mod health;
+pub mod hook;
+pub mod inspect;
+pub mod metrics;
 pub mod process;
+pub mod progress;
+pub mod report;
 pub mod rrdp;
 pub mod rsync;
+pub mod target;
 pub mod util;
+
+pub use process::SyncOutcome;
+
+/// Synchronizes the RRDP source configured in `config` once, and then each of
+/// its `rsync_modules`, like a krill-sync run does, and returns the outcome
+/// for each of them. The config should be prepared with
+/// `config::post_configure`.
+pub fn run(config: &config::Config) -> anyhow::Result<Vec<SyncOutcome>> {
+    process::process_sources(config)
+}
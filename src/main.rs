@@ -1,30 +1,101 @@
-use anyhow::{anyhow, Context, Result};
+use std::sync::{atomic::AtomicBool, Arc};
+
+use anyhow::{Context, Result};
 
-use fslock::LockFile;
 use krill_sync::{
-    config::{configure, Config},
-    process::process,
+    config::{configure, Command, Config},
+    exit::{ErrorReport, ExitCode, LockHeld},
+    file_ops::{self, FileLock},
+    process::{
+        clean, daemon, diff, export, health, process, publish, rollback, stage, validate, verify,
+    },
 };
-use log::debug;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use tracing::{debug, warn};
 
 fn main() {
-    if let Err(err) = configure_and_try_main() {
-        eprintln!("{:?}", err);
-        std::process::exit(1);
+    let config = match configure() {
+        Ok(config) => config,
+        Err(err) => {
+            // There is no config to tell whether --json was used.
+            let json = std::env::args_os().any(|arg| arg == "--json");
+            exit_with(err, ExitCode::Config, json)
+        }
+    };
+    let json = config.json;
+    if let Err(err) = try_main(config) {
+        let code = ExitCode::for_error(&err);
+        exit_with(err, code, json);
     }
 }
 
-fn configure_and_try_main() -> Result<()> {
-    try_main(configure()?)
+fn exit_with(err: anyhow::Error, code: ExitCode, json: bool) -> ! {
+    match serde_json::to_string(&ErrorReport::new(&err, code)) {
+        Ok(report) if json => println!("{}", report),
+        _ => eprintln!("{:?}", err),
+    }
+    std::process::exit(code.code());
 }
 
 fn try_main(config: Config) -> Result<()> {
+    if config.print_config {
+        print!("{}", config.describe());
+        return Ok(());
+    }
+
     // secure lock, note: will be unlocked when the LockFile goes out of scope.
-    let _lock_file = lock(&config)?;
-    process(&config)
+    // A dry run of a sync does not change anything, so it does not need the
+    // lock, nor a state dir to put it in. The health check must not wait for,
+    // or be refused by, a running daemon.
+    let dry_run = config.dry_run && config.command.is_none() && !config.daemon;
+    let unlocked = dry_run || matches!(config.command, Some(Command::Health));
+    let _lock_file = if unlocked { None } else { Some(lock(&config)?) };
+    match &config.command {
+        None if config.validate_only => validate(&config),
+        None if config.daemon => daemon(&config, &*shutdown_on_signal()?, &*reload_on_signal()?),
+        None => process(&config),
+        Some(Command::Clean { force }) => clean(&config, *force),
+        Some(Command::Verify) => verify(&config),
+        Some(Command::Stage) => stage(&config),
+        Some(Command::Publish) => publish(&config),
+        Some(Command::Health) => health(&config),
+        Some(Command::Diff { from, to, format }) => diff(&config, *from, *to, *format),
+        Some(Command::Export { since, export_dir }) => export(&config, *since, export_dir),
+        Some(Command::Rollback { to, force }) => rollback(&config, *to, *force),
+    }
+}
+
+/// Returns a flag which is set on SIGTERM or SIGINT, rather than letting the
+/// signal terminate us. The daemon checks it between runs.
+fn shutdown_on_signal() -> Result<Arc<AtomicBool>> {
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in [SIGTERM, SIGINT] {
+        signal_hook::flag::register(signal, shutdown.clone())
+            .with_context(|| format!("Cannot register handler for signal {}", signal))?;
+    }
+    Ok(shutdown)
+}
+
+/// Returns a flag which is set on SIGHUP. The daemon then reloads the config
+/// before its next run.
+#[cfg(unix)]
+fn reload_on_signal() -> Result<Arc<AtomicBool>> {
+    let reload = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::consts::SIGHUP, reload.clone())
+        .with_context(|| "Cannot register handler for signal SIGHUP")?;
+    Ok(reload)
 }
 
-fn lock(config: &Config) -> Result<LockFile> {
+#[cfg(not(unix))]
+fn reload_on_signal() -> Result<Arc<AtomicBool>> {
+    Ok(Arc::new(AtomicBool::new(false)))
+}
+
+/// Takes the lock for the state dir, so that krill-sync processes never
+/// work on the same state and revisions at the same time. The lock file has
+/// the ID of the process holding it. The lock is released when it is dropped,
+/// also when unwinding from a panic, and by the OS when the process exits.
+fn lock(config: &Config) -> Result<FileLock> {
     if !config.state_dir.exists() {
         debug!(
             "State directory '{}' does not exist yet, will try to create it.",
@@ -35,18 +106,30 @@ fn lock(config: &Config) -> Result<LockFile> {
     }
 
     let lock_file_path = config.lock_file();
-    let mut lock_file = LockFile::open(&lock_file_path)
-        .with_context(|| format!("Cannot open lockfile: {}", lock_file_path.display()))?;
-
-    if !lock_file
-        .try_lock()
-        .with_context(|| format!("Cannot lock using lockfile: {}", lock_file_path.display()))?
-    {
-        Err(anyhow!(format!(
-            "another krill-sync process holds the lock at {}",
-            lock_file_path.display()
-        )))
-    } else {
-        Ok(lock_file)
-    }
+    let lock = match file_ops::try_lock_exclusive(&lock_file_path)? {
+        Some(lock) => lock,
+        None => {
+            let holder = match file_ops::lock_holder(&lock_file_path) {
+                Some(pid) => format!(" (pid {})", pid),
+                None => String::new(),
+            };
+            if !config.wait {
+                return Err(anyhow::Error::new(LockHeld(format!(
+                    "another krill-sync process{} holds the lock at {}, use --wait to wait for it",
+                    holder,
+                    lock_file_path.display()
+                ))));
+            }
+            warn!(
+                "Another krill-sync process{} holds the lock at {}, waiting for it",
+                holder,
+                lock_file_path.display()
+            );
+            file_ops::lock_exclusive(&lock_file_path)?
+        }
+    };
+
+    lock.write_pid()
+        .with_context(|| format!("Cannot write pid to lockfile: {}", lock_file_path.display()))?;
+    Ok(lock)
 }
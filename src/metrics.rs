@@ -0,0 +1,146 @@
+use std::{fmt::Write, path::Path};
+
+use anyhow::{Context, Result};
+use uuid::Uuid;
+
+use crate::{config, file_ops, rsync::RsyncUpdateSummary, util::Time};
+
+/// Metrics for the last successful sync run, which can be written in the
+/// Prometheus text exposition format. This is intended to be picked up by
+/// e.g. the textfile collector of the Prometheus node exporter.
+#[derive(Clone, Debug)]
+pub struct SyncMetrics {
+    module: Option<String>,
+    session_and_serial: Option<(Uuid, u64)>,
+    rsync: RsyncUpdateSummary,
+    last_success: Time,
+}
+
+impl SyncMetrics {
+    pub fn new(session_and_serial: Option<(Uuid, u64)>, rsync: RsyncUpdateSummary) -> Self {
+        SyncMetrics {
+            module: None,
+            session_and_serial,
+            rsync,
+            last_success: Time::now(),
+        }
+    }
+
+    /// Labels all metrics with the given rsync module, so that the metrics
+    /// for different modules can be told apart.
+    pub fn with_module(mut self, module: Option<String>) -> Self {
+        self.module = module;
+        self
+    }
+
+    /// Formats the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus_text(&self) -> String {
+        let mut text = String::new();
+
+        if let Some((session_id, serial)) = self.session_and_serial {
+            Self::gauge_header(
+                &mut text,
+                "krill_sync_rrdp_serial",
+                "The RRDP serial of the current snapshot.",
+            );
+            let _ = writeln!(
+                text,
+                "krill_sync_rrdp_serial{} {}",
+                self.labels(Some(("session", session_id.to_string()))),
+                serial
+            );
+        }
+
+        self.gauge(
+            &mut text,
+            "krill_sync_objects_written",
+            "The number of objects written for a new rsync revision in the last run.",
+            self.rsync.objects_written as u64,
+        );
+        self.gauge(
+            &mut text,
+            "krill_sync_bytes_written",
+            "The number of bytes written for a new rsync revision in the last run.",
+            self.rsync.bytes_written,
+        );
+        let changes = self.rsync.object_changes.unwrap_or_default();
+        self.gauge(
+            &mut text,
+            "krill_sync_objects_added",
+            "The number of objects added in a new rsync revision in the last run.",
+            changes.added as u64,
+        );
+        self.gauge(
+            &mut text,
+            "krill_sync_objects_removed",
+            "The number of objects removed in a new rsync revision in the last run.",
+            changes.removed as u64,
+        );
+        self.gauge(
+            &mut text,
+            "krill_sync_objects_changed",
+            "The number of objects changed in a new rsync revision in the last run.",
+            changes.changed as u64,
+        );
+        self.gauge(
+            &mut text,
+            "krill_sync_old_revisions_cleaned",
+            "The number of old rsync revision directories removed in the last run.",
+            self.rsync.old_revisions_cleaned() as u64,
+        );
+        if let Some(lag) = self.rsync.publish_lag_seconds {
+            self.gauge(
+                &mut text,
+                "krill_sync_publish_lag_seconds",
+                "The seconds between the RRDP publication of the serial and making its rsync revision current in the last run.",
+                lag,
+            );
+        }
+        self.gauge(
+            &mut text,
+            "krill_sync_last_success_timestamp",
+            "The unix timestamp of the last successful run.",
+            self.last_success.timestamp() as u64,
+        );
+
+        text
+    }
+
+    /// Writes the metrics to the given path. Will first write to a temporary
+    /// file and then rename it, so that scrapers never see a partial file.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let tmp_path = file_ops::path_with_extension(path, config::TMP_FILE_EXT);
+
+        file_ops::write_buf(&tmp_path, self.to_prometheus_text().as_bytes())
+            .with_context(|| format!("Could not write metrics to: {}", tmp_path.display()))?;
+
+        std::fs::rename(&tmp_path, path)
+            .with_context(|| format!("Could not rename {} to {}", tmp_path.display(), path.display()))
+    }
+
+    fn gauge_header(text: &mut String, name: &str, help: &str) {
+        let _ = writeln!(text, "# HELP {} {}", name, help);
+        let _ = writeln!(text, "# TYPE {} gauge", name);
+    }
+
+    fn gauge(&self, text: &mut String, name: &str, help: &str, value: u64) {
+        Self::gauge_header(text, name, help);
+        let _ = writeln!(text, "{}{} {}", name, self.labels(None), value);
+    }
+
+    fn labels(&self, extra: Option<(&str, String)>) -> String {
+        let labels: Vec<String> = self
+            .module
+            .iter()
+            .map(|module| ("module", module.clone()))
+            .chain(extra)
+            .map(|(name, value)| format!("{}=\"{}\"", name, value))
+            .collect();
+
+        if labels.is_empty() {
+            String::new()
+        } else {
+            format!("{{{}}}", labels.join(","))
+        }
+    }
+}
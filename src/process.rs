@@ -1,11 +1,203 @@
-use anyhow::Result;
-use log::info;
+use std::{
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
-use crate::{config::Config, rrdp::RrdpState, rsync};
+use anyhow::{anyhow, Context, Result};
+use tracing::{error, info, info_span, warn};
+use uuid::Uuid;
 
-/// Responsible for the main krill-sync process
+use crate::{
+    config::{Config, LogFormat},
+    exit,
+    health::{self, LastSuccess},
+    hook,
+    metrics::SyncMetrics,
+    rrdp::{self, RrdpState},
+    rsync::{self, RsyncTimings, RsyncUpdateSummary},
+    util::Time,
+};
+
+/// The result of synchronizing a single RRDP source.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SyncOutcome {
+    /// The rsync module, if this was one of the additional sources.
+    pub module: Option<String>,
+
+    /// The session of the current snapshot, if there is one yet.
+    pub session_id: Option<Uuid>,
+
+    /// The serial of the current snapshot, if there is one yet.
+    pub serial: Option<u64>,
+
+    /// Whether there was an update at the source.
+    pub changed: bool,
+
+    /// The changes made to the rsync dir.
+    pub rsync: RsyncUpdateSummary,
+}
+
+/// How often the daemon checks for a shutdown request while sleeping.
+const DAEMON_SHUTDOWN_CHECK_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Responsible for the main krill-sync process. Synchronizes the main
+/// RRDP source, if configured, and then each additional source to its own
+/// rsync module. A failure for one source does not stop the others.
 pub fn process(config: &Config) -> Result<()> {
-    info!("Checking: {}", config.notification_uri);
+    process_sources(config).map(|_| ())
+}
+
+/// Like `process`, but returns the outcome for each source, in the same
+/// order: the main source first, if configured, and then each module.
+pub fn process_sources(config: &Config) -> Result<Vec<SyncOutcome>> {
+    for_each_source(config, sync_source)
+}
+
+/// Like `process`, but new rsync revisions are only staged, and the RRDP
+/// notification files are not updated, until `publish` is used.
+pub fn stage(config: &Config) -> Result<()> {
+    require_rsync(config, "Staging")?;
+    for_each_source(config, |config| sync(config, false)).map(|_| ())
+}
+
+/// Makes the rsync revisions which were staged current, and writes the RRDP
+/// notification files for them, for the main source and all modules.
+pub fn publish(config: &Config) -> Result<()> {
+    require_rsync(config, "Publishing")?;
+    publish_source(config)?;
+
+    for module in &config.rsync_modules {
+        publish_source(&config.for_module(module)?)?;
+    }
+
+    Ok(())
+}
+
+/// Returns an error for the subcommands which only work on rsync revisions,
+/// if rsync is disabled, i.e. if krill-sync only mirrors the RRDP files.
+fn require_rsync(config: &Config, what: &str) -> Result<()> {
+    if config.rsync_enabled() {
+        Ok(())
+    } else {
+        Err(anyhow!("{} is not supported if rsync is disabled", what))
+    }
+}
+
+fn publish_source(config: &Config) -> Result<()> {
+    if rsync::publish_staged(config)? {
+        RrdpState::recover(&config.rrdp_state_path())?.write_notification()?;
+    }
+    Ok(())
+}
+
+/// Validates the RRDP source, and any additional sources, without writing
+/// anything. Every problem is logged, and an error is returned if there were
+/// any.
+pub fn validate(config: &Config) -> Result<()> {
+    for_each_source(config, |config| {
+        let uri = config.notification_uri()?;
+        info!("Validating: {}", uri);
+
+        let problems = rrdp::validate(&config.fetcher()?)?;
+        for problem in &problems {
+            error!("{}", problem);
+        }
+
+        if problems.is_empty() {
+            info!("No problems found for {}", uri);
+            Ok(())
+        } else {
+            Err(exit::invalid_data(format!("Found {} problems for {}", problems.len(), uri)))
+        }
+    })
+    .map(|_| ())
+}
+
+/// Calls `op` for the main RRDP source, if configured, and for each additional
+/// source with the config for its module, and returns the results in that
+/// order.
+fn for_each_source<F, T>(config: &Config, op: F) -> Result<Vec<T>>
+where
+    F: Fn(&Config) -> Result<T>,
+{
+    if config.rsync_modules.is_empty() {
+        return op(config).map(|result| vec![result]);
+    }
+
+    let mut results = vec![];
+    let mut failed = vec![];
+    let mut first_error = None;
+
+    if config.notification_uri.is_some() {
+        match op(config) {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                error!("Failed to synchronize {}: {:?}", config.notification_uri()?, e);
+                failed.push(config.notification_uri()?.to_string());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    for module in &config.rsync_modules {
+        match config.for_module(module).and_then(|config| op(&config)) {
+            Ok(result) => results.push(result),
+            Err(e) => {
+                error!("Failed to synchronize module {}: {:?}", module.name(), e);
+                failed.push(module.name().to_string());
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+
+    // The first error is kept as the cause, for the exit code.
+    match first_error {
+        None => Ok(results),
+        Some(e) => Err(e.context(format!("Failed to synchronize: {}", failed.join(", ")))),
+    }
+}
+
+/// Synchronizes a single RRDP source, i.e. the notification URI of the config
+/// and not its additional `rsync_modules`. Use `Config::for_module` to get the
+/// config to synchronize one of those.
+pub fn sync_source(config: &Config) -> Result<SyncOutcome> {
+    sync(config, true)
+}
+
+/// Synchronizes a single RRDP source, but if `publish` is false, then a new
+/// rsync revision is only staged and the notification file is not updated.
+/// The progress is reported while doing so, if `config.progress` is set.
+fn sync(config: &Config, publish: bool) -> Result<SyncOutcome> {
+    config.run_progress.start_run("fetch");
+    let reporter = config.progress_reporter();
+    let outcome = sync_run(config, publish);
+    if config.dry_run {
+        let dir = dry_run_rrdp_dir(config);
+        if dir.exists() {
+            std::fs::remove_dir_all(&dir)
+                .with_context(|| format!("Cannot remove dry run dir: {}", dir.display()))?;
+        }
+    }
+    let outcome = outcome?;
+    if let Some(reporter) = reporter {
+        reporter.finish();
+    }
+    Ok(outcome)
+}
+
+fn sync_run(config: &Config, publish: bool) -> Result<SyncOutcome> {
+    let notification_uri = config.notification_uri()?;
+    let _span =
+        info_span!("sync", uri = %notification_uri, module = config.module.as_deref()).entered();
+    info!("Checking: {}", notification_uri);
+
+    if config.rsync_enabled() && !config.dry_run {
+        rsync::prepare_rsync_dir(config)?;
+        if publish {
+            rsync::check_not_staged(config)?;
+        }
+    }
 
     // ===================================================================
     // Get the current RRDP state:
@@ -18,15 +210,43 @@ pub fn process(config: &Config) -> Result<()> {
         RrdpState::create(config)?
     };
 
+    // A dry run downloads to a temporary dir, so that the RRDP files which
+    // are served are not changed.
+    let checkpoint_path = if config.dry_run {
+        let dir = dry_run_rrdp_dir(config);
+        rrdp_state = rrdp_state.with_rrdp_dir(&dir.join("rrdp"))?;
+        dir.join("rrdp-checkpoint.xml")
+    } else {
+        config.rrdp_checkpoint_path()
+    };
+
     // ===================================================================
     // Update the RRDP state, if there are any changes in the source:
     //  - remember if there was a change for writing a new rsync folder
     // ===================================================================
-    let changed = rrdp_state.update(config.rrdp_max_deltas, &config.fetcher())?;
+    let fetch_start = Instant::now();
+    let fetch_span = info_span!(
+        "fetch",
+        session = rrdp_state.snapshot().map(|snapshot| snapshot.session_id().to_string()),
+        serial = rrdp_state.snapshot().map(|snapshot| snapshot.serial())
+    );
+    let changed = fetch_span.in_scope(|| {
+        rrdp_state.update(
+            config.rrdp_max_deltas,
+            config.allow_serial_rollback,
+            config.max_object_bytes,
+            config.max_delta_total_bytes,
+            &config.fetcher()?,
+            if config.rrdp_apply_deltas { Some(checkpoint_path.as_path()) } else { None },
+        )
+    })?;
+    let fetch_time = fetch_start.elapsed();
 
     // Clean up any RRDP files and empty parent directories if they had been
     // deprecated for more than the configured 'cleanup_after' time.
-    rrdp_state.clean(config)?;
+    if !config.dry_run {
+        rrdp_state.clean(config)?;
+    }
 
     // ===================================================================
     // If enabled, use the latest local RRDP snapshot to create a local
@@ -42,22 +262,47 @@ pub fn process(config: &Config) -> Result<()> {
     // it refers to) and to avoid having to use an external rsync client.
     //
     // If there was an existing current rsync directory then it will be
-    // kept around for existing clients. On unix systems we will use a
-    // symlink to point to the current directory so that new client will
-    // get the updated content.  On non-unix systems we will rename
-    // directories in quick succession.
+    // kept around for existing clients. On unix and Windows systems we
+    // will use a symlink to point to the current directory so that new
+    // client will get the updated content. On other systems, or if the
+    // symlink cannot be created on Windows, we will rename directories in
+    // quick succession.
     //
     // We will also clean out old rsync directories if they had been
     // deprecated for more than the 'cleanup_after' time, even if there
     // was no new data to write (i.e. change == false).
-    if config.rsync_enabled() {
-        rsync::update_from_rrdp_state(&rrdp_state, changed, config)?;
+    let rsync_summary = if !config.rsync_enabled() {
+        RsyncUpdateSummary::default()
+    } else if publish {
+        rsync::update_from_rrdp_state(&rrdp_state, changed, config)?
+    } else {
+        rsync::stage_from_rrdp_state(&rrdp_state, changed, config)?
+    };
+
+    info!("{}", timings_summary(fetch_time, &rsync_summary.timings, changed));
+
+    let outcome = SyncOutcome {
+        module: config.module.clone(),
+        session_id: rrdp_state.snapshot().map(|snapshot| snapshot.session_id()),
+        serial: rrdp_state.snapshot().map(|snapshot| snapshot.serial()),
+        changed,
+        rsync: rsync_summary,
+    };
+
+    // ===================================================================
+    // In a dry run we do not publish anything, or remember what we saw.
+    // ===================================================================
+    if config.dry_run {
+        info!("Dry run: not updating notification file or persisting state");
+        return Ok(outcome);
     }
 
     // ===================================================================
-    // Update the notification file if there was any change.
+    // Update the notification file if there was any change, or if a
+    // revision which was staged earlier was published now. When staging,
+    // this is left to 'publish'.
     // ===================================================================
-    if changed {
+    if publish && (changed || outcome.rsync.published) {
         rrdp_state.write_notification()?;
     }
 
@@ -65,21 +310,320 @@ pub fn process(config: &Config) -> Result<()> {
     // Persist state
     // ===================================================================
     rrdp_state.persist(&config.rrdp_state_path())?;
+    LastSuccess::now().write(&config.last_success_path())?;
+
+    // ===================================================================
+    // Write metrics for this successful run, if configured
+    // ===================================================================
+    if let Some(metrics_path) = &config.metrics_path {
+        let session_and_serial = outcome.session_id.zip(outcome.serial);
+        SyncMetrics::new(session_and_serial, outcome.rsync.clone())
+            .with_module(config.module.clone())
+            .write(metrics_path)?;
+    }
+
+    if publish {
+        run_post_sync_command(&outcome, config);
+    }
+
+    Ok(outcome)
+}
+
+/// The temporary dir to which a dry run downloads the RRDP files. It is in
+/// `config.tmp_dir` if set, and removed when the run is done.
+fn dry_run_rrdp_dir(config: &Config) -> PathBuf {
+    let dir = config.tmp_dir.clone().unwrap_or_else(std::env::temp_dir);
+    dir.join(format!("krill-sync-dry-run-{}", std::process::id()))
+}
+
+/// Runs `config.post_sync_command`, if configured, when something was
+/// published or if `config.post_sync_on_no_change` is set. The content is
+/// already published, so a failure is only logged.
+fn run_post_sync_command(outcome: &SyncOutcome, config: &Config) {
+    let command = match &config.post_sync_command {
+        Some(command) => command,
+        None => return,
+    };
+
+    let changed = outcome.changed || outcome.rsync.published;
+    if !changed && !config.post_sync_on_no_change {
+        return;
+    }
+
+    let env = [
+        (
+            "KRILL_SYNC_SESSION",
+            outcome.session_id.map(|id| id.to_string()).unwrap_or_default(),
+        ),
+        (
+            "KRILL_SYNC_SERIAL",
+            outcome.serial.map(|serial| serial.to_string()).unwrap_or_default(),
+        ),
+        ("KRILL_SYNC_CHANGED", changed.to_string()),
+    ];
+
+    if let Err(e) = hook::run(command, &env) {
+        warn!("Post sync command failed: {:?}", e);
+    }
+}
+
+/// Describes how long the steps of a run took, in a single line.
+fn timings_summary(fetch: Duration, rsync: &RsyncTimings, changed: bool) -> String {
+    let step = |duration: Option<Duration>| match duration {
+        Some(duration) => format!("{}ms", duration.as_millis()),
+        None if changed => "skipped".to_string(),
+        None => "skipped (no changes)".to_string(),
+    };
+
+    format!(
+        "Timings: fetch {}ms, write {}, swap {}, clean {}ms",
+        fetch.as_millis(),
+        step(rsync.write),
+        step(rsync.swap),
+        rsync.clean.as_millis()
+    )
+}
+
+/// Keeps synchronizing every `config.interval_seconds` until `shutdown` is
+/// set, e.g. by a signal handler. A failed run is logged, and we will simply
+/// try again in the next run. The flag is only checked between runs, so a
+/// run is never interrupted half-way, e.g. in the middle of making a new
+/// rsync revision current.
+///
+/// If `reload` is set, e.g. on SIGHUP, then the config is reloaded before
+/// the next run, see `Config::reload`. If that fails, then we keep going
+/// with the current config.
+pub fn daemon(config: &Config, shutdown: &AtomicBool, reload: &AtomicBool) -> Result<()> {
+    let mut config = config.clone();
+    info!("Starting daemon, synchronizing every {} seconds", config.interval_seconds);
+
+    while !shutdown.load(Ordering::SeqCst) {
+        if reload.swap(false, Ordering::SeqCst) {
+            match config.reload() {
+                Ok(reloaded) => config = reloaded,
+                Err(e) => error!("Could not reload config, keeping the current config: {:?}", e),
+            }
+        }
+
+        let interval = Duration::from_secs(config.interval_seconds);
+        let started = Instant::now();
+
+        if let Err(e) = process(&config) {
+            error!("Synchronization failed, will retry in the next run: {:?}", e);
+        }
+
+        // Sleep in small steps, so that we can shut down quickly.
+        while !shutdown.load(Ordering::SeqCst) && started.elapsed() < interval {
+            let remaining = interval.saturating_sub(started.elapsed());
+            std::thread::sleep(remaining.min(DAEMON_SHUTDOWN_CHECK_INTERVAL));
+        }
+    }
+
+    info!("Shutting down daemon");
+    Ok(())
+}
+
+/// Removes old rsync revisions, without synchronizing. If `force` is set
+/// then all old revisions are removed, regardless of their age.
+pub fn clean(config: &Config, force: bool) -> Result<()> {
+    require_rsync(config, "Cleaning rsync revisions")?;
+    let cleaned = rsync::clean_old_revisions(config, force)?;
+    info!("Removed {} old rsync revisions", cleaned);
+
+    for module in &config.rsync_modules {
+        let cleaned = rsync::clean_old_revisions(&config.for_module(module)?, force)?;
+        info!("Removed {} old rsync revisions for module {}", cleaned, module.name());
+    }
+
+    Ok(())
+}
+
+/// Checks that the main source and all modules were synchronized
+/// successfully within `config.max_staleness_seconds`. This only reads the
+/// persisted state, so that it can be used while a daemon is running.
+pub fn health(config: &Config) -> Result<()> {
+    let mut configs = vec![];
+    if config.notification_uri.is_some() || config.rsync_modules.is_empty() {
+        configs.push(config.clone());
+    }
+    for module in &config.rsync_modules {
+        configs.push(config.for_module(module)?);
+    }
+
+    let now = Time::now();
+    let mut failed = vec![];
+    for config in &configs {
+        let name = config.module.as_deref().unwrap_or("main source");
+        let path = config.last_success_path();
+        match health::check_staleness(&path, config.max_staleness_seconds, now) {
+            Ok(age) => info!("Healthy: {} was synchronized {} seconds ago", name, age),
+            Err(e) => {
+                error!("Unhealthy: {}: {:?}", name, e);
+                failed.push(name.to_string());
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("Unhealthy: {}", failed.join(", ")))
+    }
+}
+
+/// Makes the old rsync revision for the serial current again, see
+/// `rsync::rollback`.
+pub fn rollback(config: &Config, to: u64, force: bool) -> Result<()> {
+    require_rsync(config, "Rolling back")?;
+    rsync::rollback(to, force, config)?;
+    info!("Rolled back the current rsync revision to serial {}", to);
+    Ok(())
+}
+
+/// Prints the differences between the rsync revisions for two serials, see
+/// `rsync::diff_revisions`.
+pub fn diff(config: &Config, from: u64, to: u64, format: LogFormat) -> Result<()> {
+    require_rsync(config, "Comparing rsync revisions")?;
+    let diff = rsync::diff_revisions(from, to, config)?;
 
+    match format {
+        LogFormat::Json => println!("{}", serde_json::to_string_pretty(&diff)?),
+        LogFormat::Text => {
+            println!(
+                "{} -> {}: {} added, {} removed, {} changed{}",
+                diff.from,
+                diff.to,
+                diff.added.len(),
+                diff.removed.len(),
+                diff.changed.len(),
+                if diff.by_path { " (compared by path)" } else { "" }
+            );
+            for (sign, keys) in [("+", &diff.added), ("-", &diff.removed), ("~", &diff.changed)] {
+                for key in keys {
+                    println!("{} {}", sign, key);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Copies the objects which were added or changed between the rsync revision
+/// for the serial and the current revision to the export dir, see
+/// `rsync::export_changes`.
+pub fn export(config: &Config, since: u64, export_dir: &Path) -> Result<()> {
+    require_rsync(config, "Exporting rsync changes")?;
+    let changes = rsync::export_changes(since, export_dir, config)?;
+    info!(
+        "Exported {} -> {} to {}: {} added, {} removed, {} changed{}",
+        changes.from,
+        changes.to,
+        export_dir.display(),
+        changes.added.len(),
+        changes.removed.len(),
+        changes.changed.len(),
+        if changes.by_path { " (compared by path)" } else { "" }
+    );
     Ok(())
 }
 
+/// Verifies the current rsync dir against the last RRDP snapshot, for the
+/// main source and all modules. Every problem is logged, and an error is
+/// returned if there were any.
+pub fn verify(config: &Config) -> Result<()> {
+    require_rsync(config, "Verifying the rsync dir")?;
+    let mut failed = vec![];
+
+    if config.rrdp_state_path().exists() {
+        if !verify_source(config)? {
+            failed.push(config.rsync_dir.display().to_string());
+        }
+    } else if config.rsync_modules.is_empty() {
+        return Err(anyhow!("There is no RRDP state to verify against"));
+    }
+
+    for module in &config.rsync_modules {
+        if !verify_source(&config.for_module(module)?)? {
+            failed.push(module.name().to_string());
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(exit::invalid_data(format!("Verification failed for: {}", failed.join(", "))))
+    }
+}
+
+fn verify_source(config: &Config) -> Result<bool> {
+    let rrdp_state = RrdpState::recover(&config.rrdp_state_path())?;
+    let report = rsync::verify_current(&rrdp_state, config)?;
+
+    for path in &report.missing {
+        error!("Missing: {}", path.display());
+    }
+    for path in &report.corrupted {
+        error!("Corrupted: {}", path.display());
+    }
+    for path in &report.extra {
+        error!("Unexpected: {}", path.display());
+    }
+    for uri in &report.manifest_mismatches {
+        error!("Does not match the revision manifest: {}", uri);
+    }
+
+    info!(
+        "Verified {} objects in {}: {} missing, {} corrupted, {} unexpected, {} not matching the manifest",
+        report.verified,
+        config.rsync_dir_current().display(),
+        report.missing.len(),
+        report.corrupted.len(),
+        report.extra.len(),
+        report.manifest_mismatches.len()
+    );
+
+    Ok(report.is_ok())
+}
+
 #[cfg(test)]
 mod tests {
 
-    use std::path::Path;
-    use std::time::Duration;
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::sync::Arc;
 
-    use crate::config::create_test_config;
+    use rpki::rrdp::{Delta, Hash, NotificationFile, Snapshot};
+
+    use crate::config::{self, create_test_config};
+    use crate::fetch::FetchSource;
     use crate::util::{https, test_with_dir};
 
     use super::*;
 
+    #[test]
+    fn summarize_timings() {
+        let ms = Duration::from_millis;
+        let rsync = RsyncTimings {
+            write: Some(ms(250)),
+            swap: Some(ms(2)),
+            clean: ms(1),
+        };
+        assert_eq!(
+            timings_summary(ms(120), &rsync, true),
+            "Timings: fetch 120ms, write 250ms, swap 2ms, clean 1ms"
+        );
+
+        let rsync = RsyncTimings {
+            clean: ms(3),
+            ..Default::default()
+        };
+        assert_eq!(
+            timings_summary(ms(5), &rsync, false),
+            "Timings: fetch 5ms, write skipped (no changes), swap skipped (no changes), clean 3ms"
+        );
+    }
+
     #[test]
     fn process_multiple_updates() {
         let rsync_dir_force_moves = false;
@@ -306,6 +850,24 @@ mod tests {
         })
     }
 
+    #[test]
+    fn rsync_preserve_module_name() {
+        test_with_dir("rsync_preserve_module_name", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let source_uri_base = "./test-resources/rrdp-rev2656/";
+
+            let mut config = create_test_config(&dir, notification_uri, source_uri_base, false);
+            config.rsync_preserve_module = true;
+
+            process(&config).unwrap();
+
+            assert_file_dir_exists(&dir, "rsync/current/repo/Acme-Corp-Intl/3/AS53508.roa");
+            assert_file_dir_removed(&dir, "rsync/current/krill-ui-dev.do.nlnetlabs.nl");
+            verify(&config).unwrap();
+        })
+    }
+
     #[test]
     fn preserve_notification_file_name() {
         test_with_dir("preserve_notification_file_name", |dir| {
@@ -347,10 +909,2357 @@ mod tests {
             );
             config.rsync_include_host = true;
 
+            let err = process(&config).unwrap_err();
+            assert!(err.to_string().contains("has no objects"));
+            assert_file_dir_removed(&dir, "rsync/current");
+
+            config.allow_empty = true;
             process(&config).unwrap();
 
             assert_file_dir_exists(&dir, "rsync/current");
             assert_file_dir_exists(&dir, "rrdp/notification.xml");
         })
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn rsync_hardlink_unchanged_objects() {
+        use std::os::unix::fs::MetadataExt;
+
+        test_with_dir("rsync_hardlink_unchanged_objects", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let rsync_dir_force_moves = false;
+
+            let mut config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                rsync_dir_force_moves,
+            );
+            config_2656.rsync_dir_use_hardlinks = true;
+            process(&config_2656).unwrap();
+
+            let mut config_2657 = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2657/",
+                rsync_dir_force_moves,
+            );
+            config_2657.rsync_dir_use_hardlinks = true;
+            process(&config_2657).unwrap();
+
+            let inode = |serial: u64, path: &str| {
+                let revision = format!(
+                    "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_{}",
+                    serial
+                );
+                std::fs::metadata(dir.join(revision).join(path)).unwrap().ino()
+            };
+
+            // unchanged objects are linked, changed objects are written
+            assert_eq!(
+                inode(2656, "Acme-Corp-Intl/3/AS53508.roa"),
+                inode(2657, "Acme-Corp-Intl/3/AS53508.roa")
+            );
+            assert_ne!(
+                inode(2656, "Acme-Corp-Intl/3/AS174.roa"),
+                inode(2657, "Acme-Corp-Intl/3/AS174.roa")
+            );
+        })
+    }
+
+    #[test]
+    fn dry_run_does_not_write_rsync_or_state() {
+        test_with_dir("dry_run_does_not_write_rsync_or_state", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let source_uri_base = "./test-resources/rrdp-rev2656/";
+
+            let mut config = create_test_config(&dir, notification_uri, source_uri_base, false);
+            config.dry_run = true;
+            config.tmp_dir = Some(dir.join("tmp"));
+
+            process(&config).unwrap();
+
+            assert_file_dir_removed(&dir, "rsync");
+            assert_file_dir_removed(&dir, "rrdp");
+            assert_file_dir_removed(&dir, "state/rrdp-state.json");
+            assert_file_dir_removed(&dir, "state/rsync-state.json");
+            assert!(std::fs::read_dir(dir.join("tmp")).map_or(true, |mut d| d.next().is_none()));
+
+            // Also after a real run, a dry run for a later serial does not
+            // change the RRDP files that are served.
+            config.dry_run = false;
+            process(&config).unwrap();
+            let rrdp_files = || {
+                let files = crate::file_ops::files_recursive(&dir.join("rrdp")).unwrap();
+                files
+                    .into_iter()
+                    .map(|path| {
+                        let data = std::fs::read(&path).unwrap();
+                        (path, data)
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let served = rrdp_files();
+
+            let mut config = create_test_config(
+                &dir,
+                config.notification_uri().unwrap().clone(),
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            config.dry_run = true;
+            config.tmp_dir = Some(dir.join("tmp"));
+            process(&config).unwrap();
+            assert_eq!(rrdp_files(), served);
+            assert_file_dir_removed(&dir, "rrdp/e9be21e7-c537-4564-b742-64700978c6b4/2657");
+        })
+    }
+
+    #[test]
+    fn write_metrics() {
+        test_with_dir("write_metrics", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let source_uri_base = "./test-resources/rrdp-rev2656/";
+
+            let mut config = create_test_config(&dir, notification_uri, source_uri_base, false);
+            config.metrics_path = Some(dir.join("metrics.prom"));
+
+            process(&config).unwrap();
+
+            let metrics = std::fs::read_to_string(dir.join("metrics.prom")).unwrap();
+            assert!(metrics.contains(
+                "krill_sync_rrdp_serial{session=\"e9be21e7-c537-4564-b742-64700978c6b4\"} 2656"
+            ));
+            assert!(metrics.contains("krill_sync_objects_written 440"));
+            assert!(metrics.contains("krill_sync_objects_added 440"));
+            assert!(metrics.contains("krill_sync_objects_removed 0"));
+            assert!(metrics.contains("krill_sync_old_revisions_cleaned 0"));
+            assert!(metrics.contains("# TYPE krill_sync_last_success_timestamp gauge"));
+
+            // For a local source, the mtime of the notification file is the
+            // publication time
+            let modified = std::fs::metadata("./test-resources/rrdp-rev2656/notification.xml")
+                .unwrap()
+                .modified()
+                .unwrap();
+            let expected = modified.elapsed().unwrap().as_secs();
+            let lag: u64 = metrics
+                .lines()
+                .find_map(|line| line.strip_prefix("krill_sync_publish_lag_seconds "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert!(lag.abs_diff(expected) <= 5, "{} {}", lag, expected);
+
+            // Nothing is published when nothing changed
+            process(&config).unwrap();
+            let metrics = std::fs::read_to_string(dir.join("metrics.prom")).unwrap();
+            assert!(!metrics.contains("krill_sync_publish_lag_seconds"));
+        })
+    }
+
+    #[test]
+    fn write_json_report() {
+        test_with_dir("write_json_report", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.report_path = Some(dir.join("report.json"));
+            process(&config).unwrap();
+
+            let read_report = || -> serde_json::Value {
+                let json = std::fs::read_to_string(dir.join("report.json")).unwrap();
+                serde_json::from_str(&json).unwrap()
+            };
+
+            let report = read_report();
+            assert_eq!(report["format_version"], 1);
+            assert_eq!(report["session_id"], "e9be21e7-c537-4564-b742-64700978c6b4");
+            assert!(report["old_serial"].is_null());
+            assert_eq!(report["new_serial"], 2656);
+            assert_eq!(report["changed"], true);
+            assert_eq!(report["written_objects"].as_array().unwrap().len(), 440);
+            assert!(report["written_objects"][0]["uri"]
+                .as_str()
+                .unwrap()
+                .starts_with("rsync://"));
+
+            let mut config_2657 = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            config_2657.report_path = config.report_path.clone();
+            process(&config_2657).unwrap();
+
+            let report = read_report();
+            assert_eq!(report["old_serial"], 2656);
+            assert_eq!(report["new_serial"], 2657);
+        })
+    }
+
+    #[test]
+    fn rsync_dir_name_template() {
+        test_with_dir("rsync_dir_name_template", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config_2656.rsync_dir_name_template = "serial-{serial}".to_string();
+            process(&config_2656).unwrap();
+            assert_file_dir_exists(&dir, "rsync/serial-2656");
+
+            // The persisted revision keeps its directory name if the
+            // template is changed afterwards.
+            let config_2657 = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            process(&config_2657).unwrap();
+            assert_file_dir_exists(&dir, "rsync/serial-2656");
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657",
+            );
+
+            let state = std::fs::read_to_string(dir.join("state/rsync-state.json")).unwrap();
+            assert!(state.contains("\"dir_name\": \"serial-2656\""));
+        })
+    }
+
+    #[test]
+    fn force_rewrite_of_current_serial() {
+        test_with_dir("process_force_rewrite_of_current_serial", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let revision = "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656";
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            process(&config).unwrap();
+
+            // Damage the current revision, a normal run does not notice
+            let roa = dir.join(revision).join("Acme-Corp-Intl/3/AS53508.roa");
+            std::fs::remove_file(&roa).unwrap();
+            process(&config).unwrap();
+            assert!(!roa.exists());
+
+            config.force_serial = Some(2657);
+            assert!(process(&config).is_err());
+
+            config.force_serial = Some(2656);
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.to_string_lossy().contains("serial_2656_forced_"));
+
+            // The damaged revision is deprecated, rather than replaced
+            assert_file_dir_exists(&dir, revision);
+            verify(&config).unwrap();
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rebuild_without_linking_damaged_files() {
+        test_with_dir("process_rebuild_without_linking_damaged_files", |dir| {
+            use std::os::unix::fs::MetadataExt;
+
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.rsync_cas_dir = Some(dir.join("cas"));
+            process(&config).unwrap();
+
+            // Damage an object in the store, which is shared by the revision
+            let roa = dir.join("rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            let data = std::fs::read(&roa).unwrap();
+            std::fs::write(&roa, b"damaged").unwrap();
+            assert!(verify(&config).is_err());
+
+            config.force_full_write = true;
+            process(&config).unwrap();
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.to_string_lossy().contains("serial_2656_forced_"));
+            assert_eq!(std::fs::read(&roa).unwrap(), data);
+            assert_eq!(roa.metadata().unwrap().nlink(), 1);
+            verify(&config).unwrap();
+
+            config.daemon = true;
+            let err = config::post_configure(config).err().unwrap();
+            assert!(err.to_string().contains("--rebuild"));
+        })
+    }
+
+    #[test]
+    fn archive_old_revisions() {
+        test_with_dir("process_archive_old_revisions", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.archive_old_revisions = true;
+            config.archive_dir = Some(dir.join("archive"));
+            process(&config).unwrap();
+
+            config.source_uri_base = Some(FetchSource::File("./test-resources/rrdp-rev2657/".into()));
+            let config = config::post_configure(config).unwrap();
+            process(&config).unwrap();
+
+            clean(&config, true).unwrap();
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+            assert_file_dir_exists(
+                &dir,
+                "archive/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656.tar.gz",
+            );
+            assert_file_dir_removed(
+                &dir,
+                "archive/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657.tar.gz",
+            );
+        })
+    }
+
+    #[test]
+    fn clean_old_revisions_on_demand() {
+        test_with_dir("clean_old_revisions_on_demand", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            process(&config_2656).unwrap();
+
+            let config_2657 = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            process(&config_2657).unwrap();
+
+            // Revision 2656 was only just deprecated, so it is kept by a
+            // normal clean, but removed when forced.
+            clean(&config_2657, false).unwrap();
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+
+            clean(&config_2657, true).unwrap();
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657",
+            );
+
+            let state = std::fs::read_to_string(dir.join("state/rsync-state.json")).unwrap();
+            assert!(!state.contains("2656"));
+        })
+    }
+
+    #[test]
+    fn remove_orphaned_rsync_dirs() {
+        test_with_dir("remove_orphaned_rsync_dirs", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.rsync_remove_orphans = true;
+
+            let orphan = "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2600";
+            let unrelated = "rsync/some_other_dir";
+            std::fs::create_dir_all(dir.join(orphan)).unwrap();
+            std::fs::create_dir_all(dir.join(unrelated)).unwrap();
+
+            process(&config).unwrap();
+
+            assert_file_dir_removed(&dir, orphan);
+            assert_file_dir_exists(&dir, unrelated);
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+        })
+    }
+
+    #[test]
+    fn sync_additional_sources_to_modules() {
+        test_with_dir("sync_additional_sources_to_modules", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.metrics_path = Some(dir.join("metrics.prom"));
+            config.rsync_modules = vec![
+                "reset=https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notifyerthingy.xml,./test-resources/rrdp-rev2-session-reset/"
+                    .parse()
+                    .unwrap(),
+            ];
+
+            process(&config).unwrap();
+
+            // The main source is unaffected
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            assert_file_dir_exists(&dir, "state/rsync-state.json");
+            assert_file_dir_exists(&dir, "metrics.prom");
+
+            // The module has its own content, 'current' symlink and state
+            assert_file_dir_exists(&dir, "rsync/reset/current");
+            assert_file_dir_exists(&dir, "rrdp/reset/notifyerthingy.xml");
+            assert_file_dir_exists(&dir, "state/rsync-state-reset.json");
+            assert_file_dir_exists(&dir, "state/rrdp-state-reset.json");
+
+            let metrics = std::fs::read_to_string(dir.join("reset-metrics.prom")).unwrap();
+            assert!(metrics.contains("krill_sync_objects_written{module=\"reset\"}"));
+
+            // The module dir is not mistaken for an orphaned revision
+            config.rsync_remove_orphans = true;
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "rsync/reset/current");
+
+            // A module gets its own dir at a remote target as well, so that
+            // it has its own 'current' symlink there
+            config.rsync_target_url = Some("sftp://rsync.example.net/srv/rsync".parse().unwrap());
+            let module = config.for_module(&config.rsync_modules[0]).unwrap();
+            assert_eq!(
+                module.rsync_target_url.unwrap().to_string(),
+                "sftp://rsync.example.net/srv/rsync/reset"
+            );
+        })
+    }
+
+    #[test]
+    fn reject_invalid_module_source() {
+        use crate::config::ModuleSource;
+
+        assert!("ripe=https://rrdp.ripe.net/notification.xml"
+            .parse::<ModuleSource>()
+            .is_ok());
+        assert!("https://rrdp.ripe.net/notification.xml".parse::<ModuleSource>().is_err());
+        assert!("..=https://rrdp.ripe.net/notification.xml".parse::<ModuleSource>().is_err());
+        assert!("a/b=https://rrdp.ripe.net/notification.xml".parse::<ModuleSource>().is_err());
+        assert!("current=https://rrdp.ripe.net/notification.xml".parse::<ModuleSource>().is_err());
+    }
+
+    #[test]
+    fn refuse_serial_rollback() {
+        test_with_dir("refuse_serial_rollback", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let config_2657 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            process(&config_2657).unwrap();
+
+            let mut config_2656 = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let err = process(&config_2656).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "upstream serial 2656 is lower than last processed 2657 for session e9be21e7-c537-4564-b742-64700978c6b4"
+            );
+
+            // The snapshot and deltas for 2656 are deprecated for 2657, but
+            // must not be cleaned when they are fetched again.
+            config_2656.allow_serial_rollback = true;
+            config_2656.cleanup_after = 0;
+            process(&config_2656).unwrap();
+
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert_eq!(
+                current,
+                Path::new("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656")
+            );
+
+            let xml = std::fs::read(dir.join("rrdp/notification.xml")).unwrap();
+            let notification = NotificationFile::parse(xml.as_slice()).unwrap();
+            assert_eq!(notification.serial(), 2656);
+            let uris = std::iter::once(notification.snapshot().uri())
+                .chain(notification.deltas().iter().map(|delta| delta.uri()));
+            for uri in uris {
+                let rel_path = uri
+                    .as_str()
+                    .strip_prefix("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/")
+                    .unwrap();
+                assert_file_dir_exists(&dir, &format!("rrdp/{}", rel_path));
+            }
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn wait_for_rsync_lock() {
+        test_with_dir("wait_for_rsync_lock", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            let lock_path = dir.join("rsync.lock");
+            config.rsync_lock_path = Some(lock_path.clone());
+
+            // Another tool holds the lock, so we can't make a new revision current
+            let other = crate::file_ops::lock_exclusive(&lock_path).unwrap();
+
+            let sync = std::thread::spawn(move || process(&config));
+            std::thread::sleep(Duration::from_millis(500));
+            assert_file_dir_removed(&dir, "rsync/current");
+
+            drop(other);
+            sync.join().unwrap().unwrap();
+            assert_file_dir_exists(&dir, "rsync/current");
+        })
+    }
+
+    #[test]
+    fn health_reflects_last_success() {
+        test_with_dir("process_health_reflects_last_success", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            assert!(health(&config).is_err());
+
+            process(&config).unwrap();
+            health(&config).unwrap();
+            assert_file_dir_exists(&dir, "state/last-success.json");
+        })
+    }
+
+    #[test]
+    fn daemon_stops_on_shutdown() {
+        test_with_dir("daemon_stops_on_shutdown", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.interval_seconds = 3600;
+
+            let shutdown = Arc::new(AtomicBool::new(false));
+            let daemon_shutdown = shutdown.clone();
+            let started = Instant::now();
+            let daemon = std::thread::spawn(move || {
+                daemon(&config, &daemon_shutdown, &AtomicBool::new(false))
+            });
+
+            // The first run happens immediately
+            while !dir.join("rsync/current").exists() {
+                assert!(started.elapsed() < Duration::from_secs(30));
+                std::thread::sleep(Duration::from_millis(50));
+            }
+
+            shutdown.store(true, Ordering::SeqCst);
+            daemon.join().unwrap().unwrap();
+            assert!(started.elapsed() < Duration::from_secs(60));
+        })
+    }
+
+    #[test]
+    fn write_rsync_revision_to_tmp_dir_first() {
+        test_with_dir("write_rsync_revision_to_tmp_dir_first", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+
+            // Left behind by an interrupted earlier run
+            let revision = "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656";
+            let tmp = format!("{}.tmp", revision);
+            std::fs::create_dir_all(dir.join(&tmp)).unwrap();
+            std::fs::write(dir.join(&tmp).join("stale.roa"), b"stale").unwrap();
+
+            process(&config).unwrap();
+
+            assert_file_dir_removed(&dir, &tmp);
+            assert_file_dir_exists(&dir, revision);
+            assert_file_dir_removed(&dir, &format!("{}/stale.roa", revision));
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn prepare_rsync_dir_on_first_run() {
+        test_with_dir("process_prepare_rsync_dir_on_first_run", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.rsync_dir = dir.join("srv/rsync/repo");
+
+            config.no_create_rsync_dir = true;
+            let err = process(&config).unwrap_err();
+            assert!(format!("{:#}", err).contains("does not exist"), "{:#}", err);
+            assert!(!config.rrdp_state_path().exists());
+
+            std::fs::create_dir_all(dir.join("srv/rsync")).unwrap();
+            std::fs::write(&config.rsync_dir, b"not a dir").unwrap();
+            let err = process(&config).unwrap_err();
+            assert!(format!("{:#}", err).contains("is not a directory"), "{:#}", err);
+
+            std::fs::remove_file(&config.rsync_dir).unwrap();
+            config.no_create_rsync_dir = false;
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "srv/rsync/repo/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn write_rsync_revision_in_tmp_dir() {
+        test_with_dir("write_rsync_revision_in_tmp_dir", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.tmp_dir = Some(dir.join("scratch"));
+
+            let tmp = "scratch/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656.tmp";
+            std::fs::create_dir_all(dir.join(tmp)).unwrap();
+            std::fs::write(dir.join(tmp).join("stale.roa"), b"stale").unwrap();
+
+            process(&config).unwrap();
+            verify(&config).unwrap();
+
+            assert_file_dir_removed(&dir, tmp);
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656.tmp",
+            );
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn rsync_exclude_objects() {
+        test_with_dir("rsync_exclude_objects", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.rsync_exclude = vec!["rsync://*/repo/Acme-Corp-Intl/*".parse().unwrap()];
+            config.report_path = Some(dir.join("report.json"));
+
+            process(&config).unwrap();
+
+            assert_file_dir_removed(&dir, "rsync/current/Acme-Corp-Intl");
+            assert_file_dir_exists(&dir, "rsync/current/ta");
+
+            let json = std::fs::read_to_string(dir.join("report.json")).unwrap();
+            let report: serde_json::Value = serde_json::from_str(&json).unwrap();
+            let written = report["written_objects"].as_array().unwrap();
+            assert!(!written.is_empty() && written.len() < 440);
+            assert!(written
+                .iter()
+                .all(|o| !o["uri"].as_str().unwrap().contains("Acme-Corp-Intl")));
+
+            // The RRDP side is not affected
+            assert_eq!(report["new_serial"], 2656);
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuse_revision_without_free_space() {
+        test_with_dir("process_refuse_revision_without_free_space", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.min_free_bytes = u64::MAX / 2;
+
+            let err = process(&config).unwrap_err();
+            assert!(err.to_string().contains("Not enough free space"));
+            assert_file_dir_removed(&dir, "rsync/current");
+
+            config.min_free_bytes = 0;
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn clean_old_revisions_before_write() {
+        test_with_dir("process_clean_old_revisions_before_write", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            for source_uri_base in
+                ["./test-resources/rrdp-rev2656/", "./test-resources/rrdp-rev2657/"]
+            {
+                let config =
+                    create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                process(&config).unwrap();
+            }
+
+            // Revision 2656 is due for cleanup, but by default it is only
+            // removed after writing the new revision.
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.cleanup_after = 0;
+            config.min_free_bytes = u64::MAX / 2;
+            assert!(process(&config).is_err());
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+
+            config.clean_before_write = true;
+            assert!(process(&config).is_err());
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+            assert_file_dir_exists(&dir, "rsync/current");
+
+            config.min_free_bytes = 0;
+            process(&config).unwrap();
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2658",
+            );
+        })
+    }
+
+    #[test]
+    fn keep_old_revisions_with_no_clean() {
+        test_with_dir("process_keep_old_revisions_with_no_clean", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config_for = |source_uri_base: &str| {
+                let mut config =
+                    create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                config.cleanup_after = 0;
+                config
+            };
+            process(&config_for("./test-resources/rrdp-rev2656/")).unwrap();
+
+            let mut config = config_for("./test-resources/rrdp-rev2657/");
+            config.no_clean = true;
+            process(&config).unwrap();
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+
+            // The state is updated, so the kept revision can be rolled back to
+            rollback(&config, 2656, false).unwrap();
+            rollback(&config, 2657, false).unwrap();
+
+            // Publishing a staged revision keeps them as well
+            let mut config_2658 = config_for("./test-resources/rrdp-rev2658/");
+            config_2658.no_clean = true;
+            stage(&config_2658).unwrap();
+            publish(&config_2658).unwrap();
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.ends_with("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2658"));
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657",
+            );
+
+            config_2658.no_clean = false;
+            process(&config_2658).unwrap();
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+
+            config_2658.no_clean = true;
+            config_2658.clean_before_write = true;
+            let err = config::post_configure(config_2658).err().unwrap();
+            assert!(err.to_string().contains("--no-clean"));
+        })
+    }
+
+    #[test]
+    fn sync_from_file_uri_source() {
+        test_with_dir("process_sync_from_file_uri_source", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+
+            let source_dir = std::env::current_dir()
+                .unwrap()
+                .join("test-resources/rrdp-rev2656/");
+            let source = format!("file://{}", source_dir.display());
+            config.source_uri_base = Some(FetchSource::from_str(&source).unwrap());
+            let config = config::post_configure(config).unwrap();
+
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn rsync_include_prefixes() {
+        test_with_dir("rsync_include_prefixes", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.rsync_include_prefixes =
+                vec!["rsync://krill-ui-dev.do.nlnetlabs.nl/repo/Acme-Corp-Intl/".to_string()];
+            config.rsync_exclude = vec!["rsync://*/repo/Acme-Corp-Intl/5/*".parse().unwrap()];
+
+            process(&config).unwrap();
+
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            assert_file_dir_removed(&dir, "rsync/current/Acme-Corp-Intl/5");
+            assert_file_dir_removed(&dir, "rsync/current/Acme-Corp-Wakanda");
+            assert_file_dir_removed(&dir, "rsync/current/ta");
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rsync_link_objects_from_store() {
+        use std::os::unix::fs::MetadataExt;
+
+        test_with_dir("rsync_link_objects_from_store", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let cas_dir = dir.join("cas");
+
+            let sources = ["./test-resources/rrdp-rev2656/", "./test-resources/rrdp-rev2657/"];
+            for source_uri_base in sources {
+                let mut config =
+                    create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                config.rsync_cas_dir = Some(cas_dir.clone());
+                process(&config).unwrap();
+            }
+
+            let inode = |serial: u64, path: &str| {
+                let revision = format!(
+                    "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_{}",
+                    serial
+                );
+                std::fs::metadata(dir.join(revision).join(path)).unwrap().ino()
+            };
+            assert_eq!(
+                inode(2656, "Acme-Corp-Intl/3/AS53508.roa"),
+                inode(2657, "Acme-Corp-Intl/3/AS53508.roa")
+            );
+            assert_ne!(
+                inode(2656, "Acme-Corp-Intl/3/AS174.roa"),
+                inode(2657, "Acme-Corp-Intl/3/AS174.roa")
+            );
+
+            let store_entries = || {
+                std::fs::read_dir(&cas_dir)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().metadata().unwrap())
+                    .collect::<Vec<_>>()
+            };
+            let before = store_entries().len();
+
+            // Entries only used by the removed revision are garbage collected
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2657/", false);
+            config.rsync_cas_dir = Some(cas_dir.clone());
+            clean(&config, true).unwrap();
+
+            let after = store_entries();
+            assert!(after.len() < before);
+            assert!(after.iter().all(|meta| meta.nlink() >= 2));
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rsync_shard_object_store() {
+        use std::os::unix::fs::MetadataExt;
+
+        test_with_dir("rsync_shard_object_store", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let cas_dir = dir.join("cas");
+            let config_for = |source_uri_base: &str, shard_chars: usize| {
+                let mut config =
+                    create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                config.rsync_cas_dir = Some(cas_dir.clone());
+                config.rsync_cas_shard_chars = shard_chars;
+                config
+            };
+
+            // Entries of a store without shards stay usable for gc
+            process(&config_for("./test-resources/rrdp-rev2656/", 0)).unwrap();
+            let config = config_for("./test-resources/rrdp-rev2657/", 2);
+            process(&config).unwrap();
+            verify(&config).unwrap();
+
+            let shard_dirs: Vec<_> = std::fs::read_dir(&cas_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| path.is_dir())
+                .collect();
+            assert!(!shard_dirs.is_empty());
+            for shard_dir in &shard_dirs {
+                let shard = shard_dir.file_name().unwrap().to_string_lossy().to_string();
+                assert_eq!(shard.len(), 2);
+                for entry in std::fs::read_dir(shard_dir).unwrap() {
+                    assert!(entry.unwrap().file_name().to_string_lossy().starts_with(&shard));
+                }
+            }
+
+            // The rsync paths do not change
+            let roa = dir.join("rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            assert!(roa.metadata().unwrap().nlink() >= 2);
+
+            // Only the 2657 revision is left, which links to sharded entries
+            clean(&config, true).unwrap();
+            let remaining = crate::file_ops::files_recursive(&cas_dir).unwrap();
+            assert!(!remaining.is_empty());
+            assert!(remaining.iter().all(|path| path.parent().unwrap() != cas_dir));
+            assert!(remaining.iter().all(|path| path.metadata().unwrap().nlink() >= 2));
+
+            let mut config = config;
+            config.rsync_cas_shard_chars = 5;
+            let err = config::post_configure(config).err().unwrap();
+            assert!(err.to_string().contains("--rsync-cas-shard-chars"));
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rsync_link_objects_from_base_dir() {
+        use std::os::unix::fs::MetadataExt;
+
+        test_with_dir("rsync_link_objects_from_base_dir", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let base_dir = dir.join("base");
+
+            // The object store of the first run serves as the base
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.rsync_cas_dir = Some(base_dir.clone());
+            process(&config).unwrap();
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2657/", false);
+            config.rsync_base_dir = Some(base_dir.clone());
+            process(&config).unwrap();
+            verify(&config).unwrap();
+
+            let path = |file: &str| {
+                dir.join("rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657")
+                    .join(file)
+            };
+
+            // Unchanged objects are hard links to the base, because rsync
+            // servers do not follow symlinks for clients
+            let unchanged = path("Acme-Corp-Intl/3/AS53508.roa");
+            let meta = unchanged.symlink_metadata().unwrap();
+            assert!(meta.file_type().is_file());
+            let hash = Hash::from_data(&std::fs::read(&unchanged).unwrap());
+            let base_meta = std::fs::metadata(base_dir.join(hash.to_string())).unwrap();
+            assert_eq!(meta.ino(), base_meta.ino());
+
+            let changed = path("Acme-Corp-Intl/3/AS174.roa");
+            assert!(changed.symlink_metadata().unwrap().file_type().is_file());
+
+            // Symlinks only when asked for
+            config.rsync_base_symlinks = true;
+            config.force_serial = Some(2657);
+            process(&config).unwrap();
+            let current = dir.join("rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            assert!(current.symlink_metadata().unwrap().file_type().is_symlink());
+            let target = std::fs::read_link(&current).unwrap();
+            assert!(target.starts_with(base_dir.canonicalize().unwrap()));
+        })
+    }
+
+    #[test]
+    fn refuse_suspicious_shrink() {
+        test_with_dir("refuse_suspicious_shrink", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            process(&config_2656).unwrap();
+
+            let mut config_empty = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-empty-snapshot/",
+                false,
+            );
+            let err = process(&config_empty).unwrap_err();
+            assert!(err.to_string().contains("fewer than the current 440"));
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_bf64ea72-ebb8-462f-99fb-8cd06f418565_serial_1",
+            );
+
+            // Shrinking to nothing also needs --allow-empty, and the current
+            // revision is kept until then.
+            config_empty.accept_shrink = true;
+            let err = process(&config_empty).unwrap_err();
+            assert!(err.to_string().contains("has no objects"));
+            assert_file_dir_exists(&dir, "rsync/current");
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_bf64ea72-ebb8-462f-99fb-8cd06f418565_serial_1",
+            );
+
+            config_empty.allow_empty = true;
+            process(&config_empty).unwrap();
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_bf64ea72-ebb8-462f-99fb-8cd06f418565_serial_1",
+            );
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rsync_file_permissions() {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        test_with_dir("rsync_file_permissions", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            let gid = std::fs::metadata(&dir).unwrap().gid();
+            config.file_permissions = crate::file_ops::FilePermissions {
+                file_mode: Some(0o640),
+                dir_mode: Some(0o750),
+                uid: None,
+                gid: Some(gid),
+            };
+            process(&config).unwrap();
+
+            let mode = |path: &str| {
+                let meta = std::fs::metadata(dir.join(path)).unwrap();
+                assert_eq!(meta.gid(), gid);
+                meta.permissions().mode() & 0o7777
+            };
+            assert_eq!(mode("rsync/current/Acme-Corp-Intl/3/AS53508.roa"), 0o640);
+            assert_eq!(mode("rsync/current/Acme-Corp-Intl/3"), 0o750);
+            assert_eq!(mode("rsync/current"), 0o750);
+        })
+    }
+
+    #[test]
+    fn verify_current_rsync_dir() {
+        test_with_dir("verify_current_rsync_dir", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            process(&config).unwrap();
+            verify(&config).unwrap();
+
+            let current = dir.join("rsync/current");
+            std::fs::remove_file(current.join("Acme-Corp-Intl/3/AS53508.roa")).unwrap();
+            std::fs::write(current.join("Acme-Corp-Intl/3/AS174.roa"), b"corrupt").unwrap();
+            std::fs::write(current.join("extra.roa"), b"extra").unwrap();
+
+            let rrdp_state = RrdpState::recover(&config.rrdp_state_path()).unwrap();
+            let report = rsync::verify_current(&rrdp_state, &config).unwrap();
+            assert_eq!(report.verified, 438);
+            assert_eq!(
+                report.missing,
+                vec![current.join("Acme-Corp-Intl/3/AS53508.roa")]
+            );
+            assert_eq!(
+                report.corrupted,
+                vec![current.join("Acme-Corp-Intl/3/AS174.roa")]
+            );
+            assert_eq!(report.extra, vec![current.join("extra.roa")]);
+
+            assert!(verify(&config).is_err());
+        })
+    }
+
+    #[test]
+    fn verify_against_revision_manifest() {
+        test_with_dir("process_verify_against_revision_manifest", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.rsync_write_manifest = true;
+            process(&config).unwrap();
+
+            // The manifest itself is not an unexpected file
+            let rrdp_state = RrdpState::recover(&config.rrdp_state_path()).unwrap();
+            let report = rsync::verify_current(&rrdp_state, &config).unwrap();
+            assert_eq!(report.verified, 440);
+            assert!(report.is_ok());
+
+            let manifest_path = dir.join("rsync/current/.krill-sync-manifest.json");
+            let manifest: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&manifest_path).unwrap()).unwrap();
+            let objects = manifest["objects"].as_array().unwrap();
+            assert_eq!(objects.len(), 440);
+            let roa = objects
+                .iter()
+                .find(|o| o["path"] == "Acme-Corp-Intl/3/AS53508.roa")
+                .unwrap();
+            assert!(roa["uri"].as_str().unwrap().ends_with("/Acme-Corp-Intl/3/AS53508.roa"));
+            assert_eq!(roa["algorithm"], "sha256");
+
+            // Drop an entry from the manifest, and the algorithm from the
+            // others, like in manifests written by earlier versions
+            let uri = roa["uri"].as_str().unwrap().to_string();
+            let mut remaining: Vec<_> = objects
+                .iter()
+                .filter(|o| o["uri"] != uri.as_str())
+                .cloned()
+                .collect();
+            for object in remaining.iter_mut() {
+                object.as_object_mut().unwrap().remove("algorithm");
+            }
+            let json = serde_json::json!({ "objects": remaining });
+            std::fs::write(&manifest_path, json.to_string()).unwrap();
+
+            let report = rsync::verify_current(&rrdp_state, &config).unwrap();
+            assert_eq!(report.manifest_mismatches, vec![uri]);
+            assert!(verify(&config).is_err());
+
+            // An unknown algorithm is an error, rather than a mismatch
+            remaining[0]["algorithm"] = "sha512".into();
+            let json = serde_json::json!({ "objects": remaining });
+            std::fs::write(&manifest_path, json.to_string()).unwrap();
+            let err = rsync::verify_current(&rrdp_state, &config).unwrap_err();
+            let msg = format!("{:#}", err);
+            assert!(msg.contains("Unknown hash algorithm 'sha512'"), "{}", msg);
+            assert!(msg.contains("supported are: sha256, sha-256"), "{}", msg);
+        })
+    }
+
+    #[test]
+    fn report_progress_of_run() {
+        test_with_dir("process_report_progress_of_run", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.progress = true;
+            config.progress_interval_seconds = 1;
+            let outcome = sync_source(&config).unwrap();
+
+            let event = config.run_progress.event(None, Duration::ZERO);
+            assert_eq!(event.phase, "done");
+            assert_eq!(event.objects_written, outcome.rsync.objects_written as u64);
+            assert_eq!(event.objects_total, Some(event.objects_written));
+            assert_eq!(event.bytes_written, outcome.rsync.bytes_written);
+
+            // A failed run ends in the phase in which it failed
+            config.notification_uri =
+                Some(https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/missing.xml"));
+            assert!(sync_source(&config).is_err());
+            assert_eq!(config.run_progress.event(None, Duration::ZERO).phase, "fetch");
+        })
+    }
+
+    #[test]
+    fn rollback_to_old_revision() {
+        test_with_dir("process_rollback_to_old_revision", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            process(&config).unwrap();
+            let config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2657/", false);
+            process(&config).unwrap();
+
+            let current = || std::fs::read_link(dir.join("rsync/current")).unwrap();
+            let state_path = config.rsync_state_path();
+            let read_state = || -> serde_json::Value {
+                serde_json::from_slice(&std::fs::read(&state_path).unwrap()).unwrap()
+            };
+            assert!(current().to_string_lossy().ends_with("_serial_2657"));
+
+            assert!(rollback(&config, 2657, false).is_err());
+            assert!(rollback(&config, 2655, false).is_err());
+
+            // Pretend that the old revision is for another session
+            let mut state = read_state();
+            let session = state["old"][0]["revision"]["session_id"].clone();
+            state["old"][0]["revision"]["session_id"] =
+                serde_json::json!("00000000-0000-0000-0000-000000000000");
+            std::fs::write(&state_path, state.to_string()).unwrap();
+            let err = rollback(&config, 2656, false).unwrap_err();
+            assert!(err.to_string().contains("--force"), "{}", err);
+
+            state["old"][0]["revision"]["session_id"] = session;
+            std::fs::write(&state_path, state.to_string()).unwrap();
+            rollback(&config, 2656, false).unwrap();
+            assert!(current().to_string_lossy().ends_with("_serial_2656"));
+            let state = read_state();
+            assert_eq!(state["current"]["serial"], 2656);
+            assert_eq!(state["old"].as_array().unwrap().len(), 1);
+            assert_eq!(state["old"][0]["revision"]["serial"], 2657);
+
+            // And forward again
+            rollback(&config, 2657, false).unwrap();
+            assert!(current().to_string_lossy().ends_with("_serial_2657"));
+        })
+    }
+
+    #[test]
+    fn diff_two_revisions() {
+        test_with_dir("process_diff_two_revisions", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            process(&config).unwrap();
+
+            config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2657/", false);
+            config.rsync_write_manifest = true;
+            process(&config).unwrap();
+
+            // The objects of 2656 are no longer in the state, and it has no
+            // manifest, so the files are compared
+            let changes = rsync::diff_revisions(2656, 2657, &config).unwrap();
+            assert!(changes.by_path);
+            assert!(changes.from.ends_with("_serial_2656"));
+            assert!(changes.to.ends_with("_serial_2657"));
+            assert!(!changes.added.is_empty() || !changes.changed.is_empty());
+            assert!(changes.changed.iter().all(|path| !path.starts_with("rsync://")));
+
+            let same = rsync::diff_revisions(2657, 2657, &config).unwrap();
+            assert!(!same.by_path);
+            assert!(same.added.is_empty() && same.removed.is_empty() && same.changed.is_empty());
+
+            assert!(rsync::diff_revisions(2655, 2657, &config).is_err());
+            diff(&config, 2656, 2657, LogFormat::Json).unwrap();
+        })
+    }
+
+    #[test]
+    fn export_changes_since_serial() {
+        test_with_dir("process_export_changes_since_serial", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            // Without manifests the objects of 2656 are unknown, so the
+            // files are compared by path.
+            for (name, write_manifest) in [("by_uri", true), ("by_path", false)] {
+                let base = dir.join(name);
+                let config_for = |source_uri_base: &str| {
+                    let mut config =
+                        create_test_config(&base, notification_uri.clone(), source_uri_base, false);
+                    config.rsync_write_manifest = write_manifest;
+                    config
+                };
+                process(&config_for("./test-resources/rrdp-rev2656/")).unwrap();
+                let config = config_for("./test-resources/rrdp-rev2658/");
+                process(&config).unwrap();
+
+                let export_dir = base.join("export");
+                export(&config, 2656, &export_dir).unwrap();
+
+                let changes: serde_json::Value = serde_json::from_slice(
+                    &std::fs::read(export_dir.join(".krill-sync-changes.json")).unwrap(),
+                )
+                .unwrap();
+                assert_eq!(changes["by_path"], !write_manifest);
+                assert_eq!(changes["added"].as_array().unwrap().len(), 1);
+                assert_eq!(changes["removed"].as_array().unwrap().len(), 0);
+                assert_eq!(changes["changed"].as_array().unwrap().len(), 5);
+
+                // Only the added and changed objects, with their current content
+                let files = crate::file_ops::files_recursive(&export_dir).unwrap();
+                assert_eq!(files.len(), 7);
+                for file in files.iter().filter(|f| !f.ends_with(".krill-sync-changes.json")) {
+                    let rel = file.strip_prefix(&export_dir).unwrap();
+                    let current = base.join("rsync/current").join(rel);
+                    assert_eq!(std::fs::read(file).unwrap(), std::fs::read(current).unwrap());
+                }
+
+                // An export never mixes with an earlier one
+                let err = export(&config, 2656, &export_dir).unwrap_err();
+                assert!(err.to_string().contains("is not empty"));
+            }
+        })
+    }
+
+    #[test]
+    fn write_rsync_index_for_revision() {
+        test_with_dir("process_write_rsync_index_for_revision", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let sources = ["./test-resources/rrdp-rev2656/", "./test-resources/rrdp-rev2657/"];
+            for source_uri_base in sources {
+                let mut config =
+                    create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                config.write_rsync_index = true;
+                process(&config).unwrap();
+                verify(&config).unwrap();
+            }
+
+            let index =
+                std::fs::read_to_string(dir.join("rsync/current/.krill-sync-index.xml")).unwrap();
+            assert!(index.starts_with(
+                "<index version=\"1\" session_id=\"e9be21e7-c537-4564-b742-64700978c6b4\" serial=\"2657\">"
+            ));
+            assert_eq!(index.matches("<object ").count(), 440);
+
+            let roa = std::fs::read(dir.join("rsync/current/Acme-Corp-Intl/3/AS53508.roa")).unwrap();
+            assert!(index.contains(&format!(
+                "<object uri=\"rsync://krill-ui-dev.do.nlnetlabs.nl/repo/Acme-Corp-Intl/3/AS53508.roa\" path=\"Acme-Corp-Intl/3/AS53508.roa\" size=\"{}\" hash=\"{}\"/>",
+                roa.len(),
+                Hash::from_data(&roa)
+            )));
+        })
+    }
+
+    #[test]
+    fn preserve_empty_dirs_in_revision() {
+        test_with_dir("process_preserve_empty_dirs_in_revision", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let sources = ["./test-resources/rrdp-rev2656/", "./test-resources/rrdp-rev2657/"];
+            for (stream, source_uri_base) in [(false, sources[0]), (true, sources[1])] {
+                let mut config =
+                    create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                config.rsync_stream_objects = stream;
+                config.preserve_empty_dirs =
+                    vec![PathBuf::from("Acme-Corp-Intl/99"), PathBuf::from("empty/ca")];
+                process(&config).unwrap();
+                verify(&config).unwrap();
+
+                assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/99");
+                assert_file_dir_exists(&dir, "rsync/current/empty/ca");
+                assert!(std::fs::read_dir(dir.join("rsync/current/empty/ca"))
+                    .unwrap()
+                    .next()
+                    .is_none());
+            }
+
+            let mut config = create_test_config(&dir, notification_uri, sources[1], false);
+            config.preserve_empty_dirs = vec![PathBuf::from("../outside")];
+            assert!(config::post_configure(config).is_err());
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symlink_current_with_absolute_path() {
+        test_with_dir("process_symlink_current_with_absolute_path", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let sources = ["./test-resources/rrdp-rev2656/", "./test-resources/rrdp-rev2657/"];
+            for (serial, source_uri_base) in [(2656, sources[0]), (2657, sources[1])] {
+                let mut config =
+                    create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                config.symlink_absolute = true;
+                process(&config).unwrap();
+                verify(&config).unwrap();
+
+                let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+                assert!(current.is_absolute());
+                let expected = dir
+                    .join(format!(
+                        "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_{}",
+                        serial
+                    ))
+                    .canonicalize()
+                    .unwrap();
+                assert_eq!(current, expected);
+                assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            }
+
+            // A next revision is linked with a relative path by default
+            let config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2658/", false);
+            process(&config).unwrap();
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert_eq!(
+                current,
+                Path::new("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2658")
+            );
+        })
+    }
+
+    #[test]
+    fn rewrite_uris_in_manifest_and_report() {
+        test_with_dir("process_rewrite_uris_in_manifest_and_report", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            config.rsync_write_manifest = true;
+            config.report_path = Some(dir.join("report.json"));
+            config.rsync_uri_rewrites = vec![
+                "rsync://krill-ui-dev.do.nlnetlabs.nl/repo/=rsync://mirror.example.net/rpki/"
+                    .parse()
+                    .unwrap(),
+            ];
+            process(&config).unwrap();
+
+            let read_json = |path: &str| -> serde_json::Value {
+                serde_json::from_slice(&std::fs::read(dir.join(path)).unwrap()).unwrap()
+            };
+            let roa_uri = "rsync://mirror.example.net/rpki/Acme-Corp-Intl/3/AS53508.roa";
+
+            let manifest = read_json("rsync/current/.krill-sync-manifest.json");
+            let roa = manifest["objects"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .find(|o| o["path"] == "Acme-Corp-Intl/3/AS53508.roa")
+                .unwrap();
+            assert_eq!(roa["uri"], roa_uri);
+
+            let report = read_json("report.json");
+            let written = report["written_objects"].as_array().unwrap();
+            assert!(written.iter().any(|o| o["uri"] == roa_uri));
+            assert!(written
+                .iter()
+                .all(|o| o["uri"].as_str().unwrap().starts_with("rsync://mirror.example.net/rpki/")));
+
+            // The files keep their paths, and verify compares rewritten URIs
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            verify(&config).unwrap();
+        })
+    }
+
+    #[test]
+    fn apply_deltas_instead_of_downloading_snapshot() {
+        use rpki::rrdp::{Delta, DeltaInfo, Hash, NotificationFile, Snapshot, SnapshotInfo};
+        use std::io::BufReader;
+
+        fn write_source_file(dir: &Path, rel: &str, bytes: &[u8]) -> (rpki::uri::Https, Hash) {
+            let path = dir.join(rel);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, bytes).unwrap();
+            let uri = https(&format!("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/{}", rel));
+            (uri, Hash::from_data(bytes))
+        }
+
+        fn write_notification(dir: &Path, notification: NotificationFile) {
+            let mut bytes = vec![];
+            notification.write_xml(&mut bytes).unwrap();
+            std::fs::write(dir.join("notification.xml"), bytes).unwrap();
+        }
+
+        test_with_dir("process_apply_deltas", |dir| {
+            let fixtures = Path::new("./test-resources/rrdp-rev2657/e9be21e7-c537-4564-b742-64700978c6b4");
+            let source = dir.join("source");
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            // Use snapshots as they would be written by this code, so that
+            // the snapshot built from the delta is identical.
+            let open = |rel: &str| BufReader::new(std::fs::File::open(fixtures.join(rel)).unwrap());
+            let snapshot_2656 = Snapshot::parse(open("2656/snapshot.xml")).unwrap();
+            let delta_2657 = Delta::parse(open("2657/rnd-d/delta.xml")).unwrap();
+            let session_id = snapshot_2656.session_id();
+
+            let mut bytes = vec![];
+            snapshot_2656.write_xml(&mut bytes).unwrap();
+            let (uri, hash) = write_source_file(&source, "2656/snapshot.xml", &bytes);
+            write_notification(
+                &source,
+                NotificationFile::new(session_id, 2656, SnapshotInfo::new(uri, hash), vec![]),
+            );
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                &format!("{}/", source.display()),
+                false,
+            );
+            process(&config).unwrap();
+
+            // The 2657 snapshot is not present at the source, so this can
+            // only succeed by applying the delta.
+            let mut bytes = vec![];
+            delta_2657.write_xml(&mut bytes).unwrap();
+            let (delta_uri, delta_hash) = write_source_file(&source, "2657/delta.xml", &bytes);
+
+            let snapshot_2657 =
+                crate::rrdp::apply_deltas_to_snapshot(snapshot_2656, vec![delta_2657], 2657).unwrap();
+            let mut bytes = vec![];
+            snapshot_2657.write_xml(&mut bytes).unwrap();
+            let snapshot_hash = Hash::from_data(&bytes);
+            let snapshot_uri = https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/2657/snapshot.xml");
+
+            write_notification(
+                &source,
+                NotificationFile::new(
+                    session_id,
+                    2657,
+                    SnapshotInfo::new(snapshot_uri, snapshot_hash),
+                    vec![DeltaInfo::new(2657, delta_uri, delta_hash)],
+                ),
+            );
+
+            // Deltas are only applied when asked for.
+            assert!(process(&config).is_err());
+            assert!(!config.rrdp_dir.join("2657/snapshot.xml").exists());
+
+            config.rrdp_apply_deltas = true;
+            process(&config).unwrap();
+
+            let written = std::fs::read(config.rrdp_dir.join("2657/snapshot.xml")).unwrap();
+            assert_eq!(Hash::from_data(&written), snapshot_hash);
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn rsync_mtime_from_rrdp() {
+        test_with_dir("process_rsync_mtime_from_rrdp", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.rsync_mtime_from_rrdp = true;
+            process(&config).unwrap();
+
+            // For a local source, the mtime of the notification file is used
+            let mtime = |path: &Path| {
+                let modified = std::fs::metadata(path).unwrap().modified().unwrap();
+                modified.duration_since(std::time::UNIX_EPOCH).unwrap().as_secs()
+            };
+            let expected = mtime(Path::new("./test-resources/rrdp-rev2656/notification.xml"));
+            let current = dir.join("rsync/current");
+            for path in crate::file_ops::files_recursive(&current).unwrap() {
+                assert_eq!(mtime(&path), expected, "{}", path.display());
+            }
+            for path in crate::file_ops::dirs_depth_first(&current).unwrap() {
+                assert_eq!(mtime(&path), expected, "{}", path.display());
+            }
+        })
+    }
+
+    #[test]
+    fn mirror_rrdp_only() {
+        test_with_dir("process_mirror_rrdp_only", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.rsync_disable = true;
+
+            let outcome = sync_source(&config).unwrap();
+            assert!(outcome.changed);
+            assert_file_dir_exists(&dir, "rrdp/notification.xml");
+            assert_file_dir_exists(&dir, "rrdp/e9be21e7-c537-4564-b742-64700978c6b4/2656/snapshot.xml");
+            assert!(config.rrdp_state_path().exists());
+            assert_file_dir_removed(&dir, "rsync");
+
+            // There are no rsync revisions to work on
+            assert!(verify(&config).is_err());
+            assert!(stage(&config).is_err());
+            assert!(clean(&config, true).is_err());
+            assert_file_dir_removed(&dir, "rsync");
+        })
+    }
+
+    #[test]
+    fn download_snapshot_if_deltas_are_too_large() {
+        test_with_dir("process_download_snapshot_if_deltas_are_too_large", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let mut config_2657 =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2657/", false);
+            config_2657.max_delta_total_bytes = Some(1);
+            let session = "e9be21e7-c537-4564-b742-64700978c6b4";
+            let notification_path = config_2656.rrdp_dir.join("notification.xml");
+
+            process(&config_2656).unwrap();
+            let notification = std::fs::read_to_string(&notification_path).unwrap();
+            assert!(notification.contains("<delta "));
+
+            // The delta for 2657 is not included, and the deltas we have
+            // no longer lead up to the new serial.
+            process(&config_2657).unwrap();
+            let notification = std::fs::read_to_string(&notification_path).unwrap();
+            assert!(notification.contains("serial=\"2657\""));
+            assert!(!notification.contains("<delta "));
+            assert_file_dir_exists(&dir, &format!("rrdp/{}/2657/rnd-sn/snapshot.xml", session));
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn run_returns_sync_outcome() {
+        test_with_dir("process_run_returns_sync_outcome", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+
+            let outcomes = crate::run(&config).unwrap();
+            assert_eq!(outcomes.len(), 1);
+            let outcome = &outcomes[0];
+            assert!(outcome.changed);
+            assert_eq!(outcome.module, None);
+            assert_eq!(outcome.serial, Some(2656));
+            assert_eq!(
+                outcome.session_id.unwrap().to_string(),
+                "e9be21e7-c537-4564-b742-64700978c6b4"
+            );
+            assert_eq!(outcome.rsync.objects_written, 440);
+
+            let outcomes = crate::run(&config).unwrap();
+            assert!(!outcomes[0].changed);
+            assert_eq!(outcomes[0].serial, Some(2656));
+            assert_eq!(outcomes[0].rsync.objects_written, 0);
+
+            // Additional sources are synchronized to their modules as well.
+            config.rsync_modules = vec![
+                "reset=https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notifyerthingy.xml,./test-resources/rrdp-rev2-session-reset/"
+                    .parse()
+                    .unwrap(),
+            ];
+            let outcomes = crate::run(&config).unwrap();
+            assert_eq!(outcomes.len(), 2);
+            assert_eq!(outcomes[0].module, None);
+            assert!(!outcomes[0].changed);
+            assert_eq!(outcomes[1].module.as_deref(), Some("reset"));
+            assert!(outcomes[1].changed);
+            assert_file_dir_exists(&dir, "rsync/reset/current");
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_post_sync_command_with_env() {
+        test_with_dir("process_run_post_sync_command_with_env", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+
+            let out = dir.join("hook.txt");
+            config.post_sync_command = Some(format!(
+                "echo \"$KRILL_SYNC_SESSION $KRILL_SYNC_SERIAL $KRILL_SYNC_CHANGED\" >> {}",
+                out.display()
+            ));
+
+            sync_source(&config).unwrap();
+            assert_eq!(
+                std::fs::read_to_string(&out).unwrap(),
+                "e9be21e7-c537-4564-b742-64700978c6b4 2656 true\n"
+            );
+
+            // Nothing changed, so the hook is not run, unless asked for
+            sync_source(&config).unwrap();
+            config.post_sync_on_no_change = true;
+            sync_source(&config).unwrap();
+            assert_eq!(
+                std::fs::read_to_string(&out).unwrap(),
+                "e9be21e7-c537-4564-b742-64700978c6b4 2656 true\n\
+                 e9be21e7-c537-4564-b742-64700978c6b4 2656 false\n"
+            );
+
+            // A failing hook does not fail the run
+            config.post_sync_command = Some("exit 1".to_string());
+            sync_source(&config).unwrap();
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rsync_swap_modes() {
+        use crate::config::RsyncSwap;
+
+        test_with_dir("process_rsync_swap_modes", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let current = dir.join("rsync/current");
+            let is_symlink = |path: &Path| path.symlink_metadata().unwrap().file_type().is_symlink();
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.rsync_swap = RsyncSwap::Auto;
+            process(&config).unwrap();
+            assert!(is_symlink(&current));
+
+            let state = std::fs::read_to_string(config.rsync_state_path()).unwrap();
+            assert!(state.contains("\"symlinks_supported\": true"));
+
+            // Switching to renaming replaces the symlink with a directory
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            config.rsync_swap = RsyncSwap::Rename;
+            process(&config).unwrap();
+            assert!(!is_symlink(&current));
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656/Acme-Corp-Intl/3/AS53508.roa",
+            );
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+
+            // And switching back renames that directory back first
+            let config = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            process(&config).unwrap();
+            assert!(is_symlink(&current));
+            assert_file_dir_exists(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657/Acme-Corp-Intl/3/AS53508.roa",
+            );
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn rsync_flat_layout() {
+        test_with_dir("process_rsync_flat_layout", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.rsync_flat_layout = true;
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "rsync/Acme-Corp-Intl/3/AS53508.roa");
+            assert_file_dir_removed(&dir, "rsync/current");
+            assert_file_dir_removed(
+                &dir,
+                "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            );
+
+            // Simulate a swap which was interrupted after the previous content
+            // was moved aside, it is put back before the next update.
+            std::fs::rename(dir.join("rsync"), dir.join("rsync.old")).unwrap();
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.rsync_flat_layout = true;
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "rsync/Acme-Corp-Intl/3/AS53508.roa");
+            assert_file_dir_removed(&dir, "rsync.old");
+            assert_file_dir_removed(&dir, "rsync.new");
+            assert_file_dir_removed(&dir, "rsync.tmp");
+
+            verify(&config).unwrap();
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rsync_flat_layout_symlink() {
+        test_with_dir("process_rsync_flat_layout_symlink", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.rsync_flat_layout = true;
+            process(&config).unwrap();
+
+            // Where the dirs cannot be exchanged, the rsync dir is a symlink
+            // to one of two dirs, which take turns.
+            std::fs::rename(dir.join("rsync"), dir.join("rsync.a")).unwrap();
+            std::os::unix::fs::symlink("rsync.a", dir.join("rsync")).unwrap();
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.rsync_flat_layout = true;
+            process(&config).unwrap();
+            assert_eq!(std::fs::read_link(dir.join("rsync")).unwrap(), Path::new("rsync.b"));
+            assert_file_dir_exists(&dir, "rsync/Acme-Corp-Intl/3/AS53508.roa");
+            assert_file_dir_removed(&dir, "rsync.a");
+            assert_file_dir_removed(&dir, "rsync.new");
+            assert_file_dir_removed(&dir, "rsync.symlink.tmp");
+
+            verify(&config).unwrap();
+        })
+    }
+
+    #[test]
+    fn skip_revision_with_identical_content() {
+        test_with_dir("process_skip_revision_with_identical_content", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2656/", false);
+            let report_path = dir.join("report.json");
+            config.report_path = Some(report_path.clone());
+
+            process(&config).unwrap();
+            let report: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+            let digest = report["digest"].as_str().unwrap().to_string();
+            assert_eq!(report["written_objects"].as_array().unwrap().len(), 440);
+
+            // Without RRDP state the snapshot is seen as a change, but the
+            // rsync content stays the same.
+            std::fs::remove_file(config.rrdp_state_path()).unwrap();
+            process(&config).unwrap();
+            let report: serde_json::Value =
+                serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap();
+            assert_eq!(report["changed"], true);
+            assert_eq!(report["digest"].as_str(), Some(digest.as_str()));
+            assert!(report["written_objects"].as_array().unwrap().is_empty());
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn stream_objects_to_rsync_dir() {
+        test_with_dir("process_stream_objects_to_rsync_dir", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            // Streaming results in the same revisions as writing all objects
+            // after reading them.
+            let sources = ["./test-resources/rrdp-rev2656/", "./test-resources/rrdp-rev2657/"];
+            let mut digests = vec![];
+            for stream in [false, true] {
+                let dir = dir.join(if stream { "streamed" } else { "buffered" });
+                for source_uri_base in sources {
+                    let mut config =
+                        create_test_config(&dir, notification_uri.clone(), source_uri_base, false);
+                    config.rsync_stream_objects = stream;
+                    config.rsync_write_manifest = true;
+                    config.report_path = Some(dir.join("report.json"));
+                    process(&config).unwrap();
+                    verify(&config).unwrap();
+                }
+
+                let report: serde_json::Value =
+                    serde_json::from_slice(&std::fs::read(dir.join("report.json")).unwrap())
+                        .unwrap();
+                assert_eq!(report["written_objects"].as_array().unwrap().len(), 440);
+                digests.push((
+                    report["digest"].as_str().unwrap().to_string(),
+                    std::fs::read(dir.join("rsync/current/.krill-sync-manifest.json")).unwrap(),
+                ));
+            }
+            assert_eq!(digests[0], digests[1]);
+        })
+    }
+
+    #[test]
+    fn count_object_changes() {
+        test_with_dir("process_count_object_changes", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let report_path = dir.join("report.json");
+            let read_report = || -> serde_json::Value {
+                serde_json::from_slice(&std::fs::read(&report_path).unwrap()).unwrap()
+            };
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.report_path = Some(report_path.clone());
+            process(&config).unwrap();
+            let report = read_report();
+            assert_eq!(report["object_changes"]["added"], 440);
+            assert_eq!(report["object_changes"]["removed"], 0);
+            assert_eq!(report["object_changes"]["changed"], 0);
+
+            // The objects are kept next to, rather than in, the state
+            let state = std::fs::read_to_string(dir.join("state/rsync-state.json")).unwrap();
+            assert!(!state.contains("objects"));
+            assert_file_dir_exists(&dir, "state/rsync-objects.json");
+
+            let mut config =
+                create_test_config(&dir, notification_uri, "./test-resources/rrdp-rev2658/", false);
+            config.report_path = Some(report_path.clone());
+            process(&config).unwrap();
+            let report = read_report();
+            assert_eq!(report["object_changes"]["added"], 1);
+            assert_eq!(report["object_changes"]["removed"], 0);
+            // The deltas for 2657 and 2658 updated 3 and 2 objects
+            assert_eq!(report["object_changes"]["changed"], 5);
+        })
+    }
+
+    fn copy_dir_all(from: &Path, to: &Path) {
+        std::fs::create_dir_all(to).unwrap();
+        for entry in std::fs::read_dir(from).unwrap() {
+            let entry = entry.unwrap();
+            let target = to.join(entry.file_name());
+            if entry.file_type().unwrap().is_dir() {
+                copy_dir_all(&entry.path(), &target);
+            } else {
+                std::fs::copy(entry.path(), &target).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn validate_objects_before_writing() {
+        test_with_dir("process_validate_objects_before_writing", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+
+            // A source with a ROA that is not a ROA
+            let source = dir.join("source");
+            copy_dir_all(Path::new("test-resources/rrdp-rev2656"), &source);
+            let snapshot_path =
+                source.join("e9be21e7-c537-4564-b742-64700978c6b4/2656/snapshot.xml");
+            let snapshot = std::fs::read_to_string(&snapshot_path).unwrap();
+            let uri = "rsync://krill-ui-dev.do.nlnetlabs.nl/repo/Acme-Corp-Intl/3/AS53508.roa";
+            let start = snapshot.find(&format!("<publish uri=\"{}\">", uri)).unwrap();
+            let end = start + snapshot[start..].find("</publish>").unwrap();
+            let bad_snapshot = format!(
+                "{}<publish uri=\"{}\">bm90IGEgcm9h{}",
+                &snapshot[..start],
+                uri,
+                &snapshot[end..]
+            );
+            std::fs::write(&snapshot_path, &bad_snapshot).unwrap();
+            let notification_path = source.join("notification.xml");
+            let notification = std::fs::read_to_string(&notification_path).unwrap().replace(
+                &Hash::from_data(snapshot.as_bytes()).to_string(),
+                &Hash::from_data(bad_snapshot.as_bytes()).to_string(),
+            );
+            std::fs::write(&notification_path, notification).unwrap();
+
+            let config_for = |name: &str, action: config::InvalidObjectAction, stream: bool| {
+                let mut config = create_test_config(
+                    &dir.join(name),
+                    notification_uri.clone(),
+                    source.to_str().unwrap(),
+                    false,
+                );
+                config.validate_objects = true;
+                config.invalid_objects = action;
+                config.rsync_stream_objects = stream;
+                config.report_path = Some(dir.join(name).join("report.json"));
+                config
+            };
+
+            let config = config_for("abort", config::InvalidObjectAction::Abort, false);
+            let err = process(&config).unwrap_err();
+            assert!(err.to_string().contains("1 malformed RPKI objects"));
+            assert_file_dir_removed(&dir, "abort/rsync/current");
+
+            for (name, stream) in [("skip", false), ("skip-streamed", true)] {
+                let config = config_for(name, config::InvalidObjectAction::Skip, stream);
+                process(&config).unwrap();
+                let report = std::fs::read(dir.join(name).join("report.json")).unwrap();
+                let report: serde_json::Value = serde_json::from_slice(&report).unwrap();
+                assert_eq!(report["invalid_objects"], serde_json::json!([uri]));
+                assert_eq!(report["written_objects"].as_array().unwrap().len(), 439);
+                let current = format!("{}/rsync/current/Acme-Corp-Intl/3", name);
+                assert_file_dir_removed(&dir, &format!("{}/AS53508.roa", current));
+                assert_file_dir_exists(&dir, &format!("{}/AS174.roa", current));
+            }
+
+            let config = config_for("keep", config::InvalidObjectAction::Keep, false);
+            process(&config).unwrap();
+            assert_file_dir_exists(&dir, "keep/rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+        })
+    }
+
+    #[test]
+    fn resume_deltas_from_checkpoint() {
+        test_with_dir("process_resume_deltas_from_checkpoint", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            process(&config_2656).unwrap();
+
+            // A source for serial 2658 with a delta for 2658 which does not
+            // apply, and without the snapshot to fall back to.
+            let source = dir.join("source");
+            copy_dir_all(Path::new("test-resources/rrdp-rev2658"), &source);
+            let session_dir = source.join("e9be21e7-c537-4564-b742-64700978c6b4");
+            let snapshot_path = session_dir.join("2658/rnd-sn/snapshot.xml");
+            let snapshot_hash = Hash::from_data(&std::fs::read(&snapshot_path).unwrap());
+            std::fs::remove_file(&snapshot_path).unwrap();
+
+            let delta_path = session_dir.join("2658/rnd-d/delta.xml");
+            let delta = std::fs::read_to_string(&delta_path).unwrap();
+            let bad_delta = delta.replacen("hash=\"e980a775", "hash=\"0980a775", 1);
+            std::fs::write(&delta_path, &bad_delta).unwrap();
+
+            // The snapshot that we build from deltas is only used if it is
+            // identical to the one in the notification file, but the XML in
+            // the test resources is formatted differently.
+            let parse_snapshot = |path: &Path| {
+                let file = std::fs::File::open(path).unwrap();
+                Snapshot::parse(std::io::BufReader::new(file)).unwrap()
+            };
+            let parse_delta = |path: &Path| {
+                let file = std::fs::File::open(path).unwrap();
+                Delta::parse(std::io::BufReader::new(file)).unwrap()
+            };
+            let expected = rrdp::apply_deltas_to_snapshot(
+                parse_snapshot(Path::new(
+                    "test-resources/rrdp-rev2656/e9be21e7-c537-4564-b742-64700978c6b4/2656/snapshot.xml",
+                )),
+                vec![
+                    parse_delta(&session_dir.join("2657/rnd-d/delta.xml")),
+                    parse_delta(Path::new(
+                        "test-resources/rrdp-rev2658/e9be21e7-c537-4564-b742-64700978c6b4/2658/rnd-d/delta.xml",
+                    )),
+                ],
+                2658,
+            )
+            .unwrap();
+            let mut expected_xml = vec![];
+            expected.write_xml(&mut expected_xml).unwrap();
+
+            let notification_path = source.join("notification.xml");
+            let notification = std::fs::read_to_string(&notification_path)
+                .unwrap()
+                .replace(
+                    &snapshot_hash.to_string(),
+                    &Hash::from_data(&expected_xml).to_string(),
+                );
+            let delta_hash = Hash::from_data(delta.as_bytes()).to_string();
+            let bad_hash = Hash::from_data(bad_delta.as_bytes()).to_string();
+            std::fs::write(&notification_path, notification.replace(&delta_hash, &bad_hash))
+                .unwrap();
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                source.to_str().unwrap(),
+                false,
+            );
+            config.rrdp_apply_deltas = true;
+            assert!(process(&config).is_err());
+
+            let checkpoint_path = config.rrdp_checkpoint_path();
+            let checkpoint = std::fs::File::open(&checkpoint_path).unwrap();
+            let checkpoint = Snapshot::parse(std::io::BufReader::new(checkpoint)).unwrap();
+            assert_eq!(checkpoint.serial(), 2657);
+
+            // With the delta fixed we can resume from the checkpoint, so we
+            // need neither the snapshot for 2658, nor our snapshot for 2656.
+            std::fs::write(&delta_path, &delta).unwrap();
+            std::fs::write(&notification_path, &notification).unwrap();
+            std::fs::remove_file(
+                dir.join("rrdp/e9be21e7-c537-4564-b742-64700978c6b4/2656/snapshot.xml"),
+            )
+            .unwrap();
+
+            process(&config).unwrap();
+            assert!(!checkpoint_path.exists());
+            verify(&config).unwrap();
+        })
+    }
+
+    #[test]
+    fn stage_then_publish() {
+        test_with_dir("process_stage_then_publish", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let config_2657 = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            let notification_path = config_2656.rrdp_dir.join("notification.xml");
+            let staged_2656 = "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656";
+            let staged_2657 = "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657";
+
+            // Staging writes the revision, but publishes nothing
+            stage(&config_2656).unwrap();
+            assert_file_dir_exists(&dir, staged_2656);
+            assert_file_dir_removed(&dir, "rsync/current");
+            assert!(!notification_path.exists());
+
+            publish(&config_2656).unwrap();
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+            let notification = std::fs::read_to_string(&notification_path).unwrap();
+            assert!(notification.contains("serial=\"2656\""));
+
+            // Publishing again is a no-op
+            publish(&config_2656).unwrap();
+
+            stage(&config_2657).unwrap();
+            assert_file_dir_exists(&dir, staged_2657);
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.ends_with("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656"));
+            let notification = std::fs::read_to_string(&notification_path).unwrap();
+            assert!(notification.contains("serial=\"2656\""));
+
+            // A normal run does not publish what was staged, it is refused
+            // and leaves the staged revision untouched
+            let rsync_state = std::fs::read_to_string(config_2657.rsync_state_path()).unwrap();
+            let err = process(&config_2657).unwrap_err();
+            assert!(format!("{:#}", err).contains("is staged, use 'krill-sync publish'"), "{:#}", err);
+            assert_eq!(std::fs::read_to_string(config_2657.rsync_state_path()).unwrap(), rsync_state);
+            assert_file_dir_exists(&dir, staged_2657);
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.ends_with("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656"));
+            let notification = std::fs::read_to_string(&notification_path).unwrap();
+            assert!(notification.contains("serial=\"2656\""));
+
+            publish(&config_2657).unwrap();
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.ends_with("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657"));
+            let notification = std::fs::read_to_string(&notification_path).unwrap();
+            assert!(notification.contains("serial=\"2657\""));
+
+            // After publishing, normal runs work again
+            process(&config_2657).unwrap();
+        })
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn pre_publish_command_can_veto() {
+        test_with_dir("process_pre_publish_command_can_veto", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let mut config_2656 = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let mut config_2657 = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            let staged_2657 = "rsync/session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657";
+
+            config_2656.pre_publish_command = Some(
+                "test \"$KRILL_SYNC_SERIAL\" = 2656 && test -f \"$KRILL_SYNC_REVISION_DIR/Acme-Corp-Intl/3/AS53508.roa\""
+                    .to_string(),
+            );
+            process(&config_2656).unwrap();
+            assert_file_dir_exists(&dir, "rsync/current/Acme-Corp-Intl/3/AS53508.roa");
+
+            // A veto leaves current untouched, and keeps the new revision
+            config_2657.pre_publish_command = Some("exit 1".to_string());
+            assert!(process(&config_2657).is_err());
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.ends_with("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656"));
+            assert_file_dir_exists(&dir, staged_2657);
+
+            config_2657.pre_publish_command = None;
+            process(&config_2657).unwrap();
+            let current = std::fs::read_link(dir.join("rsync/current")).unwrap();
+            assert!(current.ends_with("session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657"));
+        })
+    }
+
+    #[test]
+    fn validate_rrdp_source() {
+        test_with_dir("process_validate_rrdp_source", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let fetcher = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2657/",
+                false,
+            )
+            .fetcher()
+            .unwrap();
+
+            assert_eq!(rrdp::validate(&fetcher).unwrap(), Vec::<String>::new());
+            assert_file_dir_removed(&dir, "rrdp");
+            assert_file_dir_removed(&dir, "rsync");
+
+            // A delta which is missing at the source is a problem
+            let source = dir.join("source");
+            for file in ["notification.xml", "e9be21e7-c537-4564-b742-64700978c6b4/2657/rnd-sn/snapshot.xml"] {
+                let target = source.join(file);
+                std::fs::create_dir_all(target.parent().unwrap()).unwrap();
+                std::fs::copy(Path::new("./test-resources/rrdp-rev2657/").join(file), target).unwrap();
+            }
+            let config = create_test_config(
+                &dir,
+                notification_uri,
+                &format!("{}/", source.display()),
+                false,
+            );
+
+            let problems = rrdp::validate(&config.fetcher().unwrap()).unwrap();
+            assert_eq!(problems.len(), 5);
+            assert!(problems[0].starts_with("Delta https://krill-ui-dev.do.nlnetlabs.nl/rrdp/e9be21e7-c537-4564-b742-64700978c6b4/2653/delta.xml is unusable"));
+            assert!(validate(&config).is_err());
+        })
+    }
+
+    #[test]
+    fn log_written_objects() {
+        test_with_dir("process_log_written_objects", |dir| {
+            let notification_uri =
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml");
+            let log_path = dir.join("objects.log");
+
+            let mut config = create_test_config(
+                &dir,
+                notification_uri.clone(),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.object_log_path = Some(log_path.clone());
+            process(&config).unwrap();
+
+            let log = std::fs::read_to_string(&log_path).unwrap();
+            let lines: Vec<_> = log.lines().collect();
+            assert_eq!(lines.len(), 441);
+            assert!(lines[0].starts_with(
+                "# session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656 "
+            ));
+            assert!(lines[1..]
+                .iter()
+                .all(|line| line.starts_with("rsync://") && !line.contains(' ')));
+
+            // The next revision is appended, with the size and hash of objects
+            let mut config = create_test_config(
+                &dir,
+                notification_uri,
+                "./test-resources/rrdp-rev2657/",
+                false,
+            );
+            config.object_log_path = Some(log_path.clone());
+            config.log_object_hashes = true;
+            process(&config).unwrap();
+
+            let log = std::fs::read_to_string(&log_path).unwrap();
+            let lines: Vec<_> = log.lines().skip(441).collect();
+            assert!(lines[0].starts_with(
+                "# session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2657 "
+            ));
+            for line in &lines[1..] {
+                let fields: Vec<_> = line.split(' ').collect();
+                assert_eq!(fields.len(), 3, "{}", line);
+                assert!(fields[1].parse::<u64>().is_ok());
+                assert_eq!(fields[2].len(), 64);
+            }
+        })
+    }
 }
@@ -0,0 +1,173 @@
+//! Live progress of synchronization runs, see `Config::progress`. The
+//! counters are updated while a run is in progress, and a `ProgressReporter`
+//! writes them to stderr as JSON lines, one event per interval.
+
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Condvar, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+
+//------------ Progress ------------------------------------------------------
+
+/// The progress of the current run. This is shared by all clones of a
+/// config, and reset when a run starts.
+#[derive(Debug, Default)]
+pub struct Progress {
+    phase: Mutex<&'static str>,
+    objects_written: AtomicU64,
+    objects_total: AtomicU64,
+    bytes_written: AtomicU64,
+}
+
+impl Progress {
+    /// Resets the counters for a new run, which starts with the given phase.
+    pub fn start_run(&self, phase: &'static str) {
+        self.set_phase(phase);
+        self.objects_written.store(0, Ordering::Relaxed);
+        self.objects_total.store(0, Ordering::Relaxed);
+        self.bytes_written.store(0, Ordering::Relaxed);
+    }
+
+    pub fn set_phase(&self, phase: &'static str) {
+        *self.phase.lock().unwrap() = phase;
+    }
+
+    /// Sets the number of objects that will be written, if known.
+    pub fn set_objects_total(&self, total: usize) {
+        self.objects_total.store(total as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_object(&self, bytes: u64) {
+        self.objects_written.fetch_add(1, Ordering::Relaxed);
+        self.bytes_written.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn event(&self, module: Option<&str>, elapsed: Duration) -> ProgressEvent {
+        let objects_total = self.objects_total.load(Ordering::Relaxed);
+        ProgressEvent {
+            module: module.map(str::to_string),
+            phase: *self.phase.lock().unwrap(),
+            objects_written: self.objects_written.load(Ordering::Relaxed),
+            objects_total: (objects_total > 0).then_some(objects_total),
+            bytes_written: self.bytes_written.load(Ordering::Relaxed),
+            elapsed_ms: elapsed.as_millis() as u64,
+        }
+    }
+}
+
+//------------ ProgressEvent -------------------------------------------------
+
+/// A snapshot of the progress, as written on each line.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct ProgressEvent {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub module: Option<String>,
+    pub phase: &'static str,
+    pub objects_written: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub objects_total: Option<u64>,
+    pub bytes_written: u64,
+    pub elapsed_ms: u64,
+}
+
+//------------ ProgressReporter ----------------------------------------------
+
+/// Writes the progress to stderr every interval from a thread, until it is
+/// dropped, which writes a last event. Its phase is 'done' if `finish` was
+/// called, or else the phase in which the run failed.
+pub struct ProgressReporter {
+    progress: Arc<Progress>,
+    module: Option<String>,
+    started: Instant,
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProgressReporter {
+    pub fn start(progress: Arc<Progress>, module: Option<String>, interval: Duration) -> Self {
+        let started = Instant::now();
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+
+        let thread = {
+            let progress = progress.clone();
+            let module = module.clone();
+            let stop = stop.clone();
+            std::thread::spawn(move || {
+                let (stopped, wakeup) = &*stop;
+                let mut stopped = stopped.lock().unwrap();
+                loop {
+                    stopped = wakeup.wait_timeout(stopped, interval).unwrap().0;
+                    if *stopped {
+                        break;
+                    }
+                    emit(&progress.event(module.as_deref(), started.elapsed()));
+                }
+            })
+        };
+
+        ProgressReporter {
+            progress,
+            module,
+            started,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn finish(self) {
+        self.progress.set_phase("done");
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        let (stopped, wakeup) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        wakeup.notify_all();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        emit(&self.progress.event(self.module.as_deref(), self.started.elapsed()));
+    }
+}
+
+fn emit(event: &ProgressEvent) {
+    if let Ok(line) = serde_json::to_string(event) {
+        eprintln!("{}", line);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn count_progress_of_run() {
+        let progress = Progress::default();
+        progress.start_run("fetch");
+        progress.set_phase("write");
+        progress.set_objects_total(3);
+        progress.add_object(100);
+        progress.add_object(20);
+
+        let event = progress.event(Some("ta"), Duration::from_millis(1500));
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"module":"ta","phase":"write","objects_written":2,"objects_total":3,"bytes_written":120,"elapsed_ms":1500}"#
+        );
+
+        progress.start_run("fetch");
+        let event = progress.event(None, Duration::ZERO);
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"phase":"fetch","objects_written":0,"bytes_written":0,"elapsed_ms":0}"#
+        );
+    }
+}
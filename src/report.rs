@@ -0,0 +1,94 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    config, file_ops,
+    rsync::{ObjectChanges, RsyncUpdateSummary, WrittenObject},
+    util,
+};
+
+/// The version of the report format. Increase this whenever fields are
+/// changed or removed, so that consumers can tell formats apart. Adding
+/// fields is considered compatible.
+pub const REPORT_FORMAT_VERSION: u32 = 1;
+
+/// A machine-readable report of a single run, written as JSON.
+#[derive(Clone, Debug, Serialize)]
+pub struct RunReport<'a> {
+    format_version: u32,
+
+    #[serde(serialize_with = "util::ser_uuid")]
+    session_id: Uuid,
+
+    /// The serial of the previous current rsync revision, if any.
+    old_serial: Option<u64>,
+    new_serial: u64,
+    changed: bool,
+
+    /// The digest of the content of the current rsync revision, if known.
+    digest: Option<&'a str>,
+
+    /// The objects added, removed and changed in a new rsync revision, if
+    /// known.
+    object_changes: Option<ObjectChanges>,
+    written_objects: &'a [WrittenObject],
+
+    /// The URIs of malformed objects, if objects were validated.
+    invalid_objects: &'a [String],
+    removed_revision_dirs: &'a [PathBuf],
+
+    /// The seconds between the RRDP publication of the serial and making
+    /// its rsync revision current, if that happened in this run.
+    publish_lag_seconds: Option<u64>,
+}
+
+impl<'a> RunReport<'a> {
+    pub fn new(
+        session_id: Uuid,
+        old_serial: Option<u64>,
+        new_serial: u64,
+        changed: bool,
+        summary: &'a RsyncUpdateSummary,
+    ) -> Self {
+        RunReport {
+            format_version: REPORT_FORMAT_VERSION,
+            session_id,
+            old_serial,
+            new_serial,
+            changed,
+            digest: summary.digest.as_deref(),
+            object_changes: summary.object_changes,
+            written_objects: &summary.written_objects,
+            invalid_objects: &summary.invalid_objects,
+            removed_revision_dirs: &summary.removed_revision_dirs,
+            publish_lag_seconds: summary.publish_lag_seconds,
+        }
+    }
+
+    /// Writes the report to the given path, or to stdout if the path is
+    /// "-". A file is first written to a temporary file and then renamed,
+    /// so that readers never see a partial report.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self)?;
+
+        if path == Path::new("-") {
+            let mut stdout = std::io::stdout();
+            writeln!(stdout, "{}", json).with_context(|| "Could not write report to stdout")
+        } else {
+            let tmp_path = file_ops::path_with_extension(path, config::TMP_FILE_EXT);
+
+            file_ops::write_buf(&tmp_path, json.as_bytes())
+                .with_context(|| format!("Could not write report to: {}", tmp_path.display()))?;
+
+            std::fs::rename(&tmp_path, path).with_context(|| {
+                format!("Could not rename {} to {}", tmp_path.display(), path.display())
+            })
+        }
+    }
+}
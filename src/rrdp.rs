@@ -1,23 +1,29 @@
 use std::{
-    collections::VecDeque,
+    collections::{HashMap, HashSet, VecDeque},
     fs,
+    io::BufReader,
     path::{Path, PathBuf},
 };
 
 use anyhow::{anyhow, Context, Result};
-use log::{debug, info};
 use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
 use uuid::Uuid;
 
 use rpki::{
-    rrdp::{self, DeltaInfo, Hash, NotificationFile, SnapshotInfo},
+    rrdp::{
+        self, Delta, DeltaElement, DeltaInfo, Hash, NotificationFile, ObjectReader, ProcessDelta,
+        PublishElement, Snapshot, SnapshotInfo, UpdateElement, WithdrawElement,
+    },
     uri::Https,
 };
 
 use crate::{
-    config::Config,
-    fetch::{Fetcher, NotificationFileResponse},
+    config::{self, Config},
+    exit,
+    fetch::{CacheValidators, Fetcher, NotificationFileResponse},
     file_ops,
+    rsync::{self, ObjectData},
     util::{self, Time},
 };
 
@@ -44,7 +50,7 @@ impl RrdpState {
     pub fn create(config: &Config) -> Result<Self> {
         info!("No prior state found, will build up state from latest snapshot at source");
 
-        let notification_uri = &config.notification_uri;
+        let notification_uri = config.notification_uri()?;
         let rrdp_dir = &config.rrdp_dir;
 
         let notification_source = NotificationSource::build(notification_uri.clone())?;
@@ -87,11 +93,41 @@ impl RrdpState {
 
     /// Try to update this state using the notification file found in the specified fetcher.
     ///
+    /// A notification file with a lower serial than our current snapshot for
+    /// the same session is refused, unless `allow_serial_rollback` is set. In
+    /// that case the rollback is handled like a session reset.
+    ///
+    /// If a `checkpoint_path` is given, the session is unchanged and the
+    /// notification file includes all deltas since our current serial, then
+    /// the new snapshot is built by applying these deltas to our current
+    /// snapshot, rather than downloading it. The snapshot is still downloaded
+    /// if this fails, or if the result does not match the hash in the
+    /// notification file, or if more than `limit` deltas would be needed.
+    /// The hash can only match if the publisher writes its snapshot XML in
+    /// exactly the way we do, so it is up to the caller to opt in.
+    ///
+    /// If the new deltas together have more than `max_delta_total_bytes`,
+    /// then the remaining deltas are not downloaded and the snapshot is
+    /// downloaded instead. All deltas are deprecated then, because those
+    /// which we have no longer lead up to the new serial.
+    ///
+    /// If applying the deltas fails midway, then the snapshot as of the last
+    /// delta which did apply is kept at `checkpoint_path`, so that a later run
+    /// can resume from there if downloading the snapshot fails as well.
+    ///
     /// Returns:
     ///   Ok(true)  if there was an update
     ///   Ok(false) if there was no update (serial and session match current)
     ///   Err       if there was an error trying to update
-    pub fn update(&mut self, limit: Option<usize>, fetcher: &Fetcher) -> Result<bool> {
+    pub fn update(
+        &mut self,
+        limit: Option<usize>,
+        allow_serial_rollback: bool,
+        max_object_bytes: u64,
+        max_delta_total_bytes: Option<u64>,
+        fetcher: &Fetcher,
+        checkpoint_path: Option<&Path>,
+    ) -> Result<bool> {
         match self.notification_source.fetch(fetcher)? {
             None => {
                 debug!("Notification file was not changed, no updated needed.");
@@ -99,9 +135,11 @@ impl RrdpState {
             }
             Some(mut notification) => {
                 if !notification.sort_and_verify_deltas(limit) {
-                    return Err(anyhow!("Notification file contained gaps in deltas"));
+                    return Err(exit::invalid_data("Notification file contained gaps in deltas"));
                 }
 
+                let mut apply_deltas = false;
+
                 let delta_cut_off = if let Some(snapshot) = &self.snapshot {
                     if snapshot.session_id() != notification.session_id() {
                         // session reset:
@@ -111,7 +149,9 @@ impl RrdpState {
                         match snapshot.serial().cmp(&notification.serial()) {
                             std::cmp::Ordering::Less => {
                                 // session unchanged, and there is an update:
+                                // - try to apply the deltas to the current snapshot
                                 // - set delta cut off to deltas no longer in notification
+                                apply_deltas = true;
                                 notification
                                     .deltas()
                                     .first() // deltas are ordered from low to high
@@ -124,12 +164,25 @@ impl RrdpState {
                                 return Ok(false);
                             }
                             std::cmp::Ordering::Greater => {
-                                // This is a problem..
-                                return Err(anyhow!(format!(
-                                    "The notification file serial '{}' is *before* our serial '{}'. Server session reset recommended.",
+                                // This is a problem.. could be a replay, or
+                                // the upstream was restored from a backup.
+                                if !allow_serial_rollback {
+                                    return Err(anyhow!(
+                                        "upstream serial {} is lower than last processed {} for session {}",
+                                        notification.serial(),
+                                        snapshot.serial(),
+                                        snapshot.session_id()
+                                    ));
+                                }
+                                warn!(
+                                    "upstream serial {} is lower than last processed {} for session {}, accepting rollback",
                                     notification.serial(),
-                                    snapshot.serial()
-                                )));
+                                    snapshot.serial(),
+                                    snapshot.session_id()
+                                );
+                                // handle as a session reset, so that all our
+                                // deltas will be deprecated
+                                snapshot.serial() + 1
                             }
                         }
                     }
@@ -141,8 +194,38 @@ impl RrdpState {
                     notification.serial()
                 };
 
-                self.snapshot_update(&notification, fetcher)?;
-                self.deltas_update(&notification, fetcher, delta_cut_off, limit)?;
+                // Download the deltas first, so that we can try to apply them
+                // to our current snapshot.
+                let deltas_complete = self.deltas_update(
+                    &notification,
+                    fetcher,
+                    delta_cut_off,
+                    limit,
+                    max_delta_total_bytes,
+                )?;
+                if !deltas_complete {
+                    self.deltas_deprecate_before(notification.serial() + 1);
+                }
+                let from_deltas = match checkpoint_path {
+                    Some(checkpoint_path) if apply_deltas && deltas_complete => self
+                        .snapshot_from_deltas(
+                            &notification,
+                            limit,
+                            max_object_bytes,
+                            checkpoint_path,
+                        )?,
+                    _ => false,
+                };
+                if !from_deltas {
+                    self.snapshot_update(&notification, fetcher)?;
+                    if let Some(checkpoint_path) = checkpoint_path {
+                        remove_checkpoint(checkpoint_path)?;
+                    }
+                }
+
+                // After a rollback, the snapshot and deltas were fetched
+                // again to paths which we had just deprecated.
+                self.keep_referenced_files();
 
                 Ok(true)
             }
@@ -173,6 +256,18 @@ impl RrdpState {
         Ok(())
     }
 
+    /// Removes the current snapshot and deltas from the deprecated files, so
+    /// that `clean` does not remove files which the notification file refers
+    /// to.
+    fn keep_referenced_files(&mut self) {
+        let referenced: HashSet<PathBuf> = self
+            .snapshot_path()
+            .into_iter()
+            .chain(self.deltas.iter().map(|delta| self.mappings.path(delta.rel_path())))
+            .collect();
+        self.deprecated_files.retain(|deprecated| !referenced.contains(&deprecated.path));
+    }
+
     /// Persist the RRDP state to disk (as json)
     pub fn persist(&self, path: &Path) -> Result<()> {
         let json = serde_json::to_string_pretty(&self)?;
@@ -267,6 +362,199 @@ impl RrdpState {
         Ok(())
     }
 
+    /// Builds the new snapshot by applying the deltas in the notification file
+    /// since our current serial to our current snapshot. The deltas must have
+    /// been downloaded already.
+    ///
+    /// Returns Ok(false) if the snapshot should be downloaded instead, i.e. if
+    /// deltas are missing, there are more than `limit` deltas, the deltas do
+    /// not apply or contain objects larger than `max_object_bytes`, or the
+    /// resulting snapshot is not identical to the snapshot in the
+    /// notification file.
+    fn snapshot_from_deltas(
+        &mut self,
+        notification: &NotificationFile,
+        limit: Option<usize>,
+        max_object_bytes: u64,
+        checkpoint_path: &Path,
+    ) -> Result<bool> {
+        let (current_path, current_serial) = match (self.snapshot_path(), &self.snapshot) {
+            (Some(path), Some(snapshot)) if snapshot.session_id() == notification.session_id() => {
+                (path, snapshot.serial())
+            }
+            _ => return Ok(false),
+        };
+
+        let gap = notification.serial().saturating_sub(current_serial);
+        if let Some(limit) = limit {
+            if gap > limit as u64 {
+                debug!(
+                    "{} deltas since serial {} exceeds limit of {}, will download snapshot",
+                    gap, current_serial, limit
+                );
+                return Ok(false);
+            }
+        }
+
+        // The notification deltas are sorted and verified to be contiguous,
+        // so if there are as many as the gap, then none are missing.
+        let deltas: Vec<&DeltaInfo> = notification
+            .deltas()
+            .iter()
+            .filter(|delta| delta.serial() > current_serial)
+            .collect();
+        if deltas.len() as u64 != gap {
+            debug!(
+                "Notification file does not include all deltas since serial {}, will download snapshot",
+                current_serial
+            );
+            return Ok(false);
+        }
+
+        let applied = self.apply_deltas(
+            &current_path,
+            current_serial,
+            &deltas,
+            notification,
+            max_object_bytes,
+            checkpoint_path,
+        );
+        let bytes = match applied {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                warn!("Could not apply deltas to current snapshot, will download snapshot: {}", e);
+                return Ok(false);
+            }
+        };
+
+        let snapshot_info = notification.snapshot();
+        if Hash::from_data(&bytes) != snapshot_info.hash() {
+            info!("Snapshot built from deltas does not match the notification file, will download snapshot");
+            // A checkpoint that we resumed from may well be the cause.
+            remove_checkpoint(checkpoint_path)?;
+            return Ok(false);
+        }
+        remove_checkpoint(checkpoint_path)?;
+
+        let target = self.mappings.path_for_uri(snapshot_info.uri())?;
+        file_ops::write_buf(&target, &bytes)
+            .with_context(|| format!("Could not write snapshot to: {}", target.display()))?;
+
+        info!(
+            "Built snapshot for serial {} from {} deltas since serial {}",
+            notification.serial(),
+            gap,
+            current_serial
+        );
+
+        self.deprecated_files.push(DeprecatedFile::new(current_path));
+
+        let rel_path = self.mappings.relative(snapshot_info.uri())?;
+        self.snapshot = Some(SnapshotState::create(
+            notification.session_id(),
+            notification.serial(),
+            snapshot_info.hash(),
+            rel_path,
+        ));
+
+        Ok(true)
+    }
+
+    /// Applies the given deltas, in order, to the snapshot at the given path
+    /// and returns the XML of the resulting snapshot. Starts from the
+    /// checkpoint instead, if there is a usable one, see `read_checkpoint`.
+    ///
+    /// The deltas are applied one by one to the same `SnapshotElements`, and
+    /// the snapshot is only serialized at the end. If one fails, then the
+    /// snapshot as of the previous delta is written to the checkpoint path,
+    /// unless not a single delta could be applied.
+    fn apply_deltas(
+        &self,
+        snapshot_path: &Path,
+        snapshot_serial: u64,
+        deltas: &[&DeltaInfo],
+        notification: &NotificationFile,
+        max_object_bytes: u64,
+        checkpoint_path: &Path,
+    ) -> Result<Vec<u8>> {
+        let (snapshot, start_serial) =
+            match read_checkpoint(checkpoint_path, notification, snapshot_serial)? {
+                Some(checkpoint) => {
+                    let serial = checkpoint.serial();
+                    info!("Resuming from checkpoint of deltas applied up to serial {}", serial);
+                    (checkpoint, serial)
+                }
+                None => {
+                    let snapshot = Snapshot::parse(BufReader::new(fs::File::open(snapshot_path)?))
+                        .with_context(|| {
+                            format!("Cannot parse snapshot at: {}", snapshot_path.display())
+                        })?;
+                    (snapshot, snapshot_serial)
+                }
+            };
+
+        let mut elements = SnapshotElements::new(snapshot);
+        for delta in deltas.iter().filter(|delta| delta.serial() > start_serial) {
+            let applied = self
+                .parse_delta(delta, notification, max_object_bytes)
+                .and_then(|parsed| elements.apply(parsed));
+
+            if let Err(e) = applied {
+                if elements.serial > start_serial {
+                    write_checkpoint(checkpoint_path, &elements.to_snapshot())?;
+                }
+                return Err(e);
+            }
+        }
+
+        let mut bytes = vec![];
+        elements.into_snapshot().write_xml(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Parses the downloaded delta, and checks that it is for the session and
+    /// serial in the notification file.
+    fn parse_delta(
+        &self,
+        delta: &DeltaInfo,
+        notification: &NotificationFile,
+        max_object_bytes: u64,
+    ) -> Result<Delta> {
+        let path = self.mappings.path_for_uri(delta.uri())?;
+        let parsed = DeltaElements::parse(BufReader::new(fs::File::open(&path)?), max_object_bytes)
+            .with_context(|| format!("Cannot parse delta at: {}", path.display()))?;
+
+        if parsed.session_id() != notification.session_id() || parsed.serial() != delta.serial() {
+            return Err(exit::invalid_data(format!(
+                "Delta at {} does not match session {} and serial {}",
+                path.display(),
+                notification.session_id(),
+                delta.serial()
+            )));
+        }
+        Ok(parsed)
+    }
+
+    /// Returns a copy of this state which downloads to the given dir, rather
+    /// than to the RRDP dir, so that a dry run does not change what is
+    /// served. The current snapshot is copied there, so that deltas can
+    /// still be applied to it.
+    pub fn with_rrdp_dir(&self, rrdp_dir: &Path) -> Result<Self> {
+        let mut state = self.clone();
+        state.mappings.rrdp_dir = rrdp_dir.to_path_buf();
+
+        if let (Some(from), Some(to)) = (self.snapshot_path(), state.snapshot_path()) {
+            if from.is_file() {
+                file_ops::create_parent_dir(&to)?;
+                fs::copy(&from, &to).with_context(|| {
+                    format!("Could not copy {} to {}", from.display(), to.display())
+                })?;
+            }
+        }
+
+        Ok(state)
+    }
+
     pub fn snapshot_path(&self) -> Option<PathBuf> {
         self.snapshot
             .as_ref()
@@ -277,45 +565,71 @@ impl RrdpState {
         self.snapshot.as_ref()
     }
 
+    /// Returns the Last-Modified time of the notification file that we last
+    /// fetched, as a unix timestamp, if it had a valid one.
+    pub fn notification_last_modified(&self) -> Option<i64> {
+        let last_modified = self.notification_source.last_modified.as_deref()?;
+        chrono::DateTime::parse_from_rfc2822(last_modified).ok().map(|time| time.timestamp())
+    }
+
+    /// Returns when the RRDP source published the serial of the current
+    /// snapshot: the Last-Modified time of the notification file if it had
+    /// one, or else the time at which we got the snapshot for the serial.
+    pub fn publication_time(&self) -> Option<Time> {
+        self.notification_last_modified()
+            .map(Time::from_timestamp)
+            .or_else(|| self.snapshot.as_ref().map(SnapshotState::since))
+    }
+
     /// Update deltas:
     /// - deprecate old deltas
     /// - download new deltas
     /// - check limit and deprecate any surplus deltas
+    ///
+    /// Returns Ok(false), without adding any new deltas, if these have more
+    /// than `max_total_bytes` together.
     fn deltas_update(
         &mut self,
         notification: &NotificationFile,
         fetcher: &Fetcher,
         delta_cut_off: u64,
         limit: Option<usize>,
-    ) -> Result<()> {
+        max_total_bytes: Option<u64>,
+    ) -> Result<bool> {
         // First deprecate existing deltas. Note that 'delta_cut_off' here
         // may (will) be higher than the current notification serial if
         // a session reset occurred.
         self.deltas_deprecate_before(delta_cut_off);
 
         // Download new deltas, even if they would be deprecated again
-        // because of a configured limit.
-        for delta in notification.deltas() {
-            let serial = delta.serial();
-
-            let delta_is_new = self
-                .deltas
-                .front()
-                .map(|highest| highest.serial() < serial)
-                .unwrap_or(true);
-
-            if delta_is_new {
-                let uri = delta.uri();
-                let hash = delta.hash();
-
-                let target = self.mappings.path_for_uri(uri)?;
-                fetcher.retrieve_file(uri, hash, &target)?;
-
-                let rel_path = self.mappings.relative(uri)?;
+        // because of a configured limit. They may be downloaded concurrently,
+        // but are only added to our state, in order, once all are in.
+        let highest = self.deltas.front().map(|highest| highest.serial());
+        let new_deltas: Vec<&DeltaInfo> = notification
+            .deltas()
+            .iter()
+            .filter(|delta| highest.map(|highest| highest < delta.serial()).unwrap_or(true))
+            .collect();
 
-                self.deltas
-                    .push_front(DeltaState::create(serial, hash, rel_path));
+        let mut files = vec![];
+        for delta in &new_deltas {
+            files.push((delta.uri(), delta.hash(), self.mappings.path_for_uri(delta.uri())?));
+        }
+        if !fetcher.retrieve_files(&files, max_total_bytes)? {
+            info!(
+                "New deltas exceed --max-delta-total-bytes {}, will download snapshot",
+                max_total_bytes.unwrap_or_default()
+            );
+            for (_, _, path) in files.into_iter().filter(|(_, _, path)| path.exists()) {
+                self.deprecated_files.push(DeprecatedFile::new(path));
             }
+            return Ok(false);
+        }
+
+        for delta in new_deltas {
+            let rel_path = self.mappings.relative(delta.uri())?;
+            self.deltas
+                .push_front(DeltaState::create(delta.serial(), delta.hash(), rel_path));
         }
 
         // Now deprecate everything that is over the limit, we do this only
@@ -330,7 +644,7 @@ impl RrdpState {
             }
         }
 
-        Ok(())
+        Ok(true)
     }
 
     /// Remove deltas before the given serial and put them on the deprecated file list.
@@ -352,12 +666,410 @@ impl RrdpState {
     }
 }
 
+/// Collects the elements of a delta like `Delta::parse`, but refuses objects
+/// larger than `max_bytes`, see `rsync::read_object_data`.
+struct DeltaElements {
+    max_bytes: u64,
+    meta: Option<(Uuid, u64)>,
+    elements: Vec<DeltaElement>,
+}
+
+impl DeltaElements {
+    fn parse(reader: impl std::io::BufRead, max_bytes: u64) -> Result<Delta> {
+        let mut builder = DeltaElements {
+            max_bytes,
+            meta: None,
+            elements: vec![],
+        };
+        builder.process(reader)?;
+        let (session_id, serial) =
+            builder.meta.ok_or_else(|| exit::invalid_data("Delta has no session and serial"))?;
+        Ok(Delta::new(session_id, serial, builder.elements))
+    }
+}
+
+impl ProcessDelta for DeltaElements {
+    type Err = anyhow::Error;
+
+    fn meta(&mut self, session_id: Uuid, serial: u64) -> Result<()> {
+        self.meta = Some((session_id, serial));
+        Ok(())
+    }
+
+    fn publish(
+        &mut self,
+        uri: rpki::uri::Rsync,
+        hash: Option<Hash>,
+        data: &mut ObjectReader,
+    ) -> Result<()> {
+        let data = match rsync::read_object_data(data, self.max_bytes)? {
+            ObjectData::Read(data) => data,
+            ObjectData::Oversized(size) => {
+                return Err(exit::invalid_data(format!(
+                    "Object {} has {} bytes, more than --max-object-bytes {}",
+                    uri, size, self.max_bytes
+                )))
+            }
+        };
+        self.elements.push(match hash {
+            Some(hash) => DeltaElement::Update(UpdateElement::new(uri, hash, data)),
+            None => DeltaElement::Publish(PublishElement::new(uri, data)),
+        });
+        Ok(())
+    }
+
+    fn withdraw(&mut self, uri: rpki::uri::Rsync, hash: Hash) -> Result<()> {
+        self.elements.push(DeltaElement::Withdraw(WithdrawElement::new(uri, hash)));
+        Ok(())
+    }
+}
+
+/// Applies the deltas to the snapshot and returns a snapshot for the given
+/// serial, see `SnapshotElements::apply`.
+pub fn apply_deltas_to_snapshot(
+    snapshot: Snapshot,
+    deltas: Vec<Delta>,
+    serial: u64,
+) -> Result<Snapshot> {
+    let mut elements = SnapshotElements::new(snapshot);
+    for delta in deltas {
+        elements.apply(delta)?;
+    }
+    elements.serial = serial;
+    Ok(elements.into_snapshot())
+}
+
+//------------ SnapshotElements ----------------------------------------------
+
+/// The elements of a snapshot, with the position of each URI, so that deltas
+/// can be applied one after the other without rebuilding either.
+struct SnapshotElements {
+    session_id: Uuid,
+    serial: u64,
+    elements: Vec<Option<PublishElement>>,
+    positions: HashMap<String, usize>,
+}
+
+/// How to undo a change of `SnapshotElements::apply`.
+enum Undo {
+    Published(String),
+    Updated(usize, Option<PublishElement>),
+    Withdrawn(usize, String, Option<PublishElement>),
+}
+
+impl SnapshotElements {
+    fn new(snapshot: Snapshot) -> Self {
+        let session_id = snapshot.session_id();
+        let serial = snapshot.serial();
+        let elements: Vec<Option<PublishElement>> =
+            snapshot.into_elements().into_iter().map(Some).collect();
+        let positions = elements
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, el)| el.as_ref().map(|el| (el.uri().to_string(), idx)))
+            .collect();
+
+        SnapshotElements {
+            session_id,
+            serial,
+            elements,
+            positions,
+        }
+    }
+
+    /// Applies the delta, and takes its serial. Updated objects keep their
+    /// position, new objects are added at the end. Returns an error if an
+    /// update or withdraw does not match the hash of the current object, or
+    /// if a publish is for an existing object. The delta is then not applied
+    /// at all, i.e. the elements are as they were before.
+    fn apply(&mut self, delta: Delta) -> Result<()> {
+        let serial = delta.serial();
+        let mut undo = vec![];
+        for element in delta.into_elements() {
+            if let Err(e) = self.apply_element(element, &mut undo) {
+                self.undo(undo);
+                return Err(e);
+            }
+        }
+        self.serial = serial;
+        Ok(())
+    }
+
+    fn apply_element(&mut self, element: DeltaElement, undo: &mut Vec<Undo>) -> Result<()> {
+        match element {
+            DeltaElement::Publish(publish) => {
+                let uri = publish.uri().to_string();
+                if self.positions.contains_key(&uri) {
+                    return Err(anyhow!("Delta publishes existing object: {}", uri));
+                }
+                self.positions.insert(uri.clone(), self.elements.len());
+                self.elements.push(Some(publish));
+                undo.push(Undo::Published(uri));
+            }
+            DeltaElement::Update(update) => {
+                let (uri, hash, data) = update.unpack();
+                let idx = self.current_position(uri.as_str(), hash)?;
+                let previous = self.elements[idx].replace(PublishElement::new(uri, data));
+                undo.push(Undo::Updated(idx, previous));
+            }
+            DeltaElement::Withdraw(withdraw) => {
+                let (uri, hash) = withdraw.unpack();
+                let uri = uri.to_string();
+                let idx = self.current_position(&uri, hash)?;
+                let previous = self.elements[idx].take();
+                self.positions.remove(&uri);
+                undo.push(Undo::Withdrawn(idx, uri, previous));
+            }
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self, undo: Vec<Undo>) {
+        for change in undo.into_iter().rev() {
+            match change {
+                Undo::Published(uri) => {
+                    self.elements.pop();
+                    self.positions.remove(&uri);
+                }
+                Undo::Updated(idx, previous) => self.elements[idx] = previous,
+                Undo::Withdrawn(idx, uri, previous) => {
+                    self.elements[idx] = previous;
+                    self.positions.insert(uri, idx);
+                }
+            }
+        }
+    }
+
+    /// Returns the position of the current object for the URI, if it matches
+    /// the hash.
+    fn current_position(&self, uri: &str, hash: Hash) -> Result<usize> {
+        let idx = *self
+            .positions
+            .get(uri)
+            .ok_or_else(|| exit::invalid_data(format!("Delta changes unknown object: {}", uri)))?;
+
+        match &self.elements[idx] {
+            Some(current) if Hash::from_data(current.data()) == hash => Ok(idx),
+            _ => Err(exit::invalid_data(format!(
+                "Delta hash does not match current object: {}",
+                uri
+            ))),
+        }
+    }
+
+    /// Returns a copy of the snapshot as of the current serial, e.g. for a
+    /// checkpoint.
+    fn to_snapshot(&self) -> Snapshot {
+        let elements = self.elements.iter().flatten().cloned().collect();
+        Snapshot::new(self.session_id, self.serial, elements)
+    }
+
+    fn into_snapshot(self) -> Snapshot {
+        let elements = self.elements.into_iter().flatten().collect();
+        Snapshot::new(self.session_id, self.serial, elements)
+    }
+}
+
+/// Reads the checkpoint snapshot, if there is one. It is only returned if
+/// it is for the session of the notification file, and for a serial between
+/// our current serial and the notification serial. Otherwise it cannot be
+/// used, and it is removed.
+fn read_checkpoint(
+    path: &Path,
+    notification: &NotificationFile,
+    current_serial: u64,
+) -> Result<Option<Snapshot>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let checkpoint = fs::File::open(path)
+        .map_err(anyhow::Error::new)
+        .and_then(|file| Snapshot::parse(BufReader::new(file)).map_err(anyhow::Error::new));
+    match checkpoint {
+        Ok(checkpoint)
+            if checkpoint.session_id() == notification.session_id()
+                && checkpoint.serial() > current_serial
+                && checkpoint.serial() < notification.serial() =>
+        {
+            Ok(Some(checkpoint))
+        }
+        Ok(checkpoint) => {
+            info!(
+                "Discarding checkpoint for session {} and serial {}, it does not apply to session {} from serial {}",
+                checkpoint.session_id(),
+                checkpoint.serial(),
+                notification.session_id(),
+                current_serial
+            );
+            remove_checkpoint(path)?;
+            Ok(None)
+        }
+        Err(e) => {
+            warn!("Discarding unreadable checkpoint at {}: {}", path.display(), e);
+            remove_checkpoint(path)?;
+            Ok(None)
+        }
+    }
+}
+
+/// Writes the snapshot to the checkpoint path, see `RrdpState::update`.
+fn write_checkpoint(path: &Path, snapshot: &Snapshot) -> Result<()> {
+    info!(
+        "Keeping checkpoint of deltas applied up to serial {} at {}",
+        snapshot.serial(),
+        path.display()
+    );
+    let mut bytes = vec![];
+    snapshot.write_xml(&mut bytes)?;
+
+    let tmp_path = file_ops::path_with_extension(path, config::TMP_FILE_EXT);
+    file_ops::write_buf(&tmp_path, &bytes)
+        .with_context(|| format!("Could not write checkpoint to: {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| format!("Could not rename {} to {}", tmp_path.display(), path.display()))
+}
+
+fn remove_checkpoint(path: &Path) -> Result<()> {
+    if path.exists() {
+        fs::remove_file(path)
+            .with_context(|| format!("Could not remove checkpoint at: {}", path.display()))?;
+    }
+    Ok(())
+}
+
+//------------ Validation ----------------------------------------------------
+
+/// Fetches the notification file, the snapshot and all deltas from the source,
+/// and checks that they are well-formed and consistent with each other. This
+/// works in memory only, nothing is written to disk. Returns the problems
+/// found, which is empty if all is well, or an error if the notification file
+/// cannot be fetched at all.
+pub fn validate(fetcher: &Fetcher) -> Result<Vec<String>> {
+    let (mut notification, _) = fetcher.read_notification_file(None)?.content()?;
+    let session_id = notification.session_id();
+    let serial = notification.serial();
+
+    let mut problems = vec![];
+
+    if !notification.sort_and_verify_deltas(None) {
+        problems.push("Notification file contains gaps in deltas".to_string());
+    }
+    if let Some(last) = notification.deltas().last() {
+        if last.serial() != serial {
+            problems.push(format!(
+                "Highest delta serial {} does not match notification serial {}",
+                last.serial(),
+                serial
+            ));
+        }
+    }
+
+    let snapshot_info = notification.snapshot();
+    let snapshot = match fetcher
+        .fetch_file(snapshot_info.uri(), snapshot_info.hash())
+        .and_then(|bytes| Snapshot::parse(bytes.as_ref()).map_err(anyhow::Error::new))
+    {
+        Ok(snapshot) => {
+            if snapshot.session_id() != session_id || snapshot.serial() != serial {
+                problems.push(format!(
+                    "Snapshot {} has session {} and serial {}, expected {} and {}",
+                    snapshot_info.uri(),
+                    snapshot.session_id(),
+                    snapshot.serial(),
+                    session_id,
+                    serial
+                ));
+            }
+            Some(snapshot)
+        }
+        Err(e) => {
+            problems.push(format!("Snapshot {} is unusable: {}", snapshot_info.uri(), e));
+            None
+        }
+    };
+
+    let mut deltas = vec![];
+    for info in notification.deltas() {
+        match fetcher
+            .fetch_file(info.uri(), info.hash())
+            .and_then(|bytes| Delta::parse(bytes.as_ref()).map_err(anyhow::Error::new))
+        {
+            Ok(delta) => {
+                if delta.session_id() != session_id || delta.serial() != info.serial() {
+                    problems.push(format!(
+                        "Delta {} has session {} and serial {}, expected {} and {}",
+                        info.uri(),
+                        delta.session_id(),
+                        delta.serial(),
+                        session_id,
+                        info.serial()
+                    ));
+                }
+                deltas.push(delta);
+            }
+            Err(e) => problems.push(format!("Delta {} is unusable: {}", info.uri(), e)),
+        }
+    }
+
+    if let Some(snapshot) = snapshot {
+        problems.extend(check_deltas_against_snapshot(&snapshot, &deltas));
+    }
+
+    Ok(problems)
+}
+
+/// Checks that the changes of the deltas are reflected in the snapshot,
+/// unless an object was changed again by a later delta. The deltas must be
+/// ordered from low to high serial.
+fn check_deltas_against_snapshot(snapshot: &Snapshot, deltas: &[Delta]) -> Vec<String> {
+    let snapshot_hashes: HashMap<String, Hash> = snapshot
+        .elements()
+        .iter()
+        .map(|el| (el.uri().to_string(), Hash::from_data(el.data())))
+        .collect();
+
+    let mut problems = vec![];
+    let mut changed_later: HashSet<String> = HashSet::new();
+
+    for delta in deltas.iter().rev() {
+        let mut changed = vec![];
+        for element in delta.elements() {
+            let (uri, data) = match element {
+                DeltaElement::Publish(publish) => (publish.uri().to_string(), Some(publish.data())),
+                DeltaElement::Update(update) => (update.uri().to_string(), Some(update.data())),
+                DeltaElement::Withdraw(withdraw) => (withdraw.uri().to_string(), None),
+            };
+
+            if !changed_later.contains(&uri) {
+                let expected = data.map(|data| Hash::from_data(data));
+                if snapshot_hashes.get(&uri).copied() != expected {
+                    problems.push(format!(
+                        "Delta {} {} {}, but the snapshot does not match",
+                        delta.serial(),
+                        if expected.is_some() { "publishes" } else { "withdraws" },
+                        uri
+                    ));
+                }
+            }
+            changed.push(uri);
+        }
+        changed_later.extend(changed);
+    }
+
+    problems
+}
+
 //------------ SourceMappings ------------------------------------------------
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
 pub struct NotificationSource {
     uri: Https,
     name: String,
     etag: Option<String>,
+
+    /// Not present in state written by older versions.
+    #[serde(default)]
+    last_modified: Option<String>,
 }
 
 impl NotificationSource {
@@ -376,13 +1088,23 @@ impl NotificationSource {
             uri,
             name,
             etag: None,
+            last_modified: None,
         })
     }
 
     pub fn fetch(&mut self, fetcher: &Fetcher) -> Result<Option<NotificationFile>> {
-        match fetcher.read_notification_file(self.etag.as_ref())? {
-            NotificationFileResponse::Data { notification, etag } => {
-                self.etag = etag;
+        let validators = CacheValidators {
+            etag: self.etag.clone(),
+            last_modified: self.last_modified.clone(),
+        };
+
+        match fetcher.read_notification_file(Some(&validators))? {
+            NotificationFileResponse::Data {
+                notification,
+                validators,
+            } => {
+                self.etag = validators.etag;
+                self.last_modified = validators.last_modified;
                 Ok(Some(notification))
             }
             NotificationFileResponse::Unmodified => Ok(None),
@@ -491,6 +1213,11 @@ impl SnapshotState {
     pub fn rel_path(&self) -> &str {
         self.rel_path.as_str()
     }
+
+    /// The time at which we got the snapshot for this serial.
+    pub fn since(&self) -> Time {
+        self.since
+    }
 }
 
 //------------ DeltaState ----------------------------------------------------
@@ -545,3 +1272,173 @@ impl DeprecatedFile {
         }
     }
 }
+
+//------------ RrdpStateBuilder ----------------------------------------------
+
+/// Builds an `RrdpState` with a snapshot of the given objects, for tests
+/// which need a state without an RRDP source to fetch from. The snapshot is
+/// written to the rrdp dir of the config, like a downloaded snapshot, so
+/// that it can be used to write rsync revisions.
+#[cfg(test)]
+pub struct RrdpStateBuilder {
+    session_id: Uuid,
+    serial: u64,
+    objects: Vec<PublishElement>,
+}
+
+#[cfg(test)]
+impl RrdpStateBuilder {
+    pub fn new(session_id: Uuid, serial: u64) -> Self {
+        RrdpStateBuilder {
+            session_id,
+            serial,
+            objects: vec![],
+        }
+    }
+
+    /// Adds an object. Panics if the URI is not a valid rsync URI.
+    pub fn object(mut self, uri: &str, data: &[u8]) -> Self {
+        let uri = uri.parse::<rpki::uri::Rsync>().unwrap();
+        let data = bytes::Bytes::copy_from_slice(data);
+        self.objects.push(PublishElement::new(uri, data));
+        self
+    }
+
+    pub fn build(self, config: &Config) -> Result<RrdpState> {
+        let mut state = RrdpState::create(config)?;
+
+        let snapshot = Snapshot::new(self.session_id, self.serial, self.objects);
+        let mut bytes = vec![];
+        snapshot.write_xml(&mut bytes)?;
+
+        let rel_path = format!("{}/{}/snapshot.xml", self.session_id, self.serial);
+        file_ops::write_buf(&state.mappings.path(&rel_path), &bytes)?;
+        state.snapshot = Some(SnapshotState::create(
+            self.session_id,
+            self.serial,
+            Hash::from_data(&bytes),
+            rel_path,
+        ));
+
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::{
+        config::create_test_config,
+        util::{https, test_with_dir},
+    };
+
+    use super::*;
+
+    #[test]
+    fn build_state_from_objects() {
+        test_with_dir("rrdp_build_state_from_objects", |dir| {
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let session_id = Uuid::from_u128(1);
+            let state = RrdpStateBuilder::new(session_id, 7)
+                .object("rsync://localhost/repo/ta/ta.cer", b"cer")
+                .object("rsync://localhost/repo/ta/0/ta.crl", b"crl")
+                .build(&config)
+                .unwrap();
+
+            let snapshot_path = state.snapshot_path().unwrap();
+            assert_eq!(
+                snapshot_path,
+                dir.join("rrdp/00000000-0000-0000-0000-000000000001/7/snapshot.xml")
+            );
+            let snapshot =
+                Snapshot::parse(BufReader::new(fs::File::open(&snapshot_path).unwrap())).unwrap();
+            assert_eq!(snapshot.session_id(), session_id);
+            assert_eq!(snapshot.serial(), 7);
+            assert_eq!(snapshot.elements().len(), 2);
+            assert_eq!(snapshot.elements()[1].data().as_ref(), b"crl");
+            let hash = Hash::from_data(&fs::read(&snapshot_path).unwrap());
+            assert_eq!(state.snapshot().unwrap().hash(), hash);
+        })
+    }
+
+    #[test]
+    fn apply_deltas_to_snapshot_elements() {
+        let session_id = Uuid::from_u128(1);
+        let uri = |name: &str| format!("rsync://localhost/repo/{}", name).parse().unwrap();
+        let data = |data: &'static [u8]| bytes::Bytes::from_static(data);
+        let hash = |data: &[u8]| Hash::from_data(data);
+        let publish = |name: &str, content: &'static [u8]| {
+            DeltaElement::Publish(PublishElement::new(uri(name), data(content)))
+        };
+
+        let snapshot = Snapshot::new(
+            session_id,
+            1,
+            vec![
+                PublishElement::new(uri("a.cer"), data(b"a")),
+                PublishElement::new(uri("b.roa"), data(b"b")),
+            ],
+        );
+        let mut elements = SnapshotElements::new(snapshot);
+
+        let delta_2 = Delta::new(
+            session_id,
+            2,
+            vec![
+                DeltaElement::Update(UpdateElement::new(uri("a.cer"), hash(b"a"), data(b"a2"))),
+                DeltaElement::Withdraw(WithdrawElement::new(uri("b.roa"), hash(b"b"))),
+                publish("c.roa", b"c"),
+            ],
+        );
+        elements.apply(delta_2).unwrap();
+        let expected = elements.to_snapshot();
+        assert_eq!(expected.serial(), 2);
+        let uris: Vec<&str> = expected.elements().iter().map(|el| el.uri().as_str()).collect();
+        assert_eq!(uris, vec!["rsync://localhost/repo/a.cer", "rsync://localhost/repo/c.roa"]);
+
+        // A delta which fails halfway is not applied at all
+        let delta_3 = Delta::new(
+            session_id,
+            3,
+            vec![
+                publish("d.roa", b"d"),
+                DeltaElement::Update(UpdateElement::new(uri("a.cer"), hash(b"a2"), data(b"a3"))),
+                DeltaElement::Withdraw(WithdrawElement::new(uri("c.roa"), hash(b"c"))),
+                DeltaElement::Withdraw(WithdrawElement::new(uri("a.cer"), hash(b"a2"))),
+            ],
+        );
+        assert!(elements.apply(delta_3).is_err());
+        assert_eq!(elements.to_snapshot(), expected);
+        assert!(elements.apply(Delta::new(session_id, 3, vec![publish("c.roa", b"c")])).is_err());
+        assert_eq!(elements.into_snapshot(), expected);
+    }
+
+    #[test]
+    fn refuse_oversized_delta_objects() {
+        let path = Path::new(
+            "./test-resources/rrdp-rev2657/e9be21e7-c537-4564-b742-64700978c6b4/2657/rnd-d/delta.xml",
+        );
+        let open = || BufReader::new(fs::File::open(path).unwrap());
+        let delta = Delta::parse(open()).unwrap();
+        assert_eq!(DeltaElements::parse(open(), u64::MAX).unwrap(), delta);
+
+        let (uri, size) = delta
+            .elements()
+            .iter()
+            .filter_map(|element| match element {
+                DeltaElement::Publish(publish) => Some((publish.uri(), publish.data().len())),
+                DeltaElement::Update(update) => Some((update.uri(), update.data().len())),
+                DeltaElement::Withdraw(_) => None,
+            })
+            .max_by_key(|(_, size)| *size)
+            .unwrap();
+        let err = DeltaElements::parse(open(), size as u64 - 1).unwrap_err();
+        assert!(err.to_string().contains(uri.as_str()), "{}", err);
+        assert!(DeltaElements::parse(open(), size as u64).is_ok());
+    }
+}
@@ -1,37 +1,188 @@
 use std::{
+    collections::{BTreeMap, HashMap, HashSet},
     fs::File,
-    io::{BufReader, Read},
-    path::{Path, PathBuf},
+    io::{BufReader, Read, Write},
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
 };
 
 use anyhow::{anyhow, Context, Result};
-
+use bytes::Bytes;
 use filetime::{set_file_mtime, FileTime};
-use log::{info, warn};
 use rpki::{
     repository::{sigobj::SignedObject, Cert, Crl, Manifest, Roa},
-    rrdp::ProcessSnapshot,
+    rrdp::{Hash, ProcessSnapshot},
 };
 use serde::{Deserialize, Serialize};
+use tracing::{debug, error, info, info_span, warn};
 use uuid::Uuid;
 
 use crate::{
-    config::{self, Config},
-    file_ops,
+    archive,
+    cas::{self, ObjectStore},
+    config::{
+        self, Config, InvalidObjectAction, OversizedObjectAction, PathCollisionAction,
+        RsyncPathPrefix, RsyncSwap, WriteErrorMode,
+    },
+    exit,
+    file_ops::{self, FileLock, Fsync},
+    hook,
+    inspect::ObjectInspector,
+    report::RunReport,
     rrdp::RrdpState,
     util::{self, Time},
 };
 
+/// Summary of the changes made by `update_from_rrdp_state`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RsyncUpdateSummary {
+    /// The number of objects written (or linked) for a new revision.
+    pub objects_written: usize,
+
+    /// The total size of the objects written for a new revision.
+    pub bytes_written: u64,
+
+    /// The number of objects in the snapshot which were not written,
+    /// because they matched `config.rsync_exclude`.
+    pub objects_excluded: usize,
+
+    /// The objects written (or linked) for a new revision.
+    pub written_objects: Vec<WrittenObject>,
+
+    /// The URIs of objects which are not well-formed RPKI objects, if
+    /// `config.validate_objects` is set. These are left out of the new
+    /// revision if `config.invalid_objects` is 'skip'.
+    pub invalid_objects: Vec<String>,
+
+    /// The old revision directories which were removed.
+    pub removed_revision_dirs: Vec<PathBuf>,
+
+    /// Whether a new revision was made current.
+    pub published: bool,
+
+    /// The seconds between the RRDP publication of the serial, see
+    /// `RrdpState::publication_time`, and making its revision current, if
+    /// that happened in this run.
+    pub publish_lag_seconds: Option<u64>,
+
+    /// The digest of the content of the current revision, see
+    /// `revision_digest`. None for revisions written by older versions.
+    pub digest: Option<String>,
+
+    /// How the objects of a new revision differ from those of the current
+    /// revision. None if no new revision was written, or if the objects of
+    /// the current revision are unknown, because it was written by an older
+    /// version.
+    pub object_changes: Option<ObjectChanges>,
+
+    /// How long the steps of the update took.
+    pub timings: RsyncTimings,
+}
+
+/// How long the steps of `update_from_rrdp_state` took. Writing and swapping
+/// are None if they were skipped, i.e. when there was no change.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct RsyncTimings {
+    pub write: Option<Duration>,
+    pub swap: Option<Duration>,
+    pub clean: Duration,
+}
+
+impl RsyncUpdateSummary {
+    pub fn old_revisions_cleaned(&self) -> usize {
+        self.removed_revision_dirs.len()
+    }
+}
+
+/// The number of objects which were added, removed and changed in a new
+/// revision, compared to the previous revision. Objects are compared by URI
+/// and hash.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct ObjectChanges {
+    pub added: usize,
+    pub removed: usize,
+    pub changed: usize,
+}
+
+impl ObjectChanges {
+    fn between(previous: &BTreeMap<String, String>, new: &BTreeMap<String, String>) -> Self {
+        let mut changes = ObjectChanges::default();
+        for (uri, hash) in new {
+            match previous.get(uri) {
+                None => changes.added += 1,
+                Some(previous_hash) if previous_hash != hash => changes.changed += 1,
+                Some(_) => {}
+            }
+        }
+        changes.removed = previous.keys().filter(|uri| !new.contains_key(*uri)).count();
+        changes
+    }
+}
+
+/// An object written for a new revision, as listed in the run report.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct WrittenObject {
+    pub uri: String,
+    pub size: u64,
+}
+
+/// Writes a new rsync revision for the RRDP snapshot if it `changed`, and
+/// makes it current. This is refused if a revision was staged with
+/// `stage_from_rrdp_state`, which only `publish_staged` makes current.
 pub fn update_from_rrdp_state(
     rrdp_state: &RrdpState,
     changed: bool,
     config: &Config,
-) -> Result<()> {
+) -> Result<RsyncUpdateSummary> {
+    update(rrdp_state, changed, true, config)
+}
+
+/// Writes a new rsync revision for the RRDP snapshot if it `changed`, but
+/// does not make it current. Instead it is recorded as pending, until it is
+/// published with `publish_staged`. A revision which was staged before, but
+/// not published, is replaced.
+pub fn stage_from_rrdp_state(
+    rrdp_state: &RrdpState,
+    changed: bool,
+    config: &Config,
+) -> Result<RsyncUpdateSummary> {
+    update(rrdp_state, changed, false, config)
+}
+
+fn update(
+    rrdp_state: &RrdpState,
+    changed: bool,
+    publish: bool,
+    config: &Config,
+) -> Result<RsyncUpdateSummary> {
+    let mut summary = RsyncUpdateSummary::default();
+
     // Check that there is a current snapshot, if not, there is no work
     if rrdp_state.snapshot_path().is_none() {
-        return Ok(());
+        if config.force_serial.is_some() {
+            return Err(anyhow!("Cannot force rsync revision, there is no local RRDP snapshot"));
+        }
+        return Ok(summary);
     }
 
+    // The time from the RRDP source is used like a normalized mtime, for all
+    // files and dirs written in this run.
+    let rrdp_mtime_config;
+    let config = match rrdp_mtime(rrdp_state, config) {
+        Some(timestamp) => {
+            rrdp_mtime_config = Config {
+                normalize_mtime: Some(timestamp),
+                ..config.clone()
+            };
+            &rrdp_mtime_config
+        }
+        None => config,
+    };
+
     // We can assume now that there is a snapshot and unwrap things for it
     let snapshot_path = rrdp_state.snapshot_path().unwrap();
     let snapshot = rrdp_state.snapshot().unwrap();
@@ -39,306 +190,3455 @@ pub fn update_from_rrdp_state(
     let serial = snapshot.serial();
 
     let mut rsync_state = RsyncDirState::recover(config)?;
+    rsync_state.reconcile(config)?;
+    if publish {
+        rsync_state.check_not_staged()?;
+    }
+    let old_serial = rsync_state.current.as_ref().map(|current| current.serial);
 
-    let new_revision = RsyncRevision { session_id, serial };
+    let mut new_revision = RsyncRevision::new(session_id, serial, config);
 
-    if changed {
-        let mut writer = RsyncFromSnapshotWriter {
-            out_path: new_revision.path(config),
-            include_host_and_module: config.rsync_include_host,
-        };
-        writer.create_out_path_if_missing()?;
-        writer.for_snapshot_path(&snapshot_path)?;
+    let forced = check_forced_revision(session_id, serial, config)? || config.force_full_write;
+    if forced {
+        warn!(
+            "MANUAL OVERRIDE: rewriting the rsync revision for session {} serial {}, because of {}",
+            session_id,
+            serial,
+            if config.force_full_write { "--rebuild" } else { "--force-serial" }
+        );
+        // Never replace the directory of a known revision, which may well be
+        // current.
+        if rsync_state.knows_dir_name(&new_revision.dir_name()) {
+            new_revision.dir_name = Some(format!(
+                "{}_forced_{}",
+                new_revision.dir_name(),
+                Time::now().timestamp()
+            ));
+        }
+    }
+    let changed = changed || forced;
 
-        if config.rsync_dir_use_symlinks() {
-            symlink_current_to_new_revision_dir(&new_revision, config)?;
-        } else {
-            rename_new_revision_dir_to_current(&new_revision, &rsync_state, config)?;
+    if config.dry_run {
+        report_dry_run(&snapshot_path, &new_revision, &rsync_state, changed, config)?;
+        return Ok(summary);
+    }
+
+    if changed || (publish && rsync_state.pending.is_some()) {
+        // Held until the end of this block, i.e. also when we return early
+        // because of an error.
+        let _rsync_lock = lock_rsync_dir(config)?;
+
+        if changed {
+            config.run_progress.set_phase("read");
+
+            // Write in a deterministic order, so that the resulting tree
+            // does not depend on the order of the snapshot. When streaming,
+            // only the info about each object is kept in memory, and the
+            // objects are written in the order of the snapshot.
+            let mut inspector = config.validate_objects.then(|| ObjectInspector::new(config));
+            let mut keep = |object: &CurrentObject| match inspector.as_mut() {
+                Some(inspector) => inspector.check(object),
+                None => true,
+            };
+            let (objects, infos) = if config.rsync_stream_objects {
+                (None, read_snapshot_infos(&snapshot_path, config, keep)?)
+            } else {
+                let mut objects = read_snapshot_objects(&snapshot_path, config)?;
+                objects.retain(|object| keep(object));
+                objects.sort_by(|a, b| a.uri().as_str().cmp(b.uri().as_str()));
+                let infos = objects.iter().map(CurrentObject::info).collect();
+                (Some(objects), infos)
+            };
+            if let Some(inspector) = inspector {
+                summary.invalid_objects = inspector.finish()?;
+            }
+            let skipped: HashSet<&str> = match config.invalid_objects {
+                InvalidObjectAction::Skip => {
+                    summary.invalid_objects.iter().map(String::as_str).collect()
+                }
+                _ => HashSet::new(),
+            };
+
+            // Objects which map to the same file as another object are not
+            // written, so they are not part of the revision either.
+            let shadowed = find_path_collisions(&infos, config)?;
+            let infos: Vec<ObjectInfo> =
+                infos.into_iter().filter(|i| !shadowed.contains(i.uri().as_str())).collect();
+            let objects = objects.map(|objects| {
+                objects
+                    .into_iter()
+                    .filter(|o| !shadowed.contains(o.uri().as_str()))
+                    .collect::<Vec<_>>()
+            });
+            let left_out: HashSet<&str> =
+                skipped.iter().copied().chain(shadowed.iter().map(String::as_str)).collect();
+
+            // An RRDP update does not always change the content, e.g. in
+            // case of an empty delta. Then there is no need for a new revision,
+            // unless the session changed.
+            let digest = revision_digest(&infos, config);
+            let identical = rsync_state.current.as_ref().is_some_and(|current| {
+                current.session_id == session_id && current.digest.as_deref() == Some(digest.as_str())
+            });
+            if identical && rsync_state.pending.is_none() && !forced {
+                info!(
+                    "Content for serial {} is identical to the current rsync revision, not writing a new revision",
+                    serial
+                );
+            } else {
+                new_revision.digest = Some(digest);
+                new_revision.objects = Some(revision_objects(&infos, config));
+
+                let object_count = infos
+                    .iter()
+                    .filter(|o| !config.rsync_excluded(o.uri()))
+                    .count();
+                check_shrink(rsync_state.current.as_ref(), object_count, config)?;
+                check_not_empty(object_count, config)?;
+                if config.clean_before_write {
+                    // The revisions which are old enough would otherwise be
+                    // removed after the write. Newer ones are kept, so that
+                    // a rollback is still possible.
+                    config.run_progress.set_phase("clean");
+                    let clean_start = Instant::now();
+                    summary.removed_revision_dirs = info_span!("clean")
+                        .in_scope(|| rsync_state.clean_old(config, Time::now()))?;
+                    summary.timings.clean = clean_start.elapsed();
+                    rsync_state.persist(config)?;
+                    gc_object_store(config)?;
+                }
+                check_free_space(&infos, config)?;
+                new_revision.object_count = Some(object_count);
+
+                // Write everything to a temporary directory first, and only rename
+                // it when complete. So, if a revision directory exists, then it is
+                // complete. Anything left from an interrupted run is discarded.
+                let tmp_path = new_revision.tmp_path(config);
+                if let Some(tmp_dir) = &config.tmp_dir {
+                    if file_ops::same_file_system(tmp_dir, &config.rsync_dir) == Some(false) {
+                        warn!(
+                            "Tmp dir {} is on another file system than the rsync dir {}, so the new revision is copied, and unchanged objects cannot be hard-linked",
+                            tmp_dir.display(),
+                            config.rsync_dir.display()
+                        );
+                    }
+                }
+                if tmp_path.exists() {
+                    warn!(
+                        "Removing incomplete rsync dir from an earlier run: {}",
+                        tmp_path.display()
+                    );
+                    let fs = config.fs_retry();
+                    fs.remove_dir_all(&tmp_path)
+                        .with_context(|| format!("Cannot remove rsync dir: {}", tmp_path.display()))?;
+                }
+                create_out_path_if_missing(&tmp_path)?;
+
+                // Unchanged objects can be linked from the current directory, which
+                // is either a symlink to, or the renamed directory of, the previous
+                // revision. This is not needed if we link all objects from the
+                // object store. A forced revision is written from scratch, in
+                // case the current directory is damaged.
+                let link_from = if config.rsync_dir_use_hardlinks
+                    && config.rsync_cas_dir.is_none()
+                    && !forced
+                    && rsync_state.current.is_some()
+                {
+                    let current_path = config.rsync_dir_current();
+                    if current_path.is_dir() {
+                        Some(current_path)
+                    } else {
+                        warn!(
+                            "Previous rsync dir '{}' is missing, will write all objects",
+                            current_path.display()
+                        );
+                        None
+                    }
+                } else {
+                    None
+                };
+
+                config.run_progress.set_phase("write");
+                config.run_progress.set_objects_total(object_count);
+                let write_start = Instant::now();
+                let write_span = info_span!("write", session = %session_id, serial, objects = object_count);
+                write_span.in_scope(|| {
+                    match &objects {
+                        Some(objects) => {
+                            write_rsync_content(&tmp_path, objects, link_from.as_deref(), config)?
+                        }
+                        None => stream_rsync_content(
+                            &tmp_path,
+                            &snapshot_path,
+                            &infos,
+                            &left_out,
+                            link_from.as_deref(),
+                            config,
+                        )?,
+                    }
+                    write_module_readme(&tmp_path, session_id, serial, config)?;
+                    write_rsync_index(&tmp_path, session_id, serial, &infos, config)?;
+                    move_tmp_dir_to_revision_dir(&tmp_path, &new_revision.path(config), config)?;
+                    config
+                        .write_target()
+                        .upload_revision(&new_revision.path(config), &new_revision.dir_name())
+                })?;
+                summary.timings.write = Some(write_start.elapsed());
+
+                summary.written_objects = infos
+                    .iter()
+                    .filter(|o| !config.rsync_excluded(o.uri()))
+                    .map(|o| WrittenObject {
+                        uri: config.rewrite_rsync_uri(o.uri()),
+                        size: o.size(),
+                    })
+                    .collect();
+                summary.objects_written = summary.written_objects.len();
+                summary.objects_excluded = infos.len() - summary.objects_written;
+                summary.bytes_written = summary.written_objects.iter().map(|o| o.size).sum();
+                log_objects(&new_revision, &infos, config)?;
+                if !skipped.is_empty() {
+                    warn!(
+                        "Left {} malformed objects out of rsync revision {}",
+                        skipped.len(),
+                        new_revision.dir_name()
+                    );
+                }
+
+                // Always compared to the current revision, also when this
+                // replaces a staged revision.
+                let previous = match &rsync_state.current {
+                    Some(current) => current.objects.clone(),
+                    None => Some(BTreeMap::new()),
+                };
+                summary.object_changes = previous
+                    .zip(new_revision.objects.as_ref())
+                    .map(|(previous, new)| ObjectChanges::between(&previous, new));
+                match &summary.object_changes {
+                    Some(changes) => info!(
+                        "Rsync revision {} has {} objects added, {} removed and {} changed",
+                        new_revision.dir_name(),
+                        changes.added,
+                        changes.removed,
+                        changes.changed
+                    ),
+                    None => info!(
+                        "Objects of the current rsync revision are unknown, cannot count changes for {}",
+                        new_revision.dir_name()
+                    ),
+                }
+
+                new_revision.staged = !publish;
+                rsync_state.update_pending(new_revision, config);
+            }
         }
 
-        rsync_state.update_current(new_revision);
+        if publish {
+            if rsync_state.pending.is_some() {
+                config.run_progress.set_phase("swap");
+                let swap_start = Instant::now();
+                info_span!("swap", session = %session_id, serial)
+                    .in_scope(|| rsync_state.publish_pending(config))?;
+                summary.timings.swap = Some(swap_start.elapsed());
+                summary.published = true;
+
+                // A revision staged for an earlier serial is not compared
+                // to the publication of the current one.
+                let current = rsync_state.current.as_ref();
+                if let (Some(time), true) = (
+                    rrdp_state.publication_time(),
+                    current.is_some_and(|c| c.session_id == session_id && c.serial == serial),
+                ) {
+                    let lag = (Time::now().timestamp() - time.timestamp()).max(0) as u64;
+                    info!(
+                        "Published rsync revision for serial {} {} seconds after it was published by the RRDP source",
+                        serial, lag
+                    );
+                    summary.publish_lag_seconds = Some(lag);
+                }
+            }
+        } else {
+            info!(
+                "Staged rsync revision {}, it will be published by 'krill-sync publish'",
+                rsync_state.pending.as_ref().map(|p| p.dir_name()).unwrap_or_default()
+            );
+        }
     }
 
-    rsync_state.clean_old(config)?;
+    summary.digest = rsync_state.current.as_ref().and_then(|c| c.digest.clone());
+
+    config.run_progress.set_phase("clean");
+    let clean_start = Instant::now();
+    let removed =
+        info_span!("clean").in_scope(|| rsync_state.clean_old_unless_kept(config, Time::now()))?;
+    summary.removed_revision_dirs.extend(removed);
+    summary.timings.clean += clean_start.elapsed();
     rsync_state.persist(config)?;
+    gc_object_store(config)?;
 
-    Ok(())
+    if let Some(report_path) = &config.report_path {
+        RunReport::new(session_id, old_serial, serial, changed, &summary).write(report_path)?;
+    }
+
+    Ok(summary)
 }
 
-/// Create a new symlink then rename it. We need to do this because the std library
-/// refuses to overwrite an existing symlink. And if we were to remove it first, then
-/// we would introduce a race condition for clients accessing.
-fn symlink_current_to_new_revision_dir(
-    new_revision: &RsyncRevision,
-    config: &Config,
-) -> Result<()> {
-    info!(
-        "Updating symlink 'current' to '{}' under rsync dir '{}'",
-        new_revision.dir_name(),
-        config.rsync_dir.display()
-    );
-    let current_path = config.rsync_dir_current();
+/// Returns the Last-Modified time of the RRDP notification file, if
+/// `config.rsync_mtime_from_rrdp` is set and there is one.
+fn rrdp_mtime(rrdp_state: &RrdpState, config: &Config) -> Option<i64> {
+    if !config.rsync_mtime_from_rrdp {
+        return None;
+    }
+    let timestamp = rrdp_state.notification_last_modified();
+    if timestamp.is_none() {
+        warn!("The RRDP notification file has no Last-Modified time, cannot use it for mtimes");
+    }
+    timestamp
+}
 
-    let tmp_name = file_ops::path_with_extension(&current_path, config::TMP_FILE_EXT);
-    if tmp_name.exists() {
-        std::fs::remove_file(&tmp_name).with_context(|| {
-            format!(
-                "Could not remove lingering temporary symlink for current rsync dir at '{}'",
-                tmp_name.display()
+/// Makes the old rsync revision for the serial current again, e.g. to revert
+/// a bad publication quickly. Its directory must still exist, i.e. it must
+/// not have been cleaned up yet. The revision which was current becomes old,
+/// as with any other swap, but `config.pre_publish_command` is not run. The
+/// revision must be of the session of the current revision, unless `force`
+/// is set.
+///
+/// This does not change the RRDP state, so a new revision is only written
+/// once the RRDP source changes again.
+pub fn rollback(serial: u64, force: bool, config: &Config) -> Result<()> {
+    if config.rsync_flat_layout {
+        return Err(anyhow!("Cannot roll back with --rsync-flat-layout, it has no old revisions"));
+    }
+
+    let mut rsync_state = RsyncDirState::recover(config)?;
+    rsync_state.reconcile(config)?;
+
+    let current = rsync_state
+        .current
+        .as_ref()
+        .ok_or_else(|| anyhow!("There is no current rsync revision to roll back"))?;
+    if current.serial == serial {
+        return Err(anyhow!(
+            "Rsync revision {} for serial {} is current already",
+            current.dir_name(),
+            serial
+        ));
+    }
+
+    let target = rsync_state
+        .old
+        .iter()
+        .rev()
+        .map(|old| &old.revision)
+        .find(|old| old.serial == serial && old.path(config).is_dir())
+        .cloned()
+        .ok_or_else(|| {
+            anyhow!(
+                "There is no old rsync revision for serial {} left in {}",
+                serial,
+                config.rsync_dir.display()
             )
         })?;
+    if target.session_id != current.session_id && !force {
+        return Err(anyhow!(
+            "Rsync revision {} is for session {} rather than the current session {}, use --force to roll back anyway",
+            target.dir_name(),
+            target.session_id,
+            current.session_id
+        ));
     }
 
-    std::os::unix::fs::symlink(new_revision.dir_name(), &tmp_name).with_context(|| {
-        format!(
-            "Could not create temporary symlink for new rsync content at '{}'",
-            tmp_name.display()
-        )
-    })?;
+    let _rsync_lock = lock_rsync_dir(config)?;
+    config
+        .write_target()
+        .make_current(&target.dir_name())
+        .with_context(|| "Could not make the rsync revision current at the target")?;
 
-    std::fs::rename(&tmp_name, &current_path).with_context(|| {
-        format!(
-            "Could not rename symlink for current rsync dir from '{}' to '{}'",
-            tmp_name.display(),
-            current_path.display()
-        )
-    })?;
+    warn!("Rolling back rsync revision {} to {}", current.dir_name(), target.dir_name());
+    info_span!("swap", session = %target.session_id, serial)
+        .in_scope(|| rsync_state.make_current(target, config))?;
+    rsync_state.persist(config)
+}
 
-    Ok(())
+/// Makes sure that the rsync dir is a writable directory, so that problems
+/// are reported clearly before anything is fetched, rather than when the
+/// first revision is renamed into place. The dir and its parents are created
+/// if they do not exist, unless `config.create_rsync_dir()` is false. With
+/// the flat layout the rsync dir itself is replaced by each revision, so
+/// then this applies to its parent.
+pub fn prepare_rsync_dir(config: &Config) -> Result<()> {
+    let dir = if config.rsync_flat_layout {
+        flat_rsync_dir_parent(config)
+    } else {
+        config.rsync_dir.as_path()
+    };
+
+    if !dir.exists() {
+        if !config.create_rsync_dir() {
+            return Err(anyhow!(
+                "Rsync dir {} does not exist, create it first or omit --no-create-rsync-dir",
+                dir.display()
+            ));
+        }
+        info!("Creating rsync dir {}", dir.display());
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("Cannot create rsync dir {}", dir.display()))?;
+    } else if !dir.is_dir() {
+        return Err(anyhow!("Rsync dir {} is not a directory", dir.display()));
+    }
+
+    // Permissions do not tell the whole story, e.g. for read-only mounts, so
+    // try to write a file.
+    let probe = dir.join(format!(".krill-sync-probe.{}", config::TMP_FILE_EXT));
+    std::fs::write(&probe, b"")
+        .and_then(|()| std::fs::remove_file(&probe))
+        .with_context(|| format!("Rsync dir {} is not writable", dir.display()))
 }
 
-/// Rename the path for the new revision to the current rsync path, *after*
-/// renaming any existing current path to the serial and session for that
-/// revision.
-fn rename_new_revision_dir_to_current(
-    new_revision: &RsyncRevision,
-    rsync_state: &RsyncDirState,
-    config: &Config,
-) -> Result<()> {
-    info!("Renaming rsync folders for close to atomic update of the rsync module dir");
+/// Makes the rsync revision which was staged by `stage_from_rrdp_state`
+/// current, and cleans up old revisions. Returns whether there was a staged
+/// revision to publish.
+pub fn publish_staged(config: &Config) -> Result<bool> {
+    let mut rsync_state = RsyncDirState::recover(config)?;
+    rsync_state.reconcile(config)?;
 
-    let current_path = config.rsync_dir_current();
+    let published = if let Some(pending) = &rsync_state.pending {
+        let _rsync_lock = lock_rsync_dir(config)?;
+        info_span!("swap", session = %pending.session_id, serial = pending.serial)
+            .in_scope(|| rsync_state.publish_pending(config))?;
+        true
+    } else {
+        info!("There is no staged rsync revision to publish");
+        false
+    };
 
-    if let Some(current) = &rsync_state.current {
-        let current_preserve_path = current.path(config);
+    info_span!("clean").in_scope(|| rsync_state.clean_old_unless_kept(config, Time::now()))?;
+    rsync_state.persist(config)?;
+    gc_object_store(config)?;
+    Ok(published)
+}
 
-        if current_path.exists() {
-            info!(
-                "Renaming the rsync directory for previous revision to: {}",
-                current_preserve_path.display()
-            );
-            std::fs::rename(&current_path, &current_preserve_path).with_context(|| {
-                format!(
-                    "Could not rename current rsync dir from '{}' to '{}'",
-                    current_path.display(),
-                    current_preserve_path.display()
-                )
-            })?;
-        }
-    }
+/// Returns an error if there is a revision staged by `stage_from_rrdp_state`,
+/// because a normal run would otherwise publish it, without `publish_staged`.
+pub fn check_not_staged(config: &Config) -> Result<()> {
+    RsyncDirState::recover(config)?.check_not_staged()
+}
 
-    info!(
-        "Rename rsync dir for new revision to '{}'",
-        current_path.display()
-    );
-    std::fs::rename(&new_revision.path(config), &current_path).with_context(|| {
-        format!(
-            "Could not rename new rsync dir from '{}' to '{}'",
-            new_revision.path(config).display(),
-            current_path.display()
-        )
-    })?;
+/// Removes old rsync revisions which are eligible for cleanup and persists
+/// the updated state. If `force` is set, then all old revisions are removed
+/// regardless of `config.cleanup_after` and `config.rsync_keep_min_revisions`.
+/// Returns the number of removed directories.
+pub fn clean_old_revisions(config: &Config, force: bool) -> Result<usize> {
+    let mut rsync_state = RsyncDirState::recover(config)?;
+    rsync_state.reconcile(config)?;
 
-    Ok(())
+    let cleaned = if force {
+        rsync_state.clean_old_before(config, Time::now(), 0)?
+    } else {
+        rsync_state.clean_old(config, Time::now())?
+    };
+
+    rsync_state.persist(config)?;
+    gc_object_store(config)?;
+    Ok(cleaned.len())
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-struct RsyncDirState {
-    current: Option<RsyncRevision>,
-    old: Vec<DeprecatedRsyncRevision>,
+/// Returns whether a new revision is forced with `config.force_serial`. This
+/// is refused unless the session and serial match the local RRDP snapshot,
+/// because that is the only content we have.
+fn check_forced_revision(session_id: Uuid, serial: u64, config: &Config) -> Result<bool> {
+    let forced_serial = match config.force_serial {
+        Some(forced_serial) => forced_serial,
+        None => return Ok(false),
+    };
+    let forced_session = config.force_session.unwrap_or(session_id);
+
+    if forced_session != session_id || forced_serial != serial {
+        Err(anyhow!(
+            "Cannot force rsync revision for session {} serial {}, the local RRDP snapshot has session {} serial {}",
+            forced_session,
+            forced_serial,
+            session_id,
+            serial
+        ))
+    } else {
+        Ok(true)
+    }
 }
 
-impl RsyncDirState {
-    /// Gets the current state from disk, if a state file exists. Otherwise returns
-    /// a new blank state.
-    fn recover(config: &Config) -> Result<Self> {
-        let state_path = config.rsync_state_path();
-        if state_path.exists() {
-            let json_bytes = file_ops::read_file(&state_path).with_context(|| {
-                format!("Cannot read rsync state file at: {}", state_path.display())
-            })?;
-            serde_json::from_slice(json_bytes.as_ref()).with_context(|| {
-                format!(
-                    "Cannot deserialize json for current state from {}",
-                    state_path.display()
-                )
-            })
-        } else {
-            Ok(RsyncDirState {
-                current: None,
-                old: vec![],
-            })
-        }
+/// Refuses a new revision with far fewer objects than the current revision,
+/// because that may well be the result of a truncated snapshot or another
+/// upstream problem, rather than an intended change. Publishing it could
+/// make many objects disappear for relying parties.
+fn check_shrink(
+    current: Option<&RsyncRevision>,
+    object_count: usize,
+    config: &Config,
+) -> Result<()> {
+    let current_count = match current.and_then(|current| current.object_count) {
+        Some(count) if count > 0 => count,
+        _ => return Ok(()),
+    };
+
+    if object_count >= current_count {
+        return Ok(());
     }
 
-    /// Persists the state to disk
-    fn persist(&self, config: &Config) -> Result<()> {
-        let state_path = config.rsync_state_path();
-        let json = serde_json::to_string_pretty(&self)?;
-        file_ops::write_buf(&state_path, json.as_bytes()).with_context(|| "Could not save state.")
+    let shrink_pct = (current_count - object_count) as f64 * 100.0 / current_count as f64;
+    if shrink_pct <= config.max_object_shrink_pct as f64 {
+        Ok(())
+    } else if config.accept_shrink {
+        warn!(
+            "Accepting new rsync revision with {} objects, {:.1}% fewer than the current {}",
+            object_count, shrink_pct, current_count
+        );
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "New rsync revision has {} objects, {:.1}% fewer than the current {}, which exceeds --max-object-shrink-pct {}. Use --accept-shrink if this is expected.",
+            object_count,
+            shrink_pct,
+            current_count,
+            config.max_object_shrink_pct
+        ))
     }
+}
 
-    /// Updates the current revision for this state, moves a possible
-    /// existing current state to old.
-    fn update_current(&mut self, current: RsyncRevision) {
-        let existing = std::mem::replace(&mut self.current, Some(current));
-        if let Some(existing) = existing {
-            self.old.push(existing.deprecate());
-        }
+/// Refuses a new revision without any objects, unless `config.allow_empty`
+/// is set. An empty revision is almost always the result of an upstream
+/// problem, and making it current would take the repository down for
+/// relying parties.
+fn check_not_empty(object_count: usize, config: &Config) -> Result<()> {
+    if object_count > 0 {
+        Ok(())
+    } else if config.allow_empty {
+        warn!("Accepting new rsync revision without any objects");
+        Ok(())
+    } else {
+        Err(anyhow!(
+            "New rsync revision has no objects, refusing to make it current. Use --allow-empty if this is expected."
+        ))
     }
+}
 
-    /// Cleans old directories from disk when their time has come, and updates
-    /// this state (forgets these old versions). Will throw an error if removing
-    /// an old dir fails, but will simply skip removing old dirs if they had
-    /// already been removed.
-    fn clean_old(&mut self, config: &Config) -> Result<()> {
-        let clean_before = Time::seconds_ago(config.cleanup_after);
+/// Checks that there is room for all objects, plus `config.min_free_bytes`,
+/// on the file system of the rsync dir, and of the tmp dir if configured.
+/// This is an estimate: objects which are hard-linked do not actually take
+/// up more space.
+fn check_free_space(objects: &[ObjectInfo], config: &Config) -> Result<()> {
+    let needed: u64 = objects
+        .iter()
+        .filter(|o| !config.rsync_excluded(o.uri()))
+        .map(|o| o.size())
+        .sum::<u64>()
+        .saturating_add(config.min_free_bytes);
 
-        for old in self
-            .old
-            .iter()
-            .filter(|deprecated| deprecated.since <= clean_before)
-        {
-            let path = old.revision.path(config);
-            if path.exists() {
-                info!(
-                    "Removing rsync directory: {}, deprecated since: {}",
-                    path.display(),
-                    old.since
-                );
-                // Try to remove the old directory if it still exists
-                std::fs::remove_dir_all(&path).with_context(|| {
-                    format!(
-                        "Could not remove rsync dir for old revision at: {}",
-                        path.display()
-                    )
-                })?;
-            }
+    // The revision is written to the tmp dir first, and may be copied to
+    // the rsync dir.
+    let mut dirs = vec![&config.rsync_dir];
+    dirs.extend(config.tmp_dir.as_ref());
+
+    for dir in dirs {
+        let available = match file_ops::available_space(dir)? {
+            Some(available) => available,
+            None => continue,
+        };
+        if needed > available {
+            return Err(anyhow!(
+                "Not enough free space for the new rsync revision in {}: {} bytes are needed, including --min-free-bytes {}, but only {} are available",
+                dir.display(),
+                needed,
+                config.min_free_bytes,
+                available
+            ));
         }
+    }
+    Ok(())
+}
 
-        self.old
-            .retain(|deprecated| deprecated.since > clean_before);
+/// Returns a digest of the content of a revision: the SHA-256 hash over a
+/// line with the URI and hash of each object, in order of URI. Objects
+/// which are excluded from rsync are left out, and so is the module readme.
+/// The objects must be sorted by URI already.
+fn revision_digest(objects: &[ObjectInfo], config: &Config) -> String {
+    let mut lines = String::new();
+    for object in objects.iter().filter(|o| !config.rsync_excluded(o.uri())) {
+        lines.push_str(object.uri().as_str());
+        lines.push(' ');
+        lines.push_str(&object.hash().to_string());
+        lines.push('\n');
+    }
+    Hash::from_data(lines.as_bytes()).to_string()
+}
 
-        Ok(())
+/// Returns the hash of each object in a revision by URI, leaving out the
+/// objects which are excluded from rsync, as `revision_digest` does.
+fn revision_objects(objects: &[ObjectInfo], config: &Config) -> BTreeMap<String, String> {
+    objects
+        .iter()
+        .filter(|o| !config.rsync_excluded(o.uri()))
+        .map(|o| (o.uri().to_string(), o.hash().to_string()))
+        .collect()
+}
+
+/// Removes object store entries which are no longer used by any revision.
+fn gc_object_store(config: &Config) -> Result<()> {
+    if let Some(cas_dir) = &config.rsync_cas_dir {
+        ObjectStore::new(cas_dir.clone(), config.rsync_cas_shard_chars).gc()?;
     }
+    Ok(())
 }
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+/// The result of `verify_current`.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    /// The number of objects which were found with the expected content.
+    pub verified: usize,
 
-struct RsyncRevision {
-    #[serde(deserialize_with = "util::de_uuid", serialize_with = "util::ser_uuid")]
-    session_id: Uuid,
-    serial: u64,
+    /// Paths for objects in the snapshot that do not exist.
+    pub missing: Vec<PathBuf>,
+
+    /// Paths for objects in the snapshot with different content.
+    pub corrupted: Vec<PathBuf>,
+
+    /// Files which do not belong to any object in the snapshot.
+    pub extra: Vec<PathBuf>,
+
+    /// URIs of objects which are missing from, only present in, or have
+    /// another hash in the revision manifest, if there is one.
+    pub manifest_mismatches: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.missing.is_empty()
+            && self.corrupted.is_empty()
+            && self.extra.is_empty()
+            && self.manifest_mismatches.is_empty()
+    }
+}
+
+/// Verifies that the current rsync dir contains exactly the objects from
+/// the current snapshot in the RRDP state, with the right content, except
+/// for objects excluded by the config, and the module readme and index if
+/// configured.
+/// If the revision has a manifest, then it must list the same objects.
+/// This reads every file, so this can take a while for large repositories.
+pub fn verify_current(rrdp_state: &RrdpState, config: &Config) -> Result<VerifyReport> {
+    let (snapshot, snapshot_path) = match (rrdp_state.snapshot(), rrdp_state.snapshot_path()) {
+        (Some(snapshot), Some(path)) => (snapshot, path),
+        _ => return Err(anyhow!("There is no RRDP snapshot to verify against")),
+    };
+
+    // Objects which map to the same file as another object were not written.
+    let mut infos = read_snapshot_infos(&snapshot_path, config, |_| true)?;
+    let shadowed = find_path_collisions(&infos, config)?;
+    infos.retain(|info| !shadowed.contains(info.uri().as_str()));
+
+    // No new revision is written for a later serial with identical content,
+    // so then the current revision is for an earlier serial.
+    let rsync_state = RsyncDirState::recover(config)?;
+    let identical = |digest: &Option<String>| {
+        digest.as_deref() == Some(revision_digest(&infos, config).as_str())
+    };
+    match &rsync_state.current {
+        Some(current)
+            if current.session_id == snapshot.session_id()
+                && (current.serial == snapshot.serial() || identical(&current.digest)) => {}
+        Some(current) => {
+            return Err(anyhow!(
+                "The current rsync revision (session {}, serial {}) does not match the RRDP snapshot (session {}, serial {})",
+                current.session_id,
+                current.serial,
+                snapshot.session_id(),
+                snapshot.serial()
+            ))
+        }
+        None => return Err(anyhow!("There is no current rsync revision to verify")),
+    }
+
+    let current_path = config.rsync_dir_current();
+    let mut report = VerifyReport::default();
+    let mut expected = std::collections::HashSet::new();
+
+    let manifest_path = current_path.join(config::RSYNC_MANIFEST_FNAME);
+    let mut manifest = if manifest_path.is_file() {
+        expected.insert(manifest_path.clone());
+        Some(RevisionManifest::read(&manifest_path)?.into_hashes()?)
+    } else {
+        None
+    };
+
+    process_snapshot_objects(&snapshot_path, config, |object| {
+        if config.rsync_excluded(object.uri()) || shadowed.contains(object.uri().as_str()) {
+            return Ok(());
+        }
+
+        if let Some(manifest) = manifest.as_mut() {
+            let uri = config.rewrite_rsync_uri(object.uri());
+            if manifest.remove(&uri) != Some(object.hash().to_string()) {
+                report.manifest_mismatches.push(uri);
+            }
+        }
+
+        let path = make_rsync_repo_path(&current_path, object.uri(), config.rsync_path_prefix())?;
+        if !path.is_file() {
+            report.missing.push(path.clone());
+        } else if object.hash().matches(file_ops::read_file(&path)?.as_ref()) {
+            report.verified += 1;
+        } else {
+            report.corrupted.push(path.clone());
+        }
+        expected.insert(path);
+        Ok(())
+    })?;
+
+    if let Some(name) = config.rsync_module_readme.as_ref().and_then(|path| path.file_name()) {
+        expected.insert(current_path.join(name));
+    }
+    let index_path = current_path.join(config::RSYNC_INDEX_FNAME);
+    if index_path.is_file() {
+        expected.insert(index_path);
+    }
+
+    // Whatever is left in the manifest is not in the snapshot
+    if let Some(manifest) = manifest {
+        report.manifest_mismatches.extend(manifest.into_keys());
+        report.manifest_mismatches.sort();
+    }
+
+    report.extra = file_ops::files_recursive(&current_path)?
+        .into_iter()
+        .filter(|path| !expected.contains(path))
+        .collect();
+
+    Ok(report)
+}
+
+/// The result of `diff_revisions`.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Serialize)]
+pub struct RevisionDiff {
+    /// The dir names of the compared revisions.
+    pub from: String,
+    pub to: String,
+
+    /// Whether objects are identified by their path in the revision dir,
+    /// rather than by URI, because the objects of a revision are unknown.
+    pub by_path: bool,
+
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+}
+
+impl RevisionDiff {
+    fn between(
+        from: String,
+        to: String,
+        by_path: bool,
+        previous: &BTreeMap<String, String>,
+        new: &BTreeMap<String, String>,
+    ) -> Self {
+        let mut diff = RevisionDiff {
+            from,
+            to,
+            by_path,
+            ..Default::default()
+        };
+        for (key, hash) in new {
+            match previous.get(key) {
+                None => diff.added.push(key.clone()),
+                Some(previous_hash) if previous_hash != hash => diff.changed.push(key.clone()),
+                Some(_) => {}
+            }
+        }
+        diff.removed = previous.keys().filter(|key| !new.contains_key(*key)).cloned().collect();
+        diff
+    }
+}
+
+/// Compares the objects of the rsync revisions for two serials, which must
+/// still be known in the state or be present in the rsync dir. If there is
+/// more than one revision for a serial, e.g. for another session, then the
+/// most recent one is used.
+///
+/// Objects are taken from the revision manifest, see
+/// `config.rsync_write_manifest`, or else from `config.rsync_objects_path`,
+/// which only has them for the current and staged revision. If they are
+/// unknown for either revision, then the files in both revision dirs are
+/// compared by path.
+pub fn diff_revisions(from: u64, to: u64, config: &Config) -> Result<RevisionDiff> {
+    let rsync_state = RsyncDirState::recover(config)?;
+    let from = rsync_state.find_serial(from, config)?;
+    let to = rsync_state.find_serial(to, config)?;
+    diff_found_revisions(&from, &to, config)
+}
+
+fn diff_found_revisions(
+    from: &FoundRevision,
+    to: &FoundRevision,
+    config: &Config,
+) -> Result<RevisionDiff> {
+    if let (Some(previous), Some(new)) = (from.objects(config)?, to.objects(config)?) {
+        return Ok(RevisionDiff::between(
+            from.name.clone(),
+            to.name.clone(),
+            false,
+            &previous,
+            &new,
+        ));
+    }
+
+    debug!("Objects of {} or {} are unknown, comparing files", from.name, to.name);
+    Ok(RevisionDiff::between(
+        from.name.clone(),
+        to.name.clone(),
+        true,
+        &from.files(config)?,
+        &to.files(config)?,
+    ))
+}
+
+/// Copies the objects which were added or changed between the rsync revision
+/// for the serial and the current revision to the export dir, at the same
+/// paths as in the revision dir. The changes, see `diff_revisions`, are
+/// written to `config::EXPORT_CHANGES_FNAME` in the export dir, so that a
+/// downstream also knows which objects were removed.
+///
+/// The export dir must not exist yet or be empty, so that it never mixes
+/// objects of different exports.
+pub fn export_changes(since: u64, export_dir: &Path, config: &Config) -> Result<RevisionDiff> {
+    let rsync_state = RsyncDirState::recover(config)?;
+    let current = rsync_state
+        .current
+        .as_ref()
+        .ok_or_else(|| anyhow!("There is no current rsync revision to export"))?;
+    let from = rsync_state.find_serial(since, config)?;
+    let to = FoundRevision {
+        name: current.dir_name(),
+        path: config.rsync_dir_current(),
+        objects: current.objects.clone(),
+    };
+
+    if export_dir.exists() && std::fs::read_dir(export_dir)?.next().is_some() {
+        return Err(anyhow!("Export dir {} is not empty", export_dir.display()));
+    }
+
+    let changes = diff_found_revisions(&from, &to, config)?;
+    let paths = match changes.by_path {
+        true => None,
+        false => to.object_paths(config)?,
+    };
+    for key in changes.added.iter().chain(changes.changed.iter()) {
+        let rel_path = match &paths {
+            Some(paths) => paths
+                .get(key)
+                .ok_or_else(|| anyhow!("Cannot find the path of {} in {}", key, to.name))?,
+            None => key,
+        };
+        let source = to.path.join(rel_path);
+        let target = export_dir.join(rel_path);
+        file_ops::create_parent_dir(&target)?;
+        std::fs::copy(&source, &target).with_context(|| {
+            format!("Cannot copy {} to {}", source.display(), target.display())
+        })?;
+    }
+
+    let path = export_dir.join(config::EXPORT_CHANGES_FNAME);
+    let json = serde_json::to_string_pretty(&changes)?;
+    file_ops::write_buf(&path, json.as_bytes())
+        .with_context(|| format!("Could not write export changes: {}", path.display()))?;
+
+    Ok(changes)
+}
+
+/// A revision to compare in `diff_revisions`.
+struct FoundRevision {
+    name: String,
+    path: PathBuf,
+    objects: Option<BTreeMap<String, String>>,
+}
+
+impl FoundRevision {
+    /// Returns the hash of each object by URI, if known. The URIs are
+    /// rewritten like in the manifest.
+    fn objects(&self, config: &Config) -> Result<Option<BTreeMap<String, String>>> {
+        let manifest_path = self.path.join(config::RSYNC_MANIFEST_FNAME);
+        if manifest_path.is_file() {
+            let manifest = RevisionManifest::read(&manifest_path)?;
+            return Ok(Some(manifest.into_hashes()?.into_iter().collect()));
+        }
+
+        let objects = match &self.objects {
+            Some(objects) => objects,
+            None => return Ok(None),
+        };
+        let mut rewritten = BTreeMap::new();
+        for (uri, hash) in objects {
+            let uri = rpki::uri::Rsync::from_string(uri.clone())
+                .with_context(|| format!("Invalid object URI in rsync revision objects: {}", uri))?;
+            rewritten.insert(config.rewrite_rsync_uri(&uri), hash.clone());
+        }
+        Ok(Some(rewritten))
+    }
+
+    /// Returns the path relative to the revision dir of each object by URI,
+    /// if the objects are known, see `objects`.
+    fn object_paths(&self, config: &Config) -> Result<Option<BTreeMap<String, String>>> {
+        let manifest_path = self.path.join(config::RSYNC_MANIFEST_FNAME);
+        if manifest_path.is_file() {
+            let manifest = RevisionManifest::read(&manifest_path)?;
+            return Ok(Some(manifest.objects.into_iter().map(|e| (e.uri, e.path)).collect()));
+        }
+
+        let objects = match &self.objects {
+            Some(objects) => objects,
+            None => return Ok(None),
+        };
+        let mut paths = BTreeMap::new();
+        for uri in objects.keys() {
+            let uri = rpki::uri::Rsync::from_string(uri.clone())
+                .with_context(|| format!("Invalid object URI in rsync revision objects: {}", uri))?;
+            let path = make_rsync_repo_path(Path::new(""), &uri, config.rsync_path_prefix())?;
+            paths.insert(config.rewrite_rsync_uri(&uri), path.to_string_lossy().to_string());
+        }
+        Ok(Some(paths))
+    }
+
+    /// Returns the hash of each file by path, leaving out the files written
+    /// by krill-sync itself.
+    fn files(&self, config: &Config) -> Result<BTreeMap<String, String>> {
+        let readme = config.rsync_module_readme.as_ref().and_then(|path| path.file_name());
+        let mut files = BTreeMap::new();
+        for file in file_ops::files_recursive(&self.path)? {
+            let rel = file.strip_prefix(&self.path).unwrap_or(&file);
+            let name = rel.as_os_str();
+            if name == config::RSYNC_MANIFEST_FNAME
+                || name == config::RSYNC_INDEX_FNAME
+                || Some(name) == readme
+            {
+                continue;
+            }
+            let hash = Hash::from_data(file_ops::read_file(&file)?.as_ref());
+            files.insert(rel.to_string_lossy().to_string(), hash.to_string());
+        }
+        Ok(files)
+    }
+}
+
+/// Logs what an update would do, without making any changes on disk.
+fn report_dry_run(
+    snapshot_path: &Path,
+    new_revision: &RsyncRevision,
+    rsync_state: &RsyncDirState,
+    changed: bool,
+    config: &Config,
+) -> Result<()> {
+    if changed {
+        let out_path = new_revision.path(config);
+
+        let mut total = 0;
+        let mut count = 0;
+        let mut bytes = 0;
+        process_snapshot_objects(snapshot_path, config, |object| {
+            total += 1;
+            if !config.rsync_excluded(object.uri()) {
+                let path =
+                    make_rsync_repo_path(&out_path, object.uri(), config.rsync_path_prefix())?;
+                debug!("Dry run: would write {} to {}", object.uri(), path.display());
+                count += 1;
+                bytes += object.data().len();
+            }
+            Ok(())
+        })?;
+
+        info!(
+            "Dry run: would write {} objects ({} bytes) to {}, excluding {}",
+            count,
+            bytes,
+            out_path.display(),
+            total - count
+        );
+
+        if config.rsync_dir_use_symlinks() && rsync_state.symlinks_supported != Some(false) {
+            info!(
+                "Dry run: would update symlink '{}' to '{}'",
+                config.rsync_dir_current().display(),
+                new_revision.dir_name()
+            );
+        } else {
+            info!(
+                "Dry run: would rename '{}' to '{}'",
+                out_path.display(),
+                config.rsync_dir_current().display()
+            );
+        }
+
+        if let Some(current) = &rsync_state.current {
+            info!(
+                "Dry run: would deprecate the current revision {}",
+                current.dir_name()
+            );
+        }
+    } else {
+        info!("Dry run: no changes, would not write a new rsync revision");
+    }
+
+    let clean_before = Time::seconds_ago(config.cleanup_after);
+    let mut cleaned = 0;
+    for old in rsync_state.old_to_clean(clean_before, config.rsync_keep_min_revisions) {
+        let path = old.revision.path(config);
+        if path.exists() {
+            info!(
+                "Dry run: would remove rsync directory: {}, deprecated since: {}",
+                path.display(),
+                old.since
+            );
+            cleaned += 1;
+        }
+    }
+    info!("Dry run: would remove {} old rsync directories", cleaned);
+
+    Ok(())
+}
+
+/// Renames the completely written temporary directory to the directory for
+/// the new revision. An existing directory for the revision, e.g. one which
+/// was deprecated before a serial rollback, is replaced.
+///
+/// If the temporary directory is in `config.tmp_dir` on another file system,
+/// then it cannot be renamed. It is then copied next to the revision dir
+/// first and renamed from there, so the revision dir still only appears when
+/// complete.
+fn move_tmp_dir_to_revision_dir(tmp_path: &Path, out_path: &Path, config: &Config) -> Result<()> {
+    let fs = config.fs_retry();
+    if out_path.exists() {
+        warn!("Replacing existing rsync dir: {}", out_path.display());
+        fs.remove_dir_all(out_path)
+            .with_context(|| format!("Cannot remove rsync dir: {}", out_path.display()))?;
+    }
+
+    // The rsync dir does not exist yet on the first run, if the revision was
+    // written to the tmp dir.
+    file_ops::create_parent_dir(out_path)?;
+
+    let rename_err = |e: std::io::Error, from: &Path| {
+        anyhow::Error::new(e).context(format!(
+            "Could not rename new rsync dir from '{}' to '{}'",
+            from.display(),
+            out_path.display()
+        ))
+    };
+
+    match fs.rename(tmp_path, out_path) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            let copy_path = file_ops::path_with_extension(out_path, config::TMP_FILE_EXT);
+            debug!(
+                "Copying new rsync dir from '{}' to '{}', because it is on another file system",
+                tmp_path.display(),
+                copy_path.display()
+            );
+            if copy_path.exists() {
+                fs.remove_dir_all(&copy_path)
+                    .with_context(|| format!("Cannot remove rsync dir: {}", copy_path.display()))?;
+            }
+            if config.fsync() {
+                file_ops::copy_dir_all(tmp_path, &copy_path, Some(&file_ops::DiskFsync))?;
+            } else {
+                file_ops::copy_dir_all(tmp_path, &copy_path, None)?;
+            }
+            fs.rename(&copy_path, out_path).map_err(|e| rename_err(e, &copy_path))?;
+            fs.remove_dir_all(tmp_path)
+                .with_context(|| format!("Cannot remove rsync dir: {}", tmp_path.display()))
+        }
+        Err(e) => Err(rename_err(e, tmp_path)),
+    }
+}
+
+/// Copies the template at `config.rsync_module_readme`, if configured, to the
+/// root of the new revision dir, replacing {session}, {serial} and
+/// {timestamp} in its content. The file keeps the name of the template.
+fn write_module_readme(out_path: &Path, session_id: Uuid, serial: u64, config: &Config) -> Result<()> {
+    let template_path = match &config.rsync_module_readme {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let template = std::fs::read_to_string(template_path).with_context(|| {
+        format!("Cannot read rsync module readme template: {}", template_path.display())
+    })?;
+    let name = template_path.file_name().ok_or_else(|| {
+        anyhow!("Rsync module readme template has no file name: {}", template_path.display())
+    })?;
+
+    let content = template
+        .replace("{session}", &session_id.to_string())
+        .replace("{serial}", &serial.to_string())
+        .replace("{timestamp}", &Time::now().timestamp().to_string());
+
+    let path = out_path.join(name);
+    file_ops::write_buf(&path, content.as_bytes())
+        .with_context(|| format!("Could not write rsync module readme: {}", path.display()))?;
+    config.file_permissions.apply_to_file(&path)?;
+
+    if let Some(timestamp) = config.normalize_mtime {
+        let mtime = FileTime::from_unix_time(timestamp, 0);
+        set_file_mtime(&path, mtime)?;
+        set_file_mtime(out_path, mtime)?;
+    }
+
+    if config.fsync() {
+        file_ops::DiskFsync.sync_file(&path)?;
+        file_ops::DiskFsync.sync_dir(out_path)?;
+    }
+
+    Ok(())
+}
+
+/// Appends the URIs of the objects written for the new revision to
+/// `config.object_log_path`, if set, after a line with the revision. With
+/// `config.log_object_hashes` each line also has the size and hash of the
+/// object. Objects which are excluded from rsync are left out.
+fn log_objects(revision: &RsyncRevision, objects: &[ObjectInfo], config: &Config) -> Result<()> {
+    let path = match &config.object_log_path {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let mut lines = format!("# {} {}\n", revision.dir_name(), Time::now());
+    for object in objects.iter().filter(|o| !config.rsync_excluded(o.uri())) {
+        let uri = config.rewrite_rsync_uri(object.uri());
+        if config.log_object_hashes {
+            lines.push_str(&format!("{} {} {}\n", uri, object.size(), object.hash()));
+        } else {
+            lines.push_str(&format!("{}\n", uri));
+        }
+    }
+
+    std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(lines.as_bytes()))
+        .with_context(|| format!("Could not write object log: {}", path.display()))
+}
+
+/// Writes an index of the new revision to `config::RSYNC_INDEX_FNAME` in the
+/// root of its dir, if `config.write_rsync_index` is set. It is structured
+/// like an RRDP snapshot, with the session and serial, but it has the URI,
+/// path, size and hash of each object, rather than its content. Objects
+/// which are excluded from rsync are left out.
+fn write_rsync_index(
+    out_path: &Path,
+    session_id: Uuid,
+    serial: u64,
+    objects: &[ObjectInfo],
+    config: &Config,
+) -> Result<()> {
+    if !config.write_rsync_index {
+        return Ok(());
+    }
+
+    let mut paths = vec![];
+    for object in objects.iter().filter(|o| !config.rsync_excluded(o.uri())) {
+        let path = make_rsync_repo_path(Path::new(""), object.uri(), config.rsync_path_prefix())?;
+        paths.push((object, path.to_string_lossy().replace('\\', "/")));
+    }
+
+    let mut xml = vec![];
+    let mut writer = rpki::xml::encode::Writer::new(&mut xml);
+    writer
+        .element(rpki::xml::decode::Name::unqualified(b"index"))?
+        .attr("version", "1")?
+        .attr("session_id", &session_id)?
+        .attr("serial", &serial)?
+        .content(|content| {
+            for (object, path) in &paths {
+                content
+                    .element(rpki::xml::decode::Name::unqualified(b"object"))?
+                    .attr("uri", config.rewrite_rsync_uri(object.uri()).as_str())?
+                    .attr("path", path.as_str())?
+                    .attr("size", &object.size())?
+                    .attr("hash", object.hash().to_string().as_str())?;
+            }
+            Ok(())
+        })?;
+    writer.done()?;
+
+    let path = out_path.join(config::RSYNC_INDEX_FNAME);
+    file_ops::write_buf(&path, &xml)
+        .with_context(|| format!("Could not write rsync index: {}", path.display()))?;
+    config.file_permissions.apply_to_file(&path)?;
+
+    if let Some(timestamp) = config.normalize_mtime {
+        let mtime = FileTime::from_unix_time(timestamp, 0);
+        set_file_mtime(&path, mtime)?;
+        set_file_mtime(out_path, mtime)?;
+    }
+
+    if config.fsync() {
+        file_ops::DiskFsync.sync_file(&path)?;
+        file_ops::DiskFsync.sync_dir(out_path)?;
+    }
+
+    Ok(())
+}
+
+/// Takes the lock file at `config.rsync_lock_path`, if configured, so that
+/// cooperating tools, e.g. a process which takes a backup of the rsync dir,
+/// can wait until we have written the new revision and made it current.
+///
+/// This is an advisory lock: on unix we take an exclusive flock(2) on the
+/// file, and block until we get it. Cooperating tools should take a shared
+/// or exclusive flock on the same file themselves, e.g. using flock(1). It
+/// does not prevent any access by processes which do not take the lock.
+/// The lock is released when the returned FileLock is dropped, i.e. on
+/// success as well as on errors, or when the process exits. The file itself
+/// is left in place.
+fn lock_rsync_dir(config: &Config) -> Result<Option<FileLock>> {
+    match &config.rsync_lock_path {
+        None => Ok(None),
+        Some(lock_path) => {
+            file_ops::create_parent_dir(lock_path)?;
+            debug!("Waiting for rsync lock file: {}", lock_path.display());
+            file_ops::lock_exclusive(lock_path).map(Some)
+        }
+    }
+}
+
+/// Create a new symlink then rename it. We need to do this because the std library
+/// refuses to overwrite an existing symlink. And if we were to remove it first, then
+/// we would introduce a race condition for clients accessing.
+fn symlink_current_to_new_revision_dir(
+    new_revision: &RsyncRevision,
+    rsync_state: &RsyncDirState,
+    config: &Config,
+) -> Result<()> {
+    info!(
+        "Updating symlink 'current' to '{}' under rsync dir '{}'",
+        new_revision.dir_name(),
+        config.rsync_dir.display()
+    );
+    let fs = config.fs_retry();
+    let current_path = config.rsync_dir_current();
+
+    // If the previous revision was renamed to current, e.g. because we fell
+    // back to renaming before, then it has to be renamed back first.
+    let current_is_dir = current_path
+        .symlink_metadata()
+        .map(|meta| meta.is_dir())
+        .unwrap_or(false);
+    if let (true, Some(current)) = (current_is_dir, &rsync_state.current) {
+        let current_preserve_path = current.path(config);
+        fs.rename(&current_path, &current_preserve_path).with_context(|| {
+            format!(
+                "Could not rename current rsync dir from '{}' to '{}'",
+                current_path.display(),
+                current_preserve_path.display()
+            )
+        })?;
+    }
+
+    let tmp_name = file_ops::path_with_extension(&current_path, config::TMP_FILE_EXT);
+    if tmp_name.symlink_metadata().is_ok() {
+        file_ops::remove_symlink(&tmp_name).with_context(|| {
+            format!(
+                "Could not remove lingering temporary symlink for current rsync dir at '{}'",
+                tmp_name.display()
+            )
+        })?;
+    }
+
+    // A relative target keeps working when the rsync dir is moved or copied
+    let target = if config.symlink_absolute {
+        let path = new_revision.path(config);
+        path.canonicalize()
+            .with_context(|| format!("Cannot get absolute path of rsync dir '{}'", path.display()))?
+    } else {
+        PathBuf::from(new_revision.dir_name())
+    };
+
+    file_ops::create_symlink(&target, &tmp_name).with_context(|| {
+        format!(
+            "Could not create temporary symlink for new rsync content at '{}'",
+            tmp_name.display()
+        )
+    })?;
+
+    if let Err(e) = fs.rename(&tmp_name, &current_path) {
+        // Do not leave the temporary symlink behind, in case the caller
+        // falls back to renaming directories instead.
+        let _ = file_ops::remove_symlink(&tmp_name);
+        return Err(e).with_context(|| {
+            format!(
+                "Could not rename symlink for current rsync dir from '{}' to '{}'",
+                tmp_name.display(),
+                current_path.display()
+            )
+        });
+    }
+
+    Ok(())
+}
+
+/// Rename the path for the new revision to the current rsync path, *after*
+/// renaming any existing current path to the serial and session for that
+/// revision.
+fn rename_new_revision_dir_to_current(
+    new_revision: &RsyncRevision,
+    rsync_state: &RsyncDirState,
+    config: &Config,
+) -> Result<()> {
+    info!("Renaming rsync folders for close to atomic update of the rsync module dir");
+
+    let fs = config.fs_retry();
+    let current_path = config.rsync_dir_current();
+
+    // If current is a symlink, e.g. because we fell back from using symlinks,
+    // then the previous revision already has its own directory.
+    let current_is_symlink = current_path
+        .symlink_metadata()
+        .map(|meta| meta.file_type().is_symlink())
+        .unwrap_or(false);
+    if current_is_symlink {
+        file_ops::remove_symlink(&current_path)?;
+    } else if let Some(current) = &rsync_state.current {
+        let current_preserve_path = current.path(config);
+
+        if current_path.exists() {
+            info!(
+                "Renaming the rsync directory for previous revision to: {}",
+                current_preserve_path.display()
+            );
+            fs.rename(&current_path, &current_preserve_path).with_context(|| {
+                format!(
+                    "Could not rename current rsync dir from '{}' to '{}'",
+                    current_path.display(),
+                    current_preserve_path.display()
+                )
+            })?;
+        }
+    }
+
+    // The current dir may be of an unknown revision, if the state had to be
+    // reconstructed. It cannot be preserved under its own name, so it is
+    // moved out of the way and removed once the new revision is in place.
+    let unknown_path = file_ops::path_with_extension(&current_path, config::TMP_FILE_EXT);
+    let current_is_unknown = !current_is_symlink && rsync_state.current.is_none() && current_path.exists();
+    if current_is_unknown {
+        warn!(
+            "Removing rsync directory for unknown previous revision: {}",
+            current_path.display()
+        );
+        fs.rename(&current_path, &unknown_path).with_context(|| {
+            format!(
+                "Could not rename current rsync dir from '{}' to '{}'",
+                current_path.display(),
+                unknown_path.display()
+            )
+        })?;
+    }
+
+    info!(
+        "Rename rsync dir for new revision to '{}'",
+        current_path.display()
+    );
+    fs.rename(new_revision.path(config), &current_path).with_context(|| {
+        format!(
+            "Could not rename new rsync dir from '{}' to '{}'",
+            new_revision.path(config).display(),
+            current_path.display()
+        )
+    })?;
+
+    if current_is_unknown {
+        fs.remove_dir_all(&unknown_path)
+            .with_context(|| format!("Could not remove rsync dir at: {}", unknown_path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// The extension of the dir next to the rsync dir in which a new revision
+/// waits to be swapped in, with the flat layout.
+const FLAT_NEW_EXT: &str = "new";
+
+/// Returns the path next to the rsync dir with the given extension added to
+/// its name, e.g. 'rsync.new' for '/var/lib/krill-sync/rsync'.
+fn flat_sibling_path(config: &Config, ext: &str) -> PathBuf {
+    let name = config.rsync_dir.file_name().unwrap_or_default().to_string_lossy();
+    config.rsync_dir.with_file_name(format!("{}.{}", name, ext))
+}
+
+/// The extensions of the dirs next to the rsync dir which hold the content,
+/// in turn, if the rsync dir is a symlink with the flat layout.
+const FLAT_LINKED_EXTS: [&str; 2] = ["a", "b"];
+
+/// Replaces the rsync dir with the new revision, with the flat layout. Where
+/// the file system supports it, the two dirs are exchanged atomically.
+/// Otherwise the rsync dir is made a symlink, which can be replaced by
+/// renaming another one over it, as with the 'current' symlink. Only if that
+/// is not possible either, the rsync dir is renamed out of the way first, so
+/// that for a moment there is no content at all. If we are interrupted then,
+/// the previous content is put back by `restore_interrupted_flat_swap` on
+/// the next run.
+fn swap_flat_rsync_dir(new_revision: &RsyncRevision, config: &Config) -> Result<()> {
+    let rsync_dir = &config.rsync_dir;
+    let new_path = new_revision.path(config);
+    let fs = config.fs_retry();
+
+    info!(
+        "Replacing rsync dir '{}' with new revision '{}'",
+        rsync_dir.display(),
+        new_revision.dir_name()
+    );
+
+    let rsync_dir_type = rsync_dir.symlink_metadata().ok().map(|meta| meta.file_type());
+    match rsync_dir_type {
+        Some(file_type) if file_type.is_symlink() => {
+            return symlink_flat_rsync_dir(&new_path, config);
+        }
+        Some(_) => match fs.exchange(&new_path, rsync_dir) {
+            Ok(()) => {
+                sync_flat_rsync_dir_parent(config)?;
+                // The previous content is now at the new path
+                return fs.remove_dir_all(&new_path).with_context(|| {
+                    format!("Could not remove rsync dir at: {}", new_path.display())
+                });
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Unsupported => {
+                let parent = flat_rsync_dir_parent(config);
+                if config.rsync_dir_use_symlinks() && file_ops::symlink_replace_supported(parent) {
+                    return symlink_flat_rsync_dir(&new_path, config);
+                }
+                warn!(
+                    "Cannot replace rsync dir '{}' atomically, it will be missing for a moment: {}",
+                    rsync_dir.display(),
+                    e
+                );
+            }
+            Err(e) => {
+                return Err(e).with_context(|| {
+                    format!(
+                        "Could not exchange rsync dir '{}' with '{}'",
+                        rsync_dir.display(),
+                        new_path.display()
+                    )
+                });
+            }
+        },
+        None => {}
+    }
+
+    rename_flat_rsync_dir(&new_path, rsync_dir, config)
+}
+
+/// Replaces whatever is at the rsync dir with `new_path`, by renaming the
+/// rsync dir out of the way first. Renaming a symlink over a dir is not
+/// possible, so this is also used to replace the rsync dir with a symlink.
+fn rename_flat_rsync_dir(new_path: &Path, rsync_dir: &Path, config: &Config) -> Result<()> {
+    let old_path = flat_sibling_path(config, config::OLD_FILE_EXT);
+    let fs = config.fs_retry();
+
+    if rsync_dir.exists() {
+        fs.rename(rsync_dir, &old_path).with_context(|| {
+            format!(
+                "Could not rename rsync dir from '{}' to '{}'",
+                rsync_dir.display(),
+                old_path.display()
+            )
+        })?;
+    }
+
+    fs.rename(new_path, rsync_dir).with_context(|| {
+        format!(
+            "Could not rename new rsync dir from '{}' to '{}'",
+            new_path.display(),
+            rsync_dir.display()
+        )
+    })?;
+
+    sync_flat_rsync_dir_parent(config)?;
+
+    if old_path.exists() {
+        fs.remove_dir_all(&old_path)
+            .with_context(|| format!("Could not remove rsync dir at: {}", old_path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Makes the rsync dir a symlink to the content at `new_path`, which is first
+/// renamed to the one of the `FLAT_LINKED_EXTS` siblings which the rsync dir
+/// does not point to. The other one is removed after the symlink is swapped.
+fn symlink_flat_rsync_dir(new_path: &Path, config: &Config) -> Result<()> {
+    let rsync_dir = &config.rsync_dir;
+    let fs = config.fs_retry();
+
+    let [first, second] = FLAT_LINKED_EXTS.map(|ext| flat_sibling_path(config, ext));
+    let previous = std::fs::read_link(rsync_dir).ok();
+    let points_to = |path: &Path| {
+        previous.as_ref().map(|target| target.file_name() == path.file_name()).unwrap_or(false)
+    };
+    let (linked_path, previous_path) =
+        if points_to(&first) { (second, first) } else { (first, second) };
+
+    // Left behind if we were interrupted before
+    if linked_path.exists() {
+        fs.remove_dir_all(&linked_path)
+            .with_context(|| format!("Could not remove rsync dir at: {}", linked_path.display()))?;
+    }
+    fs.rename(new_path, &linked_path).with_context(|| {
+        format!(
+            "Could not rename new rsync dir from '{}' to '{}'",
+            new_path.display(),
+            linked_path.display()
+        )
+    })?;
+
+    let tmp_link = flat_sibling_path(config, "symlink.tmp");
+    if tmp_link.symlink_metadata().is_ok() {
+        file_ops::remove_symlink(&tmp_link).with_context(|| {
+            format!(
+                "Could not remove lingering temporary symlink for rsync dir at '{}'",
+                tmp_link.display()
+            )
+        })?;
+    }
+
+    let target = if config.symlink_absolute {
+        linked_path.canonicalize().with_context(|| {
+            format!("Cannot get absolute path of rsync dir '{}'", linked_path.display())
+        })?
+    } else {
+        PathBuf::from(linked_path.file_name().unwrap_or_default())
+    };
+    file_ops::create_symlink(&target, &tmp_link)?;
+
+    if previous.is_some() {
+        fs.rename(&tmp_link, rsync_dir).with_context(|| {
+            format!(
+                "Could not rename temporary symlink '{}' to '{}'",
+                tmp_link.display(),
+                rsync_dir.display()
+            )
+        })?;
+        sync_flat_rsync_dir_parent(config)?;
+    } else {
+        warn!(
+            "Replacing rsync dir '{}' with a symlink, so that it can be swapped atomically \
+             from now on. It will be missing for a moment this once.",
+            rsync_dir.display()
+        );
+        rename_flat_rsync_dir(&tmp_link, rsync_dir, config)?;
+    }
+
+    if previous_path.exists() {
+        fs.remove_dir_all(&previous_path).with_context(|| {
+            format!("Could not remove rsync dir at: {}", previous_path.display())
+        })?;
+    }
+
+    Ok(())
+}
+
+/// The dir which contains the rsync dir, with the flat layout.
+fn flat_rsync_dir_parent(config: &Config) -> &Path {
+    match config.rsync_dir.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+fn sync_flat_rsync_dir_parent(config: &Config) -> Result<()> {
+    let parent = flat_rsync_dir_parent(config);
+    if config.fsync() && parent.is_dir() {
+        file_ops::DiskFsync.sync_dir(parent)?;
+    }
+    Ok(())
+}
+
+/// Cleans up after a flat layout swap which was interrupted: the previous
+/// content is renamed back if the rsync dir is missing, and removed if
+/// the new revision was already swapped in.
+fn restore_interrupted_flat_swap(config: &Config) -> Result<()> {
+    let rsync_dir = &config.rsync_dir;
+    let old_path = flat_sibling_path(config, config::OLD_FILE_EXT);
+    if !old_path.exists() {
+        return Ok(());
+    }
+
+    let fs = config.fs_retry();
+    if rsync_dir.exists() {
+        warn!("Removing previous rsync dir left by an interrupted swap: {}", old_path.display());
+        fs.remove_dir_all(&old_path)
+            .with_context(|| format!("Could not remove rsync dir at: {}", old_path.display()))
+    } else {
+        warn!("Restoring rsync dir from interrupted swap: {}", old_path.display());
+        fs.rename(&old_path, rsync_dir).with_context(|| {
+            format!(
+                "Could not rename rsync dir from '{}' to '{}'",
+                old_path.display(),
+                rsync_dir.display()
+            )
+        })
+    }
+}
+
+/// The version of the rsync state file format written by this version. State
+/// files without a version were written before it was added, and count as
+/// version 0. See `RsyncDirState::upgrade` for how older versions are read.
+const RSYNC_STATE_VERSION: u64 = 1;
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct RsyncDirState {
+    version: u64,
+    current: Option<RsyncRevision>,
+    old: Vec<DeprecatedRsyncRevision>,
+
+    /// A revision which was written by 'stage', but not yet made current.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending: Option<RsyncRevision>,
+
+    /// Whether symlinks can be replaced in the rsync dir, as found in 'auto'
+    /// swap mode. Not present in state written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    symlinks_supported: Option<bool>,
+}
+
+impl RsyncDirState {
+    /// Gets the current state from disk, if a state file exists. Otherwise returns
+    /// a new blank state.
+    ///
+    /// If the state file cannot be parsed, e.g. because it was truncated, then
+    /// it is backed up with a '.corrupt' extension and a best-effort state is
+    /// reconstructed from the revision directories in the rsync dir.
+    fn recover(config: &Config) -> Result<Self> {
+        let state_path = config.rsync_state_path();
+        if state_path.exists() {
+            let json_bytes = file_ops::read_file(&state_path).with_context(|| {
+                format!("Cannot read rsync state file at: {}", state_path.display())
+            })?;
+            let parsed = match serde_json::from_slice(json_bytes.as_ref()) {
+                Ok(value) => serde_json::from_value::<Self>(Self::upgrade(value, &state_path)?),
+                Err(e) => Err(e),
+            };
+            match parsed {
+                Ok(mut state) => {
+                    state.read_objects(config);
+                    Ok(state)
+                }
+                Err(e) => {
+                    let backup_path = file_ops::path_with_extension(&state_path, "corrupt");
+                    error!(
+                        "Rsync state file at {} is corrupt, moving it to {} and reconstructing state from the rsync dir. Error: {}",
+                        state_path.display(),
+                        backup_path.display(),
+                        e
+                    );
+                    let fs = config.fs_retry();
+                    fs.rename(&state_path, &backup_path).with_context(|| {
+                        format!(
+                            "Could not move corrupt rsync state file from '{}' to '{}'",
+                            state_path.display(),
+                            backup_path.display()
+                        )
+                    })?;
+                    Self::reconstruct(config)
+                }
+            }
+        } else {
+            Ok(Self::empty())
+        }
+    }
+
+    /// Upgrades the JSON of a state file written by an older version to the
+    /// current format. Returns an error for a version newer than we know,
+    /// rather than treating the file as corrupt, because reconstructing the
+    /// state would lose what the newer version recorded.
+    fn upgrade(mut value: serde_json::Value, state_path: &Path) -> Result<serde_json::Value> {
+        let version = match value.get("version") {
+            Some(version) => version.as_u64().ok_or_else(|| {
+                let path = state_path.display();
+                anyhow!("Invalid version {} in rsync state file at {}", version, path)
+            })?,
+            None => 0,
+        };
+        if version > RSYNC_STATE_VERSION {
+            return Err(anyhow!(
+                "Rsync state file at {} has version {}, but this version of krill-sync only understands up to version {}. It was probably written by a newer version of krill-sync.",
+                state_path.display(),
+                version,
+                RSYNC_STATE_VERSION
+            ));
+        }
+
+        // Version 0 has the same fields as version 1, fields which were added
+        // before versioning have serde defaults. Conversions for later
+        // versions go here, in order.
+        if version < RSYNC_STATE_VERSION {
+            debug!(
+                "Upgrading rsync state file from version {} to {}",
+                version, RSYNC_STATE_VERSION
+            );
+        }
+
+        // Anything else than an object fails to parse, as a corrupt state.
+        if let Some(object) = value.as_object_mut() {
+            object.insert("version".to_string(), RSYNC_STATE_VERSION.into());
+        }
+        Ok(value)
+    }
+
+    fn empty() -> Self {
+        RsyncDirState {
+            version: RSYNC_STATE_VERSION,
+            current: None,
+            old: vec![],
+            pending: None,
+            symlinks_supported: None,
+        }
+    }
+
+    /// Reconstructs the state from the revision directories found in the rsync
+    /// dir. The directory that the 'current' symlink points to becomes the
+    /// current revision, and all other revision directories are deprecated
+    /// now, in order of serial, so that they are cleaned up as usual.
+    ///
+    /// A staged revision cannot be told apart from an old one, and the
+    /// revision of a 'current' directory which is not a symlink is unknown.
+    /// Values which are not part of the directory name template are set to a
+    /// nil session and serial 0.
+    fn reconstruct(config: &Config) -> Result<Self> {
+        let mut state = Self::empty();
+        if !config.rsync_dir.is_dir() {
+            warn!("Rsync dir {} does not exist, starting from an empty state", config.rsync_dir.display());
+            return Ok(state);
+        }
+
+        let current_name = std::fs::read_link(config.rsync_dir_current())
+            .ok()
+            .and_then(|target| target.file_name().map(|name| name.to_string_lossy().to_string()));
+
+        let mut revisions = vec![];
+        let entries = std::fs::read_dir(&config.rsync_dir)
+            .with_context(|| format!("Cannot read rsync dir {}", config.rsync_dir.display()))?;
+        for entry in entries {
+            let entry = entry
+                .with_context(|| format!("Cannot read entry in rsync dir {}", config.rsync_dir.display()))?;
+            if !entry.file_type()?.is_dir() {
+                continue;
+            }
+
+            let name = entry.file_name().to_string_lossy().to_string();
+            let values = dir_name_values(&name, &config.rsync_dir_name_template)
+                .or_else(|| dir_name_values(&name, config::DEFAULT_RSYNC_DIR_NAME_TEMPLATE));
+            if let Some((session_id, serial)) = values {
+                revisions.push(RsyncRevision {
+                    session_id: session_id.unwrap_or_else(Uuid::nil),
+                    serial: serial.unwrap_or(0),
+                    dir_name: Some(name),
+                    object_count: None,
+                    digest: None,
+                    objects: None,
+                    staged: false,
+                });
+            }
+        }
+        revisions.sort_by_key(|revision| revision.serial);
+
+        for revision in revisions {
+            if Some(revision.dir_name()) == current_name {
+                state.current = Some(revision);
+            } else {
+                state.old.push(revision.deprecate());
+            }
+        }
+
+        warn!(
+            "Reconstructed rsync state with current revision: {}, and {} old revisions",
+            state
+                .current
+                .as_ref()
+                .map(|current| current.dir_name())
+                .unwrap_or_else(|| "none".to_string()),
+            state.old.len()
+        );
+
+        Ok(state)
+    }
+
+    /// Finds revision directories in the rsync dir which are not known in this
+    /// state, e.g. because krill-sync was interrupted after writing a new
+    /// revision but before persisting its state. Such orphans are logged, or
+    /// removed if `config.rsync_remove_orphans` is set (and this is not a dry
+    /// run). Only directories with names that match the configured (or the
+    /// default) revision directory name template are considered.
+    fn reconcile(&self, config: &Config) -> Result<()> {
+        // With the flat layout the rsync dir holds the content, rather than
+        // revision dirs.
+        if config.rsync_flat_layout {
+            return restore_interrupted_flat_swap(config);
+        }
+
+        if !config.rsync_dir.is_dir() {
+            return Ok(());
+        }
+
+        let mut known: Vec<String> = self.old.iter().map(|old| old.revision.dir_name()).collect();
+        if let Some(current) = &self.current {
+            known.push(current.dir_name());
+        }
+        if let Some(pending) = &self.pending {
+            known.push(pending.dir_name());
+        }
+
+        // Never consider whatever 'current' points to an orphan, even if our
+        // state would not know about it.
+        if let Ok(target) = std::fs::read_link(config.rsync_dir_current()) {
+            if let Some(name) = target.file_name() {
+                known.push(name.to_string_lossy().to_string());
+            }
+        }
+
+        let entries = std::fs::read_dir(&config.rsync_dir)
+            .with_context(|| format!("Cannot read rsync dir {}", config.rsync_dir.display()))?;
+
+        for entry in entries {
+            let entry = entry
+                .with_context(|| format!("Cannot read entry in rsync dir {}", config.rsync_dir.display()))?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let path = entry.path();
+
+            // file_type does not follow symlinks, so 'current' is skipped here
+            // when it is a symlink.
+            if !entry.file_type()?.is_dir()
+                || path == config.rsync_dir_current()
+                || known.contains(&name)
+                || !(dir_name_matches_template(&name, &config.rsync_dir_name_template)
+                    || dir_name_matches_template(&name, config::DEFAULT_RSYNC_DIR_NAME_TEMPLATE))
+            {
+                continue;
+            }
+
+            if config.rsync_remove_orphans && !config.dry_run {
+                warn!("Removing orphaned rsync revision directory: {}", path.display());
+                let fs = config.fs_retry();
+                fs.remove_dir_all(&path).with_context(|| {
+                    format!("Could not remove orphaned rsync dir at: {}", path.display())
+                })?;
+            } else {
+                warn!(
+                    "Found orphaned rsync revision directory not tracked in state: {}",
+                    path.display()
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Finds the most recent revision for the serial, in the state or else in
+    /// the rsync dir, for `diff_revisions`.
+    fn find_serial(&self, serial: u64, config: &Config) -> Result<FoundRevision> {
+        let found = |revision: &RsyncRevision, path: PathBuf| FoundRevision {
+            name: revision.dir_name(),
+            path,
+            objects: revision.objects.clone(),
+        };
+
+        if let Some(pending) = self.pending.as_ref().filter(|r| r.serial == serial) {
+            return Ok(found(pending, pending.path(config)));
+        }
+        if let Some(current) = self.current.as_ref().filter(|r| r.serial == serial) {
+            return Ok(found(current, config.rsync_dir_current()));
+        }
+        if let Some(old) = self.old.iter().rev().find(|old| old.revision.serial == serial) {
+            let revision = &old.revision;
+            let path = revision.path(config);
+            if path.is_dir() {
+                return Ok(found(revision, path));
+            }
+        }
+
+        // E.g. a revision which is no longer in the state
+        let mut dirs = vec![];
+        if config.rsync_dir.is_dir() {
+            for entry in std::fs::read_dir(&config.rsync_dir)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                let values = dir_name_values(&name, &config.rsync_dir_name_template)
+                    .or_else(|| dir_name_values(&name, config::DEFAULT_RSYNC_DIR_NAME_TEMPLATE));
+                if entry.file_type()?.is_dir() && values.is_some_and(|(_, s)| s == Some(serial)) {
+                    dirs.push((entry.metadata()?.modified()?, name, entry.path()));
+                }
+            }
+        }
+        match dirs.into_iter().max() {
+            Some((_, name, path)) => Ok(FoundRevision {
+                name,
+                path,
+                objects: None,
+            }),
+            None => Err(anyhow!(
+                "There is no rsync revision for serial {} in {}",
+                serial,
+                config.rsync_dir.display()
+            )),
+        }
+    }
+
+    /// Returns whether a current, pending or old revision uses this dir name.
+    fn knows_dir_name(&self, dir_name: &str) -> bool {
+        self.current
+            .iter()
+            .chain(self.pending.iter())
+            .chain(self.old.iter().map(|old| &old.revision))
+            .any(|revision| revision.dir_name() == dir_name)
+    }
+
+    /// Returns whether to use a symlink for the current rsync dir. In 'auto'
+    /// swap mode the rsync dir is tested once, and the result remembered.
+    fn use_symlinks(&mut self, config: &Config) -> bool {
+        if !config.rsync_dir_use_symlinks() {
+            return false;
+        }
+        if config.rsync_swap != RsyncSwap::Auto {
+            return true;
+        }
+
+        *self.symlinks_supported.get_or_insert_with(|| {
+            let supported = file_ops::symlink_replace_supported(&config.rsync_dir);
+            info!(
+                "Symlinks are {}supported in rsync dir {}, will {}",
+                if supported { "" } else { "not " },
+                config.rsync_dir.display(),
+                if supported { "use symlinks" } else { "rename directories" }
+            );
+            supported
+        })
+    }
+
+    /// Persists the state to disk. Will first write to a temporary file and
+    /// then rename it, so that an interrupted run cannot leave a partial file.
+    fn persist(&self, config: &Config) -> Result<()> {
+        self.write_objects(config)?;
+
+        let state_path = config.rsync_state_path();
+        let tmp_path = file_ops::path_with_extension(&state_path, config::TMP_FILE_EXT);
+        let json = serde_json::to_string_pretty(&self)?;
+        file_ops::write_buf(&tmp_path, json.as_bytes()).with_context(|| "Could not save state.")?;
+        let fs = config.fs_retry();
+        fs.rename(&tmp_path, &state_path).with_context(|| {
+            format!("Could not rename {} to {}", tmp_path.display(), state_path.display())
+        })
+    }
+
+    /// Writes the objects of the current and pending revision, if known, to
+    /// `config.rsync_objects_path`, in the same way as `persist`.
+    fn write_objects(&self, config: &Config) -> Result<()> {
+        let objects = RevisionObjects {
+            current: self.current.as_ref().and_then(RevisionObjectsEntry::of),
+            pending: self.pending.as_ref().and_then(RevisionObjectsEntry::of),
+        };
+
+        let objects_path = config.rsync_objects_path();
+        let tmp_path = file_ops::path_with_extension(&objects_path, config::TMP_FILE_EXT);
+        let json = serde_json::to_string(&objects)?;
+        file_ops::write_buf(&tmp_path, json.as_bytes())
+            .with_context(|| "Could not save rsync revision objects.")?;
+        let fs = config.fs_retry();
+        fs.rename(&tmp_path, &objects_path).with_context(|| {
+            format!("Could not rename {} to {}", tmp_path.display(), objects_path.display())
+        })
+    }
+
+    /// Sets the objects of the current and pending revision from
+    /// `config.rsync_objects_path`, if they are there for the same revision
+    /// dirs. Otherwise the objects stay unknown, which is not an error: the
+    /// changes are then not counted, and revisions are compared by file.
+    fn read_objects(&mut self, config: &Config) {
+        let objects_path = config.rsync_objects_path();
+        if !objects_path.exists() {
+            return;
+        }
+        let objects = file_ops::read_file(&objects_path)
+            .and_then(|json| Ok(serde_json::from_slice::<RevisionObjects>(json.as_ref())?));
+        match objects {
+            Ok(objects) => {
+                if let Some(current) = &mut self.current {
+                    current.objects = objects.current.and_then(|entry| entry.objects_for(current));
+                }
+                if let Some(pending) = &mut self.pending {
+                    pending.objects = objects.pending.and_then(|entry| entry.objects_for(pending));
+                }
+            }
+            Err(e) => warn!(
+                "Cannot read rsync revision objects at {}, ignoring them: {}",
+                objects_path.display(),
+                e
+            ),
+        }
+    }
+
+    /// See `check_not_staged`.
+    fn check_not_staged(&self) -> Result<()> {
+        match self.pending.as_ref().filter(|pending| pending.staged) {
+            Some(staged) => Err(anyhow!(
+                "Rsync revision {} is staged, use 'krill-sync publish' to publish it, or 'krill-sync stage' to replace it",
+                staged.dir_name()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a newly written revision as pending. A previously pending
+    /// revision which was never published is deprecated, unless it is the
+    /// same directory. With the flat layout it was overwritten already.
+    fn update_pending(&mut self, pending: RsyncRevision, config: &Config) {
+        if config.rsync_flat_layout {
+            self.pending = Some(pending);
+            return;
+        }
+
+        // As in update_current, the directory may have been deprecated before.
+        let dir_name = pending.dir_name();
+        self.old.retain(|old| old.revision.dir_name() != dir_name);
+
+        if let Some(existing) = self.pending.replace(pending) {
+            if existing.dir_name() != dir_name {
+                self.old.push(existing.deprecate());
+            }
+        }
+    }
+
+    /// Makes the pending revision current, by updating the symlink or by
+    /// renaming directories. Does nothing if there is no pending revision.
+    ///
+    /// If `config.pre_publish_command` is set, then it is run first, and if
+    /// it fails then the revision is not made current. It is kept pending, and
+    /// this state is persisted, so that the directory can be inspected, and
+    /// is not considered an orphan.
+    fn publish_pending(&mut self, config: &Config) -> Result<()> {
+        if let (Some(command), Some(pending)) = (&config.pre_publish_command, &self.pending) {
+            let env = [
+                ("KRILL_SYNC_REVISION_DIR", pending.path(config).display().to_string()),
+                ("KRILL_SYNC_SESSION", pending.session_id.to_string()),
+                ("KRILL_SYNC_SERIAL", pending.serial.to_string()),
+            ];
+            if let Err(e) = hook::run(command, &env) {
+                let dir = pending.path(config);
+                self.persist(config)?;
+                return Err(e.context(format!(
+                    "Pre publish command vetoed rsync revision, keeping it staged at: {}",
+                    dir.display()
+                )));
+            }
+        }
+
+        // The remote target is updated first, so that a failure there keeps
+        // the revision staged, and everything else unchanged.
+        if let Some(pending) = &self.pending {
+            if let Err(e) = config.write_target().make_current(&pending.dir_name()) {
+                self.persist(config)?;
+                return Err(e.context("Could not make the rsync revision current at the target"));
+            }
+        }
+
+        match self.pending.take() {
+            Some(pending) => self.make_current(RsyncRevision { staged: false, ..pending }, config),
+            None => Ok(()),
+        }
+    }
+
+    /// Makes the revision current, by updating the symlink or by renaming
+    /// directories, and deprecates the revision which was current.
+    fn make_current(&mut self, new_revision: RsyncRevision, config: &Config) -> Result<()> {
+        // Revisions staged or kept earlier may have been written with
+        // --allow-empty.
+        if let Some(object_count) = new_revision.object_count {
+            check_not_empty(object_count, config)?;
+        }
+        self.log_session_change(&new_revision, config);
+
+        if config.rsync_flat_layout {
+            swap_flat_rsync_dir(&new_revision, config)?;
+
+            // There is no history, so the previous revision is not kept.
+            self.current = Some(new_revision);
+            return Ok(());
+        }
+
+        let use_moves = if self.use_symlinks(config) {
+            match symlink_current_to_new_revision_dir(&new_revision, self, config) {
+                Ok(()) => false,
+                Err(e) => {
+                    // Symlinks may require privileges that we do not have,
+                    // or the filesystem cannot rename a symlink over the
+                    // existing one.
+                    warn!(
+                        "Cannot use symlink for current rsync dir, falling back to renaming directories. Error: {:?}",
+                        e
+                    );
+                    if config.rsync_swap == RsyncSwap::Auto {
+                        self.symlinks_supported = Some(false);
+                    }
+                    true
+                }
+            }
+        } else {
+            true
+        };
+
+        if use_moves {
+            rename_new_revision_dir_to_current(&new_revision, self, config)?;
+        }
+
+        // Make sure that the new symlink or directory names are durable.
+        if config.fsync() {
+            file_ops::DiskFsync.sync_dir(&config.rsync_dir)?;
+        }
+
+        self.update_current(new_revision);
+        Ok(())
+    }
+
+    /// Logs a warning if the revision about to be made current is for
+    /// another RRDP session than the current revision.
+    fn log_session_change(&self, new_revision: &RsyncRevision, config: &Config) {
+        if let Some(current) = &self.current {
+            if current.session_id != new_revision.session_id {
+                // Including the current revision, which is deprecated now
+                let stale = self
+                    .old
+                    .iter()
+                    .filter(|old| old.revision.session_id != new_revision.session_id)
+                    .count()
+                    + 1;
+                warn!(
+                    "RRDP session changed from {} to {}, all {} rsync revisions of previous sessions will be removed once deprecated for {} seconds",
+                    current.session_id, new_revision.session_id, stale, config.cleanup_after
+                );
+            }
+        }
+    }
+
+    /// Updates the current revision for this state, moves a possible
+    /// existing current state to old.
+    fn update_current(&mut self, current: RsyncRevision) {
+        // After a serial rollback the new current revision may be one that
+        // was deprecated before, its directory must not be cleaned up.
+        self.old
+            .retain(|old| old.revision.dir_name() != current.dir_name());
+
+        let existing = self.current.replace(current);
+        if let Some(existing) = existing {
+            self.old.push(existing.deprecate());
+        }
+    }
+
+    /// Cleans old directories from disk when their time has come, and updates
+    /// this state (forgets these old versions). Will throw an error if removing
+    /// an old dir fails, but will simply skip removing old dirs if they had
+    /// already been removed. Returns the removed directories.
+    ///
+    /// The most recent `config.rsync_keep_min_revisions` old revisions are
+    /// always kept, regardless of their age. Only revisions of the session of
+    /// the current revision count here. After an RRDP session change, the old
+    /// revisions of the previous session are stale, and they are removed once
+    /// `config.cleanup_after` has passed.
+    ///
+    /// Revisions which were deprecated at least `config.cleanup_after`
+    /// seconds before `now` are eligible. The time is passed in, rather than
+    /// taken from the system clock, so that this can be tested exactly.
+    fn clean_old(&mut self, config: &Config, now: Time) -> Result<Vec<PathBuf>> {
+        let clean_before = now.seconds_before(config.cleanup_after);
+        self.clean_old_before(config, clean_before, config.rsync_keep_min_revisions)
+    }
+
+    /// Like `clean_old`, but with `config.no_clean` nothing is removed, and
+    /// only the number of revisions which are due for cleanup is logged.
+    fn clean_old_unless_kept(&mut self, config: &Config, now: Time) -> Result<Vec<PathBuf>> {
+        if !config.no_clean {
+            return self.clean_old(config, now);
+        }
+
+        let clean_before = now.seconds_before(config.cleanup_after);
+        let due = self.old_to_clean(clean_before, config.rsync_keep_min_revisions).count();
+        info!(
+            "Not removing old rsync revisions because of --no-clean, {} are due for cleanup",
+            due
+        );
+        Ok(vec![])
+    }
+
+    /// Cleans old directories which were deprecated before the given time,
+    /// except for the `keep_min` most recent ones. See `clean_old`.
+    fn clean_old_before(
+        &mut self,
+        config: &Config,
+        clean_before: Time,
+        keep_min: usize,
+    ) -> Result<Vec<PathBuf>> {
+        let mut cleaned = vec![];
+
+        for old in self.old_to_clean(clean_before, keep_min) {
+            let path = old.revision.path(config);
+            if path.exists() {
+                info!(
+                    "Removing rsync directory: {}, deprecated since: {}",
+                    path.display(),
+                    old.since
+                );
+                if let (true, Some(archive_dir)) = (config.archive_old_revisions, &config.archive_dir) {
+                    let archive_path = archive_dir.join(format!("{}.tar.gz", old.revision.dir_name()));
+                    let size = archive::tar_gz_dir(&path, &archive_path)?;
+                    info!("Archived rsync directory to: {} ({} bytes)", archive_path.display(), size);
+                }
+                config.write_target().remove_revision(&path, &old.revision.dir_name())?;
+
+                // Try to remove the old directory if it still exists
+                let fs = config.fs_retry();
+                fs.remove_dir_all(&path).with_context(|| {
+                    format!(
+                        "Could not remove rsync dir for old revision at: {}",
+                        path.display()
+                    )
+                })?;
+                cleaned.push(path);
+            }
+        }
+
+        let kept = self.kept_for_min(keep_min);
+        let mut idx = 0;
+        self.old.retain(|deprecated| {
+            let keep = kept[idx] || deprecated.since > clean_before;
+            idx += 1;
+            keep
+        });
+
+        Ok(cleaned)
+    }
+
+    /// Returns the old revisions which were deprecated before the given time,
+    /// excluding the `keep_min` most recently deprecated revisions of the
+    /// current session. Note that `self.old` is kept in order of deprecation.
+    fn old_to_clean(
+        &self,
+        clean_before: Time,
+        keep_min: usize,
+    ) -> impl Iterator<Item = &DeprecatedRsyncRevision> {
+        let kept = self.kept_for_min(keep_min);
+        self.old
+            .iter()
+            .enumerate()
+            .filter(move |(idx, deprecated)| !kept[*idx] && deprecated.since <= clean_before)
+            .map(|(_, deprecated)| deprecated)
+    }
+
+    /// Returns for each old revision whether it is one of the `keep_min` most
+    /// recently deprecated revisions of the current session. If there is no
+    /// current revision, then revisions of any session count.
+    fn kept_for_min(&self, keep_min: usize) -> Vec<bool> {
+        let session_id = self.current.as_ref().map(|current| current.session_id);
+
+        let mut kept = vec![false; self.old.len()];
+        let same_session = self
+            .old
+            .iter()
+            .enumerate()
+            .rev()
+            .filter(|(_, old)| session_id.map(|id| id == old.revision.session_id).unwrap_or(true));
+        for (idx, _) in same_session.take(keep_min) {
+            kept[idx] = true;
+        }
+        kept
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+
+struct RsyncRevision {
+    #[serde(deserialize_with = "util::de_uuid", serialize_with = "util::ser_uuid")]
+    session_id: Uuid,
+    serial: u64,
+
+    /// The directory name resolved from the configured template when this
+    /// revision was created. We keep it, so that we can still find the
+    /// directory if the template is changed later. This is not present in
+    /// state written by older versions, in which case the directory uses
+    /// the default naming.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    dir_name: Option<String>,
+
+    /// The number of objects written for this revision. Not present in
+    /// state written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    object_count: Option<usize>,
+
+    /// The digest of the content of this revision, see `revision_digest`.
+    /// Not present in state written by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+
+    /// The hash of each object in this revision by URI, so that the next
+    /// revision can be compared to it. This is only kept for the current and
+    /// pending revision, in `config.rsync_objects_path` rather than in the
+    /// state file, see `RevisionObjects`.
+    #[serde(skip)]
+    objects: Option<BTreeMap<String, String>>,
+
+    /// Whether this pending revision was staged, so that only 'publish' may
+    /// make it current. Not set for a revision which is pending because the
+    /// pre publish command vetoed it in a normal run.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    staged: bool,
 }
 
 impl RsyncRevision {
+    fn new(session_id: Uuid, serial: u64, config: &Config) -> Self {
+        let dir_name = config
+            .rsync_dir_name_template
+            .replace("{session}", &session_id.to_string())
+            .replace("{serial}", &serial.to_string())
+            .replace("{timestamp}", &Time::now().timestamp().to_string());
+
+        RsyncRevision {
+            session_id,
+            serial,
+            dir_name: Some(dir_name),
+            object_count: None,
+            digest: None,
+            objects: None,
+            staged: false,
+        }
+    }
+
     fn dir_name(&self) -> String {
-        format!("session_{}_serial_{}", self.session_id, self.serial)
+        match &self.dir_name {
+            Some(dir_name) => dir_name.clone(),
+            None => format!("session_{}_serial_{}", self.session_id, self.serial),
+        }
+    }
+
+    /// The directory for this revision. With the flat layout this is only
+    /// used until the revision is made current, and it is next to, rather
+    /// than in, the rsync dir.
+    fn path(&self, config: &Config) -> PathBuf {
+        if config.rsync_flat_layout {
+            flat_sibling_path(config, FLAT_NEW_EXT)
+        } else {
+            config.rsync_dir.join(self.dir_name())
+        }
+    }
+
+    /// The directory that the content for this revision is written to, before
+    /// it is moved to `path` when complete. This is a sibling, unless there is
+    /// a `config.tmp_dir`.
+    fn tmp_path(&self, config: &Config) -> PathBuf {
+        if let Some(tmp_dir) = &config.tmp_dir {
+            let name = if config.rsync_flat_layout {
+                config.rsync_dir.file_name().unwrap_or_default().to_string_lossy().to_string()
+            } else {
+                self.dir_name()
+            };
+            tmp_dir.join(format!("{}.{}", name, config::TMP_FILE_EXT))
+        } else if config.rsync_flat_layout {
+            flat_sibling_path(config, config::TMP_FILE_EXT)
+        } else {
+            config
+                .rsync_dir
+                .join(format!("{}.{}", self.dir_name(), config::TMP_FILE_EXT))
+        }
+    }
+
+    fn deprecate(self) -> DeprecatedRsyncRevision {
+        DeprecatedRsyncRevision {
+            since: Time::now(),
+            revision: RsyncRevision { objects: None, ..self },
+        }
+    }
+}
+
+/// The objects of the current and pending rsync revision, as kept in
+/// `config.rsync_objects_path`.
+#[derive(Debug, Default, Deserialize, Serialize)]
+struct RevisionObjects {
+    current: Option<RevisionObjectsEntry>,
+    pending: Option<RevisionObjectsEntry>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct RevisionObjectsEntry {
+    dir_name: String,
+    objects: BTreeMap<String, String>,
+}
+
+impl RevisionObjectsEntry {
+    fn of(revision: &RsyncRevision) -> Option<Self> {
+        Some(RevisionObjectsEntry {
+            dir_name: revision.dir_name(),
+            objects: revision.objects.clone()?,
+        })
+    }
+
+    /// Returns the objects if they are for the revision, and not for another
+    /// revision with the same role, e.g. if persisting the state failed.
+    fn objects_for(self, revision: &RsyncRevision) -> Option<BTreeMap<String, String>> {
+        (self.dir_name == revision.dir_name()).then_some(self.objects)
+    }
+}
+
+/// Returns whether a directory name could have been produced by the given
+/// revision directory name template. Placeholders must match their values
+/// exactly: a UUID for {session} and digits for {serial} and {timestamp}.
+fn dir_name_matches_template(name: &str, template: &str) -> bool {
+    dir_name_values(name, template).is_some()
+}
+
+/// Returns the session and serial in a directory name, if it matches the
+/// given revision directory name template. Values are None if the template
+/// has no placeholder for them.
+fn dir_name_values(name: &str, template: &str) -> Option<(Option<Uuid>, Option<u64>)> {
+    // (placeholder, whether it is a session, whether it is a serial)
+    let placeholders = [
+        ("{session}", true, false),
+        ("{serial}", false, true),
+        ("{timestamp}", false, false),
+    ];
+
+    let next = placeholders
+        .iter()
+        .filter_map(|(placeholder, is_session, is_serial)| {
+            template
+                .find(placeholder)
+                .map(|idx| (idx, placeholder.len(), *is_session, *is_serial))
+        })
+        .min();
+
+    match next {
+        None if name == template => Some((None, None)),
+        None => None,
+        Some((idx, len, is_session, is_serial)) => {
+            let (literal, rest) = (&template[..idx], &template[idx + len..]);
+            let name = name.strip_prefix(literal)?;
+
+            if is_session {
+                // UUIDs have a fixed length
+                let session = Uuid::parse_str(name.get(..36)?).ok()?;
+                let (_, serial) = dir_name_values(&name[36..], rest)?;
+                Some((Some(session), serial))
+            } else {
+                // Try each possible length of digits
+                let digits = name.chars().take_while(|c| c.is_ascii_digit()).count();
+                (1..=digits).find_map(|len| {
+                    let (session, serial) = dir_name_values(&name[len..], rest)?;
+                    if is_serial {
+                        Some((session, Some(name[..len].parse().ok()?)))
+                    } else {
+                        Some((session, serial))
+                    }
+                })
+            }
+        }
+    }
+}
+
+/// The manifest written to a revision dir if `config.rsync_write_manifest`
+/// is set. It is not an RPKI object itself.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+struct RevisionManifest {
+    objects: Vec<ManifestEntry>,
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct ManifestEntry {
+    uri: String,
+
+    /// The path relative to the revision dir.
+    path: String,
+    size: u64,
+
+    /// The hex encoded hash of the content.
+    hash: String,
+
+    /// The algorithm of the hash. Manifests written before this was added
+    /// do not have it, and use SHA-256.
+    #[serde(default = "ManifestEntry::default_algorithm")]
+    algorithm: String,
+}
+
+/// The algorithm of the hashes in a revision manifest. RRDP only uses SHA-256,
+/// so that is the only algorithm which we write or can check.
+const MANIFEST_HASH_ALGORITHM: &str = "sha256";
+
+/// The names of the algorithm which are accepted when a manifest is read.
+const MANIFEST_HASH_ALGORITHM_NAMES: [&str; 2] = [MANIFEST_HASH_ALGORITHM, "sha-256"];
+
+impl ManifestEntry {
+    fn default_algorithm() -> String {
+        MANIFEST_HASH_ALGORITHM.to_string()
+    }
+}
+
+impl RevisionManifest {
+    fn read(path: &Path) -> Result<Self> {
+        let json = file_ops::read_file(path)
+            .with_context(|| format!("Cannot read revision manifest: {}", path.display()))?;
+        serde_json::from_slice(json.as_ref())
+            .with_context(|| format!("Cannot parse revision manifest: {}", path.display()))
+    }
+
+    /// Returns the hex encoded SHA-256 hash for each URI. Returns an error if
+    /// an entry has another hash algorithm, because then its object cannot be
+    /// checked.
+    fn into_hashes(self) -> Result<HashMap<String, String>> {
+        self.objects
+            .into_iter()
+            .map(|entry| {
+                let algorithm = entry.algorithm.to_ascii_lowercase();
+                if MANIFEST_HASH_ALGORITHM_NAMES.contains(&algorithm.as_str()) {
+                    Ok((entry.uri, entry.hash.to_ascii_lowercase()))
+                } else {
+                    Err(anyhow!(
+                        "Unknown hash algorithm '{}', supported are: {}",
+                        entry.algorithm,
+                        MANIFEST_HASH_ALGORITHM_NAMES.join(", ")
+                    ))
+                    .with_context(|| format!("Cannot check {} in revision manifest", entry.uri))
+                }
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+struct DeprecatedRsyncRevision {
+    since: Time,
+    revision: RsyncRevision,
+}
+
+/// A published object taken from the current RRDP snapshot.
+///
+/// RRDP snapshots do not declare hashes for published objects, so the hash
+/// is calculated over the data as it was decoded from the snapshot XML. There
+/// is nothing to check it against before writing: the snapshot itself was
+/// checked against the hash in the notification file, and the hashes in
+/// deltas are checked when they are applied, see `rrdp::SnapshotElements`.
+#[derive(Clone, Debug)]
+pub struct CurrentObject {
+    uri: rpki::uri::Rsync,
+    hash: Hash,
+    data: Bytes,
+}
+
+impl CurrentObject {
+    pub fn new(uri: rpki::uri::Rsync, data: Bytes) -> Self {
+        let hash = Hash::from_data(data.as_ref());
+        CurrentObject { uri, hash, data }
+    }
+
+    pub fn uri(&self) -> &rpki::uri::Rsync {
+        &self.uri
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn data(&self) -> &Bytes {
+        &self.data
+    }
+
+    pub fn object_type(&self) -> ObjectType {
+        ObjectType::from_uri(&self.uri)
+    }
+
+    pub fn info(&self) -> ObjectInfo {
+        ObjectInfo {
+            uri: self.uri.clone(),
+            hash: self.hash,
+            size: self.data.len() as u64,
+        }
+    }
+}
+
+/// What we need to know about a `CurrentObject` for a revision, other than
+/// to write it: everything but its data.
+#[derive(Clone, Debug)]
+pub struct ObjectInfo {
+    uri: rpki::uri::Rsync,
+    hash: Hash,
+    size: u64,
+}
+
+impl ObjectInfo {
+    pub fn uri(&self) -> &rpki::uri::Rsync {
+        &self.uri
+    }
+
+    pub fn hash(&self) -> Hash {
+        self.hash
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    pub fn object_type(&self) -> ObjectType {
+        ObjectType::from_uri(&self.uri)
+    }
+}
+
+/// The type of an RPKI object, as inferred from the extension of its URI.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+pub enum ObjectType {
+    Cer,
+    Crl,
+    Mft,
+    Roa,
+    Asa,
+    Gbr,
+    Sig,
+    Tak,
+    Other,
+}
+
+impl ObjectType {
+    pub fn from_uri(uri: &rpki::uri::Rsync) -> Self {
+        let path = uri.path();
+        let ext = match path.rfind('.') {
+            Some(idx) if !path[idx..].contains('/') => path[idx + 1..].to_ascii_lowercase(),
+            _ => return ObjectType::Other,
+        };
+        match ext.as_str() {
+            "cer" => ObjectType::Cer,
+            "crl" => ObjectType::Crl,
+            "mft" => ObjectType::Mft,
+            "roa" => ObjectType::Roa,
+            "asa" => ObjectType::Asa,
+            "gbr" => ObjectType::Gbr,
+            "sig" => ObjectType::Sig,
+            "tak" => ObjectType::Tak,
+            _ => ObjectType::Other,
+        }
+    }
+
+    /// Returns the number of objects of each type, for types which occur.
+    pub fn count(types: impl Iterator<Item = ObjectType>) -> BTreeMap<Self, usize> {
+        let mut counts = BTreeMap::new();
+        for object_type in types {
+            *counts.entry(object_type).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+impl std::fmt::Display for ObjectType {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            ObjectType::Cer => "cer",
+            ObjectType::Crl => "crl",
+            ObjectType::Mft => "mft",
+            ObjectType::Roa => "roa",
+            ObjectType::Asa => "asa",
+            ObjectType::Gbr => "gbr",
+            ObjectType::Sig => "sig",
+            ObjectType::Tak => "tak",
+            ObjectType::Other => "other",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The data of a published object, see `read_object_data`.
+pub enum ObjectData {
+    Read(Bytes),
+
+    /// The object is larger than allowed, and has this size.
+    Oversized(u64),
+}
+
+/// Reads the data of a published object, if it has at most `max_bytes`.
+/// Otherwise only its size is determined, without keeping the rest of the
+/// data in memory. Note that the rpki crate does keep the base64 encoded
+/// data of the element in memory while it is read.
+pub fn read_object_data(data: &mut impl Read, max_bytes: u64) -> Result<ObjectData> {
+    let mut bytes: Vec<u8> = vec![];
+    data.take(max_bytes.saturating_add(1)).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 <= max_bytes {
+        return Ok(ObjectData::Read(Bytes::from(bytes)));
+    }
+
+    drop(bytes);
+    let rest = std::io::copy(data, &mut std::io::sink())?;
+    Ok(ObjectData::Oversized(max_bytes + 1 + rest))
+}
+
+/// Passes each published object in a snapshot file on to a closure. Objects
+/// larger than `config.max_object_bytes` are refused, or left out if
+/// `config.oversized_objects` is 'skip'.
+struct SnapshotObjects<'a, F> {
+    config: &'a Config,
+    process: F,
+}
+
+impl<F: FnMut(CurrentObject) -> Result<()>> ProcessSnapshot for SnapshotObjects<'_, F> {
+    type Err = anyhow::Error;
+
+    fn meta(&mut self, _session_id: Uuid, _serial: u64) -> Result<()> {
+        Ok(()) // nothing to do
+    }
+
+    fn publish(
+        &mut self,
+        uri: rpki::uri::Rsync,
+        data: &mut rpki::rrdp::ObjectReader,
+    ) -> Result<()> {
+        // Read the bytes into memory, we will need to parse this in order
+        // to fix the mtime of the file. In other words.. we _could_ copy
+        // the bytes from the reader into a file on disk, but then we would
+        // have to re-read them to parse them anyway.
+        let config = self.config;
+        let bytes = match read_object_data(data, config.max_object_bytes)? {
+            ObjectData::Read(bytes) => bytes,
+            ObjectData::Oversized(size) => {
+                let msg = format!(
+                    "Object {} has {} bytes, more than --max-object-bytes {}",
+                    uri, size, config.max_object_bytes
+                );
+                if config.oversized_objects == OversizedObjectAction::Abort {
+                    return Err(exit::invalid_data(format!(
+                        "{}, use --oversized-objects skip to leave it out",
+                        msg
+                    )));
+                }
+                warn!("{}, leaving it out", msg);
+                return Ok(());
+            }
+        };
+
+        (self.process)(CurrentObject::new(uri, bytes))
+    }
+}
+
+/// Calls `process` for each published object in the snapshot file at the
+/// given path, one at a time, as they are read. Stops at the first error.
+/// Objects larger than `config.max_object_bytes` are refused or left out,
+/// see `config.oversized_objects`.
+pub fn process_snapshot_objects(
+    snapshot: &Path,
+    config: &Config,
+    process: impl FnMut(CurrentObject) -> Result<()>,
+) -> Result<()> {
+    let source_file = File::open(snapshot)
+        .with_context(|| format!("Cannot open snapshot file at {}", snapshot.display()))?;
+    let buf_reader = BufReader::new(source_file);
+
+    SnapshotObjects { config, process }
+        .process(buf_reader)
+        .with_context(|| format!("Cannot process snapshot file at {}", snapshot.display()))
+}
+
+/// Reads all published objects from the snapshot file at the given path,
+/// see `process_snapshot_objects`.
+pub fn read_snapshot_objects(snapshot: &Path, config: &Config) -> Result<Vec<CurrentObject>> {
+    let mut objects = vec![];
+    process_snapshot_objects(snapshot, config, |object| {
+        objects.push(object);
+        Ok(())
+    })?;
+    Ok(objects)
+}
+
+/// Reads the info for the published objects from the snapshot file at the
+/// given path, sorted by URI, without keeping their data in memory. Objects
+/// for which `keep` returns false are left out.
+pub fn read_snapshot_infos(
+    snapshot: &Path,
+    config: &Config,
+    mut keep: impl FnMut(&CurrentObject) -> bool,
+) -> Result<Vec<ObjectInfo>> {
+    let mut infos = vec![];
+    process_snapshot_objects(snapshot, config, |object| {
+        if keep(&object) {
+            infos.push(object.info());
+        }
+        Ok(())
+    })?;
+    infos.sort_by(|a, b| a.uri().as_str().cmp(b.uri().as_str()));
+    Ok(infos)
+}
+
+/// Creates an empty directory for the rsync out_path. Particularly needed if the snapshot
+/// is empty since no files (and parent dirs) would be created in that case - and we want to
+/// see an empty directory. See issue #62.
+fn create_out_path_if_missing(out_path: &Path) -> Result<()> {
+    if !out_path.exists() {
+        std::fs::create_dir_all(out_path).with_context(|| {
+            format!(
+                "Cannot create output directory for rsync at {}",
+                out_path.display()
+            )
+        })
+    } else {
+        Ok(())
+    }
+}
+
+/// Maps the rsync URI of an object to its path under the rsync out_path.
+///
+/// The URIs come from the RRDP source, and although the rpki crate already
+/// refuses dot segments in the path, the host and module can be anything.
+/// So, we refuse any relative path which could escape the out_path: parent
+/// or root components, drive prefixes, and backslashes which are a path
+/// separator on Windows.
+pub fn make_rsync_repo_path(
+    out_path: &Path,
+    uri: &rpki::uri::Rsync,
+    prefix: RsyncPathPrefix,
+) -> Result<PathBuf> {
+    let rel = match prefix {
+        RsyncPathPrefix::None => uri.path().to_string(),
+        RsyncPathPrefix::Module => format!("{}/{}", uri.module_name(), uri.path()),
+        RsyncPathPrefix::HostAndModule => {
+            format!("{}/{}/{}", uri.authority(), uri.module_name(), uri.path())
+        }
+    };
+
+    let rel_path = Path::new(&rel);
+    if rel.contains('\\')
+        || rel_path
+            .components()
+            .any(|component| !matches!(component, Component::Normal(_)))
+    {
+        return Err(anyhow!(
+            "Refusing to write object outside of the rsync dir for uri: {}",
+            uri
+        ));
+    }
+
+    Ok(out_path.join(rel_path))
+}
+
+/// Writes all objects under the rsync out_path directory.
+///
+/// The writes are dispatched over a pool of worker threads, sized by
+/// `config.rsync_write_threads()`. Each worker takes the next object that
+/// has not been picked up yet, so large and small objects are spread evenly.
+/// If any write fails, the remaining workers stop picking up new objects and
+/// the first failure is returned. If `config.write_error_mode` is 'collect',
+/// then all other objects are still written and all failures are returned
+/// together.
+///
+/// Note that workers may race to create the same parent directory. This is
+/// safe because `std::fs::create_dir_all` treats a directory that was created
+/// concurrently as a success.
+///
+/// If `config.fsync()` is set, then every file is flushed to disk after it is
+/// written, and all directories are flushed after all files were written. So
+/// when this function returns the new content can safely be made current.
+///
+/// If `link_from` is given, then objects for which a file with identical
+/// content exists at the same path under that directory are hard-linked,
+/// rather than written again.
+///
+/// Objects with a URI matching `config.rsync_exclude` are skipped.
+///
+/// If `config.normalize_mtime` is set, then all written files and dirs get
+/// that mtime. Note that hard-linked files keep the mtime they were first
+/// written with.
+pub fn write_rsync_content(
+    out_path: &Path,
+    objects: &[CurrentObject],
+    link_from: Option<&Path>,
+    config: &Config,
+) -> Result<()> {
+    let writer = ObjectWriter::new(out_path, link_from, config);
+
+    if config.fsync() {
+        writer.write_all(objects, Some(&file_ops::DiskFsync))
+    } else {
+        writer.write_all(objects, None)
+    }
+}
+
+/// Writes the objects of the snapshot file at `snapshot_path` to `out_path`,
+/// like `write_rsync_content`, but one at a time as they are read from the
+/// snapshot. So, only one object is kept in memory, but they are written by a
+/// single thread. The `infos` must be those of the same snapshot, sorted by
+/// URI, as returned by `read_snapshot_infos`: an object which is not in there
+/// means the snapshot changed since, and is refused, unless its URI is in
+/// `skipped`, in which case it is left out.
+pub fn stream_rsync_content(
+    out_path: &Path,
+    snapshot_path: &Path,
+    infos: &[ObjectInfo],
+    skipped: &HashSet<&str>,
+    link_from: Option<&Path>,
+    config: &Config,
+) -> Result<()> {
+    let writer = ObjectWriter::new(out_path, link_from, config);
+
+    if config.fsync() {
+        writer.write_stream(snapshot_path, infos, skipped, Some(&file_ops::DiskFsync))
+    } else {
+        writer.write_stream(snapshot_path, infos, skipped, None)
+    }
+}
+
+/// Returns the URIs of the objects which should not be written, because
+/// they map to the same file as an object with a lower URI. If that
+/// object has the same content, then this is harmless. Otherwise the
+/// file would depend on which object happens to be written last, which
+/// is an error unless `config.on_path_collision` is 'warn'. Objects which
+/// are excluded from rsync are not written anyway, and are ignored.
+fn find_path_collisions(infos: &[ObjectInfo], config: &Config) -> Result<HashSet<String>> {
+    let mut sorted: Vec<&ObjectInfo> =
+        infos.iter().filter(|info| !config.rsync_excluded(info.uri())).collect();
+    sorted.sort_by(|a, b| a.uri().as_str().cmp(b.uri().as_str()));
+
+    let prefix = config.rsync_path_prefix();
+    let mut paths: HashMap<PathBuf, &ObjectInfo> = HashMap::new();
+    let mut shadowed = HashSet::new();
+    for info in sorted {
+        // An invalid path is refused when the object is written.
+        let path = match make_rsync_repo_path(Path::new(""), info.uri(), prefix) {
+            Ok(path) => path,
+            Err(_) => continue,
+        };
+        let first = match paths.get(&path) {
+            Some(first) => *first,
+            None => {
+                paths.insert(path, info);
+                continue;
+            }
+        };
+
+        if first.hash() != info.hash() {
+            let msg = format!(
+                "Objects {} and {} have different content, but map to the same file {}",
+                first.uri(),
+                info.uri(),
+                path.display()
+            );
+            if config.on_path_collision == PathCollisionAction::Abort {
+                return Err(exit::invalid_data(format!(
+                    "{}, use --on-path-collision warn to write the first object only",
+                    msg
+                )));
+            }
+            warn!("{}, only writing {}", msg, first.uri());
+        } else {
+            debug!("Object {} is identical to {}, writing it once", info.uri(), first.uri());
+        }
+        shadowed.insert(info.uri().to_string());
+    }
+
+    Ok(shadowed)
+}
+
+
+/// Writes objects for a new rsync revision, see `write_rsync_content`.
+struct ObjectWriter<'a> {
+    out_path: &'a Path,
+    link_from: Option<&'a Path>,
+    config: &'a Config,
+    store: Option<ObjectStore>,
+    linked: AtomicUsize,
+    stored: AtomicUsize,
+    from_base: AtomicUsize,
+    excluded: AtomicUsize,
+}
+
+impl<'a> ObjectWriter<'a> {
+    fn new(out_path: &'a Path, link_from: Option<&'a Path>, config: &'a Config) -> Self {
+        ObjectWriter {
+            out_path,
+            link_from,
+            config,
+            store: config
+                .rsync_cas_dir
+                .clone()
+                .filter(|_| !config.force_full_write)
+                .map(|dir| ObjectStore::new(dir, config.rsync_cas_shard_chars)),
+            linked: AtomicUsize::new(0),
+            stored: AtomicUsize::new(0),
+            from_base: AtomicUsize::new(0),
+            excluded: AtomicUsize::new(0),
+        }
+    }
+
+    fn write_all(&self, objects: &[CurrentObject], fsync: Option<&dyn Fsync>) -> Result<()> {
+        let out_path = self.out_path;
+        let config = self.config;
+        let infos: Vec<ObjectInfo> = objects.iter().map(CurrentObject::info).collect();
+        let shadowed = find_path_collisions(&infos, config)?;
+        let objects: Vec<&CurrentObject> =
+            objects.iter().filter(|o| !shadowed.contains(o.uri().as_str())).collect();
+        let threads = config.rsync_write_threads().min(objects.len()).max(1);
+        let next = AtomicUsize::new(0);
+        let collect = config.write_error_mode == WriteErrorMode::Collect;
+        let errors: Mutex<Vec<anyhow::Error>> = Mutex::new(vec![]);
+
+        debug!(
+            "Writing {} objects to {} using {} threads",
+            objects.len(),
+            out_path.display(),
+            threads
+        );
+
+        std::thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| loop {
+                    if !collect && !errors.lock().unwrap().is_empty() {
+                        break;
+                    }
+
+                    let idx = next.fetch_add(1, Ordering::Relaxed);
+                    let object = match objects.get(idx) {
+                        Some(object) => object,
+                        None => break,
+                    };
+
+                    if let Err(e) = self.write_object(object, fsync) {
+                        errors.lock().unwrap().push(e);
+                        if !collect {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        self.check_errors(errors.into_inner().unwrap(), objects.len())?;
+        let infos: Vec<ObjectInfo> =
+            infos.into_iter().filter(|i| !shadowed.contains(i.uri().as_str())).collect();
+        self.finish(&infos, fsync)
+    }
+
+    fn write_stream(
+        &self,
+        snapshot_path: &Path,
+        infos: &[ObjectInfo],
+        skipped: &HashSet<&str>,
+        fsync: Option<&dyn Fsync>,
+    ) -> Result<()> {
+        let collect = self.config.write_error_mode == WriteErrorMode::Collect;
+        let mut errors = vec![];
+        let shadowed = find_path_collisions(infos, self.config)?;
+
+        debug!(
+            "Writing {} objects to {} as they are read from {}",
+            infos.len(),
+            self.out_path.display(),
+            snapshot_path.display()
+        );
+
+        let mut count = 0;
+        process_snapshot_objects(snapshot_path, self.config, |object| {
+            if skipped.contains(object.uri().as_str()) {
+                return Ok(());
+            }
+            let expected = infos
+                .binary_search_by(|info| info.uri().as_str().cmp(object.uri().as_str()))
+                .ok()
+                .map(|idx| infos[idx].hash());
+            if expected != Some(object.hash()) {
+                return Err(anyhow!(
+                    "Object {} does not match the snapshot as it was read before",
+                    object.uri()
+                ));
+            }
+            count += 1;
+            if shadowed.contains(object.uri().as_str()) {
+                return Ok(());
+            }
+
+            if let Err(e) = self.write_object(&object, fsync) {
+                if !collect {
+                    return Err(e);
+                }
+                errors.push(e);
+            }
+            Ok(())
+        })?;
+
+        if count != infos.len() {
+            return Err(anyhow!(
+                "Snapshot {} has {} objects, but had {} when it was read before",
+                snapshot_path.display(),
+                count,
+                infos.len()
+            ));
+        }
+
+        self.check_errors(errors, infos.len() - shadowed.len())?;
+        let infos: Vec<ObjectInfo> =
+            infos.iter().filter(|i| !shadowed.contains(i.uri().as_str())).cloned().collect();
+        self.finish(&infos, fsync)
+    }
+
+    /// Returns the first error, or all errors if `config.write_error_mode`
+    /// is 'collect', if any.
+    fn check_errors(&self, errors: Vec<anyhow::Error>, total: usize) -> Result<()> {
+        if self.config.write_error_mode != WriteErrorMode::Collect {
+            match errors.into_iter().next() {
+                Some(e) => Err(e),
+                None => Ok(()),
+            }
+        } else if !errors.is_empty() {
+            let details: Vec<String> = errors.iter().map(|e| format!("{:#}", e)).collect();
+            Err(anyhow!(
+                "Could not write {} of {} objects:\n{}",
+                errors.len(),
+                total,
+                details.join("\n")
+            ))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Finishes the out path after all objects were written: sets the
+    /// permissions and mtimes of the dirs, writes the manifest and syncs the
+    /// dirs, as configured.
+    fn finish(&self, objects: &[ObjectInfo], fsync: Option<&dyn Fsync>) -> Result<()> {
+        let out_path = self.out_path;
+        let config = self.config;
+
+        for dir in &config.preserve_empty_dirs {
+            let path = out_path.join(dir);
+            std::fs::create_dir_all(&path)
+                .with_context(|| format!("Cannot create preserved dir: {}", path.display()))?;
+        }
+
+        let permissions = &config.file_permissions;
+        if !permissions.is_default() {
+            for dir in file_ops::dirs_depth_first(out_path)? {
+                permissions.apply_to_dir(&dir)?;
+            }
+        }
+
+        if config.rsync_write_manifest {
+            self.write_manifest(objects, fsync)?;
+        }
+
+        // Setting the mtime of a dir does not change that of its parent, so
+        // the order does not matter here.
+        if let Some(timestamp) = config.normalize_mtime {
+            let mtime = FileTime::from_unix_time(timestamp, 0);
+            for dir in file_ops::dirs_depth_first(out_path)? {
+                set_file_mtime(&dir, mtime)
+                    .with_context(|| format!("Cannot modify mtime for dir at: {}", dir.display()))?;
+            }
+        }
+
+        if let Some(fsync) = fsync {
+            if let Some(store) = &self.store {
+                store.sync_added_dirs(fsync)?;
+            }
+            for dir in file_ops::dirs_depth_first(out_path)? {
+                fsync.sync_dir(&dir)?;
+            }
+        }
+
+        if self.link_from.is_some() {
+            info!(
+                "Hard-linked {} unchanged objects from the previous rsync revision",
+                self.linked.load(Ordering::Relaxed)
+            );
+        } else if self.store.is_some() {
+            info!(
+                "Hard-linked {} objects from the object store, of which {} were added",
+                self.linked.load(Ordering::Relaxed),
+                self.stored.load(Ordering::Relaxed)
+            );
+        }
+
+        if let Some(base_dir) = &config.rsync_base_dir {
+            info!(
+                "{} {} objects from the base dir {}",
+                if config.rsync_base_symlinks { "Symlinked" } else { "Hard-linked" },
+                self.from_base.load(Ordering::Relaxed),
+                base_dir.display()
+            );
+        }
+
+        let counts = ObjectType::count(
+            objects
+                .iter()
+                .filter(|o| !config.rsync_excluded(o.uri()))
+                .map(ObjectInfo::object_type),
+        );
+        let counts: Vec<String> =
+            counts.iter().map(|(object_type, count)| format!("{} {}", count, object_type)).collect();
+        if counts.is_empty() {
+            info!("Wrote no objects");
+        } else {
+            info!("Wrote {}", counts.join(", "));
+        }
+
+        let excluded = self.excluded.load(Ordering::Relaxed);
+        if excluded > 0 {
+            info!(
+                "Included {} objects, skipped {} objects matching --rsync-exclude or not matching --rsync-include-prefix",
+                objects.len() - excluded,
+                excluded
+            );
+        }
+
+        Ok(())
+    }
+
+    fn write_object(&self, object: &CurrentObject, fsync: Option<&dyn Fsync>) -> Result<()> {
+        if self.config.rsync_excluded(object.uri()) {
+            self.excluded.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        }
+
+        self.write_file(object, fsync)?;
+        self.config.run_progress.add_object(object.data().len() as u64);
+        Ok(())
+    }
+
+    /// Writes, or links, the file for an object which is not excluded.
+    fn write_file(&self, object: &CurrentObject, fsync: Option<&dyn Fsync>) -> Result<()> {
+        let config = self.config;
+
+        let path = make_rsync_repo_path(self.out_path, object.uri(), config.rsync_path_prefix())?;
+
+        // When rebuilding, every object is written, rather than linked to
+        // files which may be damaged.
+        let base_dir = config.rsync_base_dir.as_ref().filter(|_| !config.force_full_write);
+        if let Some(base_dir) = base_dir {
+            if self.link_from_base(base_dir, &path, object)? {
+                self.from_base.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+        }
+
+        if let Some(link_from) = self.link_from {
+            // Never write into a file which may be linked to the previous
+            // revision, e.g. when a prior run for this revision was interrupted.
+            if path.symlink_metadata().is_ok() {
+                std::fs::remove_file(&path)
+                    .with_context(|| format!("Cannot remove existing file {}", path.display()))?;
+            }
+
+            if self.link_unchanged(link_from, &path, object)? {
+                self.linked.fetch_add(1, Ordering::Relaxed);
+                return Ok(());
+            }
+        } else if let Some(store) = &self.store {
+            if self.link_from_store(store, &path, object, fsync)? {
+                return Ok(());
+            }
+        }
+
+        file_ops::write_buf(&path, object.data()).with_context(|| {
+            format!(
+                "Could not copy element for uri: {}, to path: {}",
+                object.uri(),
+                path.to_string_lossy()
+            )
+        })?;
+
+        self.set_mtime(&path, object.data())?;
+
+        config.file_permissions.apply_to_file(&path)?;
+
+        if let Some(fsync) = fsync {
+            fsync.sync_file(&path)?;
+        }
+
+        Ok(())
     }
 
-    fn path(&self, config: &Config) -> PathBuf {
-        config.rsync_dir.join(&self.dir_name())
-    }
+    /// Hard-links the object from the file named after its hash in the
+    /// read-only base dir, if there is one with the same size, and content
+    /// unless hash verification is disabled. Returns false if the object needs
+    /// to be written instead, e.g. because the base dir is on a different file
+    /// system.
+    ///
+    /// With `config.rsync_base_symlinks` the object is symlinked instead,
+    /// which works across file systems. But an rsync server sends symlinks as
+    /// they are, so clients then need to use --copy-links.
+    fn link_from_base(
+        &self,
+        base_dir: &Path,
+        path: &Path,
+        object: &CurrentObject,
+    ) -> Result<bool> {
+        let base = cas::entry_path(base_dir, object.hash(), self.config.rsync_cas_shard_chars);
+        match base.metadata() {
+            Ok(meta) if meta.is_file() && meta.len() == object.data().len() as u64 => {}
+            _ => return Ok(false),
+        }
+        if self.config.verify_base_hashes()
+            && !object.hash().matches(file_ops::read_file(&base)?.as_ref())
+        {
+            warn!("Object in base dir does not match its hash, ignoring: {}", base.display());
+            return Ok(false);
+        }
+
+        // Never write through an existing file or link.
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Cannot remove existing file {}", path.display()))?;
+        }
+        file_ops::create_parent_dir(path)?;
+
+        if !self.config.rsync_base_symlinks {
+            return match std::fs::hard_link(&base, path) {
+                Ok(()) => Ok(true),
+                Err(e) => {
+                    debug!(
+                        "Cannot hard-link {} to {}, will write it instead. Error: {}",
+                        base.display(),
+                        path.display(),
+                        e
+                    );
+                    Ok(false)
+                }
+            };
+        }
+
+        // The link must work from wherever the revision dir is served.
+        let base = if base.is_absolute() {
+            base
+        } else {
+            base.canonicalize()
+                .with_context(|| format!("Cannot resolve path: {}", base.display()))?
+        };
+        file_ops::create_file_symlink(&base, path)?;
+        Ok(true)
+    }
+
+    /// Hard-links the object from the previous revision if that has a file
+    /// with the same content for the same URI. Returns false if the object
+    /// needs to be written instead.
+    fn link_unchanged(&self, link_from: &Path, path: &Path, object: &CurrentObject) -> Result<bool> {
+        let previous =
+            make_rsync_repo_path(link_from, object.uri(), self.config.rsync_path_prefix())?;
+
+        match previous.metadata() {
+            Ok(meta) if meta.is_file() && meta.len() == object.data().len() as u64 => {}
+            _ => return Ok(false),
+        }
+
+        let previous_data = file_ops::read_file(&previous)?;
+        if !object.hash().matches(previous_data.as_ref()) {
+            return Ok(false);
+        }
 
-    fn deprecate(self) -> DeprecatedRsyncRevision {
-        DeprecatedRsyncRevision {
-            since: Time::now(),
-            revision: self,
+        file_ops::create_parent_dir(path)?;
+        match std::fs::hard_link(&previous, path) {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                debug!(
+                    "Cannot hard-link {} to {}, will write it instead. Error: {}",
+                    previous.display(),
+                    path.display(),
+                    e
+                );
+                Ok(false)
+            }
         }
     }
-}
 
-#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-struct DeprecatedRsyncRevision {
-    since: Time,
-    revision: RsyncRevision,
-}
+    /// Writes the manifest for all objects which are not excluded to the out path.
+    fn write_manifest(&self, objects: &[ObjectInfo], fsync: Option<&dyn Fsync>) -> Result<()> {
+        let config = self.config;
 
-struct RsyncFromSnapshotWriter {
-    out_path: PathBuf,
-    include_host_and_module: bool,
-}
+        let mut manifest = RevisionManifest::default();
+        for object in objects.iter().filter(|o| !config.rsync_excluded(o.uri())) {
+            let path = make_rsync_repo_path(Path::new(""), object.uri(), config.rsync_path_prefix())?;
+            manifest.objects.push(ManifestEntry {
+                uri: config.rewrite_rsync_uri(object.uri()),
+                path: path.to_string_lossy().to_string(),
+                size: object.size(),
+                hash: object.hash().to_string(),
+                algorithm: MANIFEST_HASH_ALGORITHM.to_string(),
+            });
+        }
 
-impl RsyncFromSnapshotWriter {
-    /// Creates an empty directory for the rsync out_path. Particularly needed if the snapshot
-    /// is empty since no files (and parent dirs) would be created in that case - and we want to
-    /// see an empty directory. See issue #62.
-    fn create_out_path_if_missing(&self) -> Result<()> {
-        if !self.out_path.exists() {
-            std::fs::create_dir_all(&self.out_path).with_context(|| {
-                format!(
-                    "Cannot create output directory for rsync at {}",
-                    &self.out_path.display()
-                )
-            })
-        } else {
-            Ok(())
+        let path = self.out_path.join(config::RSYNC_MANIFEST_FNAME);
+        let json = serde_json::to_string_pretty(&manifest)?;
+        file_ops::write_buf(&path, json.as_bytes())
+            .with_context(|| format!("Could not write revision manifest: {}", path.display()))?;
+        config.file_permissions.apply_to_file(&path)?;
+        if let Some(timestamp) = config.normalize_mtime {
+            set_file_mtime(&path, FileTime::from_unix_time(timestamp, 0))?;
+        }
+        if let Some(fsync) = fsync {
+            fsync.sync_file(&path)?;
         }
-    }
 
-    /// Processes the given snapshot and writes any published files under the
-    /// rsync out_path directory
-    fn for_snapshot_path(&mut self, snapshot: &Path) -> Result<()> {
-        let source_file = File::open(snapshot)?;
-        let buf_reader = BufReader::new(source_file);
-        self.process(buf_reader)?;
         Ok(())
     }
-}
-
-impl ProcessSnapshot for RsyncFromSnapshotWriter {
-    type Err = anyhow::Error;
 
-    fn meta(&mut self, _session_id: Uuid, _serial: u64) -> Result<()> {
-        Ok(()) // nothing to do
+    /// Sets the mtime of a written file to `config.normalize_mtime` if set, or
+    /// else to the time derived from the object, if it can be parsed.
+    fn set_mtime(&self, path: &Path, data: &[u8]) -> Result<()> {
+        match self.config.normalize_mtime {
+            Some(timestamp) => set_file_mtime(path, FileTime::from_unix_time(timestamp, 0))
+                .with_context(|| format!("Cannot modify mtime for object at: {}", path.display())),
+            None => {
+                if let Err(e) = fix_since(path, data) {
+                    warn!("{}", e);
+                }
+                Ok(())
+            }
+        }
     }
 
-    fn publish(
-        &mut self,
-        uri: rpki::uri::Rsync,
-        data: &mut rpki::rrdp::ObjectReader,
-    ) -> Result<()> {
-        let path = if self.include_host_and_module {
-            self.out_path.join(format!(
-                "{}/{}/{}",
-                uri.authority(),
-                uri.module_name(),
-                uri.path()
-            ))
-        } else {
-            self.out_path.join(uri.path())
-        };
-
-        // Read the bytes into memory, we will need to parse this in order
-        // to fix the mtime of the file. In other words.. we _could_ copy
-        // the bytes from the reader into a file on disk, but then we would
-        // have to re-read them to parse them anyway.
-        let mut bytes: Vec<u8> = vec![];
-        data.read_to_end(&mut bytes)?;
-
-        file_ops::write_buf(&path, &bytes).with_context(|| {
-            format!(
-                "Could not copy element for uri: {}, to path: {}",
-                uri,
-                path.to_string_lossy()
-            )
+    /// Hard-links the object from the object store, adding it to the store
+    /// first if needed. Returns false if the object needs to be written
+    /// instead, e.g. because the store is on a different file system.
+    fn link_from_store(
+        &self,
+        store: &ObjectStore,
+        path: &Path,
+        object: &CurrentObject,
+        fsync: Option<&dyn Fsync>,
+    ) -> Result<bool> {
+        let (stored, added) = store.store(object.hash(), object.data(), |tmp_path| {
+            self.set_mtime(tmp_path, object.data())?;
+            self.config.file_permissions.apply_to_file(tmp_path)?;
+            match fsync {
+                Some(fsync) => fsync.sync_file(tmp_path),
+                None => Ok(()),
+            }
         })?;
 
-        if let Err(e) = fix_since(&path, &bytes) {
-            warn!("{}", e);
+        if added {
+            self.stored.fetch_add(1, Ordering::Relaxed);
         }
 
-        Ok(())
+        // Never write through an existing link into the store, if we fall
+        // back to writing the object below.
+        if path.symlink_metadata().is_ok() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("Cannot remove existing file {}", path.display()))?;
+        }
+        file_ops::create_parent_dir(path)?;
+        match std::fs::hard_link(&stored, path) {
+            Ok(()) => {
+                self.linked.fetch_add(1, Ordering::Relaxed);
+                Ok(true)
+            }
+            Err(e) => {
+                debug!(
+                    "Cannot hard-link {} to {}, will write it instead. Error: {}",
+                    stored.display(),
+                    path.display(),
+                    e
+                );
+                Ok(false)
+            }
+        }
     }
 }
 
@@ -362,7 +3662,7 @@ fn fix_since(path: &Path, data: &[u8]) -> Result<()> {
     .map_err(|_| anyhow!("Cannot parse object at: {} to derive mtime", path_str))?;
 
     let mtime = FileTime::from_unix_time(time.timestamp(), 0);
-    set_file_mtime(&path, mtime).map_err(|e| {
+    set_file_mtime(path, mtime).map_err(|e| {
         anyhow!(
             "Cannot modify mtime for object at: {}, error: {}",
             path_str,
@@ -380,25 +3680,57 @@ mod tests {
     use std::{
         fs,
         path::{Path, PathBuf},
+        str::FromStr,
     };
 
-    use crate::util::test_with_dir;
+    use crate::config::create_test_config;
+    use crate::rrdp::RrdpStateBuilder;
+    use crate::util::{https, test_with_dir};
+
+    use super::*;
+
+    #[test]
+    fn classify_objects_by_extension() {
+        let object_type = |uri: &str| ObjectType::from_uri(&rpki::uri::Rsync::from_str(uri).unwrap());
+        assert_eq!(object_type("rsync://host/repo/ta/ta.cer"), ObjectType::Cer);
+        assert_eq!(object_type("rsync://host/repo/ta/0/ta.CRL"), ObjectType::Crl);
+        assert_eq!(object_type("rsync://host/repo/ta/0/ta.mft"), ObjectType::Mft);
+        assert_eq!(object_type("rsync://host/repo/ca/0/AS65000.roa"), ObjectType::Roa);
+        assert_eq!(object_type("rsync://host/repo/ca/0/AS65000.asa"), ObjectType::Asa);
+        assert_eq!(object_type("rsync://host/repo/ca/0/contact.gbr"), ObjectType::Gbr);
+        assert_eq!(object_type("rsync://host/repo/ca/0/readme.txt"), ObjectType::Other);
+        assert_eq!(object_type("rsync://host/repo.d/ca/README"), ObjectType::Other);
 
-    use super::RsyncFromSnapshotWriter;
+        test_with_dir("rsync_classify_objects_by_extension", |dir| {
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let objects = read_snapshot_objects(Path::new("./test-resources/rrdp-rev2656/e9be21e7-c537-4564-b742-64700978c6b4/2656/snapshot.xml"), &config).unwrap();
+            let counts = ObjectType::count(objects.iter().map(CurrentObject::object_type));
+            assert_eq!(counts.values().sum::<usize>(), objects.len());
+            assert!(counts[&ObjectType::Roa] > 0);
+            assert!(!counts.contains_key(&ObjectType::Other));
+        });
+    }
 
     #[test]
     fn write_rsync_from_snapshot() {
         test_with_dir("write_rsync_from_snapshot", |dir| {
             let snapshot_path = PathBuf::from("./test-resources/rrdp-rev2658/e9be21e7-c537-4564-b742-64700978c6b4/2658/rnd-sn/snapshot.xml");
 
-            let out_path = dir.join("rsync");
-            let include_host_and_module = false;
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
 
-            let mut writer = RsyncFromSnapshotWriter {
-                out_path,
-                include_host_and_module,
-            };
-            writer.for_snapshot_path(&snapshot_path).unwrap();
+            let out_path = dir.join("rsync");
+            let objects = read_snapshot_objects(&snapshot_path, &config).unwrap();
+            write_rsync_content(&out_path, &objects, None, &config).unwrap();
 
             fn check_mtime(dir: &Path, path: &str, timestamp: i64) {
                 let path = dir.join(path);
@@ -428,4 +3760,616 @@ mod tests {
             check_mtime(&dir, "rsync/Acme-Corp-Intl/3/AS40224.roa", 1620657233);
         });
     }
+
+    #[test]
+    fn write_rsync_with_normalized_mtime() {
+        test_with_dir("rsync_write_rsync_with_normalized_mtime", |dir| {
+            let snapshot_path = PathBuf::from("./test-resources/rrdp-rev2658/e9be21e7-c537-4564-b742-64700978c6b4/2658/rnd-sn/snapshot.xml");
+
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.normalize_mtime = Some(1_500_000_000);
+
+            let out_path = dir.join("rsync");
+            let objects = read_snapshot_objects(&snapshot_path, &config).unwrap();
+            write_rsync_content(&out_path, &objects, None, &config).unwrap();
+
+            let mtime = |path: &Path| {
+                FileTime::from_last_modification_time(&fs::metadata(path).unwrap()).unix_seconds()
+            };
+            for path in file_ops::files_recursive(&out_path).unwrap() {
+                assert_eq!(mtime(&path), 1_500_000_000, "{}", path.display());
+            }
+            for path in file_ops::dirs_depth_first(&out_path).unwrap() {
+                assert_eq!(mtime(&path), 1_500_000_000, "{}", path.display());
+            }
+        });
+    }
+
+    #[test]
+    fn write_module_readme_from_template() {
+        test_with_dir("rsync_write_module_readme_from_template", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+
+            let template = dir.join("README.txt");
+            fs::write(&template, "Mirror of session {session}, serial {serial}\n").unwrap();
+            config.rsync_module_readme = Some(template);
+
+            let out_path = dir.join("rsync");
+            fs::create_dir_all(&out_path).unwrap();
+            write_module_readme(&out_path, Uuid::from_u128(1), 2658, &config).unwrap();
+
+            assert_eq!(
+                fs::read_to_string(out_path.join("README.txt")).unwrap(),
+                "Mirror of session 00000000-0000-0000-0000-000000000001, serial 2658\n"
+            );
+        });
+    }
+
+    #[test]
+    fn collect_write_errors() {
+        test_with_dir("rsync_collect_write_errors", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.write_error_mode = WriteErrorMode::Collect;
+
+            let object = |name: &str| {
+                let uri = format!("rsync://localhost/repo/ta/0/{}", name);
+                let uri = rpki::uri::Rsync::from_str(&uri).unwrap();
+                CurrentObject::new(uri, Bytes::from_static(b"original"))
+            };
+            let objects = [object("a.cer"), object("b.cer"), object("c.cer")];
+
+            // Dirs in the way of two of the files
+            let out_path = dir.join("rsync");
+            fs::create_dir_all(out_path.join("ta/0/a.cer")).unwrap();
+            fs::create_dir_all(out_path.join("ta/0/c.cer")).unwrap();
+            let err = write_rsync_content(&out_path, &objects, None, &config).unwrap_err();
+            let msg = err.to_string();
+            assert!(msg.contains("Could not write 2 of 3 objects"));
+            assert!(msg.contains("a.cer") && msg.contains("c.cer"));
+            assert!(out_path.join("ta/0/b.cer").exists());
+        });
+    }
+
+    #[test]
+    fn refuse_oversized_objects() {
+        test_with_dir("rsync_refuse_oversized_objects", |dir| {
+            let snapshot_path = PathBuf::from("./test-resources/rrdp-rev2658/e9be21e7-c537-4564-b742-64700978c6b4/2658/rnd-sn/snapshot.xml");
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            let objects = read_snapshot_objects(&snapshot_path, &config).unwrap();
+            let largest = objects.iter().max_by_key(|o| o.data().len()).unwrap();
+
+            config.max_object_bytes = largest.data().len() as u64 - 1;
+            let err = read_snapshot_objects(&snapshot_path, &config).unwrap_err();
+            let msg = format!("{:#}", err);
+            assert!(msg.contains(largest.uri().as_str()), "{}", msg);
+            assert!(msg.contains(&format!("has {} bytes", largest.data().len())), "{}", msg);
+
+            config.oversized_objects = OversizedObjectAction::Skip;
+            let read = read_snapshot_objects(&snapshot_path, &config).unwrap();
+            assert!(read.len() < objects.len());
+            assert!(read.iter().all(|o| o.data().len() as u64 <= config.max_object_bytes));
+            assert!(!read.iter().any(|o| o.uri() == largest.uri()));
+        });
+    }
+
+    #[test]
+    fn detect_path_collisions() {
+        test_with_dir("rsync_detect_path_collisions", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+
+            // Without a path prefix, the module is not part of the path
+            let object = |module: &str, data: &'static [u8]| {
+                let uri = format!("rsync://localhost/{}/ta/0/a.cer", module);
+                let uri = rpki::uri::Rsync::from_str(&uri).unwrap();
+                CurrentObject::new(uri, Bytes::from_static(data))
+            };
+            let identical = [object("repo", b"same"), object("other", b"same")];
+            let different = [object("repo", b"first"), object("other", b"second")];
+
+            let out_path = dir.join("identical");
+            write_rsync_content(&out_path, &identical, None, &config).unwrap();
+            assert_eq!(fs::read(out_path.join("ta/0/a.cer")).unwrap(), b"same");
+
+            let out_path = dir.join("abort");
+            let err = write_rsync_content(&out_path, &different, None, &config).unwrap_err();
+            let msg = err.to_string();
+            assert!(msg.contains("rsync://localhost/other/ta/0/a.cer"), "{}", msg);
+            assert!(msg.contains("rsync://localhost/repo/ta/0/a.cer"), "{}", msg);
+            assert!(!out_path.join("ta/0/a.cer").exists());
+
+            // The object with the lowest URI is written
+            config.on_path_collision = PathCollisionAction::Warn;
+            let out_path = dir.join("warn");
+            write_rsync_content(&out_path, &different, None, &config).unwrap();
+            assert_eq!(fs::read(out_path.join("ta/0/a.cer")).unwrap(), b"second");
+        });
+    }
+
+    #[test]
+    fn update_without_shadowed_objects() {
+        test_with_dir("rsync_update_without_shadowed_objects", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            config.on_path_collision = PathCollisionAction::Warn;
+            config.rsync_write_manifest = true;
+
+            // Without a path prefix, the second object maps to the same file
+            let state = RrdpStateBuilder::new(Uuid::from_u128(1), 1)
+                .object("rsync://localhost/other/ta/ta.cer", b"other")
+                .object("rsync://localhost/repo/ta/ta.cer", b"repo")
+                .object("rsync://localhost/repo/ta/0/ta.crl", b"crl")
+                .build(&config)
+                .unwrap();
+            let summary = update_from_rrdp_state(&state, true, &config).unwrap();
+            assert_eq!(summary.objects_written, 2);
+            assert!(summary
+                .written_objects
+                .iter()
+                .all(|o| o.uri != "rsync://localhost/repo/ta/ta.cer"));
+            assert_eq!(fs::read(dir.join("rsync/current/ta/ta.cer")).unwrap(), b"other");
+
+            let rsync_state = RsyncDirState::recover(&config).unwrap();
+            let current = rsync_state.current.unwrap();
+            assert_eq!(current.object_count, Some(2));
+            assert!(!current.objects.unwrap().contains_key("rsync://localhost/repo/ta/ta.cer"));
+
+            let manifest_path = dir.join("rsync/current").join(config::RSYNC_MANIFEST_FNAME);
+            let manifest = RevisionManifest::read(&manifest_path).unwrap().into_hashes().unwrap();
+            assert_eq!(manifest.len(), 2);
+            assert!(!manifest.contains_key("rsync://localhost/repo/ta/ta.cer"));
+
+            assert!(verify_current(&state, &config).unwrap().is_ok());
+        });
+    }
+
+    #[test]
+    fn update_from_built_rrdp_state() {
+        test_with_dir("rsync_update_from_built_rrdp_state", |dir| {
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let session_id = Uuid::from_u128(1);
+
+            let state = RrdpStateBuilder::new(session_id, 1)
+                .object("rsync://localhost/repo/ta/ta.cer", b"cer")
+                .object("rsync://localhost/repo/ta/0/ta.crl", b"crl")
+                .object("rsync://localhost/repo/ta/0/ta.mft", b"mft")
+                .build(&config)
+                .unwrap();
+            let summary = update_from_rrdp_state(&state, true, &config).unwrap();
+            assert_eq!(summary.objects_written, 3);
+            assert_eq!(fs::read(dir.join("rsync/current/ta/0/ta.crl")).unwrap(), b"crl");
+
+            let state = RrdpStateBuilder::new(session_id, 2)
+                .object("rsync://localhost/repo/ta/ta.cer", b"cer")
+                .object("rsync://localhost/repo/ta/0/ta.crl", b"new crl")
+                .object("rsync://localhost/repo/ta/0/ta.roa", b"roa")
+                .build(&config)
+                .unwrap();
+            let summary = update_from_rrdp_state(&state, true, &config).unwrap();
+            let changes = ObjectChanges {
+                added: 1,
+                removed: 1,
+                changed: 1,
+            };
+            assert_eq!(summary.object_changes, Some(changes));
+            assert_eq!(fs::read(dir.join("rsync/current/ta/0/ta.crl")).unwrap(), b"new crl");
+            assert!(!dir.join("rsync/current/ta/0/ta.mft").exists());
+        });
+    }
+
+    #[test]
+    fn verify_after_skipping_identical_content() {
+        test_with_dir("rsync_verify_after_skipping_identical_content", |dir| {
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2656/",
+                false,
+            );
+            let session_id = Uuid::from_u128(1);
+            let build = |serial: u64, crl: &[u8]| {
+                RrdpStateBuilder::new(session_id, serial)
+                    .object("rsync://localhost/repo/ta/ta.cer", b"cer")
+                    .object("rsync://localhost/repo/ta/0/ta.crl", crl)
+                    .build(&config)
+                    .unwrap()
+            };
+
+            update_from_rrdp_state(&build(1, b"crl"), true, &config).unwrap();
+
+            // The current revision is kept for serial 2, it has the same content
+            let state = build(2, b"crl");
+            let summary = update_from_rrdp_state(&state, true, &config).unwrap();
+            assert_eq!(summary.objects_written, 0);
+            let report = verify_current(&state, &config).unwrap();
+            assert!(report.is_ok(), "{:?}", report);
+            assert_eq!(report.verified, 2);
+
+            // But content for a later serial is not
+            let err = verify_current(&build(3, b"new crl"), &config).unwrap_err();
+            assert!(err.to_string().contains("does not match"), "{}", err);
+        });
+    }
+
+    #[derive(Default)]
+    struct RecordingFsync {
+        events: Mutex<Vec<(&'static str, PathBuf)>>,
+    }
+
+    impl Fsync for RecordingFsync {
+        fn sync_file(&self, path: &Path) -> anyhow::Result<()> {
+            self.events.lock().unwrap().push(("file", path.to_path_buf()));
+            Ok(())
+        }
+
+        fn sync_dir(&self, path: &Path) -> anyhow::Result<()> {
+            self.events.lock().unwrap().push(("dir", path.to_path_buf()));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn fsync_files_before_dirs() {
+        test_with_dir("fsync_files_before_dirs", |dir| {
+            let snapshot_path = PathBuf::from("./test-resources/rrdp-rev2658/e9be21e7-c537-4564-b742-64700978c6b4/2658/rnd-sn/snapshot.xml");
+
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+
+            let out_path = dir.join("rsync");
+            let objects = read_snapshot_objects(&snapshot_path, &config).unwrap();
+            let fsync = RecordingFsync::default();
+            let writer = ObjectWriter::new(&out_path, None, &config);
+            writer.write_all(&objects, Some(&fsync)).unwrap();
+
+            let events = fsync.events.into_inner().unwrap();
+            let files = events.iter().filter(|(kind, _)| *kind == "file").count();
+            assert_eq!(files, objects.len());
+
+            // all files are synced before any directory, and the directory
+            // for the new revision itself is synced last.
+            let first_dir = events.iter().position(|(kind, _)| *kind == "dir").unwrap();
+            assert!(events[first_dir..].iter().all(|(kind, _)| *kind == "dir"));
+            assert_eq!(events.last().unwrap(), &("dir", out_path.clone()));
+
+            // sub-directories are synced before their parents
+            let dirs: Vec<&PathBuf> = events[first_dir..].iter().map(|(_, path)| path).collect();
+            for (idx, dir) in dirs.iter().enumerate() {
+                assert!(dirs[..idx].iter().all(|earlier| !dir.starts_with(earlier)));
+            }
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn fsync_object_store_shard_dirs() {
+        test_with_dir("fsync_object_store_shard_dirs", |dir| {
+            let snapshot_path = PathBuf::from("./test-resources/rrdp-rev2658/e9be21e7-c537-4564-b742-64700978c6b4/2658/rnd-sn/snapshot.xml");
+
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            let cas_dir = dir.join("cas");
+            config.rsync_cas_dir = Some(cas_dir.clone());
+            config.rsync_cas_shard_chars = 2;
+
+            let out_path = dir.join("rsync");
+            let objects = read_snapshot_objects(&snapshot_path, &config).unwrap();
+            let fsync = RecordingFsync::default();
+            let writer = ObjectWriter::new(&out_path, None, &config);
+            writer.write_all(&objects, Some(&fsync)).unwrap();
+
+            // Every shard dir with a new entry, and the store dir after them,
+            // is synced before the revision dir.
+            let events = fsync.events.into_inner().unwrap();
+            let synced_dirs: Vec<&PathBuf> =
+                events.iter().filter(|(kind, _)| *kind == "dir").map(|(_, path)| path).collect();
+            let store_idx = synced_dirs.iter().position(|path| *path == &cas_dir).unwrap();
+            let shard_dirs: Vec<PathBuf> = fs::read_dir(&cas_dir)
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            assert!(!shard_dirs.is_empty());
+            for shard_dir in &shard_dirs {
+                let idx = synced_dirs.iter().position(|path| *path == shard_dir).unwrap();
+                assert!(idx < store_idx);
+            }
+            assert_eq!(synced_dirs.last().unwrap(), &&out_path);
+        });
+    }
+
+    #[test]
+    fn clean_old_keeps_min_revisions() {
+        test_with_dir("clean_old_keeps_min_revisions", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.rsync_keep_min_revisions = 2;
+
+            let now = Time::from_timestamp(1_600_000_000);
+            let session_id = Uuid::from_u128(1);
+            let old = |serial: u64, seconds_ago: i64| DeprecatedRsyncRevision {
+                since: now.seconds_before(seconds_ago),
+                revision: RsyncRevision::new(session_id, serial, &config),
+            };
+
+            let mut state = RsyncDirState {
+                version: RSYNC_STATE_VERSION,
+                current: Some(RsyncRevision::new(session_id, 5, &config)),
+                old: vec![old(1, 100), old(2, 100), old(3, 100), old(4, 0)],
+                pending: None,
+                symlinks_supported: None,
+            };
+            for old in &state.old {
+                fs::create_dir_all(old.revision.path(&config)).unwrap();
+            }
+
+            // Revisions 3 and 4 are the most recent, and must be kept. Of the
+            // others only 1 and 2 are old enough to be removed.
+            assert_eq!(state.clean_old(&config, now).unwrap().len(), 2);
+            let serials: Vec<u64> = state.old.iter().map(|old| old.revision.serial).collect();
+            assert_eq!(serials, vec![3, 4]);
+            assert!(!RsyncRevision::new(session_id, 1, &config).path(&config).exists());
+            assert!(RsyncRevision::new(session_id, 3, &config).path(&config).exists());
+        });
+    }
+
+    #[test]
+    fn clean_old_revisions_of_previous_session() {
+        test_with_dir("clean_old_revisions_of_previous_session", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.rsync_keep_min_revisions = 3;
+
+            let now = Time::from_timestamp(1_600_000_000);
+            let old_session = Uuid::from_u128(1);
+            let new_session = Uuid::from_u128(2);
+            let old = |session_id: Uuid, serial: u64, seconds_ago: i64| DeprecatedRsyncRevision {
+                since: now.seconds_before(seconds_ago),
+                revision: RsyncRevision::new(session_id, serial, &config),
+            };
+
+            let mut state = RsyncDirState {
+                version: RSYNC_STATE_VERSION,
+                current: Some(RsyncRevision::new(new_session, 2, &config)),
+                old: vec![old(old_session, 1, 100), old(old_session, 2, 0), old(new_session, 1, 100)],
+                pending: None,
+                symlinks_supported: None,
+            };
+            for old in &state.old {
+                fs::create_dir_all(old.revision.path(&config)).unwrap();
+            }
+
+            // The last revision of the old session is kept until cleanup_after
+            // has passed, but the first does not count for the minimum.
+            assert_eq!(state.clean_old(&config, now).unwrap().len(), 1);
+            let kept: Vec<(Uuid, u64)> = state
+                .old
+                .iter()
+                .map(|old| (old.revision.session_id, old.revision.serial))
+                .collect();
+            assert_eq!(kept, vec![(old_session, 2), (new_session, 1)]);
+        });
+    }
+
+    #[test]
+    fn clean_old_retention_boundary() {
+        test_with_dir("clean_old_retention_boundary", |dir| {
+            let mut config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            config.cleanup_after = 600;
+
+            let deprecated_at = Time::from_timestamp(1_600_000_000);
+            let session_id = Uuid::from_u128(1);
+            let mut state = RsyncDirState {
+                version: RSYNC_STATE_VERSION,
+                current: Some(RsyncRevision::new(session_id, 2, &config)),
+                old: vec![DeprecatedRsyncRevision {
+                    since: deprecated_at,
+                    revision: RsyncRevision::new(session_id, 1, &config),
+                }],
+                pending: None,
+                symlinks_supported: None,
+            };
+            let path = RsyncRevision::new(session_id, 1, &config).path(&config);
+            fs::create_dir_all(&path).unwrap();
+
+            // One second before 'cleanup_after' has passed, it is kept
+            let just_before = Time::from_timestamp(1_600_000_599);
+            assert!(state.clean_old(&config, just_before).unwrap().is_empty());
+            assert!(path.exists());
+            assert_eq!(state.old.len(), 1);
+
+            // Exactly when 'cleanup_after' has passed, it is removed
+            let exactly = Time::from_timestamp(1_600_000_600);
+            assert_eq!(state.clean_old(&config, exactly).unwrap(), vec![path.clone()]);
+            assert!(!path.exists());
+            assert!(state.old.is_empty());
+        });
+    }
+
+    #[test]
+    fn match_dir_name_template() {
+        let template = config::DEFAULT_RSYNC_DIR_NAME_TEMPLATE;
+        assert!(dir_name_matches_template(
+            "session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+            template
+        ));
+        assert!(!dir_name_matches_template("session_foo_serial_2656", template));
+        assert!(!dir_name_matches_template(
+            "session_e9be21e7-c537-4564-b742-64700978c6b4_serial_",
+            template
+        ));
+        assert!(!dir_name_matches_template("current", template));
+
+        assert!(dir_name_matches_template("2656", "{serial}"));
+        assert!(dir_name_matches_template("rev-2656-1600000000", "rev-{serial}-{timestamp}"));
+        assert!(!dir_name_matches_template("rev-2656", "rev-{serial}-{timestamp}"));
+    }
+
+    #[test]
+    fn refuse_rsync_repo_path_outside_out_path() {
+        let out_path = Path::new("/var/lib/krill-sync/rsync/current");
+        let rsync = |s: &str| rpki::uri::Rsync::from_str(s).unwrap();
+
+        let uri = rsync("rsync://host/repo/ta/ta.cer");
+        let path = |prefix| make_rsync_repo_path(out_path, &uri, prefix).unwrap();
+        assert_eq!(path(RsyncPathPrefix::None), out_path.join("ta/ta.cer"));
+        assert_eq!(path(RsyncPathPrefix::Module), out_path.join("repo/ta/ta.cer"));
+        assert_eq!(path(RsyncPathPrefix::HostAndModule), out_path.join("host/repo/ta/ta.cer"));
+
+        // The host and module are not checked for dot segments by the rpki
+        // crate, but only matter if they are included in the path.
+        for uri in ["rsync://../repo/ta.cer", "rsync://host/../ta.cer"] {
+            let uri = rsync(uri);
+            assert!(make_rsync_repo_path(out_path, &uri, RsyncPathPrefix::None).is_ok());
+
+            let err =
+                make_rsync_repo_path(out_path, &uri, RsyncPathPrefix::HostAndModule).unwrap_err();
+            assert!(err.to_string().contains(uri.as_str()));
+        }
+    }
+
+    #[test]
+    fn parse_dir_name_values() {
+        let session_id = Uuid::parse_str("e9be21e7-c537-4564-b742-64700978c6b4").unwrap();
+        assert_eq!(
+            dir_name_values(
+                "session_e9be21e7-c537-4564-b742-64700978c6b4_serial_2656",
+                config::DEFAULT_RSYNC_DIR_NAME_TEMPLATE
+            ),
+            Some((Some(session_id), Some(2656)))
+        );
+        assert_eq!(
+            dir_name_values("rev-2656-1600000000", "rev-{serial}-{timestamp}"),
+            Some((None, Some(2656)))
+        );
+        assert_eq!(dir_name_values("rev-2656", "rev-{serial}-{timestamp}"), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn recover_from_corrupt_state() {
+        test_with_dir("rsync_recover_from_corrupt_state", |dir| {
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+
+            let session_id = Uuid::from_u128(1);
+            let old = RsyncRevision::new(session_id, 1, &config);
+            let current = RsyncRevision::new(session_id, 2, &config);
+            fs::create_dir_all(old.path(&config)).unwrap();
+            fs::create_dir_all(current.path(&config)).unwrap();
+            file_ops::create_symlink(&current.path(&config), &config.rsync_dir_current()).unwrap();
+
+            // A truncated state file, as if a crash happened while writing it
+            let state_path = config.rsync_state_path();
+            file_ops::write_buf(&state_path, b"{\"current\": {\"session_id\": ").unwrap();
+
+            let state = RsyncDirState::recover(&config).unwrap();
+            assert!(file_ops::path_with_extension(&state_path, "corrupt").exists());
+            assert!(!state_path.exists());
+
+            assert_eq!(state.current.unwrap().serial, 2);
+            assert_eq!(state.old.len(), 1);
+            assert_eq!(state.old[0].revision.serial, 1);
+            assert_eq!(state.old[0].revision.session_id, session_id);
+
+            // Persisting leaves no temporary file behind
+            RsyncDirState::empty().persist(&config).unwrap();
+            assert!(state_path.exists());
+            assert!(!file_ops::path_with_extension(&state_path, config::TMP_FILE_EXT).exists());
+        });
+    }
+
+    #[test]
+    fn upgrade_state_file_versions() {
+        test_with_dir("rsync_upgrade_state_file_versions", |dir| {
+            let config = create_test_config(
+                &dir,
+                https("https://krill-ui-dev.do.nlnetlabs.nl/rrdp/notification.xml"),
+                "./test-resources/rrdp-rev2658/",
+                false,
+            );
+            let state_path = config.rsync_state_path();
+
+            // State written before versioning
+            let session_id = Uuid::from_u128(1);
+            let mut state = RsyncDirState::empty();
+            state.current = Some(RsyncRevision::new(session_id, 2, &config));
+            let mut json = serde_json::to_value(&state).unwrap();
+            json.as_object_mut().unwrap().remove("version");
+            file_ops::write_buf(&state_path, json.to_string().as_bytes()).unwrap();
+
+            let recovered = RsyncDirState::recover(&config).unwrap();
+            assert_eq!(recovered, state);
+            recovered.persist(&config).unwrap();
+            let json: serde_json::Value =
+                serde_json::from_slice(&fs::read(&state_path).unwrap()).unwrap();
+            assert_eq!(json["version"], RSYNC_STATE_VERSION);
+
+            // A future version is refused, and the file is left alone
+            let mut json = json;
+            json["version"] = (RSYNC_STATE_VERSION + 1).into();
+            file_ops::write_buf(&state_path, json.to_string().as_bytes()).unwrap();
+            let err = RsyncDirState::recover(&config).unwrap_err();
+            assert!(err.to_string().contains("newer version of krill-sync"), "{}", err);
+            assert!(state_path.exists());
+            assert!(!file_ops::path_with_extension(&state_path, "corrupt").exists());
+        });
+    }
 }
@@ -0,0 +1,312 @@
+use std::{
+    fmt,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    str::FromStr,
+};
+
+use anyhow::{anyhow, Context, Result};
+use tracing::{debug, info};
+
+use crate::file_ops;
+
+//------------ WriteTarget ---------------------------------------------------
+
+/// Where the rsync revisions are served from. Revisions are always written
+/// to the local rsync dir first. A target can then make each complete
+/// revision available elsewhere, and make it current there.
+pub trait WriteTarget: fmt::Debug {
+    /// Makes the complete revision at `local_dir` available at the target,
+    /// under `dir_name`.
+    fn upload_revision(&self, local_dir: &Path, dir_name: &str) -> Result<()>;
+
+    /// Makes the uploaded revision with this `dir_name` current.
+    fn make_current(&self, dir_name: &str) -> Result<()>;
+
+    /// Removes an old revision from the target. This is called before the
+    /// local copy at `local_dir` is removed.
+    fn remove_revision(&self, local_dir: &Path, dir_name: &str) -> Result<()>;
+}
+
+//------------ LocalTarget ---------------------------------------------------
+
+/// The local rsync dir, which is served as is. There is nothing left to do
+/// once a revision is written and swapped there.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LocalTarget;
+
+impl WriteTarget for LocalTarget {
+    fn upload_revision(&self, _local_dir: &Path, _dir_name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn make_current(&self, _dir_name: &str) -> Result<()> {
+        Ok(())
+    }
+
+    fn remove_revision(&self, _local_dir: &Path, _dir_name: &str) -> Result<()> {
+        Ok(())
+    }
+}
+
+//------------ SftpTarget ----------------------------------------------------
+
+/// A remote dir which is accessed with the OpenSSH sftp client in batch
+/// mode, so authentication must work without a prompt, e.g. using a key
+/// and the ssh config of the user.
+///
+/// The remote dir gets the same layout as the local rsync dir: a dir per
+/// revision, and a 'current' symlink. A revision is uploaded under a
+/// temporary name and renamed when complete. The symlink is replaced by
+/// renaming a new symlink over it, which is atomic if the server supports
+/// the posix-rename extension, as OpenSSH does. Otherwise the rename fails.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SftpTarget {
+    user: Option<String>,
+    host: String,
+    port: Option<u16>,
+    path: String,
+    program: String,
+}
+
+impl SftpTarget {
+    /// Returns the target for the sub dir with this name, e.g. for a module.
+    pub fn join(&self, name: &str) -> Self {
+        SftpTarget {
+            path: format!("{}/{}", self.path.trim_end_matches('/'), name),
+            ..self.clone()
+        }
+    }
+
+    fn destination(&self) -> String {
+        match &self.user {
+            Some(user) => format!("{}@{}", user, self.host),
+            None => self.host.clone(),
+        }
+    }
+
+    /// Runs the batch commands, after changing to the remote dir. Commands
+    /// which start with a '-' may fail without aborting the batch. The
+    /// remote dir is created if needed, e.g. the dir of a new module, but
+    /// its parent must exist.
+    fn run_batch(&self, commands: &[String]) -> Result<()> {
+        let mut batch = format!("-mkdir {0}\ncd {0}\n", quote(&self.path));
+        for command in commands {
+            batch.push_str(command);
+            batch.push('\n');
+        }
+        debug!("Running sftp batch for {}:\n{}", self, batch);
+
+        let mut sftp = Command::new(&self.program);
+        sftp.arg("-b").arg("-");
+        if let Some(port) = self.port {
+            sftp.arg("-P").arg(port.to_string());
+        }
+        let mut child = sftp
+            .arg(self.destination())
+            .stdin(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Could not run {} for {}", self.program, self))?;
+
+        child
+            .stdin
+            .take()
+            .ok_or_else(|| anyhow!("No stdin for {}", self.program))?
+            .write_all(batch.as_bytes())
+            .with_context(|| format!("Could not send batch to {} for {}", self.program, self))?;
+
+        let status = child
+            .wait()
+            .with_context(|| format!("Could not run {} for {}", self.program, self))?;
+        if status.success() {
+            Ok(())
+        } else {
+            Err(anyhow!("sftp batch for {} failed with {}", self, status))
+        }
+    }
+}
+
+impl WriteTarget for SftpTarget {
+    fn upload_revision(&self, local_dir: &Path, dir_name: &str) -> Result<()> {
+        info!("Uploading rsync revision {} to {}", dir_name, self);
+        let tmp_name = format!("{}.tmp", dir_name);
+        self.run_batch(&[
+            format!("put -rp {} {}", quote(&local_dir.to_string_lossy()), quote(&tmp_name)),
+            format!("rename {} {}", quote(&tmp_name), quote(dir_name)),
+        ])
+    }
+
+    fn make_current(&self, dir_name: &str) -> Result<()> {
+        info!("Updating symlink 'current' to '{}' at {}", dir_name, self);
+        self.run_batch(&[
+            format!("-rm {}", quote("current.tmp")),
+            format!("symlink {} {}", quote(dir_name), quote("current.tmp")),
+            format!("rename {} {}", quote("current.tmp"), quote("current")),
+        ])
+    }
+
+    fn remove_revision(&self, local_dir: &Path, dir_name: &str) -> Result<()> {
+        info!("Removing rsync revision {} from {}", dir_name, self);
+
+        // There is no recursive remove in sftp, but the remote revision has
+        // the same files and dirs as the local copy.
+        let remote = |path: &Path| -> Result<String> {
+            let rel = path.strip_prefix(local_dir).with_context(|| {
+                format!("{} is not in {}", path.display(), local_dir.display())
+            })?;
+            if rel.as_os_str().is_empty() {
+                Ok(dir_name.to_string())
+            } else {
+                Ok(Path::new(dir_name).join(rel).to_string_lossy().to_string())
+            }
+        };
+
+        let mut commands = vec![];
+        for file in file_ops::files_recursive(local_dir)? {
+            commands.push(format!("-rm {}", quote(&remote(&file)?)));
+        }
+        for dir in file_ops::dirs_depth_first(local_dir)? {
+            commands.push(format!("-rmdir {}", quote(&remote(&dir)?)));
+        }
+        self.run_batch(&commands)
+    }
+}
+
+impl fmt::Display for SftpTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "sftp://{}", self.destination())?;
+        if let Some(port) = self.port {
+            write!(f, ":{}", port)?;
+        }
+        write!(f, "{}", self.path)
+    }
+}
+
+impl FromStr for SftpTarget {
+    type Err = anyhow::Error;
+
+    /// Parses sftp://[user@]host[:port]/path
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || anyhow!("Expected 'sftp://[user@]host[:port]/path', got: {}", s);
+
+        let rest = s.strip_prefix("sftp://").ok_or_else(invalid)?;
+        let (authority, path) = rest.split_at(rest.find('/').ok_or_else(invalid)?);
+        let (user, host_port) = match authority.rsplit_once('@') {
+            Some((user, host_port)) => (Some(user.to_string()), host_port),
+            None => (None, authority),
+        };
+        let (host, port) = match host_port.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port.parse::<u16>().map_err(|_| invalid())?)),
+            None => (host_port, None),
+        };
+        if host.is_empty() || path.len() < 2 || user.as_deref() == Some("") {
+            return Err(invalid());
+        }
+
+        Ok(SftpTarget {
+            user,
+            host: host.to_string(),
+            port,
+            path: path.to_string(),
+            program: "sftp".to_string(),
+        })
+    }
+}
+
+/// Quotes a path for an sftp batch command.
+fn quote(path: &str) -> String {
+    format!("\"{}\"", path.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::util::test_with_dir;
+
+    use super::*;
+
+    #[test]
+    fn parse_sftp_target() {
+        let target = SftpTarget::from_str("sftp://sync@rsync.example.net:2222/srv/rsync").unwrap();
+        assert_eq!(target.user.as_deref(), Some("sync"));
+        assert_eq!(target.host, "rsync.example.net");
+        assert_eq!(target.port, Some(2222));
+        assert_eq!(target.path, "/srv/rsync");
+        assert_eq!(target.to_string(), "sftp://sync@rsync.example.net:2222/srv/rsync");
+
+        let module = target.join("ripe");
+        assert_eq!(module.path, "/srv/rsync/ripe");
+        assert_eq!(module.to_string(), "sftp://sync@rsync.example.net:2222/srv/rsync/ripe");
+
+        let target = SftpTarget::from_str("sftp://rsync.example.net/srv/rsync/").unwrap();
+        assert_eq!(target.join("ripe").path, "/srv/rsync/ripe");
+
+        let target = SftpTarget::from_str("sftp://rsync.example.net/srv/rsync").unwrap();
+        assert_eq!(target.destination(), "rsync.example.net");
+        assert_eq!(target.port, None);
+
+        for invalid in [
+            "https://rsync.example.net/srv/rsync",
+            "sftp://rsync.example.net",
+            "sftp://rsync.example.net/",
+            "sftp://rsync.example.net:ssh/srv",
+            "sftp://@rsync.example.net/srv",
+        ] {
+            assert!(SftpTarget::from_str(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_sftp_batches() {
+        use std::os::unix::fs::PermissionsExt;
+
+        test_with_dir("target_run_sftp_batches", |dir| {
+            // A fake sftp client, which logs its arguments and the batch
+            let log = dir.join("sftp.log");
+            let program = dir.join("sftp");
+            std::fs::write(
+                &program,
+                format!("#!/bin/sh\necho \"$@\" >> {0}\ncat >> {0}\n", log.display()),
+            )
+            .unwrap();
+            std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+            let mut target = SftpTarget::from_str("sftp://sync@rsync.example.net/srv/rsync").unwrap();
+            target.program = program.to_string_lossy().to_string();
+
+            let revision = dir.join("rev");
+            file_ops::write_buf(&revision.join("ta/ta.cer"), b"cer").unwrap();
+
+            target.upload_revision(&revision, "serial_2").unwrap();
+            target.make_current("serial_2").unwrap();
+            target.remove_revision(&revision, "serial_1").unwrap();
+
+            let expected = format!(
+                "-b - sync@rsync.example.net\n\
+                 -mkdir \"/srv/rsync\"\n\
+                 cd \"/srv/rsync\"\n\
+                 put -rp \"{}\" \"serial_2.tmp\"\n\
+                 rename \"serial_2.tmp\" \"serial_2\"\n\
+                 -b - sync@rsync.example.net\n\
+                 -mkdir \"/srv/rsync\"\n\
+                 cd \"/srv/rsync\"\n\
+                 -rm \"current.tmp\"\n\
+                 symlink \"serial_2\" \"current.tmp\"\n\
+                 rename \"current.tmp\" \"current\"\n\
+                 -b - sync@rsync.example.net\n\
+                 -mkdir \"/srv/rsync\"\n\
+                 cd \"/srv/rsync\"\n\
+                 -rm \"serial_1/ta/ta.cer\"\n\
+                 -rmdir \"serial_1/ta\"\n\
+                 -rmdir \"serial_1\"\n",
+                revision.display()
+            );
+            assert_eq!(std::fs::read_to_string(&log).unwrap(), expected);
+
+            std::fs::write(&program, "#!/bin/sh\ncat > /dev/null\nexit 1\n").unwrap();
+            assert!(target.make_current("serial_2").is_err());
+        })
+    }
+}
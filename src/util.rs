@@ -1,4 +1,7 @@
-use std::fmt;
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+};
 
 use bytes::Bytes;
 use chrono::{Local, TimeZone};
@@ -19,9 +22,15 @@ impl Time {
     }
 
     pub fn seconds_ago(seconds: i64) -> Self {
-        let mut now = Self::now();
-        now.0 -= seconds;
-        now
+        Self::now().seconds_before(seconds)
+    }
+
+    pub fn from_timestamp(timestamp: i64) -> Self {
+        Time(timestamp)
+    }
+
+    pub fn seconds_before(self, seconds: i64) -> Self {
+        Time(self.0 - seconds)
     }
 
     pub fn timestamp(&self) -> i64 {
@@ -42,6 +51,113 @@ impl From<rpki::repository::x509::Time> for Time {
     }
 }
 
+/// Parses a duration in seconds from a bare number of seconds, or from a
+/// number with a unit: 's', 'm', 'h' or 'd', e.g. '12h'. Several of these
+/// can be combined, e.g. '1h30m'.
+pub fn parse_duration_seconds(s: &str) -> anyhow::Result<i64> {
+    let invalid = || {
+        anyhow::anyhow!("Invalid duration '{}', expected e.g. '3600', '30m', '12h' or '2d'", s)
+    };
+
+    if let Ok(seconds) = s.parse::<i64>() {
+        return if seconds >= 0 { Ok(seconds) } else { Err(invalid()) };
+    }
+
+    let mut total: i64 = 0;
+    let mut rest = s.trim();
+    if rest.is_empty() {
+        return Err(invalid());
+    }
+    while !rest.is_empty() {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        let number: i64 = rest[..digits].parse().map_err(|_| invalid())?;
+        let unit = match rest[digits..].chars().next() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => return Err(invalid()),
+        };
+        total = number
+            .checked_mul(unit)
+            .and_then(|seconds| total.checked_add(seconds))
+            .ok_or_else(invalid)?;
+        rest = &rest[digits + 1..];
+    }
+
+    Ok(total)
+}
+
+//----------------------------------------------------------------------------
+//------------ Environment Variables -----------------------------------------
+//----------------------------------------------------------------------------
+
+/// Expands references to environment variables in a path, see `expand_vars`.
+/// Paths which are not valid UTF-8 are returned as is.
+pub fn expand_env_path(path: &Path) -> anyhow::Result<PathBuf> {
+    match path.to_str() {
+        Some(s) => expand_vars(s, |name| std::env::var(name).ok()).map(PathBuf::from),
+        None => Ok(path.to_path_buf()),
+    }
+}
+
+/// Expands `${NAME}` and `$NAME` using the lookup function, which returns
+/// None for an unset variable. An unset variable is an error, unless the
+/// `${NAME:-default}` form is used, in which case the default is used if
+/// the variable is unset or empty. Use `$$` for a literal `$`.
+pub fn expand_vars<F>(s: &str, lookup: F) -> anyhow::Result<String>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    let is_name_char = |c: char| c.is_ascii_alphanumeric() || c == '_';
+    let unset = |name: &str| {
+        anyhow::anyhow!("Environment variable '{}' used in '{}' is not set", name, s)
+    };
+
+    let mut res = String::new();
+    let mut rest = s;
+    while let Some(idx) = rest.find('$') {
+        res.push_str(&rest[..idx]);
+        rest = &rest[idx + 1..];
+
+        if let Some(after) = rest.strip_prefix('$') {
+            res.push('$');
+            rest = after;
+        } else if let Some(after) = rest.strip_prefix('{') {
+            let end = after
+                .find('}')
+                .ok_or_else(|| anyhow::anyhow!("Unterminated '${{' in '{}'", s))?;
+            let (name, default) = match after[..end].split_once(":-") {
+                Some((name, default)) => (name, Some(default)),
+                None => (&after[..end], None),
+            };
+            if name.is_empty() || !name.chars().all(is_name_char) {
+                return Err(anyhow::anyhow!("Invalid variable name '{}' in '{}'", name, s));
+            }
+            match (lookup(name), default) {
+                (Some(value), Some(default)) if value.is_empty() => res.push_str(default),
+                (Some(value), _) => res.push_str(&value),
+                (None, Some(default)) => res.push_str(default),
+                (None, None) => return Err(unset(name)),
+            }
+            rest = &after[end + 1..];
+        } else {
+            let len = rest.find(|c: char| !is_name_char(c)).unwrap_or(rest.len());
+            if len == 0 {
+                // Not a reference, keep the '$'
+                res.push('$');
+            } else {
+                let name = &rest[..len];
+                res.push_str(&lookup(name).ok_or_else(|| unset(name))?);
+                rest = &rest[len..];
+            }
+        }
+    }
+    res.push_str(rest);
+
+    Ok(res)
+}
+
 //----------------------------------------------------------------------------
 //------------ Serde Support -------------------------------------------------
 //----------------------------------------------------------------------------
@@ -84,9 +200,6 @@ where
 //----------------------------------------------------------------------------
 //------------ Test Support --------------------------------------------------
 //----------------------------------------------------------------------------
-#[cfg(test)]
-use std::path::PathBuf;
-
 #[cfg(test)]
 const TEST_BASE_DIR: &str = "./test";
 
@@ -127,3 +240,47 @@ pub fn https(s: &str) -> rpki::uri::Https {
 
     rpki::uri::Https::from_str(s).unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn parse_durations() {
+        assert_eq!(parse_duration_seconds("3600").unwrap(), 3600);
+        assert_eq!(parse_duration_seconds("45s").unwrap(), 45);
+        assert_eq!(parse_duration_seconds("30m").unwrap(), 1800);
+        assert_eq!(parse_duration_seconds("12h").unwrap(), 43200);
+        assert_eq!(parse_duration_seconds("2d").unwrap(), 172800);
+        assert_eq!(parse_duration_seconds("1h30m").unwrap(), 5400);
+
+        for invalid in ["", "-1", "2w", "h", "1.5h", "12 h", "99999999999999999d"] {
+            let err = parse_duration_seconds(invalid).unwrap_err();
+            assert!(err.to_string().contains("Invalid duration"), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn expand_variables() {
+        let lookup = |name: &str| match name {
+            "RSYNC_ROOT" => Some("/srv/rsync".to_string()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        };
+        let expand = |s: &str| expand_vars(s, lookup);
+
+        assert_eq!(expand("${RSYNC_ROOT}/repo").unwrap(), "/srv/rsync/repo");
+        assert_eq!(expand("$RSYNC_ROOT/repo").unwrap(), "/srv/rsync/repo");
+        assert_eq!(expand("${UNSET:-/var/lib}/repo").unwrap(), "/var/lib/repo");
+        assert_eq!(expand("${EMPTY:-/var/lib}/repo").unwrap(), "/var/lib/repo");
+        assert_eq!(expand("${RSYNC_ROOT:-/var/lib}").unwrap(), "/srv/rsync");
+        assert_eq!(expand("/cost/$$5/$/x").unwrap(), "/cost/$5/$/x");
+        assert_eq!(expand("/no/vars").unwrap(), "/no/vars");
+
+        assert!(expand("${UNSET}/repo").unwrap_err().to_string().contains("'UNSET'"));
+        assert!(expand("$UNSET/repo").is_err());
+        assert!(expand("${RSYNC_ROOT/repo").is_err());
+        assert!(expand("${}/repo").is_err());
+    }
+}